@@ -1,9 +1,11 @@
 #[macro_use]
 extern crate criterion;
+#[macro_use]
+extern crate papyrus;
 
-use criterion::Criterion;
+use criterion::{BatchSize, Criterion};
 
-use papyrus::code::{SourceCode, Statement, StaticFiles, StmtGrp};
+use papyrus::code::{PersistedMap, SourceCode, Statement, StaticFiles, StmtGrp};
 
 fn pfh_compile_construct(c: &mut Criterion) {
     use papyrus::code::construct_source_code;
@@ -19,7 +21,7 @@ fn pfh_compile_construct(c: &mut Criterion) {
     .collect();
 
     c.bench_function("construct_source_code", move |b| {
-        b.iter(|| construct_source_code(&map, &linking, &StaticFiles::new()))
+        b.iter(|| construct_source_code(&map, &linking, &StaticFiles::new(), &PersistedMap::new()))
     });
 }
 
@@ -30,7 +32,44 @@ fn rustfmt(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, pfh_compile_construct, rustfmt);
+/// Evaluates `2+2` in a new REPL, compiling with `rustc` directly and with `cargo`.
+fn eval_cycle(c: &mut Criterion) {
+    use papyrus::prelude::*;
+
+    let mut group = c.benchmark_group("evaluate 2+2");
+    group.sample_size(10);
+
+    for &fast_path in &[true, false] {
+        let name = if fast_path {
+            "rustc fast path"
+        } else {
+            "cargo"
+        };
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let mut repl = repl!();
+                    repl.data
+                        .with_compilation_dir("target/bench/eval-cycle")
+                        .unwrap();
+                    repl.data.compile_cache_size = 0; // a cached library is not compiled
+                    repl.data.compile_options.fast_path = fast_path;
+                    repl.line_input("2+2");
+                    match repl.read() {
+                        ReadResult::Eval(repl) => repl,
+                        ReadResult::Read(_) => panic!("expecting to evaluate"),
+                    }
+                },
+                |repl| repl.eval(&mut ()),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, pfh_compile_construct, rustfmt, eval_cycle);
 criterion_main!(benches);
 
 fn src_code() -> SourceCode {
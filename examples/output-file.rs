@@ -15,6 +15,9 @@ fn main() {
     // alias the state in the variable name.
     let mut read = repl;
 
+    // capture what evaluated code prints into the output
+    read.data.capture_output = true;
+
     // as we want to update as input comes in,
     // we need to listen to output changes
     let rx = read.output_listen();
@@ -72,7 +75,7 @@ fn write_outoput_to_file(rx: Receiver) -> std::thread::JoinHandle<()> {
 
         for chg in rx.iter() {
             match chg {
-                OutputChange::CurrentLine(line) => {
+                OutputChange::CurrentLine(line) | OutputChange::Captured(_, line) => {
                     output.truncate(pos);
                     output.push_str(&line);
                     std::fs::write("repl-output.txt", &output).unwrap();
@@ -81,6 +84,7 @@ fn write_outoput_to_file(rx: Receiver) -> std::thread::JoinHandle<()> {
                     output.push('\n');
                     pos = output.len();
                 }
+                OutputChange::Progress(_) => (),
             }
         }
     })
@@ -102,13 +102,14 @@ fn write_output_to_stdout(rx: Receiver) -> std::thread::JoinHandle<()> {
 
         for chg in rx.iter() {
             match chg {
-                OutputChange::CurrentLine(line) => {
+                OutputChange::CurrentLine(line) | OutputChange::Captured(_, line) => {
                     let mut lock = stdout.lock();
                     erase_console_line(&mut lock);
                     write!(&mut lock, "{}", line).unwrap();
                     lock.flush().unwrap();
                 }
                 OutputChange::NewLine => writeln!(&mut stdout, "").unwrap(),
+                OutputChange::Progress(_) => (), // compilation progress is not shown
             }
         }
     })
@@ -33,6 +33,25 @@
 //! to control how changes to `app_data` need to occur, especially by ensuring mutable access is
 //! harding to achieve.
 //!
+//! ## Async Mode
+//! The `async` command evaluates statements in an `async` block, so they can `.await`. `:async
+//! block-on` drives the block with a minimal executor, which has no reactor for IO or timers.
+//! A runtime's executor is used by giving its blocking function, such as `:async
+//! futures::executor::block_on`, with the runtime crate referenced through an `extern crate`
+//! input. `:async off` goes back to synchronous evaluation. Interrupting an evaluation drops its
+//! future. See [`Executor`](crate::compile::Executor).
+//!
+//! ## Types
+//! The `type` command shows the fully qualified type of an expression, such as `:type out3` or
+//! `:type a.iter()`. The expression is type checked after the current module's statements, and
+//! none of the module's code is run. Nothing is kept.
+//!
+//! ## History
+//! The `history` command, or `ls`, lists the inputs of the current module with the indices the
+//! `edit` commands take: the crates, the items with top placed ones marked, and the statement
+//! groups labelled with their `out#` binding and its type. `:history test/**` lists the modules
+//! matching a glob pattern. The listing is paged, `:history 2` shows the second page.
+//!
 //! ## Modules
 //! The `mod` command allows more than just the `lib` module to exist in the REPL. Use `mod` to have
 //! different REPL sessions all sharing the same compilation cycle. This can be useful to switch
@@ -59,6 +78,44 @@
 //! used. To recursively add files `**/*.rs` can be used. This applies to removing static files using
 //! the `rm` command.
 //!
+//! ## Dependencies
+//! The `dep` command manages the crates the REPL depends on. `:dep add serde@1.0 features=derive`
+//! adds `serde` to the current module, pinned to version `1.0` and with the `derive` feature. The
+//! other keys are `default-features=false`, `path=dir`, `git=url`, and `branch`, `tag`, or `rev`
+//! for a git source. A relative path is relative to the REPL working directory. `:dep rm serde`
+//! removes it again and `:dep ls` lists the dependencies.
+//!
+//! A dependency can also be specified inline with a `dep` attribute on an `extern crate`, such as
+//! `#[dep(version = "1.0", features = ["derive"])] extern crate serde;`. This also works at the
+//! beginning of static files. See [`Dependency`](crate::code::Dependency).
+//!
+//! ## Build Options
+//! The `build` command sets how the REPL crate is built. `:build profile release` builds with
+//! `--release`, `:build profile 3` builds the debug profile with `opt-level = 3`, and `:build
+//! profile debug` goes back to the default. `:build edition 2021` switches the Rust edition.
+//! `:build rustc-args -C target-cpu=native` passes extra arguments to `rustc`, with no arguments
+//! clearing them. `:build toolchain nightly` builds with a `rustup` toolchain, with no argument
+//! using the default. `:build ls` lists the options. See
+//! [`CompileOptions`](crate::compile::CompileOptions).
+//!
+//! Compiler warnings are off by default. `:build warnings new` shows the warnings in the input
+//! just entered, under the input, and `:build warnings all` shows the warnings in all inputs.
+//! `:build clippy on` adds clippy's lints to the warnings.
+//!
+//! When no crates are referenced the REPL crate is compiled with `rustc` directly, skipping
+//! `cargo`. `:build fast-path off` always compiles with `cargo`.
+//!
+//! ## Sessions
+//! The `session` command saves the REPL session to a file with `:session save path/to/file.json`,
+//! and replaces the current session with one loaded from a file with `:session load
+//! path/to/file.json`. A session is the inputs of all modules, static files, the linking state,
+//! executor and build options, and the editing state, so a session file can be handed over to
+//! reproduce a session. See [`ReplData::save_session`] for the file format.
+//!
+//! `:export path/to/dir` writes the session as a standalone Cargo project, so prototyped code can
+//! become a real crate. The `lib` statements are run in `fn main()`, and the statements of other
+//! modules are in a `pub fn run()` of that module. See [`ReplData::export`].
+//!
 //! # Extending Commands
 //! ## Setup
 //!
@@ -207,7 +264,11 @@
 //! custom-cmds-app [out2]: "hello, world!"
 //! ```
 use super::*;
-use crate::repl::{Editing, EditingIndex, ReplData};
+use crate::{
+    code::{CrateType, Dependency},
+    compile::{Edition, Executor, Profile, Warnings},
+    repl::{Editing, EditingIndex, ReplData},
+};
 use cmdtree::{BuildError, Builder, BuilderChain, Commander};
 use std::{
     fs,
@@ -242,6 +303,8 @@ pub enum CommandResult<D> {
     EditReplace(EditingIndex, String),
     /// Switch to a module.
     SwitchModule(PathBuf),
+    /// Show the type of an expression, type checked in the current module without being run.
+    TypeOf(String),
     /// Take an action on the `ReplData`.
     ActionOnReplData(ReplDataAction<D>),
     /// Take an action on data `D` and/or `ReplData`.
@@ -291,6 +354,31 @@ fn papyrus_cmdr<D>(
         .add_action("mut", "Begin a mutable block of code", |_, _| {
             CommandResult::BeginMutBlock
         })
+        .add_action(
+            "async",
+            "Evaluate statements in an async block. args: off, block-on, or an executor's blocking function",
+            |wtr, args| set_async(wtr, args),
+        )
+        .add_action(
+            "type",
+            "Show the type of an expression or out# binding. args: expr",
+            |wtr, args| type_of(wtr, args),
+        )
+        .add_action(
+            "history",
+            "List the inputs with their indices. args: [mod-path or glob pattern] [page]",
+            |wtr, args| history(wtr, args),
+        )
+        .add_action(
+            "ls",
+            "List the inputs with their indices. args: [mod-path or glob pattern] [page]",
+            |wtr, args| history(wtr, args),
+        )
+        .add_action(
+            "export",
+            "Export the session as a Cargo project. args: dir-path",
+            |wtr, args| export(wtr, args),
+        )
         .begin_class("edit", "Edit previous input")
         .begin_class("stmt", "Edit previous statements")
         .add_action(
@@ -330,6 +418,69 @@ fn papyrus_cmdr<D>(
         )
         .add_action("ls", "List imported static files", |_, _| ls_static_files())
         .end_class()
+        .begin_class("dep", "Handle crate dependencies")
+        .add_action(
+            "add",
+            "Add a dependency to the current module. args: name[@version] [features=a,b] [default-features=false] [path=dir] [git=url] [branch|tag|rev=ref]",
+            |wtr, args| add_dep(wtr, args),
+        )
+        .add_action(
+            "rm",
+            "Remove a dependency from the current module. args: name",
+            |wtr, args| rm_dep(wtr, args),
+        )
+        .add_action("ls", "List the dependencies of each module", |_, _| ls_deps())
+        .end_class()
+        .begin_class("build", "Set how the REPL crate is built")
+        .add_action(
+            "profile",
+            "Set the build profile. args: debug, release, or an opt-level 0-3, s, z",
+            |wtr, args| set_profile(wtr, args),
+        )
+        .add_action(
+            "edition",
+            "Set the Rust edition. args: 2018 or 2021",
+            |wtr, args| set_edition(wtr, args),
+        )
+        .add_action(
+            "rustc-args",
+            "Set extra rustc arguments, none clears them. args: rustc-args",
+            |_, args| set_rustc_args(args),
+        )
+        .add_action(
+            "toolchain",
+            "Set the rustup toolchain, none uses the default. args: toolchain-name",
+            |_, args| set_toolchain(args),
+        )
+        .add_action(
+            "warnings",
+            "Set the warnings shown. args: off, new, or all",
+            |wtr, args| set_warnings(wtr, args),
+        )
+        .add_action(
+            "clippy",
+            "Lint with clippy when warnings are shown. args: on or off",
+            |wtr, args| set_clippy(wtr, args),
+        )
+        .add_action(
+            "fast-path",
+            "Compile with rustc directly when no crates are referenced. args: on or off",
+            |wtr, args| set_fast_path(wtr, args),
+        )
+        .add_action("ls", "List the build options", |_, _| ls_build_options())
+        .end_class()
+        .begin_class("session", "Save and load REPL sessions")
+        .add_action(
+            "save",
+            "Save the session to a file. args: file-path",
+            |wtr, args| save_session(wtr, args),
+        )
+        .add_action(
+            "load",
+            "Load a session from a file, replacing the current session. args: file-path",
+            |wtr, args| load_session(wtr, args),
+        )
+        .end_class()
         .into_commander()
 }
 
@@ -470,6 +621,101 @@ fn clear_modules<D, W: Write>(args: &[&str], mut wtr: W) -> CommandResult<D> {
     }
 }
 
+// ------ HISTORY --------------------------------------------------------------
+/// The number of lines listed on each page of `:history`.
+const HISTORY_PAGE_LEN: usize = 30;
+
+fn history<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    let mut pattern = None;
+    let mut page = 1;
+    for arg in args {
+        match arg.parse::<usize>() {
+            Ok(n) if n > 0 => page = n,
+            _ => match glob::Pattern::new(arg) {
+                Ok(p) => pattern = Some(p),
+                Err(e) => {
+                    writeln!(wtr, "unrecognisable pattern: {}", e).ok();
+                    return CommandResult::Empty;
+                }
+            },
+        }
+    }
+
+    CommandResult::repl_data_fn(move |data, wtr| {
+        let lines = history_lines(data, pattern.as_ref());
+        let pages = (lines.len() + HISTORY_PAGE_LEN - 1) / HISTORY_PAGE_LEN;
+        if lines.is_empty() {
+            writeln!(wtr, "no inputs").ok();
+        } else if page > pages {
+            writeln!(wtr, "no page {}, the last page is {}", page, pages).ok();
+        } else {
+            let start = (page - 1) * HISTORY_PAGE_LEN;
+            for line in lines.iter().skip(start).take(HISTORY_PAGE_LEN) {
+                writeln!(wtr, "{}", line).ok();
+            }
+            if page < pages {
+                let pat = pattern.as_ref().map(|p| format!("{} ", p.as_str()));
+                writeln!(
+                    wtr,
+                    "page {}/{}, `:history {}{}` for the next",
+                    page,
+                    pages,
+                    pat.unwrap_or_default(),
+                    page + 1
+                )
+                .ok();
+            }
+        }
+        String::new()
+    })
+}
+
+/// List the inputs of the modules matching `pattern`, or the current module, under the module
+/// path. Each input is on one line, with the lines of multi-line inputs indented.
+fn history_lines<D>(data: &ReplData<D>, pattern: Option<&glob::Pattern>) -> Vec<String> {
+    let entry = |label: String, src: &str| {
+        let mut s = format!("  {}: ", label);
+        for (i, line) in src.lines().enumerate() {
+            if i > 0 {
+                s.push_str("\n    ");
+            }
+            s.push_str(line);
+        }
+        s
+    };
+
+    let mut lines = Vec::new();
+    let mods = data.mods_map().iter().filter(|(path, _)| match pattern {
+        Some(p) => p.matches_path(path),
+        None => path.as_path() == data.current_mod(),
+    });
+    for (path, src) in mods {
+        if src.crates.is_empty() && src.items.is_empty() && src.stmts.is_empty() {
+            continue;
+        }
+        lines.push(format!("[{}]", path.display()));
+        for (i, c) in src.crates.iter().enumerate() {
+            lines.push(entry(format!("crate {}", i), &c.src_line));
+        }
+        for (i, (item, top)) in src.items.iter().enumerate() {
+            let label = if *top {
+                format!("item {} (top)", i)
+            } else {
+                format!("item {}", i)
+            };
+            lines.push(entry(label, item));
+        }
+        for (i, grp) in src.stmts.iter().enumerate() {
+            let label = match data.out_type(path, i) {
+                Some(ty) => format!("stmt {} (out{}: {})", i, i, ty),
+                None => format!("stmt {} (out{})", i, i),
+            };
+            lines.push(entry(label, &grp.src_line()));
+        }
+    }
+    lines
+}
+
 // ------ STATIC FILES ---------------------------------------------------------
 fn add_static_file<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
     if let Some(&path) = args.get(0) {
@@ -530,6 +776,312 @@ fn ls_static_files<D>() -> CommandResult<D> {
     })
 }
 
+// ------ DEPENDENCIES ---------------------------------------------------------
+fn add_dep<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    match parse_dep(args) {
+        Ok(c) => CommandResult::repl_data_fn(move |data, _| {
+            let current = data.current_mod.clone();
+            let crates = &mut data
+                .mods_map
+                .get_mut(&current)
+                .expect("should exist")
+                .crates;
+            crates.retain(|x| x.cargo_name != c.cargo_name);
+            crates.push(c.clone());
+            format!("added dependency `{}`", c.cargo_name)
+        }),
+        Err(e) => {
+            writeln!(wtr, "{}", e).ok();
+            CommandResult::Empty
+        }
+    }
+}
+
+fn parse_dep(args: &[&str]) -> Result<CrateType, String> {
+    let (name, version) = match args.first() {
+        Some(arg) => match arg.find('@') {
+            Some(i) => (&arg[..i], Some(arg[i + 1..].to_string())),
+            None => (*arg, None),
+        },
+        None => return Err("add expects a crate name".to_string()),
+    };
+
+    let mut dep = Dependency {
+        version,
+        ..Dependency::default()
+    };
+
+    for arg in &args[1..] {
+        let (k, v) = match arg.find('=') {
+            Some(i) => (&arg[..i], arg[i + 1..].to_string()),
+            None => return Err(format!("expecting `key=value` but found `{}`", arg)),
+        };
+        match k {
+            "version" => dep.version = Some(v),
+            "path" => dep.path = Some(v),
+            "git" => dep.git = Some(v),
+            "branch" => dep.branch = Some(v),
+            "tag" => dep.tag = Some(v),
+            "rev" => dep.rev = Some(v),
+            "features" => dep.features = v.split(',').map(String::from).collect(),
+            "default-features" => {
+                dep.default_features = v.parse().map_err(|_| {
+                    format!("default-features expects true or false but found `{}`", v)
+                })?
+            }
+            _ => return Err(format!("unknown dependency key `{}`", k)),
+        }
+    }
+
+    Ok(CrateType::with_dep(name, dep))
+}
+
+fn rm_dep<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    if let Some(&name) = args.first() {
+        let name = name.replace('_', "-");
+        CommandResult::repl_data_fn(move |data, _| {
+            let current = data.current_mod.clone();
+            let crates = &mut data
+                .mods_map
+                .get_mut(&current)
+                .expect("should exist")
+                .crates;
+            let len = crates.len();
+            crates.retain(|x| x.cargo_name != name);
+            if crates.len() == len {
+                format!("no dependency `{}` in the current module", name)
+            } else {
+                format!("removed dependency `{}`", name)
+            }
+        })
+    } else {
+        writeln!(wtr, "rm expects a crate name").ok();
+        CommandResult::Empty
+    }
+}
+
+fn ls_deps<D>() -> CommandResult<D> {
+    CommandResult::repl_data_fn(|data, wtr| {
+        let mods = data
+            .mods_map()
+            .iter()
+            .map(|(k, v)| (k.as_path(), &v.crates));
+        let sfs = data
+            .static_files()
+            .iter()
+            .map(|x| (x.path.as_path(), &x.crates));
+        let mut empty = true;
+        for (path, crates) in mods.chain(sfs) {
+            for c in crates {
+                writeln!(wtr, "[{}] {}", path.display(), c.src_line).ok();
+                empty = false;
+            }
+        }
+        if empty {
+            writeln!(wtr, "no dependencies").ok();
+        }
+        String::new()
+    })
+}
+
+// ------ BUILD ----------------------------------------------------------------
+fn set_profile<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    match args.first().map(|x| x.parse::<Profile>()) {
+        Some(Ok(profile)) => CommandResult::repl_data_fn(move |data, _| {
+            let msg = format!("building with profile {}", profile);
+            data.compile_options.profile = profile.clone();
+            msg
+        }),
+        Some(Err(e)) => {
+            writeln!(wtr, "{}", e).ok();
+            CommandResult::Empty
+        }
+        None => {
+            writeln!(wtr, "profile expects debug, release, or an opt-level").ok();
+            CommandResult::Empty
+        }
+    }
+}
+
+fn set_edition<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    match args.first().map(|x| x.parse::<Edition>()) {
+        Some(Ok(edition)) => CommandResult::repl_data_fn(move |data, _| {
+            data.compile_options.edition = edition;
+            format!("building with edition {}", edition)
+        }),
+        Some(Err(e)) => {
+            writeln!(wtr, "{}", e).ok();
+            CommandResult::Empty
+        }
+        None => {
+            writeln!(wtr, "edition expects 2018 or 2021").ok();
+            CommandResult::Empty
+        }
+    }
+}
+
+fn set_rustc_args<D>(args: &[&str]) -> CommandResult<D> {
+    let args: Vec<String> = args.iter().map(|x| x.to_string()).collect();
+    CommandResult::repl_data_fn(move |data, _| {
+        data.compile_options.rustc_args = args.clone();
+        if args.is_empty() {
+            "cleared rustc arguments".to_string()
+        } else {
+            format!("building with rustc arguments `{}`", args.join(" "))
+        }
+    })
+}
+
+fn set_toolchain<D>(args: &[&str]) -> CommandResult<D> {
+    let toolchain = args.first().map(|x| x.trim_start_matches('+').to_string());
+    CommandResult::repl_data_fn(move |data, _| {
+        data.compile_options.toolchain = toolchain.clone();
+        match &toolchain {
+            Some(t) => format!("building with toolchain {}", t),
+            None => "building with the default toolchain".to_string(),
+        }
+    })
+}
+
+fn set_warnings<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    match args.first().map(|x| x.parse::<Warnings>()) {
+        Some(Ok(warnings)) => CommandResult::repl_data_fn(move |data, _| {
+            data.compile_options.warnings = warnings;
+            format!("showing warnings: {}", warnings)
+        }),
+        Some(Err(e)) => {
+            writeln!(wtr, "{}", e).ok();
+            CommandResult::Empty
+        }
+        None => {
+            writeln!(wtr, "warnings expects off, new, or all").ok();
+            CommandResult::Empty
+        }
+    }
+}
+
+fn set_clippy<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    let clippy = match args.first() {
+        Some(&"on") => true,
+        Some(&"off") => false,
+        _ => {
+            writeln!(wtr, "clippy expects on or off").ok();
+            return CommandResult::Empty;
+        }
+    };
+    CommandResult::repl_data_fn(move |data, _| {
+        data.compile_options.clippy = clippy;
+        if !clippy {
+            "clippy lints off".to_string()
+        } else if data.compile_options.warnings == Warnings::Off {
+            "clippy lints on, they are shown once warnings are on".to_string()
+        } else {
+            "clippy lints on".to_string()
+        }
+    })
+}
+
+fn set_fast_path<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    let fast_path = match args.first() {
+        Some(&"on") => true,
+        Some(&"off") => false,
+        _ => {
+            writeln!(wtr, "fast-path expects on or off").ok();
+            return CommandResult::Empty;
+        }
+    };
+    CommandResult::repl_data_fn(move |data, _| {
+        data.compile_options.fast_path = fast_path;
+        if fast_path {
+            "compiling with rustc when no crates are referenced".to_string()
+        } else {
+            "compiling with cargo".to_string()
+        }
+    })
+}
+
+fn ls_build_options<D>() -> CommandResult<D> {
+    CommandResult::repl_data_fn(|data, wtr| {
+        writeln!(wtr, "{}", data.compile_options).ok();
+        String::new()
+    })
+}
+
+// ------ SESSIONS -------------------------------------------------------------
+fn save_session<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    if let Some(&path) = args.first() {
+        let path = PathBuf::from(path);
+        CommandResult::repl_data_fn(move |data, _| match data.save_session(&path) {
+            Ok(()) => format!("saved session to `{}`", path.display()),
+            Err(e) => format!("failed to save session: {}", e),
+        })
+    } else {
+        writeln!(wtr, "save expects a file path").ok();
+        CommandResult::Empty
+    }
+}
+
+fn load_session<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    if let Some(&path) = args.first() {
+        let path = PathBuf::from(path);
+        CommandResult::repl_data_fn(move |data, _| match data.load_session(&path) {
+            Ok(()) => format!("loaded session from `{}`", path.display()),
+            Err(e) => format!("failed to load session: {}", e),
+        })
+    } else {
+        writeln!(wtr, "load expects a file path").ok();
+        CommandResult::Empty
+    }
+}
+
+fn set_async<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    let executor = match args {
+        [] => {
+            writeln!(
+                wtr,
+                "async expects off, block-on, or an executor's blocking function"
+            )
+            .ok();
+            return CommandResult::Empty;
+        }
+        ["off"] => None,
+        ["block-on"] => Some(Executor::BlockOn),
+        _ => Some(Executor::Custom(args.join(" "))),
+    };
+    CommandResult::repl_data_fn(move |data, _| match &executor {
+        Some(executor) => {
+            data.with_async(executor.clone());
+            format!("evaluating asynchronously with {}", executor)
+        }
+        None => {
+            data.with_sync();
+            "evaluating synchronously".to_string()
+        }
+    })
+}
+
+fn type_of<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    if args.is_empty() {
+        writeln!(wtr, "type expects an expression").ok();
+        CommandResult::Empty
+    } else {
+        CommandResult::TypeOf(args.join(" "))
+    }
+}
+
+fn export<D>(wtr: &mut dyn Write, args: &[&str]) -> CommandResult<D> {
+    if let Some(&dir) = args.first() {
+        let dir = PathBuf::from(dir);
+        CommandResult::repl_data_fn(move |data, _| match data.export(&dir) {
+            Ok(()) => format!("exported session to `{}`", dir.display()),
+            Err(e) => format!("failed to export session: {}", e),
+        })
+    } else {
+        writeln!(wtr, "export expects a directory path").ok();
+        CommandResult::Empty
+    }
+}
+
 fn foreach_glob_path<F>(glob: &str, wtr: &mut dyn Write, mut f: F)
 where
     F: FnMut(PathBuf, &mut dyn Write),
@@ -625,4 +1177,238 @@ mod tests {
         buf.clear();
         rm_static_file::<()>(&mut buf, &["what"]);
     }
+
+    #[test]
+    fn test_session_interface() {
+        let mut buf = Vec::new();
+        save_session::<()>(&mut buf, &[]);
+        assert_eq!(buf.as_slice(), &b"save expects a file path\n"[..]);
+
+        buf.clear();
+        load_session::<()>(&mut buf, &[]);
+        assert_eq!(buf.as_slice(), &b"load expects a file path\n"[..]);
+
+        let mut data = ReplData::<()>::default();
+        let r = match load_session::<()>(&mut buf, &["target/testing/no-session.json"]) {
+            CommandResult::ActionOnReplData(action) => action(&mut data, &mut buf),
+            _ => panic!("expecting an action on repl data"),
+        };
+        assert!(r.starts_with("failed to load session: an io error occurred: "));
+
+        buf.clear();
+        export::<()>(&mut buf, &[]);
+        assert_eq!(buf.as_slice(), &b"export expects a directory path\n"[..]);
+    }
+
+    #[test]
+    fn test_type_interface() {
+        let mut buf = Vec::new();
+        assert!(matches!(type_of::<()>(&mut buf, &[]), CommandResult::Empty));
+        assert_eq!(buf.as_slice(), &b"type expects an expression\n"[..]);
+
+        match type_of::<()>(&mut buf, &["a.iter().map(|x|", "x", "+", "1)"]) {
+            CommandResult::TypeOf(expr) => assert_eq!(expr, "a.iter().map(|x| x + 1)"),
+            _ => panic!("expecting a type of command"),
+        }
+    }
+
+    #[test]
+    fn test_history_interface() {
+        let mut buf = Vec::new();
+        assert!(matches!(
+            history::<()>(&mut buf, &["a***"]),
+            CommandResult::Empty
+        ));
+        assert!(String::from_utf8(buf)
+            .unwrap()
+            .starts_with("unrecognisable pattern: "));
+
+        let mut data = ReplData::<()>::default();
+        let mut buf = Vec::new();
+        let mut run = |r: CommandResult<()>, data: &mut ReplData<()>| match r {
+            CommandResult::ActionOnReplData(action) => action(data, &mut buf),
+            _ => panic!("expecting an action on repl data"),
+        };
+        run(history(&mut Vec::new(), &[]), &mut data);
+
+        let input = |s: &str| match crate::input::parse_program(s) {
+            crate::input::InputResult::Program(input) => input,
+            _ => panic!("expecting a program"),
+        };
+        let src = data.mods_map.get_mut(Path::new("lib")).unwrap();
+        src.crates = input("extern crate rand;").crates;
+        src.items = input("#![allow(unused)]").items;
+        src.items.extend(input("fn a() -> u8 {\n1\n}").items);
+        src.stmts
+            .push(crate::code::StmtGrp(input("let b = a();\nb").stmts));
+        src.stmts.push(crate::code::StmtGrp(input("b + 1").stmts));
+        let src = data.mods_map.entry(PathBuf::from("test/a")).or_default();
+        for i in 0..30 {
+            src.stmts
+                .push(crate::code::StmtGrp(input(&i.to_string()).stmts));
+        }
+
+        run(history(&mut Vec::new(), &[]), &mut data);
+        run(history(&mut Vec::new(), &["test/*"]), &mut data);
+        run(history(&mut Vec::new(), &["test/*", "2"]), &mut data);
+        run(history(&mut Vec::new(), &["test/*", "3"]), &mut data);
+
+        let mut ans = "no inputs
+[lib]
+  crate 0: extern crate rand;
+  item 0 (top): #![allow(unused)]
+  item 1: fn a() -> u8 {
+        1
+    }
+  stmt 0 (out0): let b = a(); b
+  stmt 1 (out1): b + 1
+[test/a]
+"
+        .to_string();
+        for i in 0..29 {
+            ans += &format!("  stmt {} (out{}): {}\n", i, i, i);
+        }
+        ans += "page 1/2, `:history test/* 2` for the next
+  stmt 29 (out29): 29
+no page 3, the last page is 2
+";
+        assert_eq!(String::from_utf8(buf).unwrap(), ans);
+    }
+
+    #[test]
+    fn test_async_interface() {
+        let mut buf = Vec::new();
+        set_async::<()>(&mut buf, &[]);
+        assert_eq!(
+            buf.as_slice(),
+            &b"async expects off, block-on, or an executor's blocking function\n"[..]
+        );
+
+        let mut data = ReplData::<()>::default();
+        let run = |r: CommandResult<()>, data: &mut ReplData<()>| match r {
+            CommandResult::ActionOnReplData(action) => action(data, &mut Vec::new()),
+            _ => panic!("expecting an action on repl data"),
+        };
+        let r = run(set_async(&mut buf, &["block-on"]), &mut data);
+        assert_eq!(r, "evaluating asynchronously with block-on");
+        assert_eq!(data.linking().executor, Some(Executor::BlockOn));
+        let r = run(
+            set_async(&mut buf, &["futures::executor::block_on"]),
+            &mut data,
+        );
+        assert_eq!(
+            r,
+            "evaluating asynchronously with futures::executor::block_on"
+        );
+        let r = run(set_async(&mut buf, &["off"]), &mut data);
+        assert_eq!(r, "evaluating synchronously");
+        assert_eq!(data.linking().executor, None);
+    }
+
+    #[test]
+    fn test_build_interface() {
+        let mut buf = Vec::new();
+        set_profile::<()>(&mut buf, &["fast"]);
+        set_edition::<()>(&mut buf, &[]);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "expecting debug, release, or an opt-level (0-3, s, z) but found `fast`\nedition expects 2018 or 2021\n"
+        );
+        let mut buf = Vec::new();
+        set_warnings::<()>(&mut buf, &["some"]);
+        set_clippy::<()>(&mut buf, &["yes"]);
+        set_fast_path::<()>(&mut buf, &[]);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "expecting off, new, or all but found `some`\nclippy expects on or off\nfast-path expects on or off\n"
+        );
+
+        let mut data = ReplData::<()>::default();
+        let mut buf = Vec::new();
+        let mut run = |r: CommandResult<()>, data: &mut ReplData<()>| match r {
+            CommandResult::ActionOnReplData(action) => action(data, &mut buf),
+            _ => panic!("expecting an action on repl data"),
+        };
+        let r = run(set_profile(&mut Vec::new(), &["3"]), &mut data);
+        assert_eq!(r, "building with profile opt-level 3");
+        let r = run(set_edition(&mut Vec::new(), &["2021"]), &mut data);
+        assert_eq!(r, "building with edition 2021");
+        run(set_rustc_args(&["-C", "target-cpu=native"]), &mut data);
+        let r = run(set_toolchain(&["+nightly"]), &mut data);
+        assert_eq!(r, "building with toolchain nightly");
+        assert_eq!(
+            data.compile_options,
+            crate::compile::CompileOptions {
+                profile: Profile::OptLevel("3".into()),
+                edition: Edition::E2021,
+                rustc_args: vec!["-C".into(), "target-cpu=native".into()],
+                toolchain: Some("nightly".into()),
+                ..Default::default()
+            }
+        );
+        let r = run(set_rustc_args(&[]), &mut data);
+        assert_eq!(r, "cleared rustc arguments");
+        run(set_toolchain(&[]), &mut data);
+        let r = run(set_clippy(&mut Vec::new(), &["on"]), &mut data);
+        assert_eq!(r, "clippy lints on, they are shown once warnings are on");
+        let r = run(set_warnings(&mut Vec::new(), &["new"]), &mut data);
+        assert_eq!(r, "showing warnings: new input");
+        let r = run(set_fast_path(&mut Vec::new(), &["off"]), &mut data);
+        assert_eq!(r, "compiling with cargo");
+        run(ls_build_options(), &mut data);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "profile: opt-level 3\nedition: 2021\nrustc args: none\ntoolchain: default\nwarnings: new input\nclippy: on\nfast path: off\n"
+        );
+    }
+
+    #[test]
+    fn test_dep_interface() {
+        let c = parse_dep(&["serde@1.0", "features=derive,std", "default-features=false"]).unwrap();
+        assert_eq!(&c.cargo_name, "serde");
+        assert_eq!(c.dep.version.as_deref(), Some("1.0"));
+        assert_eq!(c.dep.features, vec!["derive", "std"]);
+        assert!(!c.dep.default_features);
+
+        let c = parse_dep(&["my-crate", "path=../my_crate"]).unwrap();
+        assert_eq!(
+            &c.src_line,
+            r#"#[dep(path = "../my_crate")] extern crate my_crate;"#
+        );
+
+        assert_eq!(parse_dep(&[]).unwrap_err(), "add expects a crate name");
+        assert_eq!(
+            parse_dep(&["a", "b"]).unwrap_err(),
+            "expecting `key=value` but found `b`"
+        );
+        assert_eq!(
+            parse_dep(&["a", "foo=b"]).unwrap_err(),
+            "unknown dependency key `foo`"
+        );
+
+        let mut data = ReplData::<()>::default();
+        let mut buf = Vec::new();
+        let mut run = |r: CommandResult<()>, data: &mut ReplData<()>| match r {
+            CommandResult::ActionOnReplData(action) => action(data, &mut buf),
+            _ => panic!("expecting an action on repl data"),
+        };
+        let r = run(add_dep(&mut Vec::new(), &["rand@0.7"]), &mut data);
+        assert_eq!(r, "added dependency `rand`");
+        run(add_dep(&mut Vec::new(), &["rand@0.8"]), &mut data);
+        assert_eq!(data.current_src().crates.len(), 1);
+        assert_eq!(
+            data.current_src().crates[0].dep.version.as_deref(),
+            Some("0.8")
+        );
+        run(ls_deps(), &mut data);
+        let r = run(rm_dep(&mut Vec::new(), &["rand"]), &mut data);
+        assert_eq!(r, "removed dependency `rand`");
+        let r = run(rm_dep(&mut Vec::new(), &["rand"]), &mut data);
+        assert_eq!(r, "no dependency `rand` in the current module");
+        run(ls_deps(), &mut data);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "[lib] #[dep(version = \"0.8\")] extern crate rand;\nno dependencies\n"
+        );
+    }
 }
@@ -56,6 +56,604 @@ use std::{
 
 type ReturnRange = std::ops::Range<usize>;
 type ReturnRangeMap<'a> = fxhash::FxHashMap<&'a Path, ReturnRange>;
+type PlanMap<'a> = fxhash::FxHashMap<&'a Path, PersistPlan>;
+
+/// The crate root module used by the evaluation functions to restore and persist bindings.
+///
+/// The `Store` and `Value` types must match [`compile::StoreHandle`](crate::compile). The host
+/// holds the values opaquely, only the libraries know their types. A value is boxed twice so it
+/// crosses the `extern "C"` callbacks as a thin pointer, with the library's function to drop it.
+const PERSIST_MOD: &str = r#"
+#[doc(hidden)]
+pub mod papyrus_store {
+type Handle = *mut std::os::raw::c_void;
+type Boxed = Box<dyn std::any::Any + Send>;
+#[repr(C)]
+pub struct Value {
+ptr: Handle,
+drop: Option<unsafe extern "C" fn(Handle)>,
+}
+#[repr(C)]
+pub struct Store {
+values: Handle,
+take: unsafe extern "C" fn(Handle, *const u8, usize) -> Value,
+put: unsafe extern "C" fn(Handle, *const u8, usize, Value),
+note: unsafe extern "C" fn(Handle, *const u8, usize, *const u8, usize),
+}
+unsafe impl Send for Store {}
+unsafe extern "C" fn drop_value(ptr: Handle) {
+drop(Box::from_raw(ptr as *mut Boxed));
+}
+fn note(store: &mut Store, name: &str, text: &str) {
+unsafe { (store.note)(store.values, name.as_ptr(), name.len(), text.as_ptr(), text.len()) }
+}
+fn put_boxed(store: &mut Store, name: &str, value: Boxed) {
+let value = Value { ptr: Box::into_raw(Box::new(value)) as Handle, drop: Some(drop_value) };
+unsafe { (store.put)(store.values, name.as_ptr(), name.len(), value) }
+}
+pub fn take<T: 'static>(store: &mut Store, name: &str) -> Option<T> {
+let value = unsafe { (store.take)(store.values, name.as_ptr(), name.len()) };
+if value.ptr.is_null() {
+return None;
+}
+let value = unsafe { Box::from_raw(value.ptr as *mut Boxed) };
+value.downcast().ok().map(|x| *x)
+}
+pub fn put<T: Send + 'static>(store: &mut Store, name: &str, value: T) {
+put_boxed(store, name, Box::new(value));
+}
+pub fn rebuild(store: &mut Store) {
+note(store, "papyrus::rebuild", "");
+}
+pub fn typed<T>(store: &mut Store, value: T) -> T {
+note(store, "papyrus::type", std::any::type_name::<T>());
+value
+}
+pub fn ret<K>(store: &mut Store, value: K) -> std::result::Result<K, Box<dyn std::error::Error>> {
+note(store, "papyrus::returned", "");
+std::result::Result::Ok(value)
+}
+pub fn put_out<'s, T: Send + 'static>(store: &'s mut Store, name: &str, value: T) -> &'s T {
+let value = Box::new(typed(store, value));
+let shown: *const T = &*value;
+put_boxed(store, name, value);
+// the host does not move or drop the value while the store is borrowed
+unsafe { &*shown }
+}
+}
+"#;
+/// The crate root module converting the value of the last statement into a `Kserd`.
+///
+/// Autoref specialization picks the first representation the type implements, `ToKserd`, then
+/// `Debug`, then `Display`, then the type name. The fallbacks are marked with a [`Repr`] identity.
+/// A stored value is shown from a reference through `ShowRef`, which clones it for `ToKserd`.
+/// `papyrus_ret!` shows the value of an early return. Like the transport module, `kserd` is not
+/// imported, the persistent module code is added to the module.
+const SHOW_MOD: [&str; 2] = [
+    r#"
+#[doc(hidden)]
+pub mod papyrus_show {
+#[macro_export]
+macro_rules! papyrus_ret {
+($store:expr, $value:expr) => {
+$crate::papyrus_store::ret($store, { use $crate::papyrus_show::*; (&&&&Show::new($value)).show() })
+};
+}
+pub struct Show<T>(std::cell::Cell<Option<T>>);
+impl<T> Show<T> {
+pub fn new(value: T) -> Self {
+Show(std::cell::Cell::new(Some(value)))
+}
+fn take(&self) -> T {
+self.0.take().expect("a value is only shown once")
+}
+}
+fn repr(id: &'static str, value: String) -> kserd::Kserd<'static> {
+kserd::Kserd::with_id(id, kserd::Value::new_string(value)).expect("valid identity")
+}
+pub trait ViaKserd {
+fn show(self) -> kserd::Kserd<'static>;
+}
+impl<'a, T: kserd::ToKserd<'a>> ViaKserd for &&&&Show<T> {
+fn show(self) -> kserd::Kserd<'static> {
+kserd::ToKserd::into_kserd(self.take()).unwrap().into_owned()
+}
+}
+pub trait ViaDebug {
+fn show(self) -> kserd::Kserd<'static>;
+}
+impl<T: std::fmt::Debug> ViaDebug for &&&Show<T> {
+fn show(self) -> kserd::Kserd<'static> {
+repr("papyrus-debug", format!("{:?}", self.take()))
+}
+}
+pub trait ViaDisplay {
+fn show(self) -> kserd::Kserd<'static>;
+}
+impl<T: std::fmt::Display> ViaDisplay for &&Show<T> {
+fn show(self) -> kserd::Kserd<'static> {
+repr("papyrus-display", self.take().to_string())
+}
+}
+pub trait ViaTypeName {
+fn show(self) -> kserd::Kserd<'static>;
+}
+impl<T> ViaTypeName for &Show<T> {
+fn show(self) -> kserd::Kserd<'static> {
+repr("papyrus-type", std::any::type_name::<T>().to_string())
+}
+}
+pub struct ShowRef<'a, T>(pub &'a T);
+pub trait RefViaKserd {
+fn show(self) -> kserd::Kserd<'static>;
+}
+impl<'a, T: kserd::ToKserd<'a> + Clone> RefViaKserd for &&&&ShowRef<'_, T> {
+fn show(self) -> kserd::Kserd<'static> {
+kserd::ToKserd::into_kserd(self.0.clone()).unwrap().into_owned()
+}
+}
+pub trait RefViaDebug {
+fn show(self) -> kserd::Kserd<'static>;
+}
+impl<T: std::fmt::Debug> RefViaDebug for &&&ShowRef<'_, T> {
+fn show(self) -> kserd::Kserd<'static> {
+repr("papyrus-debug", format!("{:?}", self.0))
+}
+}
+pub trait RefViaDisplay {
+fn show(self) -> kserd::Kserd<'static>;
+}
+impl<T: std::fmt::Display> RefViaDisplay for &&ShowRef<'_, T> {
+fn show(self) -> kserd::Kserd<'static> {
+repr("papyrus-display", self.0.to_string())
+}
+}
+pub trait RefViaTypeName {
+fn show(self) -> kserd::Kserd<'static>;
+}
+impl<T> RefViaTypeName for &ShowRef<'_, T> {
+fn show(self) -> kserd::Kserd<'static> {
+repr("papyrus-type", std::any::type_name::<T>().to_string())
+}
+}
+"#,
+    "}\n",
+];
+/// Shows the value of the last statement through [`SHOW_MOD`], split around the statement index.
+///
+/// The traits must be in scope for the method call to resolve. The value's type is recorded in
+/// the store.
+const SHOW: [&str; 2] = [
+    "{ use crate::papyrus_show::*; (&&&&Show::new(crate::papyrus_store::typed(__papyrus_store, out",
+    "))).show() }\n",
+];
+/// Persists the `out#` binding of the last statement by moving it into the store, and shows it
+/// from the stored reference. Split around the binding name, which is repeated.
+const SHOW_OUT: [&str; 3] = [
+    "{ use crate::papyrus_show::*; (&&&&ShowRef(crate::papyrus_store::put_out(__papyrus_store, \"",
+    "\", ",
+    "))).show() }\n",
+];
+/// The evaluation functions return the encoded result of an inner closure, which catches panics.
+///
+/// Panics can not unwind over the `extern "C"` boundary. `catch` also records the panic's
+/// location and backtrace. The closure returns a `Result`, so the statements can use `?`.
+const EVAL_BEGIN: &str = ") -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+";
+const EVAL_END: &str = "})))\n}\n";
+/// Wraps the statements, the last of which is the value's `Kserd`.
+const EVAL_BODY: [&str; 2] = ["std::result::Result::Ok({\n", "})\n"];
+/// With an executor, the statements are wrapped in a cancellable `async` block passed to the
+/// executor.
+const ASYNC_BODY: [&str; 2] = [
+    "(crate::papyrus_async::Cancellable::new(async move {\n",
+    "}))\n",
+];
+/// The module driving and cancelling asynchronous evaluations, see
+/// [`compile::Executor`](crate::compile::Executor).
+const ASYNC_MOD: [&str; 2] = [
+    "#[doc(hidden)]\npub mod papyrus_async {\n",
+    "#[no_mangle]
+pub extern \"C\" fn papyrus_cancel() {
+cancel()
+}
+}
+",
+];
+/// The module encoding the results, see [`compile::transport`](crate::compile).
+const TRANSPORT_MOD: [&str; 2] = [
+    "#[doc(hidden)]\npub mod papyrus_transport {\n",
+    "#[no_mangle]
+pub extern \"C\" fn papyrus_free_output(output: Output) {
+output.free()
+}
+}
+",
+];
+/// Added to the transport module when evaluating in a worker process with app data.
+///
+/// The app data is decoded before, and encoded after, the evaluation function is run.
+const TRANSPORT_DATA: &str = r#"pub fn with_data<T, F>(data: &mut Data, mutable: bool, f: F) -> Output
+where
+T: kserd::encode::Serialize + for<'de> kserd::encode::Deserialize<'de>,
+F: FnOnce(&mut T) -> Output,
+{
+let app_data = match decode(data.bytes()) {
+Ok(Evaluated::Kserd(k)) => k.decode::<T>().map_err(|e| e.to_string()),
+Ok(Evaluated::Panic(e)) => Err(e.message),
+Ok(Evaluated::Error(e)) => Err(e.message),
+Err(_) => Err("malformed bytes".to_string()),
+};
+let mut app_data = match app_data {
+Ok(x) => x,
+Err(e) => return Output::new(Err(Panic::new(format!("failed to deserialize app data: {}", e)))),
+};
+let out = f(&mut app_data);
+if mutable {
+let k = kserd::Kserd::enc(&app_data).map_err(|e| Panic::new(format!("failed to serialize app data: {}", e)));
+data.out = Output::new(k);
+}
+out
+}
+"#;
+/// Fragments of the evaluation function taking serialized app data, used by a worker process.
+const DATA_FN: [&str; 5] = [
+    "#[no_mangle]\npub extern \"C\" fn ",
+    "_data(__papyrus_store: &mut crate::papyrus_store::Store, __papyrus_data: &mut crate::papyrus_transport::Data) -> crate::papyrus_transport::Output {\ncrate::papyrus_transport::with_data(__papyrus_data, ",
+    ", |app_data: &mut ",
+    "| ",
+    "(__papyrus_store, app_data))\n}\n",
+];
+/// Fragments of a type probe, see [`type_probe`].
+///
+/// The probe calls a closure (or drives a cancellable `async` block) like the evaluation
+/// function's, giving `return` and `?` their types. The statements and the expression are in a
+/// branch of it which is never taken and diverges, where the store and app data are diverging
+/// placeholders. They are only type checked, inferring the expression's type.
+const TYPE_PROBE: [&str; 5] = [
+    "#[no_mangle]\n#[allow(unreachable_code, unused)]\npub extern \"C\" fn ",
+    "(len: &mut usize) -> *const u8 {
+fn __papyrus_same<T>(_: &Option<std::marker::PhantomData<T>>, _: &T) {}
+fn __papyrus_name<T>(_: &Option<std::marker::PhantomData<T>>) -> &'static str { std::any::type_name::<T>() }
+let mut __papyrus_type = \"\";
+let __papyrus_type_ref = &mut __papyrus_type;
+",
+    "let __papyrus_ty = None;
+if false {
+let __papyrus_store: &mut crate::papyrus_store::Store = loop {};
+",
+    "__papyrus_same(&__papyrus_ty, &(\n",
+    "\n));\nloop {}\n}\n*__papyrus_type_ref = __papyrus_name(&__papyrus_ty);\nstd::result::Result::Ok(kserd::Kserd::new_unit())\n",
+];
+/// The closure of a synchronous type probe, and the driven `async` block of an asynchronous one.
+const TYPE_PROBE_BODY: [&str; 2] = [
+    "let _ = (|| -> crate::papyrus_transport::Eval {\n",
+    "})();\n",
+];
+const TYPE_PROBE_ASYNC_BODY: [&str; 2] = [
+    "let _ = crate::papyrus_async::block_on(crate::papyrus_async::Cancellable::new(async move {\n",
+    "}));\n",
+];
+const TYPE_PROBE_END: &str = "*len = __papyrus_type.len();\n__papyrus_type.as_ptr()\n}\n";
+/// The value store argument of the evaluation functions.
+const STORE_ARG: &str = "__papyrus_store: &mut crate::papyrus_store::Store";
+/// Fragments of restoring bindings from the value store.
+const RESTORE: [&str; 6] = [
+    "#[allow(unused_mut)]\nlet (",
+    ") = if false {\n",
+    ")\n} else {\nmatch (",
+    ") {\n(",
+    ") => (",
+    "),\n_ => {\ncrate::papyrus_store::rebuild(__papyrus_store);\nreturn std::result::Result::Ok(kserd::Kserd::new_str(\"rebuild\"));\n}\n}\n};\n",
+];
+const TAKE: &str = "crate::papyrus_store::take(__papyrus_store, \"";
+/// Fragments of persisting a binding into the value store.
+const PUT: [&str; 3] = [
+    "crate::papyrus_store::put(__papyrus_store, \"",
+    "\", ",
+    ");\n",
+];
+
+/// How the value of an evaluation is represented.
+///
+/// The value of the last statement is converted with `ToKserd`. Types which do not implement it
+/// fall back to a string of their `Debug` or `Display` formatting, or their type name, with an
+/// identity marking the fallback.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Repr {
+    /// Converted with `ToKserd`.
+    Kserd,
+    /// Formatted with `Debug`.
+    Debug,
+    /// Formatted with `Display`.
+    Display,
+    /// Only the type name, the type implements none of the above.
+    TypeName,
+}
+
+impl Repr {
+    const IDS: [(&'static str, Repr); 3] = [
+        ("papyrus-debug", Repr::Debug),
+        ("papyrus-display", Repr::Display),
+        ("papyrus-type", Repr::TypeName),
+    ];
+
+    /// The representation of an evaluation's value.
+    pub fn of(kserd: &::kserd::Kserd) -> Self {
+        Repr::IDS
+            .iter()
+            .find(|x| kserd.id() == Some(x.0))
+            .map(|x| x.1)
+            .unwrap_or(Repr::Kserd)
+    }
+}
+
+impl fmt::Display for Repr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Repr::Kserd => write!(f, "ToKserd"),
+            Repr::Debug => write!(f, "Debug"),
+            Repr::Display => write!(f, "Display"),
+            Repr::TypeName => write!(f, "type name"),
+        }
+    }
+}
+
+/// Maps the generated source code back to the REPL inputs that produced it.
+///
+/// Built alongside the source code in [`construct_source_code`], each statement and item records
+/// the lines it spans in the generated code. A line and column reported by `rustc` can then be
+/// resolved back to the module, statement or item, and column of the input.
+#[derive(Debug, Default)]
+pub struct SourceMap<'a> {
+    returns: ReturnRangeMap<'a>,
+    lines: Vec<LineOrigin<'a>>,
+    /// The 0-based lines which persist a binding into the value store.
+    persists: Vec<(usize, &'a Path, String)>,
+    /// Bytes of the buffer already counted for new lines.
+    scanned: usize,
+    /// The (0-based) line that `scanned` ends on.
+    line: usize,
+}
+
+#[derive(Debug)]
+struct LineOrigin<'a> {
+    /// The 0-based line of the generated code the input starts on.
+    line: usize,
+    /// The number of lines the input spans.
+    len: usize,
+    mod_path: &'a Path,
+    kind: SrcKind,
+    /// Number of characters prepended to the first line, such as `let out0 = `.
+    col_offset: usize,
+}
+
+/// The kind of input a location refers to.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SrcKind {
+    /// A statement, `grp` is the statement group index (the `out#`), `stmt` the index within the
+    /// group.
+    Stmt {
+        /// Statement group index.
+        grp: usize,
+        /// Statement index within the group.
+        stmt: usize,
+    },
+    /// An item at the given index.
+    Item(usize),
+}
+
+/// A location in the REPL input, resolved through a [`SourceMap`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SrcLocation<'a> {
+    /// The module the input is in.
+    pub mod_path: &'a Path,
+    /// The statement or item.
+    pub kind: SrcKind,
+    /// The 0-based line within the statement or item.
+    pub line: usize,
+    /// The 0-based character column within the line.
+    pub col: usize,
+}
+
+impl<'a> SourceMap<'a> {
+    /// The byte range of the return expression of a module's evaluation function.
+    pub fn return_range(&self, mod_path: &Path) -> Option<ReturnRange> {
+        self.returns.get(mod_path).cloned()
+    }
+
+    /// Resolve a 1-based `line` and `col` of the generated code (as reported by `rustc`) to the
+    /// input location.
+    ///
+    /// Returns `None` if the line was not produced by a statement or item, such as the generated
+    /// function signatures or persistent module code.
+    pub fn lookup(&self, line: usize, col: usize) -> Option<SrcLocation<'a>> {
+        let line = line.checked_sub(1)?;
+        let idx = match self.lines.binary_search_by(|x| x.line.cmp(&line)) {
+            Ok(i) => i,
+            Err(i) => i.checked_sub(1)?,
+        };
+        let origin = &self.lines[idx];
+        let rel = line - origin.line;
+        if rel >= origin.len {
+            return None;
+        }
+
+        let col = col.saturating_sub(1);
+        let col = if rel == 0 {
+            col.saturating_sub(origin.col_offset)
+        } else {
+            col
+        };
+
+        Some(SrcLocation {
+            mod_path: origin.mod_path,
+            kind: origin.kind,
+            line: rel,
+            col,
+        })
+    }
+
+    /// The module and binding persisted into the value store on the 1-based `line` of the
+    /// generated code.
+    pub fn persisted_binding(&self, line: usize) -> Option<(&'a Path, &str)> {
+        let line = line.checked_sub(1)?;
+        self.persists
+            .binary_search_by(|x| x.0.cmp(&line))
+            .ok()
+            .map(|i| (self.persists[i].1, self.persists[i].2.as_str()))
+    }
+
+    /// Record that the line about to be appended to `buf` persists `name`.
+    fn record_persist(&mut self, buf: &str, mod_path: &'a Path, name: &str) {
+        self.scan(buf);
+        self.persists.push((self.line, mod_path, name.to_string()));
+    }
+
+    fn scan(&mut self, buf: &str) {
+        self.line += buf[self.scanned..].matches('\n').count();
+        self.scanned = buf.len();
+    }
+
+    /// Record that `text` is about to be appended to `buf`, after `col_offset` characters on the
+    /// current line.
+    fn record(
+        &mut self,
+        buf: &str,
+        mod_path: &'a Path,
+        kind: SrcKind,
+        col_offset: usize,
+        text: &str,
+    ) {
+        self.scan(buf);
+
+        self.lines.push(LineOrigin {
+            line: self.line,
+            len: std::cmp::max(text.lines().count(), 1),
+            mod_path,
+            kind,
+            col_offset,
+        });
+    }
+}
+
+/// Statement groups of a module which have been evaluated, with their bindings persisted in a
+/// value store.
+///
+/// The evaluated statement groups are not run again, rather their bindings are restored from the
+/// store. The statements are still compiled so the types of the bindings can be inferred.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Persisted {
+    /// The number of leading statement groups which have been evaluated.
+    pub grps: usize,
+    /// The bindings available in the store. Evaluated groups with bindings which are not
+    /// available are run again.
+    pub available: BTreeSet<String>,
+    /// Bindings which are not to be persisted, usually as they were moved or borrow a value.
+    pub excluded: BTreeSet<String>,
+}
+
+/// Mapping of modules to their persisted statement groups.
+pub type PersistedMap = BTreeMap<PathBuf, Persisted>;
+
+/// The bindings a module's evaluation function restores and persists.
+#[derive(Debug, Default, PartialEq)]
+struct PersistPlan {
+    /// The number of leading statement groups which are restored rather than run.
+    restored: usize,
+    /// The bindings restored from the store.
+    restore: Vec<String>,
+    /// The bindings moved into the store after evaluation.
+    put: Vec<String>,
+    /// The trailing `out#`, moved into the store and shown from there.
+    out: Option<String>,
+}
+
+impl PersistPlan {
+    fn new(src_code: &SourceCode, persisted: Option<&Persisted>) -> Self {
+        let c = src_code.stmts.len();
+        if c == 0 {
+            return Self::default();
+        }
+        let default = Persisted::default();
+        let persisted = persisted.unwrap_or(&default);
+        let available =
+            |x: &String| persisted.available.contains(x) && !persisted.excluded.contains(x);
+
+        // a group is only restored if all its `let` bindings are available, otherwise it and the
+        // following groups are run again. An `out#` which could not be stored is left out.
+        let mut restored = 0;
+        let mut names = Vec::new();
+        for grp in src_code.stmts.iter().take(persisted.grps) {
+            let mut grp_names = Vec::new();
+            grp.bindings(restored, &mut grp_names);
+            let out = format!("out{}", restored);
+            grp_names.retain(|x| *x != out || available(x));
+            if !grp_names.iter().all(available) {
+                break;
+            }
+            names.append(&mut grp_names);
+            restored += 1;
+        }
+        dedup(&mut names);
+        let restore = names.clone();
+
+        for (i, grp) in src_code.stmts.iter().enumerate().skip(restored) {
+            grp.bindings(i, &mut names);
+        }
+
+        let out = if c > restored {
+            Some(format!("out{}", c - 1))
+        } else {
+            None
+        };
+
+        dedup(&mut names);
+        names.retain(|x| !persisted.excluded.contains(x) && Some(x) != out.as_ref());
+
+        Self {
+            restored,
+            restore,
+            put: names,
+            out: out.filter(|x| !persisted.excluded.contains(x)),
+        }
+    }
+}
+
+/// Pushes the names a pattern binds by value.
+///
+/// Capitalised identifiers are skipped as they would resolve to unit structs or constants.
+fn pat_bindings(pat: &syn::Pat, names: &mut Vec<String>) {
+    use syn::Pat;
+
+    match pat {
+        Pat::Ident(p) => {
+            let name = p.ident.to_string();
+            if p.by_ref.is_none() && !name.starts_with(char::is_uppercase) {
+                names.push(name);
+            }
+            if let Some((_, p)) = &p.subpat {
+                pat_bindings(p, names);
+            }
+        }
+        Pat::Box(p) => pat_bindings(&p.pat, names),
+        Pat::Or(p) => p.cases.iter().take(1).for_each(|p| pat_bindings(p, names)),
+        Pat::Reference(p) => pat_bindings(&p.pat, names),
+        Pat::Slice(p) => p.elems.iter().for_each(|p| pat_bindings(p, names)),
+        Pat::Struct(p) => p.fields.iter().for_each(|f| pat_bindings(&f.pat, names)),
+        Pat::Tuple(p) => p.elems.iter().for_each(|p| pat_bindings(p, names)),
+        Pat::TupleStruct(p) => p.pat.elems.iter().for_each(|p| pat_bindings(p, names)),
+        Pat::Type(p) => pat_bindings(&p.pat, names),
+        _ => (),
+    }
+}
+
+/// Remove duplicates, keeping the first occurrence.
+fn dedup(names: &mut Vec<String>) {
+    let mut seen = BTreeSet::new();
+    names.retain(|x| seen.insert(x.clone()));
+}
 
 /// Mapping of modules to source code.
 pub type ModsMap = BTreeMap<PathBuf, SourceCode>;
@@ -138,10 +736,18 @@ impl StmtGrp {
     }
 
     /// Stringfy's the statements and assigns trailing expressions with `let out# = expr;`.
-    fn assign_let_binding(&self, input_num: usize, buf: &mut String) {
+    ///
+    /// `record` is invoked before each statement is written, with the statement index, the
+    /// number of characters prepended to the statement, and the statement code.
+    fn assign_let_binding<F>(&self, input_num: usize, buf: &mut String, record: &mut F)
+    where
+        F: FnMut(&str, usize, usize, &str),
+    {
         let stmts = &self.0;
+        let last = stmts.len().saturating_sub(1);
 
-        for stmt in &stmts[0..stmts.len().saturating_sub(1)] {
+        for (i, stmt) in stmts[0..last].iter().enumerate() {
+            record(buf, i, 0, &stmt.expr);
             buf.push_str(&stmt.expr);
             if stmt.semi {
                 buf.push(';');
@@ -150,14 +756,36 @@ impl StmtGrp {
         }
 
         if !stmts.is_empty() {
+            let start = buf.len();
             buf.push_str("let out");
             buf.push_str(&input_num.to_string());
             buf.push_str(" = ");
-            buf.push_str(&stmts[stmts.len() - 1].expr);
+            let col_offset = buf.len() - start;
+            record(buf, last, col_offset, &stmts[last].expr);
+            buf.push_str(&stmts[last].expr);
             buf.push(';');
         }
     }
 
+    /// Pushes the names bound by `let` statements, and the `out#` binding.
+    pub(crate) fn bindings(&self, input_num: usize, names: &mut Vec<String>) {
+        let stmts = &self.0;
+
+        for stmt in &stmts[0..stmts.len().saturating_sub(1)] {
+            if !stmt.expr.starts_with("let") {
+                continue;
+            }
+
+            if let Ok(syn::Stmt::Local(local)) = syn::parse_str(&format!("{};", stmt.expr)) {
+                pat_bindings(&local.pat, names);
+            }
+        }
+
+        if !stmts.is_empty() {
+            names.push(format!("out{}", input_num));
+        }
+    }
+
     fn assign_let_binding_length(&self, input_num: usize) -> usize {
         let stmts = &self.0;
         let mut cap = 0;
@@ -180,14 +808,30 @@ impl StmtGrp {
 }
 
 /// Construct a single string containing all the source code in `mods_map`.
+///
+/// A [`SourceMap`] is returned which maps the generated code back to the inputs.
+///
+/// The statement groups in `persisted` have their bindings restored from the value store rather
+/// than being run again. Pass an empty map to run all the statements.
 pub fn construct_source_code<'a>(
     mods_map: &'a ModsMap,
     linking_config: &LinkingConfiguration,
     static_files: &StaticFiles,
-) -> (String, ReturnRangeMap<'a>) {
+    persisted: &PersistedMap,
+) -> (String, SourceMap<'a>) {
     // assumed to be sorted, FileMap is BTreeMap
 
-    let (cap, map) = calc_capacity(mods_map, linking_config, static_files);
+    let plans: PlanMap = mods_map
+        .iter()
+        .map(|(k, v)| (k.as_path(), PersistPlan::new(v, persisted.get(k))))
+        .collect();
+
+    let (cap, returns) = calc_capacity(mods_map, linking_config, static_files, &plans);
+
+    let mut map = SourceMap {
+        returns,
+        ..Default::default()
+    };
 
     let mut contents = String::with_capacity(cap);
 
@@ -197,7 +841,7 @@ pub fn construct_source_code<'a>(
     }
 
     // do the lib first
-    if let Some(lib) = mods_map.get(Path::new("lib")) {
+    if let Some((lib_path, lib)) = mods_map.get_key_value(Path::new("lib")) {
         // add static file links
         for n in static_files
             .iter()
@@ -215,7 +859,9 @@ pub fn construct_source_code<'a>(
             &into_mod_path_vec(Path::new("lib")),
             linking_config,
             &StaticFiles::new(), // don't pass through as handled as mods above
+            &plans[lib_path.as_path()],
             &mut contents,
+            (lib_path, &mut map),
         );
     }
 
@@ -245,7 +891,9 @@ pub fn construct_source_code<'a>(
             &into_mod_path_vec(file),
             linking_config,
             static_files,
+            &plans[file],
             &mut contents,
+            (file, &mut map),
         );
     }
 
@@ -258,6 +906,33 @@ pub fn construct_source_code<'a>(
         contents.push('}');
     }
 
+    contents.push_str(PERSIST_MOD);
+
+    contents.push_str(SHOW_MOD[0]);
+    if !linking_config.persistent_module_code.is_empty() {
+        contents.push_str(&linking_config.persistent_module_code);
+        contents.push('\n');
+    }
+    contents.push_str(SHOW_MOD[1]);
+
+    if linking_config.executor.is_some() {
+        contents.push_str(ASYNC_MOD[0]);
+        contents.push_str(crate::compile::EXECUTOR_SRC);
+        contents.push_str(ASYNC_MOD[1]);
+    }
+
+    // the transport module uses the same kserd as the evaluation functions
+    contents.push_str(TRANSPORT_MOD[0]);
+    contents.push_str(crate::compile::TRANSPORT_SRC);
+    if linking_config.worker_data_type().is_some() {
+        contents.push_str(TRANSPORT_DATA);
+    }
+    if !linking_config.persistent_module_code.is_empty() {
+        contents.push_str(&linking_config.persistent_module_code);
+        contents.push('\n');
+    }
+    contents.push_str(TRANSPORT_MOD[1]);
+
     debug_assert_eq!(
         cap,
         contents.len(),
@@ -267,6 +942,45 @@ pub fn construct_source_code<'a>(
     (contents, map)
 }
 
+/// The name of the function exported by a [`type_probe`].
+pub(crate) const TYPE_OF_FN: &str = "papyrus_type_of";
+
+/// Constructs a type probe for a module: an item exporting a function which returns the type name
+/// of `expr`, as if it were evaluated after the module's statements.
+///
+/// The statements and `expr` are only type checked, calling the probe runs none of them.
+pub(crate) fn type_probe(
+    src_code: &SourceCode,
+    linking_config: &linking::LinkingConfiguration,
+    expr: &str,
+) -> String {
+    let mut buf = String::from(TYPE_PROBE[0]);
+    buf.push_str(TYPE_OF_FN);
+    buf.push_str(TYPE_PROBE[1]);
+    let body = if linking_config.executor.is_some() {
+        TYPE_PROBE_ASYNC_BODY
+    } else {
+        TYPE_PROBE_BODY
+    };
+    buf.push_str(body[0]);
+    buf.push_str(TYPE_PROBE[2]);
+    if linking_config.data_type.is_some() {
+        buf.push_str("let ");
+        linking_config.construct_fn_args(&mut buf);
+        buf.push_str(" = loop {};\n");
+    }
+    for (i, grp) in src_code.stmts.iter().enumerate() {
+        grp.assign_let_binding(i, &mut buf, &mut |_, _, _, _| ());
+        buf.push('\n');
+    }
+    buf.push_str(TYPE_PROBE[3]);
+    buf.push_str(expr);
+    buf.push_str(TYPE_PROBE[4]);
+    buf.push_str(body[1]);
+    buf.push_str(TYPE_PROBE_END);
+    buf
+}
+
 /// Constructs the evaluation function name given the mod sequence path.
 /// Appends to the buffer.
 pub fn eval_fn_name<S: AsRef<str>>(mod_path: &[S], buf: &mut String) {
@@ -309,6 +1023,7 @@ fn calc_capacity<'a>(
     mods_map: &'a ModsMap,
     linking_config: &LinkingConfiguration,
     static_files: &StaticFiles,
+    plans: &PlanMap,
 ) -> (usize, ReturnRangeMap<'a>) {
     fn mv_rng(mut rng: ReturnRange, by: usize) -> ReturnRange {
         rng.start += by;
@@ -340,6 +1055,7 @@ fn calc_capacity<'a>(
             &into_mod_path_vec(Path::new("lib")),
             linking_config,
             &StaticFiles::new(),
+            &plans[Path::new("lib")],
         );
 
         map.insert(Path::new("lib"), mv_rng(src_code_return, cap));
@@ -369,6 +1085,7 @@ fn calc_capacity<'a>(
             &into_mod_path_vec(file),
             linking_config,
             static_files,
+            &plans[file],
         );
 
         map.insert(file, mv_rng(src_code_return, cap));
@@ -383,19 +1100,41 @@ fn calc_capacity<'a>(
         .unwrap_or(0);
     cap += lvl;
 
+    cap += PERSIST_MOD.len() + SHOW_MOD[0].len() + SHOW_MOD[1].len();
+    if !linking_config.persistent_module_code.is_empty() {
+        cap += linking_config.persistent_module_code.len() + 1;
+    }
+
+    if linking_config.executor.is_some() {
+        cap += ASYNC_MOD[0].len() + crate::compile::EXECUTOR_SRC.len() + ASYNC_MOD[1].len();
+    }
+
+    cap += TRANSPORT_MOD[0].len() + crate::compile::TRANSPORT_SRC.len() + TRANSPORT_MOD[1].len();
+    if linking_config.worker_data_type().is_some() {
+        cap += TRANSPORT_DATA.len();
+    }
+    if !linking_config.persistent_module_code.is_empty() {
+        cap += linking_config.persistent_module_code.len() + 1;
+    }
+
     (cap, map)
 }
 
-/// Build the buffer with the stringified contents of SourceCode
-fn append_buffer<S: AsRef<str>>(
+/// Build the buffer with the stringified contents of SourceCode.
+///
+/// The lines of each statement and item are recorded in the source map against the module key.
+fn append_buffer<'a, S: AsRef<str>>(
     src_code: &SourceCode,
     mod_path: &[S],
     linking_config: &linking::LinkingConfiguration,
     static_files: &StaticFiles,
+    plan: &PersistPlan,
     buf: &mut String,
+    (mod_key, map): (&'a Path, &mut SourceMap<'a>),
 ) {
     // do up top items first.
-    for item in src_code.items.iter().filter(|x| x.1) {
+    for (i, item) in src_code.items.iter().enumerate().filter(|x| (x.1).1) {
+        map.record(buf, mod_key, SrcKind::Item(i), 0, &item.0);
         buf.push_str(item.0.as_str());
         buf.push('\n');
     }
@@ -421,27 +1160,123 @@ fn append_buffer<S: AsRef<str>>(
     buf.push_str("#[no_mangle]\npub extern \"C\" fn "); // 31 len
     eval_fn_name(mod_path, buf);
     buf.push('(');
+    buf.push_str(STORE_ARG);
+    if linking_config.data_type.is_some() {
+        buf.push_str(", ");
+    }
     linking_config.construct_fn_args(buf);
-    buf.push_str(") -> kserd::Kserd<'static> {\n"); // 29 len
+    buf.push_str(EVAL_BEGIN);
+    if let Some(executor) = &linking_config.executor {
+        buf.push_str(executor.call());
+        buf.push_str(ASYNC_BODY[0]);
+    }
+    buf.push_str(EVAL_BODY[0]);
+
+    let append_grp = |i: usize, grp: &StmtGrp, buf: &mut String, map: &mut SourceMap<'a>| {
+        grp.assign_let_binding(i, buf, &mut |buf, stmt, col_offset, text| {
+            map.record(
+                buf,
+                mod_key,
+                SrcKind::Stmt { grp: i, stmt },
+                col_offset,
+                text,
+            )
+        });
+        buf.push('\n');
+    };
+
+    // restore the bindings of evaluated stmts
+    let restore = &plan.restore;
+    if !restore.is_empty() {
+        buf.push_str(RESTORE[0]);
+        for name in restore {
+            buf.push_str("mut ");
+            buf.push_str(name);
+            buf.push_str(", ");
+        }
+        buf.push_str(RESTORE[1]);
+        // the evaluated stmts are never run, they are only there to infer the binding types
+        for (i, x) in src_code.stmts[..plan.restored].iter().enumerate() {
+            append_grp(i, x, buf, map);
+        }
+        buf.push('(');
+        push_names(restore, buf);
+        buf.push_str(RESTORE[2]);
+        for name in restore {
+            buf.push_str(TAKE);
+            buf.push_str(name);
+            buf.push_str("\"), ");
+        }
+        buf.push_str(RESTORE[3]);
+        for name in restore {
+            buf.push_str("Some(");
+            buf.push_str(name);
+            buf.push_str("), ");
+        }
+        buf.push_str(RESTORE[4]);
+        push_names(restore, buf);
+        buf.push_str(RESTORE[5]);
+    }
 
     // add stmts
     let c = src_code.stmts.len();
-    if c >= 1 {
-        // only add statements if more than zero!
-        src_code.stmts.iter().enumerate().for_each(|(i, x)| {
-            x.assign_let_binding(i, buf);
-            buf.push('\n');
-        });
-        buf.push_str("kserd::ToKserd::into_kserd(out");
+    if c > plan.restored {
+        // only add statements if there are unevaluated ones!
+        for (i, x) in src_code.stmts.iter().enumerate().skip(plan.restored) {
+            append_grp(i, x, buf, map);
+        }
+    }
+
+    // persist the bindings
+    for name in &plan.put {
+        map.record_persist(buf, mod_key, name);
+        buf.push_str(PUT[0]);
+        buf.push_str(name);
+        buf.push_str(PUT[1]);
+        buf.push_str(name);
+        buf.push_str(PUT[2]);
+    }
+
+    if let Some(out) = &plan.out {
+        map.record_persist(buf, mod_key, out);
+        buf.push_str(SHOW_OUT[0]);
+        buf.push_str(out);
+        buf.push_str(SHOW_OUT[1]);
+        buf.push_str(out);
+        buf.push_str(SHOW_OUT[2]);
+    } else if c > plan.restored {
+        buf.push_str(SHOW[0]);
         buf.push_str(&c.saturating_sub(1).to_string());
-        buf.push_str(").unwrap().into_owned()\n");
+        buf.push_str(SHOW[1]);
     } else {
         buf.push_str("kserd::Kserd::new_str(\"no statements\")\n");
     }
-    buf.push_str("}\n");
+    buf.push_str(EVAL_BODY[1]);
+    if linking_config.executor.is_some() {
+        buf.push_str(ASYNC_BODY[1]);
+    }
+    buf.push_str(EVAL_END);
+
+    // a worker process passes the app data serialized
+    if let Some(data_type) = linking_config.worker_data_type() {
+        buf.push_str(DATA_FN[0]);
+        eval_fn_name(mod_path, buf);
+        buf.push_str(DATA_FN[1]);
+        buf.push_str(if linking_config.mutable {
+            "true"
+        } else {
+            "false"
+        });
+        buf.push_str(DATA_FN[2]);
+        buf.push_str(data_type);
+        buf.push_str(DATA_FN[3]);
+        eval_fn_name(mod_path, buf);
+        buf.push_str(DATA_FN[4]);
+    }
 
     // add items
-    for item in src_code.items.iter().filter(|x| !x.1) {
+    for (i, item) in src_code.items.iter().enumerate().filter(|x| !(x.1).1) {
+        map.record(buf, mod_key, SrcKind::Item(i), 0, &item.0);
         buf.push_str(item.0.as_str());
         buf.push('\n');
     }
@@ -452,6 +1287,7 @@ fn append_buffer_length<S: AsRef<str>>(
     mod_path: &[S],
     linking_config: &linking::LinkingConfiguration,
     static_files: &StaticFiles,
+    plan: &PersistPlan,
 ) -> (usize, ReturnRange) {
     let mut cap: usize = src_code
         .items
@@ -474,30 +1310,83 @@ fn append_buffer_length<S: AsRef<str>>(
         .sum::<usize>();
 
     // wrap stmts
-    cap += 31 + eval_fn_name_length(mod_path) + 1 + linking_config.construct_fn_args_length() + 29;
+    cap += 31 + eval_fn_name_length(mod_path) + 1 + STORE_ARG.len();
+    if linking_config.data_type.is_some() {
+        cap += 2;
+    }
+    cap += linking_config.construct_fn_args_length() + EVAL_BEGIN.len() + EVAL_BODY[0].len();
+    let async_body = linking_config
+        .executor
+        .as_ref()
+        .map(|x| x.call().len() + ASYNC_BODY[0].len());
+    cap += async_body.unwrap_or(0);
+
+    let grp_len = |(i, x): (usize, &StmtGrp)| x.assign_let_binding_length(i) + 1;
+
+    // restore
+    let restore = &plan.restore;
+    if !restore.is_empty() {
+        cap += RESTORE.iter().map(|x| x.len()).sum::<usize>()
+            + 1 // (
+            + restore
+                .iter()
+                .map(|x| {
+                    6 + x.len() // mut #,
+                    + (x.len() + 2) * 2 // #, twice
+                    + TAKE.len() + x.len() + 4 // take #"),
+                    + 8 + x.len() // Some(#),
+                })
+                .sum::<usize>()
+            + src_code.stmts[..plan.restored]
+                .iter()
+                .enumerate()
+                .map(grp_len)
+                .sum::<usize>();
+    }
 
     // add stmts
     let c = src_code.stmts.len();
-    let (add, rng) = if c >= 1 {
-        let stmts = src_code
+    if c > plan.restored {
+        cap += src_code
             .stmts
             .iter()
             .enumerate()
-            .map(|(i, x)| x.assign_let_binding_length(i) + 1)
+            .skip(plan.restored)
+            .map(grp_len)
             .sum::<usize>();
-        let return_str = 30 // kserd::ToKserd::into_kserd(out
-            + c.saturating_sub(1).to_string().len()
-            + 24; // ).unwrap().into_owned()\n
+    }
 
-        (
-            stmts + return_str,
-            cap + stmts..cap + stmts + return_str - 1,
-        )
+    // persist
+    cap += plan
+        .put
+        .iter()
+        .map(|x| PUT.iter().map(|x| x.len()).sum::<usize>() + x.len() * 2)
+        .sum::<usize>();
+
+    let (add, rng) = if let Some(out) = &plan.out {
+        let return_str = SHOW_OUT.iter().map(|x| x.len()).sum::<usize>() + out.len() * 2;
+
+        (return_str, cap..cap + return_str - 1)
+    } else if c > plan.restored {
+        let return_str = SHOW[0].len() + c.saturating_sub(1).to_string().len() + SHOW[1].len();
+
+        (return_str, cap..cap + return_str - 1)
     } else {
         // kserd::Kserd::new_str("no statements")\n
         (39, cap..cap + 38)
     };
-    cap += add + 2; // }\n
+    cap += add + EVAL_BODY[1].len() + EVAL_END.len();
+    if async_body.is_some() {
+        cap += ASYNC_BODY[1].len();
+    }
+
+    // worker data fn
+    if let Some(data_type) = linking_config.worker_data_type() {
+        cap += DATA_FN.iter().map(|x| x.len()).sum::<usize>()
+            + eval_fn_name_length(mod_path) * 2
+            + if linking_config.mutable { 4 } else { 5 }
+            + data_type.len();
+    }
 
     // add items
     cap += src_code
@@ -510,6 +1399,13 @@ fn append_buffer_length<S: AsRef<str>>(
     (cap, rng)
 }
 
+fn push_names(names: &[String], buf: &mut String) {
+    for name in names {
+        buf.push_str(name);
+        buf.push_str(", ");
+    }
+}
+
 /// A single item.
 ///
 /// Wraps as `(content, top_placement)`.
@@ -531,6 +1427,10 @@ pub struct Statement {
 ///
 /// Crates are parsed and made suitable for `Cargo.toml`. The input line is kept verbatim.
 ///
+/// The dependency can be specified with a `dep` attribute, such as
+/// `#[dep(version = "1.0", features = ["derive"])] extern crate serde;`. See [`Dependency`] for the
+/// keys. Without a `dep` attribute any version from crates.io is used.
+///
 /// # Examples
 /// ```rust
 /// # use papyrus::code::CrateType;
@@ -538,6 +1438,11 @@ pub struct Statement {
 /// let cr = CrateType::parse_str(input).unwrap();
 /// assert_eq!(&cr.src_line, input);
 /// assert_eq!(&cr.cargo_name, "a-crate");
+///
+/// let input = r#"#[dep(version = "1.0", default_features = false)] extern crate serde;"#;
+/// let cr = CrateType::parse_str(input).unwrap();
+/// assert_eq!(cr.dep.version.as_deref(), Some("1.0"));
+/// assert!(!cr.dep.default_features);
 /// ```
 #[derive(Clone, Debug, PartialEq)]
 pub struct CrateType {
@@ -547,11 +1452,25 @@ pub struct CrateType {
     /// The name to use in cargo.
     /// Usually `crate_name` will turn into `crate-name`. The default behaviour is to replace `_` with a dash (`-`).
     pub cargo_name: String,
+    /// The dependency specification written to `Cargo.toml`.
+    pub dep: Dependency,
 }
 
 impl CrateType {
     /// Parses a string to return the `CrateType`.
     pub fn parse_str(string: &str) -> Result<Self, &'static str> {
+        if let Ok(item) = syn::parse_str::<syn::ItemExternCrate>(string) {
+            let mut dep = Dependency::default();
+            for attr in item.attrs.iter().filter(|a| a.path.is_ident("dep")) {
+                dep.parse_attr(attr)?;
+            }
+            return Ok(CrateType {
+                src_line: string.to_string(),
+                cargo_name: item.ident.to_string().replace('_', "-"),
+                dep,
+            });
+        }
+
         let line = string
             .replace(';', "")
             .replace('_', "-")
@@ -560,7 +1479,9 @@ impl CrateType {
             .next()
             .expect("string should have one line")
             .to_string();
-        if line.contains("extern crate ") {
+        if line.starts_with('#') {
+            Err(DEP_ATTR_ERR)
+        } else if line.contains("extern crate ") {
             Ok(CrateType {
                 src_line: string.to_string(),
                 cargo_name: line
@@ -568,11 +1489,163 @@ impl CrateType {
                     .nth(2)
                     .expect("should always have trailing item")
                     .to_string(),
+                dep: Dependency::default(),
             })
         } else {
             Err("line needs `extern crate NAME;`")
         }
     }
+
+    /// Builds the crate from a cargo name and dependency, the source line has the equivalent
+    /// `dep` attribute.
+    ///
+    /// # Examples
+    /// ```rust
+    /// # use papyrus::code::{CrateType, Dependency};
+    /// let dep = Dependency {
+    ///     version: Some("1.0".to_string()),
+    ///     features: vec!["derive".to_string()],
+    ///     ..Dependency::default()
+    /// };
+    /// let cr = CrateType::with_dep("serde-json", dep);
+    /// assert_eq!(
+    ///     &cr.src_line,
+    ///     r#"#[dep(version = "1.0", features = ["derive"])] extern crate serde_json;"#
+    /// );
+    /// assert_eq!(CrateType::parse_str(&cr.src_line), Ok(cr));
+    /// ```
+    pub fn with_dep(cargo_name: &str, dep: Dependency) -> Self {
+        let mut args = Vec::new();
+        let mut arg = |k: &str, v: &Option<String>| {
+            if let Some(v) = v {
+                args.push(format!("{} = {:?}", k, v));
+            }
+        };
+        arg("version", &dep.version);
+        arg("path", &dep.path);
+        arg("git", &dep.git);
+        arg("branch", &dep.branch);
+        arg("tag", &dep.tag);
+        arg("rev", &dep.rev);
+        if !dep.features.is_empty() {
+            args.push(format!("features = {:?}", dep.features));
+        }
+        if !dep.default_features {
+            args.push("default_features = false".to_string());
+        }
+
+        let mut src_line = String::new();
+        if !args.is_empty() {
+            src_line.push_str("#[dep(");
+            src_line.push_str(&args.join(", "));
+            src_line.push_str(")] ");
+        }
+        src_line.push_str("extern crate ");
+        src_line.push_str(&cargo_name.replace('-', "_"));
+        src_line.push(';');
+
+        CrateType {
+            src_line,
+            cargo_name: cargo_name.to_string(),
+            dep,
+        }
+    }
+}
+
+const DEP_ATTR_ERR: &str = "`dep` attribute expects `key = value` pairs, with string values except `features = [\"a\", \"b\"]` and `default_features = bool`";
+
+/// The Cargo dependency specification of a crate.
+///
+/// The fields mirror the keys of a Cargo dependency table. If no version, path, or git source is
+/// specified any version from crates.io is used.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dependency {
+    /// The version requirement, such as `1.0`.
+    pub version: Option<String>,
+    /// Path to a local crate. A relative path is relative to the REPL's working directory.
+    pub path: Option<String>,
+    /// Url of a git repository.
+    pub git: Option<String>,
+    /// The git branch.
+    pub branch: Option<String>,
+    /// The git tag.
+    pub tag: Option<String>,
+    /// The git revision.
+    pub rev: Option<String>,
+    /// Features to enable.
+    pub features: Vec<String>,
+    /// Enable the default features. Defaults to `true`.
+    pub default_features: bool,
+}
+
+impl Default for Dependency {
+    fn default() -> Self {
+        Dependency {
+            version: None,
+            path: None,
+            git: None,
+            branch: None,
+            tag: None,
+            rev: None,
+            features: Vec::new(),
+            default_features: true,
+        }
+    }
+}
+
+impl Dependency {
+    /// Sets the keys of a `#[dep(key = value, ..)]` attribute.
+    fn parse_attr(&mut self, attr: &syn::Attribute) -> Result<(), &'static str> {
+        use syn::{punctuated::Punctuated, Expr, Lit, Token};
+
+        let args = attr
+            .parse_args_with(Punctuated::<Expr, Token![,]>::parse_terminated)
+            .map_err(|_| DEP_ATTR_ERR)?;
+
+        let string = |e: &Expr| match e {
+            Expr::Lit(syn::ExprLit {
+                lit: Lit::Str(s), ..
+            }) => Ok(s.value()),
+            _ => Err(DEP_ATTR_ERR),
+        };
+
+        for arg in args {
+            let (key, value) = match arg {
+                Expr::Assign(syn::ExprAssign { left, right, .. }) => match *left {
+                    Expr::Path(p) => match p.path.get_ident() {
+                        Some(key) => (key.to_string(), *right),
+                        None => return Err(DEP_ATTR_ERR),
+                    },
+                    _ => return Err(DEP_ATTR_ERR),
+                },
+                _ => return Err(DEP_ATTR_ERR),
+            };
+
+            match key.as_str() {
+                "version" => self.version = Some(string(&value)?),
+                "path" => self.path = Some(string(&value)?),
+                "git" => self.git = Some(string(&value)?),
+                "branch" => self.branch = Some(string(&value)?),
+                "tag" => self.tag = Some(string(&value)?),
+                "rev" => self.rev = Some(string(&value)?),
+                "features" => match value {
+                    Expr::Array(a) => {
+                        self.features = a.elems.iter().map(string).collect::<Result<_, _>>()?
+                    }
+                    _ => return Err(DEP_ATTR_ERR),
+                },
+                "default_features" => match value {
+                    Expr::Lit(syn::ExprLit {
+                        lit: Lit::Bool(b), ..
+                    }) => self.default_features = b.value,
+                    _ => return Err(DEP_ATTR_ERR),
+                },
+                _ => return Err("unknown `dep` key, expecting one of version, path, git, branch, tag, rev, features, default_features"),
+            }
+        }
+
+        Ok(())
+    }
 }
 
 // ###### STATIC FILES ###################################################################
@@ -788,6 +1861,24 @@ pub fn static_file_mod_name(path: &Path) -> Option<&str> {
 mod tests {
     use super::*;
 
+    /// The modules appended to the end of the generated source code.
+    fn tail(persistent_module_code: &str) -> String {
+        let module = |begin: &str, end: &str| {
+            let mut s = begin.to_string();
+            if !persistent_module_code.is_empty() {
+                s += persistent_module_code;
+                s += "\n";
+            }
+            s + end
+        };
+        PERSIST_MOD.to_string()
+            + &module(SHOW_MOD[0], SHOW_MOD[1])
+            + &module(
+                &(TRANSPORT_MOD[0].to_string() + crate::compile::TRANSPORT_SRC),
+                TRANSPORT_MOD[1],
+            )
+    }
+
     #[test]
     fn file_map_with_lvls_test() {
         let map = vec![
@@ -827,6 +1918,7 @@ mod tests {
             Ok(CrateType {
                 src_line: s,
                 cargo_name: String::from("somelib"),
+                dep: Dependency::default(),
             })
         );
 
@@ -837,6 +1929,7 @@ mod tests {
             Ok(CrateType {
                 src_line: s,
                 cargo_name: String::from("some-lib"),
+                dep: Dependency::default(),
             })
         );
 
@@ -847,6 +1940,7 @@ mod tests {
             Ok(CrateType {
                 src_line: s,
                 cargo_name: String::from("some"),
+                dep: Dependency::default(),
             })
         );
 
@@ -857,16 +1951,70 @@ mod tests {
             Ok(CrateType {
                 src_line: s,
                 cargo_name: String::from("some-lib"),
+                dep: Dependency::default(),
             })
         );
     }
 
+    #[test]
+    fn test_parse_crate_dep() {
+        let c = CrateType::parse_str(
+            r#"#[dep(version = "0.3", path = "../rand", git = "https://a.b/rand", branch = "dev", tag = "v1", rev = "abc", features = ["a", "b"], default_features = false)]
+pub extern crate rand_core as rc;"#,
+        )
+        .unwrap();
+        assert_eq!(&c.cargo_name, "rand-core");
+        assert_eq!(
+            c.dep,
+            Dependency {
+                version: Some("0.3".to_string()),
+                path: Some("../rand".to_string()),
+                git: Some("https://a.b/rand".to_string()),
+                branch: Some("dev".to_string()),
+                tag: Some("v1".to_string()),
+                rev: Some("abc".to_string()),
+                features: vec!["a".to_string(), "b".to_string()],
+                default_features: false,
+            }
+        );
+
+        // other attributes are ignored
+        let c = CrateType::parse_str("#[macro_use] extern crate serde;").unwrap();
+        assert_eq!(c.dep, Dependency::default());
+
+        let err = |s| CrateType::parse_str(s).unwrap_err();
+        assert_eq!(err("#[dep(version)] extern crate a;"), DEP_ATTR_ERR);
+        assert_eq!(err("#[dep(version = 1)] extern crate a;"), DEP_ATTR_ERR);
+        assert_eq!(
+            err("#[dep(features = \"a\")] extern crate a;"),
+            DEP_ATTR_ERR
+        );
+        assert_eq!(err("#[dep(version = \"1\"] extern crate a;"), DEP_ATTR_ERR);
+        assert!(err("#[dep(foo = \"1\")] extern crate a;").starts_with("unknown `dep` key"));
+
+        let dep = Dependency {
+            git: Some("https://a.b/c".to_string()),
+            default_features: false,
+            ..Dependency::default()
+        };
+        let c = CrateType::with_dep("a-b", dep);
+        assert_eq!(
+            &c.src_line,
+            r#"#[dep(git = "https://a.b/c", default_features = false)] extern crate a_b;"#
+        );
+        assert_eq!(CrateType::parse_str(&c.src_line), Ok(c));
+        assert_eq!(
+            &CrateType::with_dep("a", Dependency::default()).src_line,
+            "extern crate a;"
+        );
+    }
+
     #[test]
     fn assign_let_binding_test() {
         let mut grp = StmtGrp(vec![]);
 
         let mut s = String::new();
-        grp.assign_let_binding(0, &mut s);
+        grp.assign_let_binding(0, &mut s, &mut |_, _, _, _| ());
 
         let ans = "";
         assert_eq!(&s, ans);
@@ -878,7 +2026,7 @@ mod tests {
         });
 
         let mut s = String::new();
-        grp.assign_let_binding(0, &mut s);
+        grp.assign_let_binding(0, &mut s, &mut |_, _, _, _| ());
 
         let ans = "let out0 = a;";
         assert_eq!(&s, ans);
@@ -890,14 +2038,14 @@ mod tests {
         });
 
         let mut s = String::new();
-        grp.assign_let_binding(0, &mut s);
+        grp.assign_let_binding(0, &mut s, &mut |_, _, _, _| ());
 
         let ans = "a\nlet out0 = b;";
         assert_eq!(&s, ans);
         assert_eq!(grp.assign_let_binding_length(0), ans.len());
 
         let mut s = String::new();
-        grp.assign_let_binding(100, &mut s);
+        grp.assign_let_binding(100, &mut s, &mut |_, _, _, _| ());
 
         let ans = "a\nlet out100 = b;";
         assert_eq!(&s, ans);
@@ -918,19 +2066,30 @@ mod tests {
             &mod_path,
             &linking_config,
             &StaticFiles::new(),
+            &PersistPlan::default(),
             &mut s,
+            (Path::new("test"), &mut SourceMap::default()),
+        );
+        let (len, rng) = append_buffer_length(
+            &src_code,
+            &mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistPlan::default(),
         );
-        let (len, rng) =
-            append_buffer_length(&src_code, &mod_path, &linking_config, &StaticFiles::new());
 
         let ans = r##"#[no_mangle]
-pub extern "C" fn _intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 "##;
         assert_eq!(&s, ans);
         assert_eq!(len, ans.len());
-        assert_eq!(rng, 73..111);
+        assert_eq!(rng, 298..336);
         assert_eq!(&ans[rng], r#"kserd::Kserd::new_str("no statements")"#);
 
         // alter mod path
@@ -942,19 +2101,30 @@ kserd::Kserd::new_str("no statements")
             &mod_path,
             &linking_config,
             &StaticFiles::new(),
+            &PersistPlan::default(),
             &mut s,
+            (Path::new("test"), &mut SourceMap::default()),
+        );
+        let (len, rng) = append_buffer_length(
+            &src_code,
+            &mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistPlan::default(),
         );
-        let (len, rng) =
-            append_buffer_length(&src_code, &mod_path, &linking_config, &StaticFiles::new());
 
         let ans = r##"#[no_mangle]
-pub extern "C" fn _some_path_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _some_path_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 "##;
         assert_eq!(&s, ans);
         assert_eq!(len, ans.len());
-        assert_eq!(rng, 83..121);
+        assert_eq!(rng, 308..346);
         assert_eq!(&ans[rng], r#"kserd::Kserd::new_str("no statements")"#);
 
         // alter the linking config
@@ -969,19 +2139,30 @@ kserd::Kserd::new_str("no statements")
             &mod_path,
             &linking_config,
             &StaticFiles::new(),
+            &PersistPlan::default(),
             &mut s,
+            (Path::new("test"), &mut SourceMap::default()),
+        );
+        let (len, rng) = append_buffer_length(
+            &src_code,
+            &mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistPlan::default(),
         );
-        let (len, rng) =
-            append_buffer_length(&src_code, &mod_path, &linking_config, &StaticFiles::new());
 
         let ans = r##"#[no_mangle]
-pub extern "C" fn _some_path_intern_eval(app_data: &String) -> kserd::Kserd<'static> {
+pub extern "C" fn _some_path_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store, app_data: &String) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 "##;
         assert_eq!(&s, ans);
         assert_eq!(len, ans.len());
-        assert_eq!(rng, 100..138);
+        assert_eq!(rng, 327..365);
         assert_eq!(&ans[rng], r#"kserd::Kserd::new_str("no statements")"#);
 
         // add an item and new input
@@ -994,21 +2175,32 @@ kserd::Kserd::new_str("no statements")
             &mod_path,
             &linking_config,
             &StaticFiles::new(),
+            &PersistPlan::default(),
             &mut s,
+            (Path::new("test"), &mut SourceMap::default()),
+        );
+        let (len, rng) = append_buffer_length(
+            &src_code,
+            &mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistPlan::default(),
         );
-        let (len, rng) =
-            append_buffer_length(&src_code, &mod_path, &linking_config, &StaticFiles::new());
 
         let ans = r##"#[no_mangle]
-pub extern "C" fn _some_path_intern_eval(app_data: &String) -> kserd::Kserd<'static> {
+pub extern "C" fn _some_path_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store, app_data: &String) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 fn a() {}
 fn b() {}
 "##;
         assert_eq!(&s, ans);
         assert_eq!(len, ans.len());
-        assert_eq!(rng, 100..138);
+        assert_eq!(rng, 327..365);
         assert_eq!(&ans[rng], r#"kserd::Kserd::new_str("no statements")"#);
 
         // add stmts
@@ -1049,31 +2241,339 @@ fn b() {}
             &mod_path,
             &linking_config,
             &StaticFiles::new(),
+            &PersistPlan::default(),
             &mut s,
+            (Path::new("test"), &mut SourceMap::default()),
+        );
+        let (len, rng) = append_buffer_length(
+            &src_code,
+            &mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistPlan::default(),
         );
-        let (len, rng) =
-            append_buffer_length(&src_code, &mod_path, &linking_config, &StaticFiles::new());
 
         let ans = r##"#![feature(UP_TOP)]
 some-injected-persistent-code
 #[no_mangle]
-pub extern "C" fn _some_path_intern_eval(app_data: &String) -> kserd::Kserd<'static> {
+pub extern "C" fn _some_path_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store, app_data: &String) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 let a = 1;
 let out0 = b;
 let c = 2;
 let out1 = d;
-kserd::ToKserd::into_kserd(out1).unwrap().into_owned()
+{ use crate::papyrus_show::*; (&&&&Show::new(crate::papyrus_store::typed(__papyrus_store, out1))).show() }
+})
+})))
 }
 fn a() {}
 fn b() {}
 "##;
         assert_eq!(&s, ans);
         assert_eq!(len, ans.len());
-        assert_eq!(rng, 200..254);
+        assert_eq!(rng, 427..533);
+        assert_eq!(
+            &ans[rng],
+            "{ use crate::papyrus_show::*; (&&&&Show::new(crate::papyrus_store::typed(__papyrus_store, out1))).show() }"
+        );
+    }
+
+    #[test]
+    fn persist_plan_test() {
+        let mut src_code = SourceCode::default();
+        assert_eq!(PersistPlan::new(&src_code, None), PersistPlan::default());
+
+        src_code.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "let (a, ref b) = (1, 2)".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "a".to_string(),
+                semi: false,
+            },
+        ]));
+        src_code.stmts.push(StmtGrp(vec![Statement {
+            expr: "a + 1".to_string(),
+            semi: false,
+        }]));
+
+        // nothing evaluated
+        let plan = PersistPlan::new(&src_code, None);
+        assert_eq!(plan.restored, 0);
+        assert!(plan.restore.is_empty());
+        assert_eq!(plan.put, vec!["a", "out0"]);
+        assert_eq!(plan.out.as_deref(), Some("out1"));
+
+        // first group evaluated
+        let mut persisted = Persisted {
+            grps: 1,
+            available: vec!["a".to_string(), "out0".to_string()]
+                .into_iter()
+                .collect(),
+            excluded: Default::default(),
+        };
+        let plan = PersistPlan::new(&src_code, Some(&persisted));
+        assert_eq!(plan.restored, 1);
+        assert_eq!(plan.restore, vec!["a", "out0"]);
+        assert_eq!(plan.put, vec!["a", "out0"]);
+        assert_eq!(plan.out.as_deref(), Some("out1"));
+
+        // an excluded out# is left out, but its group is not run again
+        persisted.excluded.insert("out0".to_string());
+        let plan = PersistPlan::new(&src_code, Some(&persisted));
+        assert_eq!(plan.restored, 1);
+        assert_eq!(plan.restore, vec!["a"]);
+        assert_eq!(plan.put, vec!["a"]);
+
+        // other excluded bindings are not restored, so their group is run again
+        persisted.excluded.insert("a".to_string());
+        let plan = PersistPlan::new(&src_code, Some(&persisted));
+        assert_eq!(plan.restored, 0);
+        assert!(plan.restore.is_empty());
+        assert!(plan.put.is_empty());
+
+        // all groups evaluated
+        persisted.grps = 2;
+        persisted.excluded.clear();
+        persisted.available.insert("out1".to_string());
+        let plan = PersistPlan::new(&src_code, Some(&persisted));
+        assert_eq!(plan.restored, 2);
+        assert_eq!(plan.out, None);
+    }
+
+    #[test]
+    fn construct_persisted_test() {
+        use linking::LinkingConfiguration;
+
+        let mut src_code = SourceCode::default();
+        src_code.stmts.push(StmtGrp(vec![Statement {
+            expr: "String::new()".to_string(),
+            semi: false,
+        }]));
+        src_code.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "let b = out0.len()".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "b".to_string(),
+                semi: false,
+            },
+        ]));
+        let persisted = Persisted {
+            grps: 1,
+            available: vec!["out0".to_string()].into_iter().collect(),
+            excluded: Default::default(),
+        };
+        let plan = PersistPlan::new(&src_code, Some(&persisted));
+        let linking_config = LinkingConfiguration::default();
+        let mod_path: &[&str] = &[];
+
+        let mut s = String::new();
+        let mut map = SourceMap::default();
+        append_buffer(
+            &src_code,
+            mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            &plan,
+            &mut s,
+            (Path::new("test"), &mut map),
+        );
+        let (len, rng) = append_buffer_length(
+            &src_code,
+            mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            &plan,
+        );
+
+        let ans = r##"#[no_mangle]
+pub extern "C" fn _intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
+#[allow(unused_mut)]
+let (mut out0, ) = if false {
+let out0 = String::new();
+(out0, )
+} else {
+match (crate::papyrus_store::take(__papyrus_store, "out0"), ) {
+(Some(out0), ) => (out0, ),
+_ => {
+crate::papyrus_store::rebuild(__papyrus_store);
+return std::result::Result::Ok(kserd::Kserd::new_str("rebuild"));
+}
+}
+};
+let b = out0.len();
+let out1 = b;
+crate::papyrus_store::put(__papyrus_store, "out0", out0);
+crate::papyrus_store::put(__papyrus_store, "b", b);
+{ use crate::papyrus_show::*; (&&&&ShowRef(crate::papyrus_store::put_out(__papyrus_store, "out1", out1))).show() }
+})
+})))
+}
+"##;
+        assert_eq!(&s, ans);
+        assert_eq!(len, ans.len());
+        assert_eq!(
+            &ans[rng],
+            "{ use crate::papyrus_show::*; (&&&&ShowRef(crate::papyrus_store::put_out(__papyrus_store, \"out1\", out1))).show() }"
+        );
+
+        // the persisting lines map back to the bindings
+        assert_eq!(map.persisted_binding(20), Some((Path::new("test"), "out0")));
+        assert_eq!(map.persisted_binding(21), Some((Path::new("test"), "b")));
+        assert_eq!(map.persisted_binding(22), Some((Path::new("test"), "out1")));
+        assert_eq!(map.persisted_binding(23), None);
+        assert_eq!(map.persisted_binding(19), None);
+    }
+
+    #[test]
+    fn construct_worker_data_test() {
+        use linking::LinkingConfiguration;
+
+        let src_code = SourceCode::default();
+        let plan = PersistPlan::new(&src_code, None);
+        let linking_config = LinkingConfiguration {
+            data_type: Some("String".to_string()),
+            mutable: true,
+            worker: true,
+            ..Default::default()
+        };
+        let mod_path = &["a"];
+
+        let mut s = String::new();
+        let mut map = SourceMap::default();
+        append_buffer(
+            &src_code,
+            mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            &plan,
+            &mut s,
+            (Path::new("a"), &mut map),
+        );
+        let (len, _) = append_buffer_length(
+            &src_code,
+            mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            &plan,
+        );
+
+        let ans = r##"#[no_mangle]
+pub extern "C" fn _a_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store, app_data: &mut String) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
+kserd::Kserd::new_str("no statements")
+})
+})))
+}
+#[no_mangle]
+pub extern "C" fn _a_intern_eval_data(__papyrus_store: &mut crate::papyrus_store::Store, __papyrus_data: &mut crate::papyrus_transport::Data) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::with_data(__papyrus_data, true, |app_data: &mut String| _a_intern_eval(__papyrus_store, app_data))
+}
+"##;
+        assert_eq!(&s, ans);
+        assert_eq!(len, ans.len());
+
+        // the transport module gains the data handling
+        let files = vec![("lib".into(), src_code)].into_iter().collect();
+        let (s, _) = construct_source_code(
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+        );
+        assert!(s.contains(TRANSPORT_DATA));
+    }
+
+    #[test]
+    fn construct_async_test() {
+        use linking::LinkingConfiguration;
+
+        let mut src_code = SourceCode::default();
+        src_code.stmts.push(StmtGrp(vec![Statement {
+            expr: "f().await".to_string(),
+            semi: false,
+        }]));
+        let plan = PersistPlan::default();
+        let linking_config = LinkingConfiguration {
+            executor: Some(crate::compile::Executor::BlockOn),
+            ..Default::default()
+        };
+        let mod_path = &["lib"];
+
+        let mut s = String::new();
+        let mut map = SourceMap::default();
+        append_buffer(
+            &src_code,
+            mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            &plan,
+            &mut s,
+            (Path::new("lib"), &mut map),
+        );
+        let (len, rng) = append_buffer_length(
+            &src_code,
+            mod_path,
+            &linking_config,
+            &StaticFiles::new(),
+            &plan,
+        );
+
+        let ans = r##"#[no_mangle]
+pub extern "C" fn _lib_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+crate::papyrus_async::block_on(crate::papyrus_async::Cancellable::new(async move {
+std::result::Result::Ok({
+let out0 = f().await;
+{ use crate::papyrus_show::*; (&&&&Show::new(crate::papyrus_store::typed(__papyrus_store, out0))).show() }
+})
+}))
+})))
+}
+"##;
+        assert_eq!(&s, ans);
+        assert_eq!(len, ans.len());
         assert_eq!(
             &ans[rng],
-            "kserd::ToKserd::into_kserd(out1).unwrap().into_owned()"
+            "{ use crate::papyrus_show::*; (&&&&Show::new(crate::papyrus_store::typed(__papyrus_store, out0))).show() }"
+        );
+
+        // the async module is only added with an executor
+        let files: ModsMap = vec![("lib".into(), src_code)].into_iter().collect();
+        let (s, _) = construct_source_code(
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
         );
+        assert!(s.contains(ASYNC_MOD[0]));
+        let (s, _) = construct_source_code(
+            &files,
+            &LinkingConfiguration::default(),
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+        );
+        assert!(!s.contains(ASYNC_MOD[0]));
+    }
+
+    #[test]
+    fn repr_of_value() {
+        use ::kserd::{Kserd, Value};
+
+        for (id, repr) in Repr::IDS.iter() {
+            assert!(SHOW_MOD[0].contains(&format!("repr(\"{}\", ", id)));
+            let kserd = Kserd::with_id(*id, Value::new_string("x".into())).unwrap();
+            assert_eq!(Repr::of(&kserd), *repr);
+        }
+        assert_eq!(Repr::of(&Kserd::new_str("x")), Repr::Kserd);
+        assert_eq!(Repr::TypeName.to_string(), "type name");
     }
 
     #[test]
@@ -1093,65 +2593,90 @@ fn b() {}
         .into_iter()
         .collect();
 
-        let (s, map) = construct_source_code(&map, &linking, &StaticFiles::new());
+        let (s, map) =
+            construct_source_code(&map, &linking, &StaticFiles::new(), &PersistedMap::new());
 
         let ans = r##"#[no_mangle]
-pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _lib_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 mod foo {
 #[no_mangle]
-pub extern "C" fn _foo_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _foo_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 mod bar {
 #[no_mangle]
-pub extern "C" fn _foo_bar_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _foo_bar_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 }}
 mod test {
 #[no_mangle]
-pub extern "C" fn _test_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _test_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 mod inner {
 #[no_mangle]
-pub extern "C" fn _test_inner_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _test_inner_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 }
 mod inner2 {
 #[no_mangle]
-pub extern "C" fn _test_inner2_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _test_inner2_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 }}"##;
 
         let return_stmt = r#"kserd::Kserd::new_str("no statements")"#;
-        assert_eq!(&s, ans);
+        assert_eq!(s, ans.to_string() + &tail(""));
         assert_eq!(
-            &ans[map.get(Path::new("lib")).unwrap().clone()],
+            &ans[map.return_range(Path::new("lib")).unwrap()],
             return_stmt
         );
         assert_eq!(
-            &ans[map.get(Path::new("foo")).unwrap().clone()],
+            &ans[map.return_range(Path::new("foo")).unwrap()],
             return_stmt
         );
         assert_eq!(
-            &ans[map.get(Path::new("foo/bar")).unwrap().clone()],
+            &ans[map.return_range(Path::new("foo/bar")).unwrap()],
             return_stmt
         );
         assert_eq!(
-            &ans[map.get(Path::new("test")).unwrap().clone()],
+            &ans[map.return_range(Path::new("test")).unwrap()],
             return_stmt
         );
         assert_eq!(
-            &ans[map.get(Path::new("test/inner")).unwrap().clone()],
+            &ans[map.return_range(Path::new("test/inner")).unwrap()],
             return_stmt
         );
         assert_eq!(
-            &ans[map.get(Path::new("test/inner2")).unwrap().clone()],
+            &ans[map.return_range(Path::new("test/inner2")).unwrap()],
             return_stmt
         );
     }
@@ -1208,16 +2733,21 @@ kserd::Kserd::new_str("no statements")
         let linking = LinkingConfiguration::default();
         let map = vec![("lib".into(), v)].into_iter().collect();
 
-        let (s, _map) = construct_source_code(&map, &linking, &StaticFiles::new());
+        let (s, _map) =
+            construct_source_code(&map, &linking, &StaticFiles::new(), &PersistedMap::new());
 
         let ans = r##"Up Top
 #[no_mangle]
-pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _lib_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 Test1
 "##;
-        assert_eq!(&s, ans);
+        assert_eq!(s, ans.to_string() + &tail(""));
     }
 
     #[test]
@@ -1330,78 +2860,102 @@ Test1
         .into_iter()
         .collect();
 
-        let (s, map) = construct_source_code(&map, &linking, &static_files);
+        let (s, map) = construct_source_code(&map, &linking, &static_files, &PersistedMap::new());
 
         let ans = r##"mod bar2;
 mod foo2;
 #[no_mangle]
-pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _lib_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 mod foo {
 use crate::bar2;
 use crate::foo2;
 #[no_mangle]
-pub extern "C" fn _foo_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _foo_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 mod bar {
 use crate::bar2;
 use crate::foo2;
 #[no_mangle]
-pub extern "C" fn _foo_bar_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _foo_bar_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 }}
 mod test {
 use crate::bar2;
 use crate::foo2;
 #[no_mangle]
-pub extern "C" fn _test_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _test_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 mod inner {
 use crate::bar2;
 use crate::foo2;
 #[no_mangle]
-pub extern "C" fn _test_inner_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _test_inner_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 }
 mod inner2 {
 use crate::bar2;
 use crate::foo2;
 #[no_mangle]
-pub extern "C" fn _test_inner2_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _test_inner2_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 }}"##;
 
         let return_stmt = r#"kserd::Kserd::new_str("no statements")"#;
         println!("{}", s);
-        assert_eq!(&s, ans);
+        assert_eq!(s, ans.to_string() + &tail(""));
         assert_eq!(
-            &ans[map.get(Path::new("lib")).unwrap().clone()],
+            &ans[map.return_range(Path::new("lib")).unwrap()],
             return_stmt
         );
         assert_eq!(
-            &ans[map.get(Path::new("foo")).unwrap().clone()],
+            &ans[map.return_range(Path::new("foo")).unwrap()],
             return_stmt
         );
         assert_eq!(
-            &ans[map.get(Path::new("foo/bar")).unwrap().clone()],
+            &ans[map.return_range(Path::new("foo/bar")).unwrap()],
             return_stmt
         );
         assert_eq!(
-            &ans[map.get(Path::new("test")).unwrap().clone()],
+            &ans[map.return_range(Path::new("test")).unwrap()],
             return_stmt
         );
         assert_eq!(
-            &ans[map.get(Path::new("test/inner")).unwrap().clone()],
+            &ans[map.return_range(Path::new("test/inner")).unwrap()],
             return_stmt
         );
         assert_eq!(
-            &ans[map.get(Path::new("test/inner2")).unwrap().clone()],
+            &ans[map.return_range(Path::new("test/inner2")).unwrap()],
             return_stmt
         );
     }
@@ -1476,21 +3030,25 @@ kserd::Kserd::new_str("no statements")
         .into_iter()
         .collect();
 
-        let (s, map) = construct_source_code(&map, &linking, &static_files);
+        let (s, map) = construct_source_code(&map, &linking, &static_files, &PersistedMap::new());
 
         let ans = r##"mod bar2;
 mod foo2;
 #[no_mangle]
-pub extern "C" fn _lib_intern_eval() -> kserd::Kserd<'static> {
+pub extern "C" fn _lib_intern_eval(__papyrus_store: &mut crate::papyrus_store::Store) -> crate::papyrus_transport::Output {
+crate::papyrus_transport::Output::eval(crate::papyrus_transport::catch(std::panic::AssertUnwindSafe(|| -> crate::papyrus_transport::Eval {
+std::result::Result::Ok({
 kserd::Kserd::new_str("no statements")
+})
+})))
 }
 "##;
 
         let return_stmt = r#"kserd::Kserd::new_str("no statements")"#;
         println!("{}", s);
-        assert_eq!(&s, ans);
+        assert_eq!(s, ans.to_string() + &tail(""));
         assert_eq!(
-            &ans[map.get(Path::new("lib")).unwrap().clone()],
+            &ans[map.return_range(Path::new("lib")).unwrap()],
             return_stmt
         );
     }
@@ -1,71 +1,240 @@
-use super::LIBRARY_NAME;
-use std::io::{self, BufRead, BufReader};
+use super::fast_path::{self, FastPath};
+use super::interrupt::{Cancel, Cancelled, POLL_INTERVAL};
+use super::progress::{CompileEvent, Tracker};
+use super::{CompileOptions, CrateSource, Diagnostic, Profile, SessionDir, Warnings, LIBRARY_NAME};
+use crate::code::{ModsMap, SourceMap};
+use std::io::{self, BufRead, BufReader, Read};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
 use std::{error, fmt};
 
 /// Run `rustc` in the given compilation directory.
+///
+/// `cargo`'s output is requested as json and passed to `event_cb` as [`CompileEvent`]s, the
+/// crates compiled, the compiler's diagnostics, and the library produced. Compile errors are also
+/// collected into the returned error.
 pub fn compile<P, F>(
     compile_dir: P,
     linking_config: &crate::linking::LinkingConfiguration,
-    mut stderr_line_cb: F,
+    event_cb: F,
 ) -> Result<PathBuf, CompilationError>
 where
     P: AsRef<Path>,
-    F: FnMut(&str),
+    F: FnMut(&CompileEvent),
 {
-    let compile_dir = compile_dir.as_ref();
-    let lib_file = compile_dir.join("target/debug/");
-    let lib_file = if cfg!(windows) {
-        lib_file.join(format!("{}.dll", LIBRARY_NAME))
-    } else if cfg!(target_os = "macos") {
-        lib_file.join(format!("lib{}.dylib", LIBRARY_NAME))
-    } else {
-        lib_file.join(format!("lib{}.so", LIBRARY_NAME))
-    };
+    compile_cancellable(
+        &SessionDir::unshared(compile_dir.as_ref()),
+        linking_config,
+        &CrateSource::default(),
+        &CompileOptions::default(),
+        &Cancel::never(),
+        event_cb,
+    )
+    .map(|(lib_file, _)| lib_file)
+}
 
-    let mut args = vec!["rustc".to_owned(), "--".to_owned(), "-Awarnings".to_owned()];
+/// A line a compiler process writes.
+pub(super) enum Line {
+    Stdout(String),
+    Stderr(String),
+}
+
+/// [`compile`] in a session's directory, sourcing crates from `source`, building with `options`,
+/// and killing `cargo` if `cancel` trips before it finishes.
+///
+/// If the crate has no dependencies other than `kserd`, and `kserd` has been built before, `rustc`
+/// is invoked directly instead of `cargo`.
+///
+/// Returns the library file and the warnings, if `options` has warnings on.
+pub(crate) fn compile_cancellable<F>(
+    session: &SessionDir,
+    linking_config: &crate::linking::LinkingConfiguration,
+    source: &CrateSource,
+    options: &CompileOptions,
+    cancel: &Cancel,
+    mut event_cb: F,
+) -> Result<(PathBuf, Vec<Diagnostic>), CompilationError>
+where
+    F: FnMut(&CompileEvent),
+{
+    let compile_dir = session.path();
+    let target_dir = session.target();
+    let lib_file = library_file(&target_dir, &options.profile);
+
+    source
+        .write_config(compile_dir)
+        .map_err(CompilationError::IOError)?;
+
+    // the arguments passed to the compilation of the REPL crate
+    let mut rustc_args = Vec::new();
+    if options.warnings == Warnings::Off {
+        rustc_args.push("-Awarnings".to_owned());
+    }
+    rustc_args.extend(options.rustc_args.iter().cloned());
 
     for external in linking_config.external_libs.iter() {
-        args.push("-L".to_owned());
-        args.push(format!("dependency={}", external.deps_path().display()));
-        args.push("--extern".to_owned());
-        args.push(format!(
+        rustc_args.push("-L".to_owned());
+        rustc_args.push(format!("dependency={}", external.deps_path().display()));
+        rustc_args.push("--extern".to_owned());
+        rustc_args.push(format!(
             "{}={}",
             external.lib_name(),
             external.lib_path().display()
         ));
     }
 
-    let mut child = Command::new("cargo")
-        .current_dir(compile_dir)
-        .args(&args)
+    if let Some(fast) = FastPath::find(session, options) {
+        let r = fast.compile(
+            session,
+            &lib_file,
+            options,
+            &rustc_args,
+            cancel,
+            &mut event_cb,
+        );
+        if let Some(r) = r {
+            return r;
+        }
+    }
+
+    let mut args = Vec::new();
+    args.extend(options.toolchain.as_ref().map(|t| format!("+{}", t)));
+    args.push("rustc".to_owned());
+    args.push("--message-format=json".to_owned());
+    // the verbose output has the crate's metadata, which the fast path compiles with
+    let verbose = FastPath::eligible(session, options);
+    if verbose {
+        args.push("-v".to_owned());
+    }
+    if options.profile == Profile::Release {
+        args.push("--release".to_owned());
+    }
+    args.extend(source.network_arg().map(String::from));
+    args.push("--".to_owned());
+    args.extend(rustc_args);
+
+    let mut cmd = Command::new("cargo");
+    if options.clippy_driver() {
+        cmd.env("RUSTC_WORKSPACE_WRAPPER", "clippy-driver");
+    }
+    cmd.current_dir(compile_dir)
+        .env("CARGO_TARGET_DIR", &target_dir)
+        .args(&args);
+
+    let mut tracker = Tracker::new(compile_dir);
+    let mut diagnostics = Vec::new();
+    let mut stderr = String::new();
+    let mut kserd = None;
+    let mut metadata = None;
+    let status = run(cmd, cancel, |line| match line {
+        Line::Stdout(line) => {
+            if kserd.is_none() {
+                kserd = fast_path::kserd_rlib(&line);
+            }
+            for event in tracker.stdout_line(&line) {
+                event_cb(&event);
+                match event {
+                    CompileEvent::Diagnostic(d) if d.is_error() || d.is_warning() => {
+                        diagnostics.push(d)
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Line::Stderr(line) if verbose && fast_path::is_verbose(&line) => {
+            if metadata.is_none() {
+                metadata = fast_path::crate_metadata(&line);
+            }
+        }
+        Line::Stderr(line) => {
+            event_cb(&tracker.stderr_line(&line));
+            stderr.push_str(&line);
+            stderr.push('\n');
+        }
+    })?;
+
+    let (diagnostics, warnings) = diagnostics.into_iter().partition(Diagnostic::is_error);
+
+    event_cb(&tracker.finished(status.success()));
+    if status.success() {
+        if let (Some(kserd), Some(metadata)) = (kserd, metadata) {
+            FastPath::record(session, options, &kserd, &metadata);
+        }
+        Ok((lib_file, warnings))
+    } else if let Some(name) = source.unavailable_crate(&stderr) {
+        Err(CompilationError::CrateUnavailable(name, source.to_string()))
+    } else {
+        Err(CompilationError::CompileError(diagnostics, stderr))
+    }
+}
+
+/// Runs `cmd`, passing the lines it writes to `line_cb`, and killing it if `cancel` trips before it
+/// finishes.
+pub(super) fn run<F>(
+    mut cmd: Command,
+    cancel: &Cancel,
+    mut line_cb: F,
+) -> Result<ExitStatus, CompilationError>
+where
+    F: FnMut(Line),
+{
+    let mut child = cmd
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
         .map_err(|_| CompilationError::NoBuildCommand)?;
 
-    let stderr = {
-        let rdr = BufReader::new(child.stderr.as_mut().expect("stderr should be piped"));
-        let mut s = String::new();
-        for line in rdr.lines() {
-            let line = line.unwrap();
-            stderr_line_cb(&line);
-            s.push_str(&line);
-            s.push('\n');
+    // both pipes are read on other threads so neither fills up and blocks the child, the lines
+    // are sent back so the callback runs on this thread while cancel is polled
+    let (tx, rx) = mpsc::channel();
+    let stdout = child.stdout.take().expect("stdout should be piped");
+    send_lines(stdout, tx.clone(), Line::Stdout);
+    let stderr = child.stderr.take().expect("stderr should be piped");
+    send_lines(stderr, tx, Line::Stderr);
+
+    loop {
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok(line) => line_cb(line),
+            Err(RecvTimeoutError::Timeout) => {
+                if let Err(c) = cancel.check() {
+                    // rustc processes started by cargo are left to finish on their own
+                    child.kill().ok();
+                    child.wait().ok();
+                    return Err(CompilationError::Cancelled(c));
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
         }
-        s
-    };
+    }
 
-    match child.wait() {
-        Ok(ex) => {
-            if ex.success() {
-                Ok(lib_file)
-            } else {
-                Err(CompilationError::CompileError(stderr))
+    child.wait().map_err(CompilationError::IOError)
+}
+
+/// Sends the lines read from `pipe` on another thread.
+fn send_lines<R, F>(pipe: R, tx: Sender<Line>, line: F)
+where
+    R: Read + Send + 'static,
+    F: Fn(String) -> Line + Send + 'static,
+{
+    std::thread::spawn(move || {
+        for l in BufReader::new(pipe).lines().map_while(Result::ok) {
+            if tx.send(line(l)).is_err() {
+                break;
             }
         }
-        Err(e) => Err(CompilationError::IOError(e)),
+    });
+}
+
+/// The library file `cargo` outputs in the target directory for the profile.
+pub(crate) fn library_file(target_dir: &Path, profile: &Profile) -> PathBuf {
+    let lib_file = target_dir.join(profile.target_subdir());
+    if cfg!(windows) {
+        lib_file.join(format!("{}.dll", LIBRARY_NAME))
+    } else if cfg!(target_os = "macos") {
+        lib_file.join(format!("lib{}.dylib", LIBRARY_NAME))
+    } else {
+        lib_file.join(format!("lib{}.so", LIBRARY_NAME))
     }
 }
 
@@ -95,6 +264,25 @@ pub fn unshackle_library_file<P: AsRef<Path>>(libpath: P) -> PathBuf {
     rename_lib_file(libpath).unwrap_or_else(|_| libpath.to_owned())
 }
 
+/// Deletes the renamed library files in the target directory.
+///
+/// A file is deleted once its library is unloaded, this removes files left behind by sessions
+/// which did not exit cleanly. Files which are still loaded may fail to delete, which is ignored.
+pub(crate) fn sweep_library_files(target_dir: &Path) {
+    let entries = ["debug", "release"]
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(target_dir.join(dir)).ok())
+        .flatten();
+
+    for entry in entries.filter_map(Result::ok) {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with("papyrus.") && name.ends_with(".lib") {
+            std::fs::remove_file(entry.path()).ok();
+        }
+    }
+}
+
 fn rename_lib_file<P: AsRef<Path>>(compiled_lib: P) -> io::Result<PathBuf> {
     let no_parent = PathBuf::new();
     let parent = compiled_lib.as_ref().parent().unwrap_or(&no_parent);
@@ -112,10 +300,77 @@ fn rename_lib_file<P: AsRef<Path>>(compiled_lib: P) -> io::Result<PathBuf> {
 pub enum CompilationError {
     /// Failed to initialise `cargo build`. Usually because `cargo` is not in your `PATH` or Rust is not installed.
     NoBuildCommand,
-    /// A compiling error occured, with the contents of the stderr.
-    CompileError(String),
+    /// A compiling error occured, with the error diagnostics and the contents of the stderr.
+    CompileError(Vec<Diagnostic>, String),
     /// Generic IO errors.
     IOError(io::Error),
+    /// Compilation was interrupted or timed out, and `cargo` was killed.
+    Cancelled(Cancelled),
+    /// A crate could not be found offline or in the source replacement, with the crate name and
+    /// the source searched.
+    CrateUnavailable(String, String),
+}
+
+impl CompilationError {
+    /// Render the error, with compile errors rendered under the REPL inputs which caused them.
+    ///
+    /// `srcmap` and `mods` should be what the compilation directory was built with.
+    pub fn render(&self, srcmap: &SourceMap, mods: &ModsMap) -> String {
+        match self {
+            CompilationError::CompileError(diagnostics, _) if !diagnostics.is_empty() => {
+                let mut s = String::new();
+                for d in diagnostics {
+                    if !s.is_empty() {
+                        s.push('\n');
+                    }
+                    d.render(srcmap, mods, &mut s)
+                        .expect("writing to string buffer should not fail");
+                }
+                s
+            }
+            e => e.to_string(),
+        }
+    }
+}
+
+impl CompilationError {
+    /// The bindings which failed to compile as they could not be persisted into the value store,
+    /// usually because they were moved or are borrowing.
+    ///
+    /// If a persisted binding is borrowed by another, only the borrowing binding is returned.
+    pub fn unpersistable<'a>(&self, srcmap: &SourceMap<'a>) -> Vec<(&'a Path, String)> {
+        let diagnostics = match self {
+            CompilationError::CompileError(d, _) => d,
+            _ => return Vec::new(),
+        };
+
+        let mut v = Vec::new();
+        for d in diagnostics {
+            let (primary, secondary): (Vec<_>, Vec<_>) = d
+                .spans
+                .iter()
+                .filter_map(|x| {
+                    srcmap
+                        .persisted_binding(x.line_start)
+                        .map(|b| (x.is_primary, b))
+                })
+                .partition(|x| x.0);
+            let bindings = if secondary.is_empty() {
+                primary
+            } else {
+                secondary
+            };
+            v.extend(
+                bindings
+                    .into_iter()
+                    .map(|(_, (path, name))| (path, name.to_string())),
+            );
+        }
+
+        v.sort();
+        v.dedup();
+        v
+    }
 }
 
 impl error::Error for CompilationError {}
@@ -126,8 +381,20 @@ impl fmt::Display for CompilationError {
             CompilationError::NoBuildCommand => {
                 write!(f, "cargo build command failed to start, is rust installed?")
             }
-            CompilationError::CompileError(e) => write!(f, "{}", e),
+            CompilationError::CompileError(diagnostics, stderr) => {
+                if diagnostics.is_empty() {
+                    write!(f, "{}", stderr)
+                } else {
+                    diagnostics.iter().try_for_each(|d| write!(f, "{}", d))
+                }
+            }
             CompilationError::IOError(e) => write!(f, "io error occurred: {}", e),
+            CompilationError::Cancelled(c) => write!(f, "compilation {}", c),
+            CompilationError::CrateUnavailable(name, source) => write!(
+                f,
+                "crate `{}` is not available in {}, add it to the source or allow network access",
+                name, source
+            ),
         }
     }
 }
@@ -139,9 +406,122 @@ fn compilation_error_fmt_test() {
         &e.to_string(),
         "cargo build command failed to start, is rust installed?"
     );
-    let e = CompilationError::CompileError("compile err".to_string());
+    let e = CompilationError::CompileError(Vec::new(), "compile err".to_string());
     assert_eq!(&e.to_string(), "compile err");
+    let d = Diagnostic {
+        level: "error".to_string(),
+        message: "mismatched types".to_string(),
+        code: None,
+        spans: Vec::new(),
+        children: Vec::new(),
+        rendered: Some("error: mismatched types\n".to_string()),
+    };
+    let e = CompilationError::CompileError(vec![d.clone(), d], "compile err".to_string());
+    assert_eq!(
+        &e.to_string(),
+        "error: mismatched types\nerror: mismatched types\n"
+    );
     let ioe = io::Error::new(io::ErrorKind::Other, "test");
     let e = CompilationError::IOError(ioe);
     assert_eq!(&e.to_string(), "io error occurred: test");
+    let e = CompilationError::Cancelled(Cancelled::Interrupted);
+    assert_eq!(&e.to_string(), "compilation interrupted");
+    let e = CompilationError::CrateUnavailable("rand".to_string(), "cargo's cache".to_string());
+    assert_eq!(
+        &e.to_string(),
+        "crate `rand` is not available in cargo's cache, add it to the source or allow network access"
+    );
+}
+
+#[test]
+fn sweep_library_files_test() {
+    let dir = Path::new("target/testing/sweep_library_files");
+    std::fs::create_dir_all(dir.join("target/debug")).unwrap();
+    let lib = dir.join("target/debug/libpapyrus_mem_code.so");
+    std::fs::write(&lib, "").unwrap();
+    let renamed = unshackle_library_file(&lib);
+    assert!(renamed.exists());
+    std::fs::write(&lib, "").unwrap();
+
+    sweep_library_files(&dir.join("target"));
+    assert!(!renamed.exists());
+    assert!(lib.exists());
+}
+
+#[test]
+fn unpersistable_test() {
+    use crate::code::{construct_source_code, SourceCode, Statement, StaticFiles, StmtGrp};
+    use crate::compile::DiagnosticSpan;
+
+    let mut src = SourceCode::default();
+    src.stmts.push(StmtGrp(vec![
+        Statement {
+            expr: "let a = String::new()".to_string(),
+            semi: true,
+        },
+        Statement {
+            expr: "let b = &a".to_string(),
+            semi: true,
+        },
+        Statement {
+            expr: "()".to_string(),
+            semi: false,
+        },
+    ]));
+    let mods: ModsMap = vec![(PathBuf::from("lib"), src)].into_iter().collect();
+    let (code, srcmap) = construct_source_code(
+        &mods,
+        &Default::default(),
+        &StaticFiles::new(),
+        &Default::default(),
+    );
+
+    let line = |name: &str| {
+        let pat = format!("__papyrus_store, \"{}\", ", name);
+        code.lines().position(|l| l.contains(&pat)).unwrap() + 1
+    };
+    let span = |line_start: usize, is_primary: bool| DiagnosticSpan {
+        file_name: "src/lib.rs".to_string(),
+        line_start,
+        line_end: line_start,
+        column_start: 1,
+        column_end: 2,
+        is_primary,
+        label: None,
+    };
+    let diagnostic = |spans| Diagnostic {
+        level: "error".to_string(),
+        message: "`a` does not live long enough".to_string(),
+        code: Some("E0597".to_string()),
+        spans,
+        children: Vec::new(),
+        rendered: None,
+    };
+
+    // the borrowing binding is preferred over the borrowed one
+    let e = CompilationError::CompileError(
+        vec![diagnostic(vec![
+            span(line("a"), true),
+            span(line("b"), false),
+        ])],
+        String::new(),
+    );
+    assert_eq!(
+        e.unpersistable(&srcmap),
+        vec![(Path::new("lib"), "b".to_string())]
+    );
+
+    // a primary span is used if it is the only one on a persisting line
+    let e = CompilationError::CompileError(
+        vec![diagnostic(vec![span(line("a"), true), span(1, false)])],
+        String::new(),
+    );
+    assert_eq!(
+        e.unpersistable(&srcmap),
+        vec![(Path::new("lib"), "a".to_string())]
+    );
+
+    assert!(CompilationError::NoBuildCommand
+        .unpersistable(&srcmap)
+        .is_empty());
 }
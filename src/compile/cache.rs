@@ -0,0 +1,177 @@
+//! Reusing compiled libraries when the compilation directory is unchanged.
+//!
+//! Compiled libraries are copied into `cache/` in the compilation directory, shared between
+//! sessions, named by a hash of the generated source, the manifest, the linked libraries, the
+//! crate source, and the build options. The least recently used entries are removed once the
+//! cache is full.
+use super::{
+    build::library_file, compile_cancellable, Cancel, CompilationError, CompileEvent,
+    CompileOptions, CrateSource, Diagnostic, SessionDir,
+};
+use crate::linking::LinkingConfiguration;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+/// [`compile_cancellable`], reusing a cached library if the session's directory has been compiled
+/// before.
+///
+/// At most `size` libraries are cached, a `size` of zero disables the cache. Caching is best
+/// effort, failing to read or write the cache falls back to compiling. A library from the cache
+/// has no warnings, and only its artifact and the build finishing are passed to `event_cb`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn compile_cached<F>(
+    session: &SessionDir,
+    linking_config: &LinkingConfiguration,
+    source: &CrateSource,
+    options: &CompileOptions,
+    size: usize,
+    cancel: &Cancel,
+    mut event_cb: F,
+) -> Result<(PathBuf, Vec<Diagnostic>), CompilationError>
+where
+    F: FnMut(&CompileEvent),
+{
+    let start = Instant::now();
+    let key = match size {
+        0 => None,
+        _ => build_hash(session.path(), linking_config, source, options).ok(),
+    };
+    let key = match key {
+        Some(k) => k,
+        None => {
+            return compile_cancellable(session, linking_config, source, options, cancel, event_cb)
+        }
+    };
+
+    let cache = session.cache();
+    let entry = cache.join(key.to_hex().as_str());
+    let lib_file = library_file(&session.target(), &options.profile);
+
+    // the library is copied to where cargo outputs it, to be unshackled as if compiled
+    if entry.is_file() && copy(&entry, &lib_file).is_ok() {
+        touch(&entry).ok();
+        event_cb(&CompileEvent::Artifact(lib_file.clone()));
+        event_cb(&CompileEvent::Finished(true, start.elapsed()));
+        return Ok((lib_file, Vec::new()));
+    }
+
+    let (lib_file, warnings) =
+        compile_cancellable(session, linking_config, source, options, cancel, event_cb)?;
+
+    if add_entry(&lib_file, &entry).is_ok() {
+        evict(&cache, size).ok();
+    }
+
+    Ok((lib_file, warnings))
+}
+
+/// Hashes what is compiled: the generated source and static files, `Cargo.toml`, the linked
+/// libraries, the crate source, and the build options.
+fn build_hash(
+    compile_dir: &Path,
+    linking_config: &LinkingConfiguration,
+    source: &CrateSource,
+    options: &CompileOptions,
+) -> io::Result<blake3::Hash> {
+    fn hash_dir(hasher: &mut blake3::Hasher, root: &Path, dir: &Path) -> io::Result<()> {
+        let mut entries = fs::read_dir(dir)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for path in entries {
+            if path.is_dir() {
+                hash_dir(hasher, root, &path)?;
+            } else {
+                let rel = path.strip_prefix(root).unwrap_or(&path);
+                hasher.update(rel.to_string_lossy().as_bytes());
+                hasher.update(&fs::read(&path)?);
+            }
+        }
+        Ok(())
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    hasher.update(&fs::read(compile_dir.join("Cargo.toml"))?);
+    let src = compile_dir.join("src");
+    hash_dir(&mut hasher, &src, &src)?;
+
+    // a relinked library is detected by its modification time
+    let mut externs: Vec<_> = linking_config.external_libs.iter().collect();
+    externs.sort_by_key(|x| x.lib_path());
+    for external in externs {
+        let meta = fs::metadata(external.lib_path())?;
+        hasher.update(external.lib_path().to_string_lossy().as_bytes());
+        hasher.update(format!("{:?}{}", meta.modified()?, meta.len()).as_bytes());
+    }
+
+    hasher.update(format!("{:?}{:?}", source, options).as_bytes());
+
+    Ok(hasher.finalize())
+}
+
+fn copy(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(from, to).map(|_| ())
+}
+
+/// Writes the library into the cache, renaming it into place so other sessions do not read a
+/// partial entry. The written entry has the current modification time.
+fn add_entry(lib_file: &Path, entry: &Path) -> io::Result<()> {
+    let tmp = entry.with_extension(uuid::Uuid::new_v4().to_simple().to_string());
+    let lib = fs::read(lib_file)?;
+    if let Some(parent) = entry.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let written = fs::write(&tmp, lib).and_then(|_| fs::rename(&tmp, entry));
+    if written.is_err() {
+        fs::remove_file(&tmp).ok();
+    }
+    written
+}
+
+/// Marks the entry as recently used by rewriting it.
+fn touch(entry: &Path) -> io::Result<()> {
+    add_entry(entry, entry)
+}
+
+/// Removes the least recently used entries past `size`.
+fn evict(cache: &Path, size: usize) -> io::Result<()> {
+    let mut entries = fs::read_dir(cache)?
+        .filter_map(Result::ok)
+        .filter_map(|e| Some((e.metadata().ok()?.modified().ok()?, e.path())))
+        .collect::<Vec<_>>();
+    entries.sort_by(|a, b| b.cmp(a));
+    for (_, path) in entries.into_iter().skip(size) {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn evict_least_recently_used() {
+        let cache = Path::new("target/testing/lib-cache");
+        fs::remove_dir_all(cache).ok();
+        fs::create_dir_all(cache).unwrap();
+        for name in &["a", "b", "c"] {
+            fs::write(cache.join(name), name).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        touch(&cache.join("a")).unwrap();
+
+        evict(cache, 2).unwrap();
+        assert!(cache.join("a").exists());
+        assert!(!cache.join("b").exists());
+        assert!(cache.join("c").exists());
+    }
+}
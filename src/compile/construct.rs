@@ -1,23 +1,32 @@
-use super::LIBRARY_NAME;
+use super::{transport::KSERD_VERSION, CompileOptions, Edition, Profile, LIBRARY_NAME};
 use crate::{
-    code::{self, CrateType, ModsMap, StaticFiles},
+    code::{
+        self, CrateType, Dependency, ModsMap, PersistedMap, SourceCode, SourceMap, StaticFiles,
+    },
     linking,
 };
 use std::{
+    borrow::Cow,
+    collections::BTreeSet,
     fs,
     io::{self, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 /// Constructs the compile directory.
 /// Takes a list of source files and writes the contents to file.
-/// Builds `Cargo.toml` using crates found in `SourceFile`.
-pub fn build_compile_dir<P>(
+/// Builds `Cargo.toml` using crates found in `SourceFile`, and the edition and profile of
+/// `options`.
+///
+/// Returns the [`SourceMap`] of the written `lib.rs`.
+pub fn build_compile_dir<'a, P>(
     compile_dir: P,
-    mods_map: &ModsMap,
+    mods_map: &'a ModsMap,
     linking_config: &linking::LinkingConfiguration,
     static_files: &StaticFiles,
-) -> io::Result<()>
+    persisted: &PersistedMap,
+    options: &CompileOptions,
+) -> io::Result<SourceMap<'a>>
 where
     P: AsRef<Path>,
 {
@@ -30,16 +39,250 @@ where
     let crates = dedup_crates(crates);
 
     // write cargo toml contents
-    create_file_and_dir(compile_dir.join("Cargo.toml"))?
-        .write_all(cargotoml_contents(LIBRARY_NAME, crates.into_iter()).as_bytes())?;
+    create_file_and_dir(compile_dir.join("Cargo.toml"))?.write_all(
+        cargotoml_contents(
+            LIBRARY_NAME,
+            crates.into_iter(),
+            linking_config.worker_data_type().is_some(),
+            options,
+        )
+        .as_bytes(),
+    )?;
 
-    let (src_code, _map) = code::construct_source_code(mods_map, linking_config, static_files);
+    let (src_code, map) =
+        code::construct_source_code(mods_map, linking_config, static_files, persisted);
 
     create_file_and_dir(compile_dir.join("src/lib.rs"))?.write_all(src_code.as_bytes())?;
 
+    Ok(map)
+}
+
+/// Writes the REPL session as a standalone Cargo project into `export_dir`.
+///
+/// The `lib` module becomes `src/main.rs`, with its statements folded into `fn main()`. Other
+/// modules are written to `src/<mod-path>.rs`, with their statements folded into a `pub fn run()`.
+/// Items are written verbatim. A trailing expression is only bound to `out#` if a later statement
+/// group of the module uses it. Static files are copied from the compile directory, and the
+/// referenced crates are listed in `[dependencies]`. The project uses the `edition` of the REPL.
+///
+/// External libraries and app data can not be exported, code using them needs fixing by hand.
+pub fn export_project<P, Q>(
+    export_dir: P,
+    compile_dir: Q,
+    mods_map: &ModsMap,
+    linking_config: &linking::LinkingConfiguration,
+    static_files: &StaticFiles,
+    edition: Edition,
+) -> io::Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let export_dir = export_dir.as_ref();
+    let compile_dir = compile_dir.as_ref();
+
+    let crates = mods_map
+        .iter()
+        .flat_map(|kvp| kvp.1.crates.iter())
+        .chain(static_files.iter().flat_map(|x| x.crates.iter()));
+    let crates = dedup_crates(crates);
+
+    create_file_and_dir(export_dir.join("Cargo.toml"))?.write_all(
+        export_cargotoml_contents(&package_name(export_dir), crates.into_iter(), edition)
+            .as_bytes(),
+    )?;
+
+    let static_mods: Vec<&str> = static_files
+        .iter()
+        .filter_map(|x| code::static_file_mod_name(&x.path))
+        .collect();
+
+    // parent modules may only exist as a path prefix
+    let mods: BTreeSet<PathBuf> = mods_map
+        .keys()
+        .filter(|x| x.as_path() != Path::new("lib"))
+        .flat_map(|x| x.ancestors().filter(|x| x != &Path::new("")))
+        .map(Path::to_path_buf)
+        .collect();
+
+    let lib = Path::new("lib");
+    let src = export_mod_code(
+        mods_map.get(lib),
+        mods.iter().filter(|x| x.parent() == Some(Path::new(""))),
+        &static_mods,
+        linking_config,
+        true,
+    );
+    create_file_and_dir(export_dir.join("src/main.rs"))?.write_all(src.as_bytes())?;
+
+    for m in &mods {
+        let src = export_mod_code(
+            mods_map.get(m),
+            mods.iter().filter(|x| x.parent() == Some(m.as_path())),
+            &static_mods,
+            linking_config,
+            false,
+        );
+        create_file_and_dir(export_dir.join("src").join(m).with_extension("rs"))?
+            .write_all(src.as_bytes())?;
+    }
+
+    for sf in static_files.iter() {
+        let code = fs::read(compile_dir.join("src").join(&sf.path))?;
+        create_file_and_dir(export_dir.join("src").join(&sf.path))?.write_all(&code)?;
+    }
+
     Ok(())
 }
 
+/// The code of an exported module, with the child modules declared.
+///
+/// The crate root declares the static file modules, other modules import them, the same as the
+/// compiled library.
+fn export_mod_code<'a>(
+    src_code: Option<&SourceCode>,
+    children: impl Iterator<Item = &'a PathBuf>,
+    static_mods: &[&str],
+    linking_config: &linking::LinkingConfiguration,
+    root: bool,
+) -> String {
+    let mut buf = String::new();
+
+    let empty = SourceCode::default();
+    let src_code = src_code.unwrap_or(&empty);
+
+    // inner attributes must come before the module declarations
+    for item in src_code.items.iter().filter(|x| x.1) {
+        buf.push_str(&item.0);
+        buf.push_str("\n\n");
+    }
+
+    let decls = buf.len();
+    for child in children
+        .filter_map(|x| x.file_name())
+        .filter_map(|x| x.to_str())
+    {
+        buf.push_str("mod ");
+        buf.push_str(child);
+        buf.push_str(";\n");
+    }
+    for m in static_mods {
+        buf.push_str(if root { "mod " } else { "use crate::" });
+        buf.push_str(m);
+        buf.push_str(";\n");
+    }
+    if buf.len() > decls {
+        buf.push('\n');
+    }
+
+    if !linking_config.persistent_module_code.is_empty() {
+        buf.push_str(&linking_config.persistent_module_code);
+        buf.push_str("\n\n");
+    }
+
+    if root || !src_code.stmts.is_empty() {
+        buf.push_str(if root {
+            "fn main() -> Result<(), Box<dyn std::error::Error>> {\n"
+        } else {
+            "pub fn run() -> Result<(), Box<dyn std::error::Error>> {\n"
+        });
+        let grps = &src_code.stmts;
+        for (i, grp) in grps.iter().enumerate() {
+            let used_later = |name: &str| {
+                grps[i + 1..]
+                    .iter()
+                    .flat_map(|x| x.0.iter())
+                    .any(|x| uses_ident(&x.expr, name))
+            };
+            let code: Vec<_> = grp.0.iter().map(|x| unrewrite_returns(&x.expr)).collect();
+
+            // an early return ends only its group, as when evaluated, unless later groups use
+            // its bindings
+            let mut names = Vec::new();
+            grp.bindings(i, &mut names);
+            let returns = code.iter().any(|x| matches!(x, Cow::Owned(_))); // only returns are rewritten
+            let closure = returns && !names.iter().any(|x| used_later(x));
+            let indent = if closure { "        " } else { "    " };
+            if closure {
+                buf.push_str("    (|| -> Result<(), Box<dyn std::error::Error>> {\n");
+            }
+
+            let last = grp.0.len().saturating_sub(1);
+            for (j, (stmt, code)) in grp.0.iter().zip(&code).enumerate() {
+                buf.push_str(indent);
+                if j == last && !stmt.semi {
+                    let out = format!("out{}", i);
+                    if used_later(&out) {
+                        buf.push_str("let ");
+                        buf.push_str(&out);
+                        buf.push_str(" = ");
+                    }
+                }
+                buf.push_str(code);
+                buf.push_str(";\n");
+            }
+
+            if closure {
+                buf.push_str("        Ok(())\n    })()?;\n");
+            }
+        }
+        buf.push_str("    Ok(())\n}\n\n");
+    }
+
+    for item in src_code.items.iter().filter(|x| !x.1) {
+        buf.push_str(&item.0);
+        buf.push_str("\n\n");
+    }
+
+    buf.pop();
+    buf
+}
+
+/// Returns in statements are rewritten to return through the value store when parsed, in the
+/// exported function they drop the value and return `Ok(())`.
+fn unrewrite_returns(code: &str) -> Cow<'_, str> {
+    // formatted, and as the token stream prints when not formatted
+    const RET: [&str; 2] = [
+        "crate::papyrus_ret!(__papyrus_store, ",
+        "crate :: papyrus_ret ! (__papyrus_store , ",
+    ];
+    if RET.iter().any(|x| code.contains(x)) {
+        RET.iter()
+            .fold(code.to_string(), |s, x| s.replace(x, "(|_| Ok(()))("))
+            .into()
+    } else {
+        code.into()
+    }
+}
+
+/// `code` contains `ident` which is not part of a longer identifier.
+fn uses_ident(code: &str, ident: &str) -> bool {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    code.match_indices(ident).any(|(i, _)| {
+        !code[..i].ends_with(is_ident) && !code[i + ident.len()..].starts_with(is_ident)
+    })
+}
+
+/// The package name from the export directory name.
+fn package_name(export_dir: &Path) -> String {
+    let name: String = export_dir
+        .file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or_default()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '-' | '_' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            _ => '-',
+        })
+        .collect();
+
+    match name.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => name,
+        _ => "papyrus-export".to_string(),
+    }
+}
+
 fn dedup_crates<'a>(crates: impl Iterator<Item = &'a CrateType>) -> Vec<&'a CrateType> {
     let mut crates: Vec<&CrateType> = crates.collect();
     crates.sort_by_key(|x| &x.cargo_name);
@@ -57,12 +300,27 @@ fn create_file_and_dir<P: AsRef<Path>>(file: P) -> io::Result<fs::File> {
     fs::File::create(file)
 }
 
-fn cargotoml_contents<'a, I: Iterator<Item = &'a CrateType>>(lib_name: &str, crates: I) -> String {
+/// The `encode` feature of `kserd` is enabled if app data is serialized.
+fn cargotoml_contents<'a, I: Iterator<Item = &'a CrateType>>(
+    lib_name: &str,
+    crates: I,
+    encode: bool,
+    options: &CompileOptions,
+) -> String {
+    // a custom opt-level overrides the dev profile, numbers are integers in toml
+    let profile = match &options.profile {
+        Profile::OptLevel(l) if l.parse::<u8>().is_ok() => {
+            format!("\n[profile.dev]\nopt-level = {}\n", l)
+        }
+        Profile::OptLevel(l) => format!("\n[profile.dev]\nopt-level = \"{}\"\n", l),
+        _ => String::new(),
+    };
+
     format!(
         r#"[package]
 name = "{lib_name}"
 version = "0.1.0"
-edition = "2018"
+edition = "{edition}"
 
 [lib]
 name = "{lib_name}"
@@ -70,17 +328,97 @@ crate-type = [ "cdylib" ]
 path = "src/lib.rs"
 
 [dependencies]
-kserd = {{ version = "0.3", default-features = false, features = [ "format" ] }}
+kserd = {{ version = "{kserd}", default-features = false, features = [ "format"{encode} ] }}
 {crates}
-"#,
+{profile}"#,
         lib_name = lib_name,
-        crates = crates
-            .map(|c| format!(r#"{} = "*""#, c.cargo_name))
-            .collect::<Vec<_>>()
-            .join("\n")
+        edition = options.edition,
+        kserd = KSERD_VERSION,
+        encode = if encode { r#", "encode""# } else { "" },
+        crates = dependencies(crates),
+        profile = profile
+    )
+}
+
+/// An exported project is a binary, it does not need `kserd`.
+fn export_cargotoml_contents<'a, I: Iterator<Item = &'a CrateType>>(
+    name: &str,
+    crates: I,
+    edition: Edition,
+) -> String {
+    format!(
+        r#"[package]
+name = "{name}"
+version = "0.1.0"
+edition = "{edition}"
+
+[dependencies]
+{crates}
+"#,
+        name = name,
+        edition = edition,
+        crates = dependencies(crates)
     )
 }
 
+fn dependencies<'a, I: Iterator<Item = &'a CrateType>>(crates: I) -> String {
+    crates
+        .map(|c| format!("{} = {}", c.cargo_name, dependency_toml(&c.dep)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A version only dependency is written as a string, otherwise as an inline table.
+///
+/// A relative path is made absolute with the current directory, as `Cargo.toml` is not written
+/// to the REPL's working directory.
+fn dependency_toml(dep: &Dependency) -> String {
+    let version = match &dep.version {
+        Some(v) => Some(v.as_str()),
+        None if dep.path.is_none() && dep.git.is_none() => Some("*"),
+        None => None,
+    };
+
+    let path = dep.path.as_ref().map(|p| {
+        std::env::current_dir()
+            .map(|d| d.join(p))
+            .unwrap_or_else(|_| p.into())
+            .to_string_lossy()
+            .into_owned()
+    });
+
+    let mut keys = Vec::new();
+    let strs = [
+        ("version", version),
+        ("path", path.as_deref()),
+        ("git", dep.git.as_deref()),
+        ("branch", dep.branch.as_deref()),
+        ("tag", dep.tag.as_deref()),
+        ("rev", dep.rev.as_deref()),
+    ];
+    for (k, v) in strs.iter() {
+        if let Some(v) = v {
+            keys.push(format!("{} = {}", k, toml_str(v)));
+        }
+    }
+    if !dep.features.is_empty() {
+        let features: Vec<_> = dep.features.iter().map(|f| toml_str(f)).collect();
+        keys.push(format!("features = [ {} ]", features.join(", ")));
+    }
+    if !dep.default_features {
+        keys.push("default-features = false".to_string());
+    }
+
+    match version {
+        Some(v) if keys.len() == 1 => toml_str(v),
+        _ => format!("{{ {} }}", keys.join(", ")),
+    }
+}
+
+fn toml_str(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -116,4 +454,144 @@ mod tests {
         let v: Vec<_> = crates.iter().map(|x| &x.cargo_name).collect();
         assert_eq!(&v, &["rand", "third"]);
     }
+
+    #[test]
+    fn test_cargotoml_options() {
+        let toml = cargotoml_contents("lib", std::iter::empty(), false, &Default::default());
+        assert!(toml.contains("edition = \"2018\""));
+        assert!(!toml.contains("[profile.dev]"));
+
+        let options = CompileOptions {
+            profile: Profile::OptLevel("3".into()),
+            edition: Edition::E2021,
+            ..Default::default()
+        };
+        let toml = cargotoml_contents("lib", std::iter::empty(), false, &options);
+        assert!(toml.contains("edition = \"2021\""));
+        assert!(toml.ends_with("\n[profile.dev]\nopt-level = 3\n"));
+
+        let options = CompileOptions {
+            profile: Profile::OptLevel("z".into()),
+            ..Default::default()
+        };
+        let toml = cargotoml_contents("lib", std::iter::empty(), false, &options);
+        assert!(toml.ends_with("\n[profile.dev]\nopt-level = \"z\"\n"));
+    }
+
+    #[test]
+    fn test_dependency_toml() {
+        let dep = |s: &str| dependency_toml(&CrateType::parse_str(s).unwrap().dep);
+
+        assert_eq!(dep("extern crate a;"), r#""*""#);
+        assert_eq!(
+            dep(r#"#[dep(version = "1.0")] extern crate a;"#),
+            r#""1.0""#
+        );
+        assert_eq!(
+            dep(r#"#[dep(features = ["x", "y"], default_features = false)] extern crate a;"#),
+            r#"{ version = "*", features = [ "x", "y" ], default-features = false }"#
+        );
+        assert_eq!(
+            dep(r#"#[dep(git = "https://a.b/c", rev = "a\"b")] extern crate a;"#),
+            r#"{ git = "https://a.b/c", rev = "a\"b" }"#
+        );
+        let path = std::env::current_dir().unwrap().join("../a");
+        assert_eq!(
+            dep(r#"#[dep(path = "../a", version = "0.1")] extern crate a;"#),
+            format!(
+                r#"{{ version = "0.1", path = {} }}"#,
+                toml_str(&path.to_string_lossy())
+            )
+        );
+    }
+
+    #[test]
+    fn test_uses_ident() {
+        assert!(uses_ident("out0", "out0"));
+        assert!(uses_ident("out0 + 1", "out0"));
+        assert!(uses_ident("f(&out0)", "out0"));
+        assert!(!uses_ident("out01", "out0"));
+        assert!(!uses_ident("my_out0", "out0"));
+        assert!(!uses_ident("out1", "out0"));
+    }
+
+    #[test]
+    fn test_package_name() {
+        assert_eq!(package_name(Path::new("foo/My Crate")), "my-crate");
+        assert_eq!(package_name(Path::new("a_b-c")), "a_b-c");
+        assert_eq!(package_name(Path::new("1abc")), "papyrus-export");
+        assert_eq!(package_name(Path::new("/")), "papyrus-export");
+    }
+
+    #[test]
+    fn export_mod_code_test() {
+        use crate::code::{Statement, StmtGrp};
+
+        let mut src = SourceCode::default();
+        src.items.push(("#![allow(unused)]".to_string(), true));
+        src.items.push(("struct A;".to_string(), false));
+        src.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "let a = 1".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "a".to_string(),
+                semi: false,
+            },
+        ]));
+        src.stmts.push(StmtGrp(vec![Statement {
+            expr: "a".to_string(),
+            semi: false,
+        }]));
+        src.stmts.push(StmtGrp(vec![Statement {
+            expr: "if a > 1 {\n    return crate::papyrus_ret!(__papyrus_store, a);\n}".to_string(),
+            semi: false,
+        }]));
+        let linking = linking::LinkingConfiguration::default();
+
+        assert_eq!(
+            export_mod_code(Some(&src), std::iter::empty(), &[], &linking, false),
+            "#![allow(unused)]\n\npub fn run() -> Result<(), Box<dyn std::error::Error>> {\n    let a = 1;\n    a;\n    a;\n    (|| -> Result<(), Box<dyn std::error::Error>> {\n        if a > 1 {\n    return (|_| Ok(()))(a);\n};\n        Ok(())\n    })()?;\n    Ok(())\n}\n\nstruct A;\n"
+        );
+        assert_eq!(
+            export_mod_code(None, std::iter::empty(), &["stat"], &linking, true),
+            "mod stat;\n\nfn main() -> Result<(), Box<dyn std::error::Error>> {\n    Ok(())\n}\n"
+        );
+
+        // inner attributes come before the module declarations
+        let child = PathBuf::from("child");
+        assert_eq!(
+            export_mod_code(
+                Some(&src),
+                std::iter::once(&child),
+                &["stat"],
+                &linking,
+                true
+            ),
+            "#![allow(unused)]\n\nmod child;\nmod stat;\n\nfn main() -> Result<(), Box<dyn std::error::Error>> {\n    let a = 1;\n    a;\n    a;\n    (|| -> Result<(), Box<dyn std::error::Error>> {\n        if a > 1 {\n    return (|_| Ok(()))(a);\n};\n        Ok(())\n    })()?;\n    Ok(())\n}\n\nstruct A;\n"
+        );
+
+        // a group returning early stays in the function when later groups use its bindings
+        let mut src = SourceCode::default();
+        src.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "let a = std::env::var(\"A\")?".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "if a.is_empty() {\n    return crate::papyrus_ret!(__papyrus_store, ());\n}"
+                    .to_string(),
+                semi: false,
+            },
+        ]));
+        src.stmts.push(StmtGrp(vec![Statement {
+            expr: "a.len()".to_string(),
+            semi: false,
+        }]));
+        assert_eq!(
+            export_mod_code(Some(&src), std::iter::empty(), &[], &linking, true),
+            "fn main() -> Result<(), Box<dyn std::error::Error>> {\n    let a = std::env::var(\"A\")?;\n    if a.is_empty() {\n    return (|_| Ok(()))(());\n};\n    a.len();\n    Ok(())\n}\n"
+        );
+    }
 }
@@ -0,0 +1,402 @@
+//! Structured compiler diagnostics, parsed from cargo's `--message-format=json` output.
+use crate::code::{ModsMap, SourceMap, SrcKind, SrcLocation};
+use serde_json::Value;
+use std::fmt::{self, Write};
+
+/// A diagnostic message emitted by `rustc`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// The diagnostic level, such as `error` or `warning`.
+    pub level: String,
+    /// The primary message.
+    pub message: String,
+    /// The diagnostic code, such as `E0308`.
+    pub code: Option<String>,
+    /// The locations in the generated source code the diagnostic refers to.
+    pub spans: Vec<DiagnosticSpan>,
+    /// Sub-diagnostics such as notes and help.
+    pub children: Vec<Diagnostic>,
+    /// The diagnostic as rendered by `rustc`.
+    pub rendered: Option<String>,
+}
+
+/// A location in the generated source code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiagnosticSpan {
+    /// The file, relative to the compilation directory.
+    pub file_name: String,
+    /// 1-based starting line.
+    pub line_start: usize,
+    /// 1-based ending line.
+    pub line_end: usize,
+    /// 1-based starting character column.
+    pub column_start: usize,
+    /// 1-based ending character column (exclusive).
+    pub column_end: usize,
+    /// This span is the primary location of the diagnostic.
+    pub is_primary: bool,
+    /// A label attached to the span.
+    pub label: Option<String>,
+}
+
+impl Diagnostic {
+    /// Parse a line of cargo's json output, returning a diagnostic if the line is a compiler
+    /// message.
+    pub fn parse_line(line: &str) -> Option<Self> {
+        let value: Value = serde_json::from_str(line).ok()?;
+        if value.get("reason")?.as_str()? != "compiler-message" {
+            return None;
+        }
+        Self::from_value(value.get("message")?)
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        let s = |v: &Value| v.as_str().map(String::from);
+
+        Some(Diagnostic {
+            level: s(value.get("level")?)?,
+            message: s(value.get("message")?)?,
+            code: value.get("code").and_then(|c| c.get("code")).and_then(&s),
+            spans: value
+                .get("spans")
+                .and_then(Value::as_array)
+                .map(|x| x.iter().filter_map(DiagnosticSpan::from_value).collect())
+                .unwrap_or_default(),
+            children: value
+                .get("children")
+                .and_then(Value::as_array)
+                .map(|x| x.iter().filter_map(Diagnostic::from_value).collect())
+                .unwrap_or_default(),
+            rendered: value.get("rendered").and_then(&s),
+        })
+    }
+
+    /// This diagnostic is an error which is not the trailing `aborting due to` message.
+    pub fn is_error(&self) -> bool {
+        self.level == "error" && !self.message.starts_with("aborting due to")
+    }
+
+    /// This diagnostic is a warning with a location, not a summary such as `1 warning emitted`.
+    pub fn is_warning(&self) -> bool {
+        self.level == "warning" && !self.spans.is_empty()
+    }
+
+    /// The primary span, if there is one.
+    pub fn primary_span(&self) -> Option<&DiagnosticSpan> {
+        self.spans.iter().find(|x| x.is_primary)
+    }
+
+    /// Render the diagnostic under the REPL input which caused it.
+    ///
+    /// If the primary span can not be mapped back to an input, such as a span in a static file,
+    /// `rustc`'s rendering is used.
+    pub fn render(&self, srcmap: &SourceMap, mods: &ModsMap, buf: &mut String) -> fmt::Result {
+        let span = self
+            .primary_span()
+            .filter(|span| span.file_name == "src/lib.rs");
+        let (span, loc) = match span.and_then(|span| {
+            srcmap
+                .lookup(span.line_start, span.column_start)
+                .map(|l| (span, l))
+        }) {
+            Some(x) => x,
+            None => return write!(buf, "{}", self),
+        };
+
+        write!(buf, "{}", self.level)?;
+        if let Some(code) = &self.code {
+            write!(buf, "[{}]", code)?;
+        }
+        writeln!(buf, ": {}", self.message)?;
+
+        let end = |line: &str| {
+            if span.line_end == span.line_start {
+                let end = srcmap
+                    .lookup(span.line_end, span.column_end)
+                    .map(|x| x.col)
+                    .unwrap_or(loc.col);
+                std::cmp::max(end, loc.col)
+            } else {
+                line.chars().count()
+            }
+        };
+
+        if write_input(buf, &loc, mods, end)? {
+            if let Some(label) = &span.label {
+                write!(buf, " {}", label)?;
+            }
+            writeln!(buf)?;
+        }
+
+        for child in &self.children {
+            writeln!(buf, " = {}: {}", child.level, child.message)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Render the warnings which map back to a REPL input accepted by `filter`, each under its input.
+///
+/// Warnings in the generated code or in static files are skipped.
+pub(crate) fn render_warnings<F>(
+    warnings: &[Diagnostic],
+    srcmap: &SourceMap,
+    mods: &ModsMap,
+    filter: F,
+) -> String
+where
+    F: Fn(&SrcLocation) -> bool,
+{
+    let mut s = String::new();
+    for d in warnings {
+        let shown = d
+            .primary_span()
+            .filter(|span| span.file_name == "src/lib.rs")
+            .and_then(|span| srcmap.lookup(span.line_start, span.column_start))
+            .map(|loc| filter(&loc))
+            .unwrap_or(false);
+        if shown {
+            if !s.is_empty() {
+                s.push('\n');
+            }
+            d.render(srcmap, mods, &mut s)
+                .expect("writing to string buffer should not fail");
+        }
+    }
+    s
+}
+
+/// Write the REPL input `loc` refers to, followed by the input's line with `loc.col..end`
+/// underlined. `end` is given the line.
+///
+/// The underline is not followed by a new line. Returns `false` if the input line was not found,
+/// in which case only the reference to the input is written.
+pub(crate) fn write_input<F>(
+    buf: &mut String,
+    loc: &SrcLocation,
+    mods: &ModsMap,
+    end: F,
+) -> Result<bool, fmt::Error>
+where
+    F: FnOnce(&str) -> usize,
+{
+    let src = mods.get(loc.mod_path).and_then(|code| match loc.kind {
+        SrcKind::Stmt { grp, stmt } => code
+            .stmts
+            .get(grp)
+            .and_then(|x| x.0.get(stmt))
+            .map(|x| x.expr.as_str()),
+        SrcKind::Item(i) => code.items.get(i).map(|x| x.0.as_str()),
+    });
+
+    write!(buf, " --> [{}] ", loc.mod_path.display())?;
+    match loc.kind {
+        SrcKind::Stmt { grp, .. } => writeln!(buf, "out{}", grp)?,
+        SrcKind::Item(i) => writeln!(buf, "item {}", i)?,
+    }
+
+    match src.and_then(|s| s.lines().nth(loc.line)) {
+        Some(line) => {
+            writeln!(buf, "{}", line)?;
+            crate::input::underline(buf, line, loc.col..end(line))?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.rendered {
+            Some(r) => write!(f, "{}", r),
+            None => writeln!(f, "{}: {}", self.level, self.message),
+        }
+    }
+}
+
+impl DiagnosticSpan {
+    fn from_value(value: &Value) -> Option<Self> {
+        let n = |k: &str| value.get(k).and_then(Value::as_u64).map(|x| x as usize);
+
+        Some(DiagnosticSpan {
+            file_name: value.get("file_name")?.as_str()?.to_string(),
+            line_start: n("line_start")?,
+            line_end: n("line_end")?,
+            column_start: n("column_start")?,
+            column_end: n("column_end")?,
+            is_primary: value.get("is_primary")?.as_bool()?,
+            label: value.get("label").and_then(Value::as_str).map(String::from),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{SourceCode, Statement, StaticFiles, StmtGrp};
+    use crate::linking::LinkingConfiguration;
+    use std::path::PathBuf;
+
+    const LINE: &str = r#"{"reason":"compiler-message","package_id":"papyrus_mem_code 0.1.0","target":{"name":"papyrus_mem_code"},"message":{"rendered":"error[E0308]: mismatched types\n","children":[{"children":[],"code":null,"level":"help","message":"try using a conversion method","rendered":null,"spans":[]}],"code":{"code":"E0308","explanation":null},"level":"error","message":"mismatched types","spans":[{"byte_end":0,"byte_start":0,"column_end":COLEND,"column_start":COLSTART,"expansion":null,"file_name":"src/lib.rs","is_primary":true,"label":"expected `u32`, found `&str`","line_end":LINE,"line_start":LINE,"suggested_replacement":null,"suggestion_applicability":null,"text":[]}]}}"#;
+
+    #[test]
+    fn parse_lines() {
+        assert_eq!(Diagnostic::parse_line("not json"), None);
+        assert_eq!(
+            Diagnostic::parse_line(r#"{"reason":"build-finished","success":false}"#),
+            None
+        );
+
+        let line = LINE
+            .replace("COLSTART", "5")
+            .replace("COLEND", "10")
+            .replace("LINE", "3");
+        let d = Diagnostic::parse_line(&line).unwrap();
+        assert_eq!(d.level, "error");
+        assert_eq!(d.message, "mismatched types");
+        assert_eq!(d.code.as_deref(), Some("E0308"));
+        assert_eq!(d.children.len(), 1);
+        assert_eq!(d.children[0].level, "help");
+        assert!(d.is_error());
+        assert_eq!(
+            d.primary_span(),
+            Some(&DiagnosticSpan {
+                file_name: "src/lib.rs".to_string(),
+                line_start: 3,
+                line_end: 3,
+                column_start: 5,
+                column_end: 10,
+                is_primary: true,
+                label: Some("expected `u32`, found `&str`".to_string()),
+            })
+        );
+        assert_eq!(&d.to_string(), "error[E0308]: mismatched types\n");
+    }
+
+    #[test]
+    fn render_under_input() {
+        let mut src = SourceCode::default();
+        src.stmts.push(StmtGrp(vec![Statement {
+            expr: "let a: u32 = \"a\"".to_string(),
+            semi: true,
+        }]));
+        src.stmts.push(StmtGrp(vec![Statement {
+            expr: "a + \"b\"".to_string(),
+            semi: false,
+        }]));
+        let mods: ModsMap = vec![(PathBuf::from("lib"), src)].into_iter().collect();
+        let (code, srcmap) = crate::code::construct_source_code(
+            &mods,
+            &LinkingConfiguration::default(),
+            &StaticFiles::new(),
+            &Default::default(),
+        );
+
+        // find the generated location of `"a"` in the first input
+        let (line, col) = code
+            .lines()
+            .enumerate()
+            .find_map(|(i, l)| l.find("\"a\"").map(|c| (i + 1, c + 1)))
+            .unwrap();
+        let d = Diagnostic::parse_line(
+            &LINE
+                .replace("COLSTART", &col.to_string())
+                .replace("COLEND", &(col + 3).to_string())
+                .replace("LINE", &line.to_string()),
+        )
+        .unwrap();
+
+        let mut s = String::new();
+        d.render(&srcmap, &mods, &mut s).unwrap();
+        assert_eq!(
+            &s,
+            "error[E0308]: mismatched types
+ --> [lib] out0
+let a: u32 = \"a\"
+             ^^^ expected `u32`, found `&str`
+ = help: try using a conversion method
+"
+        );
+
+        // trailing expression has the `let out# = ` prefix removed
+        let (line, col) = code
+            .lines()
+            .enumerate()
+            .find_map(|(i, l)| l.find("\"b\"").map(|c| (i + 1, c + 1)))
+            .unwrap();
+        let d = Diagnostic::parse_line(
+            &LINE
+                .replace("COLSTART", &col.to_string())
+                .replace("COLEND", &(col + 3).to_string())
+                .replace("LINE", &line.to_string()),
+        )
+        .unwrap();
+        let mut s = String::new();
+        d.render(&srcmap, &mods, &mut s).unwrap();
+        assert!(s.contains(" --> [lib] out1\na + \"b\"\n    ^^^ expected"));
+
+        // the same line in a static file is not mapped
+        let d = Diagnostic::parse_line(
+            &LINE
+                .replace("COLSTART", &col.to_string())
+                .replace("COLEND", &(col + 3).to_string())
+                .replace("LINE", &line.to_string())
+                .replace("src/lib.rs", "src/foo.rs"),
+        )
+        .unwrap();
+        let mut s = String::new();
+        d.render(&srcmap, &mods, &mut s).unwrap();
+        assert_eq!(&s, "error[E0308]: mismatched types\n");
+
+        // unmapped lines fall back to rustc rendering
+        let d = Diagnostic::parse_line(
+            &LINE
+                .replace("COLSTART", "1")
+                .replace("COLEND", "2")
+                .replace("LINE", "1"),
+        )
+        .unwrap();
+        let mut s = String::new();
+        d.render(&srcmap, &mods, &mut s).unwrap();
+        assert_eq!(&s, "error[E0308]: mismatched types\n");
+    }
+
+    #[test]
+    fn render_filtered_warnings() {
+        let mut src = SourceCode::default();
+        src.stmts.push(StmtGrp(vec![Statement {
+            expr: "let mut a = 1".to_string(),
+            semi: true,
+        }]));
+        let mods: ModsMap = vec![(PathBuf::from("lib"), src)].into_iter().collect();
+        let (code, srcmap) = crate::code::construct_source_code(
+            &mods,
+            &LinkingConfiguration::default(),
+            &StaticFiles::new(),
+            &Default::default(),
+        );
+        let (line, col) = code
+            .lines()
+            .enumerate()
+            .find_map(|(i, l)| l.find("mut a").map(|c| (i + 1, c + 1)))
+            .unwrap();
+        let warning = LINE
+            .replace("COLSTART", &col.to_string())
+            .replace("COLEND", &(col + 5).to_string())
+            .replace("LINE", &line.to_string())
+            .replace(r#""level":"error""#, r#""level":"warning""#);
+        let w = Diagnostic::parse_line(&warning).unwrap();
+        assert!(w.is_warning() && !w.is_error());
+        let static_file =
+            Diagnostic::parse_line(&warning.replace("src/lib.rs", "src/foo.rs")).unwrap();
+
+        let warnings = vec![w, static_file];
+        let s = render_warnings(&warnings, &srcmap, &mods, |_| true);
+        assert!(s.starts_with(
+            "warning[E0308]: mismatched types\n --> [lib] out0\nlet mut a = 1\n    ^^^^^"
+        ));
+        assert_eq!(s.matches("warning").count(), 1);
+        assert_eq!(render_warnings(&warnings, &srcmap, &mods, |_| false), "");
+    }
+}
@@ -1,58 +1,283 @@
+use super::interrupt::{Cancel, Cancelled, POLL_INTERVAL};
+use super::store::{StoreHandle, Values};
+use super::transport::{self, ErrorChain, Evaluated, Output, Panic, TransportError};
+use crate::code::{ModsMap, SourceMap};
 use ::kserd::Kserd;
 use libloading::{Library, Symbol};
-use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::{
+    fmt, fs,
+    mem::ManuallyDrop,
+    ops::Deref,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 /// We don't type anything here. You must be **VERY** careful to pass through the correct borrow to match the
 /// function signature!
-type DataFunc<D> = unsafe fn(D) -> Kserd<'static>;
+type DataFunc<D> = unsafe extern "C" fn(&mut StoreHandle, D) -> Output;
 
-type ExecResult = Result<(Kserd<'static>, Library), &'static str>;
+type FreeFunc = unsafe extern "C" fn(Output);
+
+/// Matches the exported function of [`code::type_probe`](crate::code::type_probe).
+type TypeOfFunc = unsafe extern "C" fn(&mut usize) -> *const u8;
+
+/// How long a cancelled asynchronous evaluation is given to return.
+const CANCEL_GRACE: Duration = Duration::from_secs(1);
+
+type ExecResult = Result<(Kserd<'static>, LoadedLibrary), ExecError>;
+
+/// A loaded library, the library file is deleted once it is unloaded.
+///
+/// Each compiled library is [unshackled](super::unshackle_library_file) to a unique file, which
+/// is only needed while the library is loaded.
+pub(crate) struct LoadedLibrary {
+    lib: ManuallyDrop<Library>,
+    path: PathBuf,
+}
+
+impl Deref for LoadedLibrary {
+    type Target = Library;
+
+    fn deref(&self) -> &Library {
+        &self.lib
+    }
+}
+
+impl Drop for LoadedLibrary {
+    fn drop(&mut self) {
+        // the library must be unloaded before the file can be removed on Windows
+        unsafe { ManuallyDrop::drop(&mut self.lib) };
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Error type for executing an evaluation function.
+#[derive(Debug, PartialEq)]
+pub enum ExecError {
+    /// The library file failed to load, with the loading error.
+    LoadLibrary(String),
+    /// The function was not found in the library.
+    MissingSymbol(String),
+    /// The evaluated code panicked.
+    Panic(Panic),
+    /// The evaluated code returned an error, such as through the `?` operator.
+    Error(ErrorChain),
+    /// The result returned by the library could not be decoded.
+    Transport(TransportError),
+    /// The evaluation was interrupted or timed out, and abandoned.
+    Cancelled(Cancelled),
+}
 
 pub(crate) fn exec<P: AsRef<Path>, D>(
     library_file: P,
     function_name: &str,
+    store: &mut Values,
     app_data: D,
 ) -> ExecResult {
-    exec_no_redirect(library_file, function_name, app_data)
+    exec_no_redirect(library_file, function_name, store, app_data)
+}
+
+/// Execute a function taking no app data on another thread, abandoning it if `cancel` trips.
+///
+/// The store's values are moved to the evaluating thread and moved back once it returns. An
+/// asynchronous evaluation is cancelled through the library, and given a moment to return. An
+/// abandoned evaluation keeps running until it returns, after which its values and library are
+/// leaked, as they may reference libraries the REPL has since unloaded.
+pub(crate) fn exec_cancellable(
+    library_file: &Path,
+    function_name: &str,
+    store: &mut Values,
+    cancel: &Cancel,
+) -> ExecResult {
+    let (tx, rx) = mpsc::channel();
+    let file = library_file.to_path_buf();
+    let function_name = function_name.to_string();
+    let mut values = std::mem::take(store);
+
+    std::thread::spawn(move || {
+        let r = exec(file, &function_name, &mut values, &());
+        if let Err(mpsc::SendError(abandoned)) = tx.send((r, values)) {
+            std::mem::forget(abandoned);
+        }
+    });
+
+    loop {
+        match rx.recv_timeout(POLL_INTERVAL) {
+            Ok((r, values)) => {
+                *store = values;
+                return r;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if let Err(c) = cancel.check() {
+                    // the cancel is sent until the evaluation returns, as the future may not be
+                    // built yet
+                    let grace = std::time::Instant::now();
+                    while grace.elapsed() < CANCEL_GRACE {
+                        super::executor::cancel_library(library_file);
+                        if let Ok((r, values)) = rx.recv_timeout(POLL_INTERVAL) {
+                            *store = values;
+                            // the evaluation may have finished before it was cancelled
+                            if r.is_ok() {
+                                return r;
+                            }
+                            break;
+                        }
+                    }
+                    return Err(ExecError::Cancelled(c));
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(ExecError::Panic(Panic::new(
+                    "evaluation thread panicked".to_string(),
+                )))
+            }
+        }
+    }
 }
 
 fn exec_no_redirect<P: AsRef<Path>, Data>(
     library_file: P,
     function_name: &str,
+    store: &mut Values,
     app_data: Data,
 ) -> ExecResult {
     let lib = get_lib(library_file)?;
     let func = get_func(&lib, function_name)?;
+    let free: Symbol<FreeFunc> = unsafe {
+        lib.get(transport::FREE_FN.as_bytes())
+            .map_err(|_| ExecError::MissingSymbol(transport::FREE_FN.to_string()))?
+    };
 
-    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe { func(app_data) }));
+    // panics are caught in the library, unwinding can not cross the extern "C" boundary
+    let bytes = unsafe {
+        let output = func(&mut StoreHandle::new(store), app_data);
+        let bytes = output.to_vec();
+        free(output);
+        bytes
+    };
 
-    match res {
-        Ok(kserd) => Ok((kserd, lib)),
-        Err(_) => Err("a panic occured with evaluation"),
+    let r = transport::decode(&bytes);
+    if !matches!(r, Ok(Evaluated::Kserd(_))) {
+        // the failed evaluation's values are dropped by the caller, but must be dropped while the
+        // library which stored them is loaded
+        store.clear();
     }
+
+    match r {
+        Ok(Evaluated::Kserd(kserd)) => Ok((kserd, lib)),
+        Ok(Evaluated::Panic(panic)) => Err(ExecError::Panic(panic)),
+        Ok(Evaluated::Error(e)) => Err(ExecError::Error(e)),
+        Err(e) => {
+            error!("failed to decode evaluation result: {}", e);
+            Err(ExecError::Transport(e))
+        }
+    }
+}
+
+/// The type name returned by a library's type probe.
+///
+/// Only the probe is called, none of the library's evaluation functions are run. The library is
+/// unloaded before returning.
+pub(crate) fn type_name(library_file: &Path) -> Result<String, ExecError> {
+    let lib = get_lib(library_file)?;
+    let func: Symbol<TypeOfFunc> = unsafe {
+        lib.get(crate::code::TYPE_OF_FN.as_bytes())
+            .map_err(|_| ExecError::MissingSymbol(crate::code::TYPE_OF_FN.to_string()))?
+    };
+
+    // the name is a &'static str in the library, copied before it is unloaded
+    let name = unsafe {
+        let mut len = 0;
+        let ptr = func(&mut len);
+        String::from_utf8_lossy(std::slice::from_raw_parts(ptr, len)).into_owned()
+    };
+
+    Ok(name)
 }
 
-fn get_lib<P: AsRef<Path>>(path: P) -> Result<Library, &'static str> {
+fn get_lib<P: AsRef<Path>>(path: P) -> Result<LoadedLibrary, ExecError> {
     // If segfaults are occurring maybe use this, SIGSEV?
     // This is shown in https://github.com/nagisa/rust_libloading/issues/41
     // let lib: Library =
     // 	libloading::os::unix::Library::open(Some(library_file.as_ref()), 0x2 | 0x1000)
     // 		.unwrap()
     // 		.into();
-    unsafe { 
-        Library::new(path.as_ref()).map_err(|e| {
+    let path = path.as_ref();
+    match unsafe { Library::new(path) } {
+        Ok(lib) => Ok(LoadedLibrary {
+            lib: ManuallyDrop::new(lib),
+            path: path.to_path_buf(),
+        }),
+        Err(e) => {
             error!("failed to load library file: {}", e);
-            "failed to load library file"
-        })
+            Err(ExecError::LoadLibrary(e.to_string()))
+        }
     }
 }
 
 fn get_func<'l, Data>(
-    lib: &'l Library,
+    lib: &'l LoadedLibrary,
     name: &str,
-) -> Result<Symbol<'l, DataFunc<Data>>, &'static str> {
+) -> Result<Symbol<'l, DataFunc<Data>>, ExecError> {
     unsafe {
         lib.get(name.as_bytes())
-            .map_err(|_| "failed to find function in library")
+            .map_err(|_| ExecError::MissingSymbol(name.to_string()))
     }
 }
+
+impl ExecError {
+    /// Render the error, with a panic in REPL input rendered under the input.
+    ///
+    /// `srcmap` and `mods` should be what the compilation directory was built with.
+    pub fn render(&self, srcmap: &SourceMap, mods: &ModsMap) -> String {
+        match self {
+            ExecError::Panic(panic) => render_panic(panic, srcmap, mods),
+            e => e.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecError::LoadLibrary(e) => write!(f, "failed to load library file: {}", e),
+            ExecError::MissingSymbol(name) => {
+                write!(f, "failed to find function in library: {}", name)
+            }
+            ExecError::Panic(panic) => write!(f, "{}", panic),
+            ExecError::Error(e) => write!(f, "{}", e),
+            ExecError::Transport(e) => write!(f, "{}", e),
+            ExecError::Cancelled(c) => write!(f, "evaluation {}", c),
+        }
+    }
+}
+
+/// Render a panic under the REPL input it occurred in, falling back to its `Display`.
+pub(crate) fn render_panic(panic: &Panic, srcmap: &SourceMap, mods: &ModsMap) -> String {
+    use std::fmt::Write;
+
+    let loc = panic
+        .location
+        .as_ref()
+        .filter(|l| l.file == "src/lib.rs") // the generated code, other files are not mapped
+        .and_then(|l| srcmap.lookup(l.line as usize, l.col as usize));
+    let loc = match loc {
+        Some(x) => x,
+        None => return panic.to_string(),
+    };
+
+    let mut s = String::new();
+    let mut render = || -> fmt::Result {
+        writeln!(s, "evaluation panicked: {}", panic.message)?;
+        if super::diagnostic::write_input(&mut s, &loc, mods, |_| loc.col + 1)? {
+            writeln!(s)?;
+        }
+        if let Some(b) = &panic.backtrace {
+            write!(s, "stack backtrace:\n{}", b)?;
+        }
+        Ok(())
+    };
+    render().expect("writing to string buffer should not fail");
+    s.trim_end().to_string()
+}
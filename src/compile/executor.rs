@@ -0,0 +1,171 @@
+//! Evaluating statements asynchronously, so they can `.await`.
+//!
+//! With an [`Executor`] set, the statements of an evaluation function are wrapped in an
+//! `async move` block, which the executor drives to completion. The future is wrapped by the
+//! `papyrus_async` module of the generated crate (`executor_rt.rs`), which resolves it to an error
+//! once the host calls the library's `papyrus_cancel`, dropping the future.
+use super::interrupt::{Cancel, Cancelled, POLL_INTERVAL};
+use libloading::{Library, Symbol};
+use std::{
+    fmt,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::Mutex,
+};
+
+#[allow(dead_code)]
+#[path = "executor_rt.rs"]
+mod rt;
+
+/// The source of the `papyrus_async` module of the generated crate.
+pub(crate) const EXECUTOR_SRC: &str = include_str!("executor_rt.rs");
+
+/// The library function which cancels a running evaluation.
+pub(crate) const CANCEL_FN: &str = "papyrus_cancel";
+
+/// Drives the future of asynchronously evaluated statements to completion.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Executor {
+    /// A minimal executor in the generated crate, parking the evaluating thread while the future
+    /// is pending.
+    ///
+    /// It has no reactor, futures which need a runtime's IO or timers do not complete.
+    BlockOn,
+    /// A runtime's blocking entry point, called with the future, such as
+    /// `futures::executor::block_on` or `tokio::runtime::Runtime::new().unwrap().block_on`.
+    ///
+    /// The runtime crate must be referenced with an `extern crate` input, or linked.
+    Custom(String),
+}
+
+impl Executor {
+    /// The code the future is passed to, in parentheses.
+    pub(crate) fn call(&self) -> &str {
+        match self {
+            Executor::BlockOn => "crate::papyrus_async::block_on",
+            Executor::Custom(s) => s,
+        }
+    }
+}
+
+impl fmt::Display for Executor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Executor::BlockOn => write!(f, "block-on"),
+            Executor::Custom(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// Cancel the evaluation running in the library, if the library is loaded and evaluates
+/// asynchronously.
+pub(crate) fn cancel_library(library_file: &Path) {
+    // loading a loaded library returns the same library, only incrementing its reference count
+    unsafe {
+        if let Ok(lib) = Library::new(library_file) {
+            let func: Result<Symbol<unsafe extern "C" fn()>, _> = lib.get(CANCEL_FN.as_bytes());
+            if let Ok(func) = func {
+                func();
+            }
+        }
+    }
+}
+
+/// Run `f`, an evaluation of the library, cancelling it if `cancel` trips.
+///
+/// Returns the reason if the evaluation was cancelled.
+pub(crate) fn watch<F, R>(library_file: &Path, cancel: &Cancel, f: F) -> (R, Option<Cancelled>)
+where
+    F: FnOnce() -> R,
+{
+    let done = AtomicBool::new(false);
+    let cancelled = Mutex::new(None);
+
+    let r = std::thread::scope(|s| {
+        s.spawn(|| {
+            while !done.load(Ordering::SeqCst) {
+                if let Err(c) = cancel.check() {
+                    // the cancel is cleared when the future is built, which could be after this
+                    // cancel, so it is sent until the evaluation returns
+                    cancel_library(library_file);
+                    if let Ok(mut x) = cancelled.lock() {
+                        x.get_or_insert(c);
+                    }
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        });
+        let r = f();
+        done.store(true, Ordering::SeqCst);
+        r
+    });
+
+    (r, cancelled.into_inner().ok().flatten())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    type Eval = Result<u8, Box<dyn std::error::Error>>;
+
+    /// Pending on the first poll, waking itself.
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn block_on_and_cancel() {
+        let r: Eval = rt::block_on(rt::Cancellable::new(async {
+            YieldOnce(false).await;
+            Ok(1)
+        }));
+        assert_eq!(r.unwrap(), 1);
+
+        // the future never completes, a cancel from another thread wakes and drops it
+        let finished = std::sync::Arc::new(AtomicBool::new(false));
+        let h = {
+            let finished = finished.clone();
+            std::thread::spawn(move || {
+                while !finished.load(Ordering::SeqCst) {
+                    std::thread::sleep(POLL_INTERVAL);
+                    rt::cancel();
+                }
+            })
+        };
+        let r: Eval = rt::block_on(rt::Cancellable::new(async {
+            std::future::pending::<()>().await;
+            Ok(1)
+        }));
+        finished.store(true, Ordering::SeqCst);
+        h.join().unwrap();
+        assert_eq!(r.unwrap_err().to_string(), "evaluation cancelled");
+
+        // a new evaluation clears the cancel
+        let r: Eval = rt::block_on(rt::Cancellable::new(async { Ok(2) }));
+        assert_eq!(r.unwrap(), 2);
+    }
+
+    #[test]
+    fn executor_call() {
+        assert_eq!(Executor::BlockOn.call(), "crate::papyrus_async::block_on");
+        let custom = Executor::Custom("futures::executor::block_on".to_string());
+        assert_eq!(custom.call(), "futures::executor::block_on");
+        assert_eq!(Executor::BlockOn.to_string(), "block-on");
+    }
+}
@@ -0,0 +1,109 @@
+//! Driving and cancelling the future of asynchronously evaluated statements.
+//!
+//! This file is included verbatim as the `papyrus_async` module of the generated crate when an
+//! executor is set, and must only depend on `std`. The host includes it as `compile::executor::rt`.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+
+/// Set by the host to cancel the running evaluation.
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+/// The waker of the running evaluation, woken on a cancel so the cancel is noticed.
+static WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+
+/// Cancel the running evaluation, its future is dropped the next time it is polled.
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+    if let Some(waker) = WAKER.lock().ok().and_then(|mut x| x.take()) {
+        waker.wake();
+    }
+}
+
+/// The future of the evaluated statements, which resolves to an error once cancelled.
+pub struct Cancellable<F>(Option<Pin<Box<F>>>);
+
+impl<F, T> Cancellable<F>
+where
+    F: Future<Output = Result<T, Box<dyn std::error::Error>>>,
+{
+    /// Wrap the evaluation's future, clearing any previous cancel.
+    pub fn new(f: F) -> Self {
+        CANCELLED.store(false, Ordering::SeqCst);
+        Cancellable(Some(Box::pin(f)))
+    }
+}
+
+impl<F, T> Future for Cancellable<F>
+where
+    F: Future<Output = Result<T, Box<dyn std::error::Error>>>,
+{
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // the waker is stored before checking, a cancel in between wakes this waker
+        if let Ok(mut x) = WAKER.lock() {
+            *x = Some(cx.waker().clone());
+        }
+        if CANCELLED.load(Ordering::SeqCst) {
+            self.0 = None; // drop the future, and the values it holds, now
+            return Poll::Ready(Err("evaluation cancelled".into()));
+        }
+
+        match self.0.as_mut() {
+            Some(f) => f.as_mut().poll(cx),
+            None => Poll::Ready(Err("evaluation cancelled".into())),
+        }
+    }
+}
+
+impl<F> Drop for Cancellable<F> {
+    fn drop(&mut self) {
+        // the waker may reference a runtime which is shut down after the evaluation
+        if let Ok(mut x) = WAKER.lock() {
+            x.take();
+        }
+    }
+}
+
+/// Run a future to completion on the current thread, blocking the thread while it is pending.
+///
+/// There is no reactor, futures which need a runtime's IO or timers must use a runtime's executor.
+pub fn block_on<F: Future>(f: F) -> F::Output {
+    // not `std::thread::park`, `std::thread::current` registers a thread local destructor which
+    // runs when the thread exits, possibly after the library is unloaded
+    #[derive(Default)]
+    struct Signal {
+        woken: Mutex<bool>,
+        cvar: Condvar,
+    }
+
+    impl Wake for Signal {
+        fn wake(self: Arc<Self>) {
+            self.wake_by_ref();
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            if let Ok(mut woken) = self.woken.lock() {
+                *woken = true;
+                self.cvar.notify_one();
+            }
+        }
+    }
+
+    let mut f = Box::pin(f);
+    let signal = Arc::new(Signal::default());
+    let waker = Waker::from(signal.clone());
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(x) = f.as_mut().poll(&mut cx) {
+            return x;
+        }
+        let mut woken = signal.woken.lock().unwrap_or_else(|e| e.into_inner());
+        while !*woken {
+            woken = signal.cvar.wait(woken).unwrap_or_else(|e| e.into_inner());
+        }
+        *woken = false;
+    }
+}
@@ -0,0 +1,399 @@
+//! Compiling with `rustc` directly when the REPL crate only depends on `kserd`.
+//!
+//! Each `cargo` build resolves the manifest, takes locks, and fingerprints the dependencies, which
+//! dominates compiling a small input. When the REPL crate depends on no crates other than `kserd`,
+//! the `kserd` library `cargo` last built is recorded in the target directory, keyed by the
+//! manifest, and later builds of the same manifest invoke `rustc` against it.
+//!
+//! `rustc` is passed the `-C metadata` and `-C extra-filename` `cargo` compiled the crate with,
+//! read from `cargo`'s verbose output. The metadata is part of a type's `TypeId`, so values of
+//! types the crate defines are the same type to libraries built either way.
+//!
+//! If `rustc` can not load the recorded library, such as after a toolchain update, the record is
+//! removed and the build falls back to `cargo`.
+use super::build::{run, Line};
+use super::interrupt::Cancel;
+use super::progress::{CompileEvent, Progress};
+use super::{CompilationError, CompileOptions, Diagnostic, Profile, SessionDir, LIBRARY_NAME};
+use serde_json::Value;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Instant,
+};
+
+/// The file in the profile's target directory recording the `kserd` library.
+const RECORD: &str = "papyrus-fast-path";
+
+/// Errors loading a dependency, which mean the recorded library is unusable.
+const LOAD_ERRORS: &[&str] = &["E0460", "E0463", "E0514"];
+
+/// A recorded `kserd` library, usable to compile the session's crate with `rustc`.
+pub(crate) struct FastPath {
+    record: PathBuf,
+    rlib: PathBuf,
+    metadata: Vec<String>,
+}
+
+impl FastPath {
+    /// The recorded library, if the session's crate can be compiled with `rustc` directly.
+    pub(crate) fn find(session: &SessionDir, options: &CompileOptions) -> Option<Self> {
+        let key = key(session, options)?;
+        let record = record_file(session, options);
+        let contents = fs::read_to_string(&record).ok()?;
+        let mut lines = contents.lines();
+        if lines.next()? != key {
+            return None;
+        }
+        let rlib = PathBuf::from(lines.next()?);
+        let metadata: Vec<String> = lines.next()?.split(' ').map(String::from).collect();
+        if rlib.is_file() && metadata[0].starts_with("metadata=") {
+            Some(FastPath {
+                record,
+                rlib,
+                metadata,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Whether the session's crate could be compiled with `rustc` directly, `cargo` is then run
+    /// verbosely to read the crate's metadata.
+    pub(crate) fn eligible(session: &SessionDir, options: &CompileOptions) -> bool {
+        key(session, options).is_some()
+    }
+
+    /// Records the `kserd` library and the crate's `-C` metadata arguments of a successful `cargo`
+    /// build.
+    pub(crate) fn record(
+        session: &SessionDir,
+        options: &CompileOptions,
+        rlib: &Path,
+        metadata: &[String],
+    ) {
+        if let Some(key) = key(session, options) {
+            let contents = format!("{}\n{}\n{}\n", key, rlib.display(), metadata.join(" "));
+            fs::write(record_file(session, options), contents).ok();
+        }
+    }
+
+    /// Compiles the session's crate to `lib_file` with `rustc`, passing `rustc_args` and reporting
+    /// the crate as the only one compiled.
+    ///
+    /// Returns `None` if the build should fall back to `cargo`.
+    pub(crate) fn compile<F>(
+        self,
+        session: &SessionDir,
+        lib_file: &Path,
+        options: &CompileOptions,
+        rustc_args: &[String],
+        cancel: &Cancel,
+        event_cb: &mut F,
+    ) -> Option<Result<(PathBuf, Vec<Diagnostic>), CompilationError>>
+    where
+        F: FnMut(&CompileEvent),
+    {
+        let start = Instant::now();
+        let target_dir = session.target().join(options.profile.target_subdir());
+        let edition = options.edition.to_string();
+        let deps = format!("dependency={}", target_dir.join("deps").display());
+        let kserd = format!("kserd={}", self.rlib.display());
+
+        let mut cmd = Command::new(std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into()));
+        cmd.current_dir(session.path())
+            .args(["--crate-name", LIBRARY_NAME, "--crate-type", "cdylib"])
+            .args(["--edition", edition.as_str(), "--error-format=json"])
+            .args(profile_args(&options.profile, &target_dir))
+            .args(self.metadata.iter().flat_map(|a| ["-C", a.as_str()]))
+            .arg("-o")
+            .arg(lib_file)
+            .args([
+                "-L",
+                deps.as_str(),
+                "--extern",
+                kserd.as_str(),
+                "src/lib.rs",
+            ])
+            .args(rustc_args);
+
+        let progress = |finished| Progress { finished, total: 1 };
+        event_cb(&CompileEvent::CrateStarted(
+            LIBRARY_NAME.to_string(),
+            "0.1.0".to_string(),
+            progress(0),
+        ));
+
+        let mut diagnostics = Vec::new();
+        let mut stderr = String::new();
+        let status = run(cmd, cancel, |line| {
+            if let Line::Stderr(line) = line {
+                let event = match parse_diagnostic(&line) {
+                    Some(d) => CompileEvent::Diagnostic(d),
+                    None => CompileEvent::Status(line.trim().to_string()),
+                };
+                event_cb(&event);
+                match event {
+                    CompileEvent::Diagnostic(d) if d.is_error() || d.is_warning() => {
+                        diagnostics.push(d)
+                    }
+                    CompileEvent::Status(_) => {
+                        stderr.push_str(&line);
+                        stderr.push('\n');
+                    }
+                    _ => (),
+                }
+            }
+        });
+
+        let status = match status {
+            Ok(x) => x,
+            Err(CompilationError::NoBuildCommand) => return None,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let (errors, warnings): (Vec<_>, _) =
+            diagnostics.into_iter().partition(Diagnostic::is_error);
+        let unusable = errors.is_empty()
+            || errors.iter().any(|d| {
+                d.code
+                    .as_deref()
+                    .map(|c| LOAD_ERRORS.contains(&c))
+                    .unwrap_or(false)
+            });
+
+        if status.success() {
+            event_cb(&CompileEvent::CrateFinished(
+                LIBRARY_NAME.to_string(),
+                progress(1),
+            ));
+            event_cb(&CompileEvent::Artifact(lib_file.to_path_buf()));
+            event_cb(&CompileEvent::Finished(true, start.elapsed()));
+            Some(Ok((lib_file.to_path_buf(), warnings)))
+        } else if unusable {
+            fs::remove_file(&self.record).ok();
+            None
+        } else {
+            event_cb(&CompileEvent::Finished(false, start.elapsed()));
+            Some(Err(CompilationError::CompileError(errors, stderr)))
+        }
+    }
+}
+
+/// The `kserd` library in a line of `cargo`'s json output, if the line is its artifact.
+pub(crate) fn kserd_rlib(line: &str) -> Option<PathBuf> {
+    if !line.contains(r#""name":"kserd""#) {
+        return None;
+    }
+    let value: Value = serde_json::from_str(line).ok()?;
+    if value.get("reason")?.as_str()? != "compiler-artifact"
+        || value.get("target")?.get("name")?.as_str()? != "kserd"
+    {
+        return None;
+    }
+    value
+        .get("filenames")?
+        .as_array()?
+        .iter()
+        .filter_map(Value::as_str)
+        .find(|f| f.ends_with(".rlib"))
+        .map(PathBuf::from)
+}
+
+/// The `-C metadata` and any `-C extra-filename` arguments of the session's crate, if the line
+/// `cargo -v` writes to `stderr` is the crate's `rustc` invocation.
+///
+/// Only the first of each is taken, the arguments passed through `cargo rustc` follow `cargo`'s.
+pub(crate) fn crate_metadata(line: &str) -> Option<Vec<String>> {
+    let cmd = line.trim().strip_prefix("Running `")?;
+    let words: Vec<&str> = cmd.split_whitespace().collect();
+    let arg = |flag: &str, prefix: &str| {
+        words
+            .windows(2)
+            .find(|w| w[0] == flag && w[1].starts_with(prefix))
+            .map(|w| w[1])
+    };
+    if arg("--crate-name", LIBRARY_NAME)? != LIBRARY_NAME {
+        return None;
+    }
+
+    let metadata = arg("-C", "metadata=")?;
+    // a `cdylib` has no extra filename
+    let extra = arg("-C", "extra-filename=");
+    Some(
+        std::iter::once(metadata)
+            .chain(extra)
+            .map(String::from)
+            .collect(),
+    )
+}
+
+/// Whether a line `cargo` writes to `stderr` is only written with `-v`.
+pub(crate) fn is_verbose(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("Running `") || line.starts_with("Fresh ") || line.starts_with("Dirty ")
+}
+
+/// Identifies the manifest the library was built for, `None` if the fast path can not be used.
+///
+/// The manifest must have no dependencies other than `kserd`, and the build must not need a
+/// toolchain or `clippy-driver`.
+fn key(session: &SessionDir, options: &CompileOptions) -> Option<String> {
+    if !options.fast_path || options.toolchain.is_some() || options.clippy_driver() {
+        return None;
+    }
+
+    let manifest = fs::read_to_string(session.path().join("Cargo.toml")).ok()?;
+    let mut deps = manifest
+        .lines()
+        .skip_while(|l| l.trim() != "[dependencies]")
+        .skip(1)
+        .take_while(|l| !l.starts_with('['))
+        .filter(|l| !l.trim().is_empty());
+    match (deps.next(), deps.next()) {
+        (Some(kserd), None) if kserd.starts_with("kserd ") => (),
+        _ => return None,
+    }
+
+    Some(blake3::hash(manifest.as_bytes()).to_hex().to_string())
+}
+
+fn record_file(session: &SessionDir, options: &CompileOptions) -> PathBuf {
+    session
+        .target()
+        .join(options.profile.target_subdir())
+        .join(RECORD)
+}
+
+/// The arguments `cargo` passes `rustc` for the profile.
+fn profile_args(profile: &Profile, target_dir: &Path) -> Vec<String> {
+    let incremental = || {
+        format!(
+            "incremental={}",
+            target_dir.join("incremental").join(RECORD).display()
+        )
+    };
+    let args: Vec<String> = match profile {
+        Profile::Debug => vec!["debuginfo=2".into(), incremental()],
+        Profile::Release => vec!["opt-level=3".into()],
+        Profile::OptLevel(l) => vec![
+            format!("opt-level={}", l),
+            "debuginfo=2".into(),
+            "debug-assertions=on".into(),
+            incremental(),
+        ],
+    };
+    args.into_iter()
+        .flat_map(|a| vec!["-C".into(), a])
+        .collect()
+}
+
+/// A diagnostic `rustc` writes to `stderr` with `--error-format=json`.
+fn parse_diagnostic(line: &str) -> Option<Diagnostic> {
+    // rustc's diagnostics are cargo's compiler messages without the wrapping
+    let line = format!(r#"{{"reason":"compiler-message","message":{}}}"#, line);
+    Diagnostic::parse_line(&line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_and_find() {
+        let dir = Path::new("target/testing/fast-path");
+        fs::remove_dir_all(dir).ok();
+        let session = SessionDir::unshared(dir);
+        let options = CompileOptions::default();
+        let manifest = |deps: &str| {
+            fs::create_dir_all(dir).unwrap();
+            let contents = format!(
+                "[package]\nname = \"papyrus_mem_code\"\n\n[dependencies]\nkserd = {{ version = \"0.5\" }}\n{}\n",
+                deps
+            );
+            fs::write(dir.join("Cargo.toml"), contents).unwrap();
+        };
+        let rlib = dir.join("target/debug/deps/libkserd-abc.rlib");
+        let metadata = vec!["metadata=1a".to_string(), "extra-filename=-2b".to_string()];
+        fs::create_dir_all(rlib.parent().unwrap()).unwrap();
+        fs::write(&rlib, "").unwrap();
+
+        manifest("");
+        assert!(FastPath::find(&session, &options).is_none());
+        FastPath::record(&session, &options, &rlib, &metadata);
+        let fast = FastPath::find(&session, &options).unwrap();
+        assert_eq!(fast.rlib, rlib);
+        assert_eq!(fast.metadata, metadata);
+
+        // a record without the metadata needs cargo to record it again
+        let record = record_file(&session, &options);
+        let contents = fs::read_to_string(&record).unwrap();
+        let old = contents.lines().take(2).collect::<Vec<_>>().join("\n");
+        fs::write(&record, old).unwrap();
+        assert!(FastPath::find(&session, &options).is_none());
+        FastPath::record(&session, &options, &rlib, &metadata);
+
+        // options which need cargo
+        let clippy = CompileOptions {
+            warnings: super::super::Warnings::All,
+            clippy: true,
+            ..Default::default()
+        };
+        assert!(FastPath::find(&session, &clippy).is_none());
+        let off = CompileOptions {
+            fast_path: false,
+            ..Default::default()
+        };
+        assert!(FastPath::find(&session, &off).is_none());
+
+        // other dependencies need cargo, a changed manifest needs a new record
+        manifest("rand = \"0.8\"");
+        assert!(FastPath::find(&session, &options).is_none());
+        FastPath::record(&session, &options, &rlib, &metadata);
+        assert!(FastPath::find(&session, &options).is_none());
+        manifest("\n[profile.dev]\nopt-level = 1");
+        assert!(FastPath::find(&session, &options).is_none());
+    }
+
+    #[test]
+    fn parse_output() {
+        let artifact = r#"{"reason":"compiler-artifact","target":{"kind":["lib"],"name":"kserd"},"filenames":["/t/debug/deps/libkserd-abc.rlib","/t/debug/deps/libkserd-abc.rmeta"]}"#;
+        assert_eq!(
+            kserd_rlib(artifact),
+            Some(PathBuf::from("/t/debug/deps/libkserd-abc.rlib"))
+        );
+        assert_eq!(kserd_rlib(&artifact.replace("kserd", "rand")), None);
+
+        let d = parse_diagnostic(
+            r#"{"$message_type":"diagnostic","message":"can't find crate for `kserd`","code":{"code":"E0463","explanation":null},"level":"error","spans":[],"children":[],"rendered":"error[E0463]: can't find crate for `kserd`\n"}"#,
+        )
+        .unwrap();
+        assert_eq!(d.code.as_deref(), Some("E0463"));
+        assert_eq!(parse_diagnostic("error: linking failed"), None);
+
+        let running = "     Running `rustc --crate-name papyrus_mem_code --edition=2018 src/lib.rs --crate-type cdylib -C debuginfo=2 --check-cfg 'cfg(docsrs,test)' -C metadata=31a3 -C extra-filename=-735f --out-dir /t/debug/deps -C metadata=user`";
+        assert!(is_verbose(running));
+        assert_eq!(
+            crate_metadata(running),
+            Some(vec![
+                "metadata=31a3".to_string(),
+                "extra-filename=-735f".to_string()
+            ])
+        );
+        assert_eq!(
+            crate_metadata(&running.replace("papyrus_mem_code", "kserd")),
+            None
+        );
+        assert_eq!(
+            crate_metadata(&running.replace(" -C extra-filename=-735f", "")),
+            Some(vec!["metadata=31a3".to_string()])
+        );
+        assert!(is_verbose("       Fresh kserd v0.5.0"));
+        assert!(!is_verbose("   Compiling kserd v0.5.0"));
+        assert_eq!(crate_metadata("   Compiling kserd v0.5.0"), None);
+
+        let args = profile_args(&Profile::Release, Path::new("t"));
+        assert_eq!(args, vec!["-C", "opt-level=3"]);
+    }
+}
@@ -0,0 +1,109 @@
+//! Interrupting compilation and evaluation.
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// How often a blocked compilation or evaluation checks if it should stop.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A handle to interrupt the in-flight compilation or evaluation, such as on Ctrl-C.
+///
+/// The handle can be cloned and sent to other threads, all clones interrupt the same REPL.
+/// Interrupting while nothing is in flight has no effect, the flag is reset as each evaluation
+/// begins.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    /// Interrupt the in-flight compilation or evaluation.
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    /// An interrupt has been requested.
+    pub fn is_interrupted(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn reset(&self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// The reason compilation or evaluation was stopped early.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cancelled {
+    /// Stopped through an [`Interrupt`].
+    Interrupted,
+    /// Stopped after running longer than the timeout.
+    TimedOut(Duration),
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cancelled::Interrupted => write!(f, "interrupted"),
+            Cancelled::TimedOut(d) => write!(f, "timed out after {:.1}s", d.as_secs_f64()),
+        }
+    }
+}
+
+/// Checks an [`Interrupt`] and an optional timeout, started on creation.
+#[derive(Debug, Clone)]
+pub(crate) struct Cancel {
+    interrupt: Interrupt,
+    timeout: Option<(Instant, Duration)>,
+}
+
+impl Cancel {
+    pub(crate) fn new(interrupt: Interrupt, timeout: Option<Duration>) -> Self {
+        Cancel {
+            interrupt,
+            timeout: timeout.map(|d| (Instant::now(), d)),
+        }
+    }
+
+    /// Never cancels.
+    pub(crate) fn never() -> Self {
+        Cancel::new(Interrupt::default(), None)
+    }
+
+    /// Returns the reason if compilation or evaluation should stop.
+    pub(crate) fn check(&self) -> Result<(), Cancelled> {
+        if self.interrupt.is_interrupted() {
+            return Err(Cancelled::Interrupted);
+        }
+        match self.timeout {
+            Some((start, d)) if start.elapsed() >= d => Err(Cancelled::TimedOut(d)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancel_checks() {
+        let interrupt = Interrupt::default();
+        let cancel = Cancel::new(interrupt.clone(), None);
+        assert_eq!(cancel.check(), Ok(()));
+        interrupt.interrupt();
+        assert_eq!(cancel.check(), Err(Cancelled::Interrupted));
+        interrupt.reset();
+        assert_eq!(cancel.check(), Ok(()));
+
+        let cancel = Cancel::new(interrupt, Some(Duration::from_millis(0)));
+        assert_eq!(
+            cancel.check(),
+            Err(Cancelled::TimedOut(Duration::from_millis(0)))
+        );
+        assert_eq!(Cancel::never().check(), Ok(()));
+    }
+}
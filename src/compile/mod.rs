@@ -1,12 +1,43 @@
 //! Pertains to compiling a working directory into a library, then executing a function in that library.
 
 mod build;
+mod cache;
 mod construct;
+mod diagnostic;
 mod execute;
-
+mod executor;
+mod fast_path;
+mod interrupt;
+mod options;
+mod progress;
+mod redirect;
+mod session_dir;
+mod source;
+mod store;
+mod transport;
+mod worker;
+
+pub(crate) use self::build::compile_cancellable;
+pub(crate) use self::cache::compile_cached;
 pub use self::build::{compile, unshackle_library_file, CompilationError};
-pub use self::construct::build_compile_dir;
-pub(crate) use self::execute::exec;
+pub use self::construct::{build_compile_dir, export_project};
+pub use self::diagnostic::{Diagnostic, DiagnosticSpan};
+pub(crate) use self::diagnostic::render_warnings;
+pub use self::execute::ExecError;
+pub(crate) use self::execute::{exec, exec_cancellable, type_name, LoadedLibrary};
+pub use self::executor::Executor;
+pub(crate) use self::executor::{watch, EXECUTOR_SRC};
+pub(crate) use self::interrupt::Cancel;
+pub use self::interrupt::{Cancelled, Interrupt};
+pub use self::options::{CompileOptions, Edition, Profile, Warnings};
+pub use self::progress::{CompileEvent, Progress};
+pub(crate) use self::redirect::capture;
+pub(crate) use self::session_dir::SessionDir;
+pub use self::source::{CrateSource, Network, SourceReplacement};
+pub use self::store::{Stored, ValueStore, Values};
+pub use self::transport::{ErrorChain, Location, Panic};
+pub(crate) use self::transport::TRANSPORT_SRC;
+pub(crate) use self::worker::{AppData, WorkerBackend, WorkerError};
 
 /// The library name to compile as.c
 const LIBRARY_NAME: &str = "papyrus_mem_code";
@@ -26,7 +57,15 @@ mod tests {
         let linking_config = LinkingConfiguration::default();
 
         // build
-        build_compile_dir(&compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+        build_compile_dir(
+            &compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
         assert!(fs::read_to_string(&format!("{}/src/lib.rs", compile_dir))
             .unwrap()
             .contains("\nlet out0 = 2+2;"));
@@ -35,7 +74,7 @@ mod tests {
         let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
 
         // eval
-        let r = exec::<_, _>(path, "_lib_intern_eval", &()).unwrap(); // execute library fn
+        let r = exec::<_, _>(path, "_lib_intern_eval", &mut Values::new(), &()).unwrap(); // execute library fn
 
         assert_eq!(r.0, Kserd::new_num(4));
     }
@@ -51,7 +90,15 @@ mod tests {
         );
 
         // build
-        build_compile_dir(&compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+        build_compile_dir(
+            &compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
         assert!(fs::read_to_string(&format!("{}/src/lib.rs", compile_dir))
             .unwrap()
             .contains("\nlet out0 = 2+2;"));
@@ -60,7 +107,7 @@ mod tests {
         let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
 
         // eval
-        let r = exec::<_, _>(path, "_lib_intern_eval", &()).unwrap(); // execute library fn
+        let r = exec::<_, _>(path, "_lib_intern_eval", &mut Values::new(), &()).unwrap(); // execute library fn
 
         assert_eq!(r.0, Kserd::new_num(4));
     }
@@ -76,7 +123,15 @@ mod tests {
         );
 
         // build
-        build_compile_dir(&compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+        build_compile_dir(
+            &compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
         assert!(fs::read_to_string(&format!("{}/src/lib.rs", compile_dir))
             .unwrap()
             .contains("\nlet out0 = 2+2;"));
@@ -91,7 +146,7 @@ mod tests {
         let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
 
         // eval
-        let r = exec::<_, _>(path, "_lib_intern_eval", &()).unwrap(); // execute library fn
+        let r = exec::<_, _>(path, "_lib_intern_eval", &mut Values::new(), &()).unwrap(); // execute library fn
 
         assert_eq!(r.0, Kserd::new_num(4));
     }
@@ -107,7 +162,15 @@ mod tests {
         );
 
         // build
-        build_compile_dir(&compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+        build_compile_dir(
+            &compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
         assert!(fs::read_to_string(&format!("{}/src/lib.rs", compile_dir))
             .unwrap()
             .contains("\nlet out0 = 2+2;"));
@@ -122,7 +185,7 @@ mod tests {
         let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
 
         // eval
-        let r = exec(path, "_lib_intern_eval", &()).unwrap(); // execute library fn
+        let r = exec(path, "_lib_intern_eval", &mut Values::new(), &()).unwrap(); // execute library fn
 
         assert_eq!(r.0, Kserd::new_num(4));
     }
@@ -134,7 +197,15 @@ mod tests {
         let linking_config = LinkingConfiguration::default();
 
         // build
-        build_compile_dir(&compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+        build_compile_dir(
+            &compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
         assert!(fs::read_to_string(&format!("{}/src/lib.rs", compile_dir))
             .unwrap()
             .contains("\nlet out0 = 2+;"));
@@ -143,7 +214,7 @@ mod tests {
         let r = compile(&compile_dir, &linking_config, |_| ());
         assert!(r.is_err());
         match r.unwrap_err() {
-            CompilationError::CompileError(_) => (),
+            CompilationError::CompileError(..) => (),
             _ => panic!("expecting CompileError"),
         }
     }
@@ -171,6 +242,323 @@ mod tests {
     //     assert_eq!(r, Err("a panic occured with evaluation"));
     // }
 
+    #[test]
+    fn panic_eval_test() {
+        let compile_dir = "target/testing/panic_eval";
+        let mut code = SourceCode::default();
+        code.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "panic!(\"oh no\")".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "2+2".to_string(),
+                semi: false,
+            },
+        ]));
+        let files = vec![("lib".into(), code)].into_iter().collect();
+        let linking_config = LinkingConfiguration::default();
+
+        build_compile_dir(
+            compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+        let path = compile(compile_dir, &linking_config, |_| ()).unwrap();
+
+        // the panic is caught in the library and returned as an error
+        let r = exec::<_, _>(path, "_lib_intern_eval", &mut Values::new(), &());
+        let panic = match r {
+            Err(ExecError::Panic(panic)) => panic,
+            r => panic!("expecting a panic, got {:?}", r.map(|x| x.0)),
+        };
+        assert_eq!(&panic.message, "oh no");
+        let location = panic.location.unwrap();
+        assert_eq!(&location.file, "src/lib.rs");
+
+        // the location maps back to the input
+        let srcmap = build_compile_dir(
+            compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+        let loc = srcmap
+            .lookup(location.line as usize, location.col as usize)
+            .unwrap();
+        assert_eq!(loc.kind, SrcKind::Stmt { grp: 0, stmt: 0 });
+        assert_eq!(loc.col, 0);
+    }
+
+    #[test]
+    fn worker_eval_test() {
+        let compile_dir = "target/testing/worker_eval";
+        let mut code = SourceCode::default();
+        code.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "app_data.push_str(\", world!\")".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "app_data.len()".to_string(),
+                semi: false,
+            },
+        ]));
+        let files = vec![("lib".into(), code)].into_iter().collect();
+        let linking_config = LinkingConfiguration {
+            data_type: Some("String".to_string()),
+            mutable: true,
+            worker: true,
+            ..Default::default()
+        };
+
+        build_compile_dir(
+            compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+        let path = compile(compile_dir, &linking_config, |_| ()).unwrap();
+
+        // the app data is serialized to the worker and back
+        let mut worker = WorkerBackend::new();
+        let mut data = "Hello".to_string();
+        let r = worker.exec(
+            compile_dir.as_ref(),
+            None,
+            &path,
+            "_lib_intern_eval",
+            "lib".as_ref(),
+            std::iter::empty(),
+            AppData::Mut(&mut data),
+            &Cancel::never(),
+            None,
+        );
+        assert_eq!(r, Ok((Kserd::new_num(13), vec!["out0".to_string()])));
+        assert_eq!(data, "Hello, world!");
+
+        // a change of toolchain restarts the worker, built with that toolchain
+        let path = compile(compile_dir, &linking_config, |_| ()).unwrap();
+        let r = worker.exec(
+            compile_dir.as_ref(),
+            Some("stable"),
+            &path,
+            "_lib_intern_eval",
+            "lib".as_ref(),
+            std::iter::empty(),
+            AppData::Mut(&mut data),
+            &Cancel::never(),
+            None,
+        );
+        assert_eq!(r, Ok((Kserd::new_num(21), vec!["out0".to_string()])));
+        let exe = format!("papyrus_worker{}", std::env::consts::EXE_SUFFIX);
+        assert!(std::path::Path::new(compile_dir)
+            .join("worker/target/toolchain-stable/debug")
+            .join(exe)
+            .is_file());
+    }
+
+    #[test]
+    fn cancel_compile_and_exec_test() {
+        use std::time::Duration;
+
+        let compile_dir = "target/testing/cancel_compile_and_exec";
+        let mut code = SourceCode::default();
+        code.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "std::thread::sleep(std::time::Duration::from_secs(60))".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "2+2".to_string(),
+                semi: false,
+            },
+        ]));
+        let files = vec![("lib".into(), code)].into_iter().collect();
+        let linking_config = LinkingConfiguration::default();
+
+        build_compile_dir(
+            compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+
+        // cargo is killed
+        let interrupt = Interrupt::default();
+        interrupt.interrupt();
+        let r = compile_cancellable(
+            &SessionDir::unshared(compile_dir.as_ref()),
+            &linking_config,
+            &CrateSource::default(),
+            &CompileOptions::default(),
+            &Cancel::new(interrupt, None),
+            |_| (),
+        );
+        match r {
+            Err(CompilationError::Cancelled(Cancelled::Interrupted)) => (),
+            r => panic!("expecting compilation to be interrupted, got {:?}", r),
+        }
+
+        // the evaluation is abandoned
+        let path = compile(compile_dir, &linking_config, |_| ()).unwrap();
+        let timeout = Duration::from_millis(200);
+        let mut values = Values::new();
+        values.insert("a".to_string(), Stored::note("1"));
+        let r = exec_cancellable(
+            &path,
+            "_lib_intern_eval",
+            &mut values,
+            &Cancel::new(Interrupt::default(), Some(timeout)),
+        );
+        assert_eq!(
+            r.map(|x| x.0),
+            Err(ExecError::Cancelled(Cancelled::TimedOut(timeout)))
+        );
+        assert!(values.is_empty());
+    }
+
+    #[test]
+    fn worker_cancel_test() {
+        use std::time::Duration;
+
+        let compile_dir = "target/testing/worker_cancel";
+        let mut code = SourceCode::default();
+        code.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "std::thread::sleep(std::time::Duration::from_secs(60))".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "2+2".to_string(),
+                semi: false,
+            },
+        ]));
+        let files = vec![("lib".into(), code)].into_iter().collect();
+        let linking_config = LinkingConfiguration {
+            worker: true,
+            ..Default::default()
+        };
+
+        build_compile_dir(
+            compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+        let path = compile(compile_dir, &linking_config, |_| ()).unwrap();
+
+        // the worker is killed
+        let mut worker = WorkerBackend::<()>::new();
+        let timeout = Duration::from_millis(200);
+        let mut exec = |cancel| {
+            worker.exec(
+                compile_dir.as_ref(),
+                None,
+                &path,
+                "_lib_intern_eval",
+                "lib".as_ref(),
+                std::iter::empty(),
+                AppData::None,
+                &cancel,
+                None,
+            )
+        };
+        let r = exec(Cancel::new(Interrupt::default(), Some(timeout)));
+        assert_eq!(r, Err(WorkerError::Cancelled(Cancelled::TimedOut(timeout))));
+
+        // a new worker is started, which is interrupted
+        let interrupt = Interrupt::default();
+        let cancel = Cancel::new(interrupt.clone(), None);
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(500));
+            interrupt.interrupt();
+        });
+        let r = exec(cancel);
+        assert_eq!(r, Err(WorkerError::Cancelled(Cancelled::Interrupted)));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn exec_and_capture_test() {
+        use crate::output::{Output, OutputChange, Stream};
+
+        let compile_dir = "target/testing/exec_and_capture_test";
+        let mut code = SourceCode::default();
+        code.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "println!(\"Hello\")".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "eprintln!(\"oh no\")".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "print!(\"world\")".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "2+2".to_string(),
+                semi: false,
+            },
+        ]));
+        let files = vec![("lib".into(), code)].into_iter().collect();
+        let linking_config = LinkingConfiguration::default();
+
+        build_compile_dir(
+            compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+        let path = compile(compile_dir, &linking_config, |_| ()).unwrap();
+
+        let mut output = Output::new().into_write();
+        let rx = output.listen();
+        let r = capture(&mut output, || {
+            exec(path, "_lib_intern_eval", &mut Values::new(), &())
+        })
+        .unwrap();
+        output.close();
+
+        assert_eq!(r.0, Kserd::new_num(4));
+
+        // other tests can be writing to stdout at the same time
+        let captured = rx
+            .iter()
+            .filter(|chg| matches!(chg, OutputChange::Captured(..)))
+            .collect::<Vec<_>>();
+        for line in &[
+            OutputChange::Captured(Stream::Stdout, "Hello".to_string()),
+            OutputChange::Captured(Stream::Stderr, "oh no".to_string()),
+            // the library's buffer is flushed once evaluated
+            OutputChange::Captured(Stream::Stdout, "world".to_string()),
+        ] {
+            assert!(captured.contains(line), "{:?} not in {:?}", line, captured);
+        }
+        assert!(output.buffer().contains("Hello\n"));
+    }
+
     fn pass_compile_eval_file() -> (PathBuf, SourceCode) {
         let mut code = SourceCode::default();
         code.stmts.push(StmtGrp(vec![Statement {
@@ -189,6 +577,321 @@ mod tests {
         ("lib".into(), code)
     }
 
+    #[test]
+    fn unloaded_library_file_is_deleted() {
+        let compile_dir = "target/testing/unloaded_library_file_is_deleted";
+        let files = vec![pass_compile_eval_file()].into_iter().collect();
+        let linking_config = LinkingConfiguration::default();
+
+        build_compile_dir(
+            &compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+        let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
+        let path = unshackle_library_file(path);
+
+        let (_, lib) = exec::<_, _>(&path, "_lib_intern_eval", &mut Values::new(), &()).unwrap();
+        assert!(path.exists());
+        drop(lib);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn cached_compile_test() {
+        let compile_dir = "target/testing/cached_compile";
+        fs::remove_dir_all(compile_dir).ok();
+        let files = vec![pass_compile_eval_file()].into_iter().collect();
+        let linking_config = LinkingConfiguration::default();
+        let source = CrateSource::default();
+
+        build_compile_dir(
+            compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+        let (path, _) = compile_cached(
+            &SessionDir::unshared(compile_dir.as_ref()),
+            &linking_config,
+            &source,
+            &CompileOptions::default(),
+            1,
+            &Cancel::never(),
+            |_| (),
+        )
+        .unwrap();
+        unshackle_library_file(path);
+        let cache = PathBuf::from(compile_dir).join("cache");
+        assert_eq!(fs::read_dir(&cache).unwrap().count(), 1);
+
+        // an interrupted compilation fails, so the library must come from the cache
+        let interrupt = Interrupt::default();
+        interrupt.interrupt();
+        let cancel = Cancel::new(interrupt, None);
+        let (path, _) = compile_cached(
+            &SessionDir::unshared(compile_dir.as_ref()),
+            &linking_config,
+            &source,
+            &CompileOptions::default(),
+            1,
+            &cancel,
+            |_| (),
+        )
+        .unwrap();
+        let r = exec::<_, _>(path, "_lib_intern_eval", &mut Values::new(), &()).unwrap();
+        assert_eq!(r.0, Kserd::new_num(4));
+
+        // a different source is compiled, and the cache holds one library
+        let source = CrateSource {
+            network: Network::Offline,
+            replacement: None,
+        };
+        compile_cached(
+            &SessionDir::unshared(compile_dir.as_ref()),
+            &linking_config,
+            &source,
+            &CompileOptions::default(),
+            1,
+            &Cancel::never(),
+            |_| (),
+        )
+        .unwrap();
+        assert_eq!(fs::read_dir(&cache).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn concurrent_sessions_compile_test() {
+        let root = "target/testing/concurrent_sessions_compile";
+
+        let run = |expr: &'static str| {
+            std::thread::spawn(move || {
+                let linking_config = LinkingConfiguration::default();
+                let session = SessionDir::create(root.as_ref()).unwrap();
+                let mut code = SourceCode::default();
+                code.stmts.push(StmtGrp(vec![Statement {
+                    expr: expr.to_string(),
+                    semi: false,
+                }]));
+                let files = vec![("lib".into(), code)].into_iter().collect();
+                build_compile_dir(
+                    session.path(),
+                    &files,
+                    &linking_config,
+                    &StaticFiles::new(),
+                    &PersistedMap::new(),
+                    &CompileOptions::default(),
+                )
+                .unwrap();
+
+                let lock = session.lock_build(&Cancel::never()).unwrap();
+                let (path, _) = compile_cached(
+                    &session,
+                    &linking_config,
+                    &CrateSource::default(),
+                    &CompileOptions::default(),
+                    0,
+                    &Cancel::never(),
+                    |_| (),
+                )
+                .unwrap();
+                let path = unshackle_library_file(path);
+                drop(lock);
+                path
+            })
+        };
+
+        let a = run("1+1");
+        let b = run("2+2");
+        let eval = |path| exec::<_, _>(path, "_lib_intern_eval", &mut Values::new(), &()).unwrap();
+        assert_eq!(eval(a.join().unwrap()).0, Kserd::new_num(2));
+        assert_eq!(eval(b.join().unwrap()).0, Kserd::new_num(4));
+
+        // both sessions have ended and their directories removed
+        assert_eq!(
+            fs::read_dir(PathBuf::from(root).join("sessions"))
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn release_profile_compile_test() {
+        let compile_dir = "target/testing/release_profile_compile";
+        let files = vec![pass_compile_eval_file()].into_iter().collect();
+        let linking_config = LinkingConfiguration::default();
+        let options = CompileOptions {
+            profile: Profile::Release,
+            edition: Edition::E2021,
+            ..Default::default()
+        };
+
+        build_compile_dir(
+            compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &options,
+        )
+        .unwrap();
+        let (path, _) = compile_cancellable(
+            &SessionDir::unshared(compile_dir.as_ref()),
+            &linking_config,
+            &CrateSource::default(),
+            &options,
+            &Cancel::never(),
+            |_| (),
+        )
+        .unwrap();
+        assert_eq!(path.parent().unwrap().file_name().unwrap(), "release");
+
+        let r = exec::<_, _>(path, "_lib_intern_eval", &mut Values::new(), &()).unwrap();
+        assert_eq!(r.0, Kserd::new_num(4));
+    }
+
+    #[test]
+    fn fast_path_compile_test() {
+        let compile_dir = "target/testing/fast_path_compile";
+        fs::remove_dir_all(compile_dir).ok();
+        let linking_config = LinkingConfiguration::default();
+        let session = SessionDir::unshared(compile_dir.as_ref());
+        let build = |file| {
+            let files = vec![file].into_iter().collect();
+            build_compile_dir(
+                compile_dir,
+                &files,
+                &linking_config,
+                &StaticFiles::new(),
+                &PersistedMap::new(),
+                &CompileOptions::default(),
+            )
+            .unwrap();
+        };
+        let compile = |events: &mut Vec<CompileEvent>| {
+            compile_cancellable(
+                &session,
+                &linking_config,
+                &CrateSource::default(),
+                &CompileOptions::default(),
+                &Cancel::never(),
+                |e| events.push(e.clone()),
+            )
+        };
+
+        // cargo builds kserd first, rustc is invoked after
+        build(pass_compile_eval_file());
+        let (path, _) = compile(&mut Vec::new()).unwrap();
+        unshackle_library_file(path);
+        let mut events = Vec::new();
+        let (path, _) = compile(&mut events).unwrap();
+        assert_eq!(
+            events[0],
+            CompileEvent::CrateStarted(
+                LIBRARY_NAME.to_string(),
+                "0.1.0".to_string(),
+                Progress {
+                    finished: 0,
+                    total: 1
+                }
+            )
+        );
+        assert!(matches!(
+            events.last(),
+            Some(CompileEvent::Finished(true, _))
+        ));
+        assert!(!events.iter().any(|e| matches!(e, CompileEvent::Status(_))));
+
+        let r = exec::<_, _>(path, "_lib_intern_eval", &mut Values::new(), &()).unwrap();
+        assert_eq!(r.0, Kserd::new_num(4));
+
+        // types the crate defines have the same `TypeId` as when cargo compiled them
+        let mut code = SourceCode::default();
+        code.items.push(("struct Foo;".to_string(), false));
+        code.stmts.push(StmtGrp(vec![Statement {
+            expr: "format!(\"{:?}\", std::any::TypeId::of::<Foo>())".to_string(),
+            semi: false,
+        }]));
+        build(("lib".into(), code));
+        let type_id = |fast: bool| {
+            let options = CompileOptions {
+                fast_path: fast,
+                ..Default::default()
+            };
+            let (path, _) = compile_cancellable(
+                &session,
+                &linking_config,
+                &CrateSource::default(),
+                &options,
+                &Cancel::never(),
+                |_| (),
+            )
+            .unwrap();
+            let path = unshackle_library_file(path);
+            let mut values = Values::new();
+            let (r, lib) = exec::<_, _>(path, "_lib_intern_eval", &mut values, &()).unwrap();
+            // the stored output must be dropped before its library
+            drop(values);
+            drop(lib);
+            r
+        };
+        assert_eq!(type_id(false), type_id(true));
+
+        // compile errors are diagnostics, as with cargo
+        build(fail_compile_file());
+        match compile(&mut Vec::new()) {
+            Err(CompilationError::CompileError(d, _)) => assert!(!d.is_empty()),
+            _ => panic!("expecting a compile error"),
+        }
+    }
+
+    #[test]
+    fn offline_unavailable_crate_test() {
+        let compile_dir = "target/testing/offline_unavailable_crate";
+        let mut code = SourceCode::default();
+        code.crates
+            .push(CrateType::parse_str("extern crate papyrus_not_a_crate;").unwrap());
+        let files = vec![("lib".into(), code)].into_iter().collect();
+        let linking_config = LinkingConfiguration::default();
+
+        build_compile_dir(
+            compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
+
+        let source = CrateSource {
+            network: Network::Offline,
+            replacement: None,
+        };
+        let r = compile_cancellable(
+            &SessionDir::unshared(compile_dir.as_ref()),
+            &linking_config,
+            &source,
+            &CompileOptions::default(),
+            &Cancel::never(),
+            |_| (),
+        );
+        match r {
+            Err(CompilationError::CrateUnavailable(name, _)) => {
+                assert_eq!(name, "papyrus-not-a-crate")
+            }
+            r => panic!("expecting an unavailable crate, got {:?}", r),
+        }
+    }
+
     #[test]
     fn output_externally_linked_type_as_kserd() {
         let compile_dir = "target/testing/output_externally_linked_type_as_kserd";
@@ -218,7 +921,15 @@ mod tests {
             .push_str("use external_kserd::{kserd, rand};");
 
         // build
-        build_compile_dir(&compile_dir, &files, &linking_config, &StaticFiles::new()).unwrap();
+        build_compile_dir(
+            &compile_dir,
+            &files,
+            &linking_config,
+            &StaticFiles::new(),
+            &PersistedMap::new(),
+            &CompileOptions::default(),
+        )
+        .unwrap();
         let filestr = fs::read_to_string(&format!("{}/src/lib.rs", compile_dir)).unwrap();
         assert!(filestr.contains("\nlet out0 = rand::random::<u8>();"));
         assert!(filestr.contains("\nlet out1 = 2+2;"));
@@ -227,7 +938,7 @@ mod tests {
         let path = compile(&compile_dir, &linking_config, |_| ()).unwrap();
 
         // eval
-        let r = exec::<_, _>(path, "_lib_intern_eval", &()).unwrap(); // execute library fn
+        let r = exec::<_, _>(path, "_lib_intern_eval", &mut Values::new(), &()).unwrap(); // execute library fn
 
         assert_eq!(r.0, Kserd::new_num(4));
     }
@@ -0,0 +1,234 @@
+//! How the REPL crate is built: the profile, edition, extra `rustc` arguments and toolchain, and
+//! which warnings are shown.
+use std::{fmt, str::FromStr};
+
+/// The profile the REPL crate is built with.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Profile {
+    /// The `dev` profile, unoptimized. The default.
+    #[default]
+    Debug,
+    /// The `release` profile, built with `--release`.
+    Release,
+    /// The `dev` profile with a custom `opt-level`, one of `0`, `1`, `2`, `3`, `s` or `z`.
+    OptLevel(String),
+}
+
+impl Profile {
+    /// The directory in `target` the library is output to.
+    pub(crate) fn target_subdir(&self) -> &'static str {
+        match self {
+            Profile::Release => "release",
+            _ => "debug",
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    /// Parses `debug`, `release` or an opt-level.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(Profile::Debug),
+            "release" => Ok(Profile::Release),
+            "0" | "1" | "2" | "3" | "s" | "z" => Ok(Profile::OptLevel(s.to_string())),
+            _ => Err(format!(
+                "expecting debug, release, or an opt-level (0-3, s, z) but found `{}`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Profile::Debug => write!(f, "debug"),
+            Profile::Release => write!(f, "release"),
+            Profile::OptLevel(l) => write!(f, "opt-level {}", l),
+        }
+    }
+}
+
+/// The Rust edition of the REPL crate.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Edition {
+    /// Rust 2018. The default.
+    #[default]
+    E2018,
+    /// Rust 2021.
+    E2021,
+}
+
+impl FromStr for Edition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "2018" => Ok(Edition::E2018),
+            "2021" => Ok(Edition::E2021),
+            _ => Err(format!("expecting 2018 or 2021 but found `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Edition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Edition::E2018 => write!(f, "2018"),
+            Edition::E2021 => write!(f, "2021"),
+        }
+    }
+}
+
+/// The compiler warnings shown after an input compiles.
+///
+/// Only warnings which map back to a REPL input are shown, warnings in the generated code and
+/// static files are not.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Warnings {
+    /// Warnings are not shown, `rustc` runs with `-Awarnings`. The default.
+    #[default]
+    Off,
+    /// Only warnings in the input just entered are shown.
+    NewInput,
+    /// Warnings in all the REPL inputs are shown.
+    All,
+}
+
+impl FromStr for Warnings {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Warnings::Off),
+            "new" => Ok(Warnings::NewInput),
+            "all" => Ok(Warnings::All),
+            _ => Err(format!("expecting off, new, or all but found `{}`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Warnings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warnings::Off => write!(f, "off"),
+            Warnings::NewInput => write!(f, "new input"),
+            Warnings::All => write!(f, "all"),
+        }
+    }
+}
+
+/// Options for building the REPL crate.
+///
+/// The profile and edition are written to `Cargo.toml`. The `rustc` arguments are passed to the
+/// compilation of the REPL crate only, not its dependencies, for example `-C target-cpu=native`.
+/// A toolchain is passed to `cargo` as `+toolchain`, which requires `rustup`.
+///
+/// With `clippy` the REPL crate is compiled through `clippy-driver`, adding clippy's lints to the
+/// warnings. It has no effect if warnings are off.
+///
+/// With `fast_path` a REPL crate which depends on no crates is compiled by invoking `rustc`
+/// directly, skipping `cargo`, once `cargo` has built `kserd`. A toolchain or clippy always use
+/// `cargo`.
+///
+/// Linked external libraries must be compiled with the same toolchain and profile as the REPL
+/// crate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileOptions {
+    /// The build profile.
+    pub profile: Profile,
+    /// The Rust edition.
+    pub edition: Edition,
+    /// Extra arguments passed to `rustc`.
+    pub rustc_args: Vec<String>,
+    /// The `rustup` toolchain to build with, such as `nightly`. `None` uses the default.
+    pub toolchain: Option<String>,
+    /// The warnings shown.
+    pub warnings: Warnings,
+    /// Lint with clippy.
+    pub clippy: bool,
+    /// Compile with `rustc` directly when possible. On by default.
+    pub fast_path: bool,
+}
+
+impl CompileOptions {
+    /// The REPL crate is compiled through `clippy-driver`.
+    pub(crate) fn clippy_driver(&self) -> bool {
+        self.clippy && self.warnings != Warnings::Off
+    }
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            profile: Profile::default(),
+            edition: Edition::default(),
+            rustc_args: Vec::new(),
+            toolchain: None,
+            warnings: Warnings::default(),
+            clippy: false,
+            fast_path: true,
+        }
+    }
+}
+
+impl fmt::Display for CompileOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "profile: {}", self.profile)?;
+        writeln!(f, "edition: {}", self.edition)?;
+        match self.rustc_args.as_slice() {
+            [] => writeln!(f, "rustc args: none")?,
+            args => writeln!(f, "rustc args: {}", args.join(" "))?,
+        }
+        writeln!(
+            f,
+            "toolchain: {}",
+            self.toolchain.as_deref().unwrap_or("default")
+        )?;
+        writeln!(f, "warnings: {}", self.warnings)?;
+        writeln!(f, "clippy: {}", if self.clippy { "on" } else { "off" })?;
+        write!(
+            f,
+            "fast path: {}",
+            if self.fast_path { "on" } else { "off" }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_options() {
+        assert_eq!("debug".parse(), Ok(Profile::Debug));
+        assert_eq!("release".parse(), Ok(Profile::Release));
+        assert_eq!("s".parse(), Ok(Profile::OptLevel("s".to_string())));
+        assert_eq!(
+            "fast".parse::<Profile>(),
+            Err("expecting debug, release, or an opt-level (0-3, s, z) but found `fast`".into())
+        );
+        assert_eq!(Profile::Release.target_subdir(), "release");
+        assert_eq!(Profile::OptLevel("3".into()).target_subdir(), "debug");
+
+        assert_eq!("2021".parse(), Ok(Edition::E2021));
+        assert!("2015".parse::<Edition>().is_err());
+
+        let options = CompileOptions {
+            rustc_args: vec!["-C".into(), "target-cpu=native".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            options.to_string(),
+            "profile: debug\nedition: 2018\nrustc args: -C target-cpu=native\ntoolchain: default\nwarnings: off\nclippy: off\nfast path: on"
+        );
+
+        assert_eq!("new".parse(), Ok(Warnings::NewInput));
+        assert_eq!(
+            "some".parse::<Warnings>(),
+            Err("expecting off, new, or all but found `some`".into())
+        );
+    }
+}
@@ -0,0 +1,219 @@
+//! Compilation progress, parsed from `cargo`'s output.
+use super::{Diagnostic, LIBRARY_NAME};
+use serde_json::Value;
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// An event while compiling the REPL crate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileEvent {
+    /// A crate started compiling, with its name and version.
+    CrateStarted(String, String, Progress),
+    /// A crate finished compiling, or was already compiled, with its name.
+    CrateFinished(String, Progress),
+    /// The REPL library was produced at the path.
+    Artifact(PathBuf),
+    /// The compiler emitted a diagnostic, for the REPL crate or a dependency.
+    Diagnostic(Diagnostic),
+    /// Any other line `cargo` writes to `stderr`, such as `Updating crates.io index`.
+    Status(String),
+    /// The build finished, successfully or not, taking the duration.
+    Finished(bool, Duration),
+}
+
+/// The number of crates compiled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// The crates finished.
+    pub finished: usize,
+    /// The estimated number of crates to compile, at least `finished`.
+    ///
+    /// The estimate is the packages in `Cargo.lock`, which can include packages not compiled for
+    /// the platform.
+    pub total: usize,
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.finished, self.total)
+    }
+}
+
+/// Turns the lines `cargo` writes into [`CompileEvent`]s.
+pub(crate) struct Tracker {
+    lockfile: PathBuf,
+    packages: Option<usize>,
+    finished: usize,
+    start: Instant,
+}
+
+impl Tracker {
+    /// Tracks a build in the compilation directory.
+    pub(crate) fn new(compile_dir: &Path) -> Self {
+        Tracker {
+            lockfile: compile_dir.join("Cargo.lock"),
+            packages: None,
+            finished: 0,
+            start: Instant::now(),
+        }
+    }
+
+    /// Parses a line of `cargo`'s json output on `stdout`.
+    pub(crate) fn stdout_line(&mut self, line: &str) -> Vec<CompileEvent> {
+        let value: Value = match serde_json::from_str(line) {
+            Ok(x) => x,
+            Err(_) => return Vec::new(),
+        };
+        let s = |v: Option<&Value>| v.and_then(Value::as_str).map(String::from);
+
+        match value.get("reason").and_then(Value::as_str) {
+            Some("compiler-message") => Diagnostic::parse_line(line)
+                .map(CompileEvent::Diagnostic)
+                .into_iter()
+                .collect(),
+            Some("compiler-artifact") => {
+                let target = value.get("target");
+                let build_script = target
+                    .and_then(|t| t.get("kind"))
+                    .and_then(Value::as_array)
+                    .map(|k| k.iter().any(|k| k == "custom-build"))
+                    .unwrap_or(false);
+                let name = match s(target.and_then(|t| t.get("name"))) {
+                    Some(name) if !build_script => name,
+                    _ => return Vec::new(),
+                };
+
+                self.finished += 1;
+                let mut events = Vec::new();
+                if name == LIBRARY_NAME {
+                    let file = value
+                        .get("filenames")
+                        .and_then(Value::as_array)
+                        .and_then(|x| s(x.first()));
+                    events.extend(file.map(|f| CompileEvent::Artifact(f.into())));
+                }
+                events.insert(0, CompileEvent::CrateFinished(name, self.progress()));
+                events
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Parses a line `cargo` writes to `stderr`.
+    pub(crate) fn stderr_line(&mut self, line: &str) -> CompileEvent {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("Compiling"), Some(name), Some(version)) => CompileEvent::CrateStarted(
+                name.to_string(),
+                version.trim_start_matches('v').to_string(),
+                self.progress(),
+            ),
+            _ => CompileEvent::Status(line.trim().to_string()),
+        }
+    }
+
+    /// The build finished.
+    pub(crate) fn finished(&self, success: bool) -> CompileEvent {
+        CompileEvent::Finished(success, self.start.elapsed())
+    }
+
+    fn progress(&mut self) -> Progress {
+        if self.packages.is_none() {
+            // cargo writes the lock file once dependencies are resolved
+            self.packages = fs::read_to_string(&self.lockfile)
+                .ok()
+                .map(|s| s.lines().filter(|l| l.trim() == "[[package]]").count());
+        }
+
+        Progress {
+            finished: self.finished,
+            total: self.packages.unwrap_or(0).max(self.finished),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_cargo_output() {
+        let dir = Path::new("target/testing/progress-tracker");
+        fs::create_dir_all(dir).unwrap();
+        fs::write(
+            dir.join("Cargo.lock"),
+            "version = 3\n\n[[package]]\nname = \"a\"\n\n[[package]]\nname = \"b\"\n\n[[package]]\nname = \"papyrus_mem_code\"\n",
+        )
+        .unwrap();
+        let mut tracker = Tracker::new(dir);
+
+        assert_eq!(
+            tracker.stderr_line("    Updating crates.io index"),
+            CompileEvent::Status("Updating crates.io index".to_string())
+        );
+        assert_eq!(
+            tracker.stderr_line("   Compiling rand v0.8.5"),
+            CompileEvent::CrateStarted(
+                "rand".to_string(),
+                "0.8.5".to_string(),
+                Progress {
+                    finished: 0,
+                    total: 3
+                }
+            )
+        );
+
+        // build scripts are not counted
+        let artifact = |kind: &str, name: &str| {
+            format!(
+                r#"{{"reason":"compiler-artifact","target":{{"kind":["{}"],"name":"{}"}},"filenames":["target/debug/lib{}.so"],"fresh":false}}"#,
+                kind, name, name
+            )
+        };
+        assert_eq!(
+            tracker.stdout_line(&artifact("custom-build", "build-script-build")),
+            vec![]
+        );
+        assert_eq!(
+            tracker.stdout_line(&artifact("lib", "rand")),
+            vec![CompileEvent::CrateFinished(
+                "rand".to_string(),
+                Progress {
+                    finished: 1,
+                    total: 3
+                }
+            )]
+        );
+        assert_eq!(
+            tracker.stdout_line(&artifact("cdylib", LIBRARY_NAME)),
+            vec![
+                CompileEvent::CrateFinished(
+                    LIBRARY_NAME.to_string(),
+                    Progress {
+                        finished: 2,
+                        total: 3
+                    }
+                ),
+                CompileEvent::Artifact(format!("target/debug/lib{}.so", LIBRARY_NAME).into())
+            ]
+        );
+
+        let message = r#"{"reason":"compiler-message","message":{"level":"warning","message":"unused","spans":[]}}"#;
+        assert!(matches!(
+            tracker.stdout_line(message).as_slice(),
+            [CompileEvent::Diagnostic(d)] if d.message == "unused"
+        ));
+        assert_eq!(tracker.stdout_line("not json"), vec![]);
+        assert_eq!(
+            tracker.stdout_line(r#"{"reason":"build-finished","success":true}"#),
+            vec![]
+        );
+        assert!(matches!(
+            tracker.finished(true),
+            CompileEvent::Finished(true, _)
+        ));
+    }
+}
@@ -0,0 +1,193 @@
+//! Capturing the standard output and error of evaluated code.
+//!
+//! For the duration of an evaluation the process's stdout and stderr file descriptors are
+//! redirected (`dup2`) onto pipes. A thread drains the pipes, writing each line into the
+//! [`Output`] as it arrives. The redirection is process wide, so writes from _any_ thread are
+//! captured while evaluating.
+//!
+//! Only supported on Linux, elsewhere the evaluation runs without capturing.
+use crate::output::{self, Output};
+
+/// Run `f` with stdout and stderr captured into `output`.
+///
+/// If the streams can not be redirected the failure is logged and `f` runs without capturing.
+#[cfg(target_os = "linux")]
+pub(crate) fn capture<F, R>(output: &mut Output<output::Write>, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    use self::linux::*;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // pending host output should not be captured
+    std::io::stdout().flush().ok();
+    std::io::stderr().flush().ok();
+
+    let redirected = redirect(libc::STDOUT_FILENO, output::Stream::Stdout).and_then(|out| {
+        redirect(libc::STDERR_FILENO, output::Stream::Stderr).map(|err| (out, err))
+    });
+
+    let ((out, out_pipe), (err, err_pipe)) = match redirected {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("failed to redirect output of evaluation: {}", e);
+            return f();
+        }
+    };
+
+    let done = AtomicBool::new(false);
+
+    std::thread::scope(|s| {
+        s.spawn(|| drain([out_pipe, err_pipe], output, &done));
+
+        let r = f();
+
+        // restores the descriptors, nothing more is written to the pipes by this process
+        drop((out, err));
+        done.store(true, Ordering::Release);
+
+        r
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn capture<F, R>(_output: &mut Output<output::Write>, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    f()
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::*;
+    use std::{
+        fs::File,
+        io::{self, Read},
+        os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    /// How long to wait on the pipes before checking if the evaluation finished.
+    const POLL_TIMEOUT_MS: libc::c_int = 50;
+
+    /// A redirected file descriptor, restored on drop.
+    pub struct Redirected {
+        fd: RawFd,
+        saved: OwnedFd,
+    }
+
+    impl Drop for Redirected {
+        fn drop(&mut self) {
+            if unsafe { libc::dup2(self.saved.as_raw_fd(), self.fd) } == -1 {
+                error!(
+                    "failed to restore file descriptor {}: {}",
+                    self.fd,
+                    io::Error::last_os_error()
+                );
+            }
+        }
+    }
+
+    /// The reading end of a redirected stream.
+    pub struct Pipe {
+        stream: output::Stream,
+        rdr: File,
+        /// Bytes of an incomplete line.
+        buf: Vec<u8>,
+    }
+
+    fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+        if ret == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+
+    /// Redirect `fd` onto a new pipe.
+    pub fn redirect(fd: RawFd, stream: output::Stream) -> io::Result<(Redirected, Pipe)> {
+        let mut fds = [0; 2];
+        // close-on-exec, child processes only inherit the write end through `fd`
+        cvt(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) })?;
+        let (rdr, wtr) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+        // a child process could keep the write end open, so reads must not block
+        cvt(unsafe { libc::fcntl(rdr.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) })?;
+
+        let saved = cvt(unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) })?;
+        let saved = unsafe { OwnedFd::from_raw_fd(saved) };
+
+        cvt(unsafe { libc::dup2(wtr.as_raw_fd(), fd) })?;
+
+        let redirected = Redirected { fd, saved };
+        let pipe = Pipe {
+            stream,
+            rdr: File::from(rdr),
+            buf: Vec::new(),
+        };
+
+        Ok((redirected, pipe))
+    }
+
+    /// Write lines from the pipes into `output` until `done`, then write what remains.
+    pub fn drain(mut pipes: [Pipe; 2], output: &mut Output<output::Write>, done: &AtomicBool) {
+        loop {
+            // checked before reading so the last read happens after the descriptors are restored
+            let finished = done.load(Ordering::Acquire);
+
+            for pipe in pipes.iter_mut() {
+                pipe.read(output);
+            }
+
+            if finished {
+                break;
+            }
+
+            let mut fds = [&pipes[0], &pipes[1]].map(|pipe| libc::pollfd {
+                fd: pipe.rdr.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            });
+            unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, POLL_TIMEOUT_MS) };
+        }
+
+        for pipe in pipes.iter_mut() {
+            pipe.finish(output);
+        }
+    }
+
+    impl Pipe {
+        /// Read what is available, writing the complete lines.
+        fn read(&mut self, output: &mut Output<output::Write>) {
+            let mut chunk = [0; 4096];
+            loop {
+                match self.rdr.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        self.buf.extend_from_slice(&chunk[..n]);
+                        self.write_lines(output);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(_) => break, // would block
+                }
+            }
+        }
+
+        fn write_lines(&mut self, output: &mut Output<output::Write>) {
+            while let Some(idx) = self.buf.iter().position(|&b| b == b'\n') {
+                let line = self.buf.drain(..=idx).collect::<Vec<_>>();
+                output.write_captured(self.stream, &String::from_utf8_lossy(&line));
+            }
+        }
+
+        /// Writes a trailing incomplete line.
+        fn finish(&mut self, output: &mut Output<output::Write>) {
+            if !self.buf.is_empty() {
+                output.write_captured(self.stream, &String::from_utf8_lossy(&self.buf));
+                self.buf.clear();
+            }
+        }
+    }
+}
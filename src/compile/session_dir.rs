@@ -0,0 +1,332 @@
+//! Sharing a compilation directory between concurrent sessions.
+//!
+//! Each session writes its crate to its own directory under `sessions/`, held by an advisory lock
+//! on `sessions/<id>.lock` for the life of the session. A session directory whose lock can be
+//! taken belongs to a session which has ended, and is removed when the next session starts.
+//!
+//! The `target` directory and the library cache are shared so dependencies are compiled once.
+//! Builds take a lock on the compilation directory, held until the compiled library has been
+//! unshackled, so one session's library is not overwritten by another's.
+use super::interrupt::{Cancel, POLL_INTERVAL};
+use super::{build::sweep_library_files, CompilationError};
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+const SESSIONS_DIR: &str = "sessions";
+const BUILD_LOCK: &str = ".build.lock";
+
+/// The directory a session compiles in.
+#[derive(Debug)]
+pub(crate) struct SessionDir {
+    root: PathBuf,
+    dir: PathBuf,
+    /// The session's lock, `None` if the directory is not a session's.
+    lock: Option<(File, PathBuf)>,
+}
+
+/// Held while building, until the library is unshackled.
+pub(crate) struct BuildLock {
+    _lock: Option<File>,
+}
+
+impl SessionDir {
+    /// Creates a locked session directory in the compilation directory `root`.
+    ///
+    /// Stale session directories are removed first. If no other session is running the renamed
+    /// library files are also swept.
+    pub(crate) fn create(root: &Path) -> io::Result<Self> {
+        let root = absolute(root)?;
+        let sessions = root.join(SESSIONS_DIR);
+        fs::create_dir_all(&sessions)?;
+
+        if sweep_stale_sessions(&sessions)? == 0 {
+            sweep_library_files(&root.join("target"));
+        }
+
+        // the lock is taken before the directory exists, a directory without a lock is stale.
+        // it is taken under a name the sweep ignores and renamed into place, so another
+        // session's sweep never sees an unlocked lock file of a session which is starting
+        let id = uuid::Uuid::new_v4().to_simple().to_string();
+        let new_lock = sessions.join(format!("{}.new", id));
+        let lock_file = sessions.join(format!("{}.lock", id));
+        let lock = open_lock(&new_lock)?;
+        let locked = try_lock(&lock).and_then(|locked| match locked {
+            true => fs::rename(&new_lock, &lock_file),
+            false => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "session lock is held",
+            )),
+        });
+        if let Err(e) = locked {
+            drop(lock);
+            fs::remove_file(&new_lock).ok();
+            return Err(e);
+        }
+
+        let dir = sessions.join(id);
+        fs::create_dir_all(&dir)?;
+
+        // a lock file is needed to build with `--frozen`
+        let lockfile = root.join("Cargo.lock");
+        if lockfile.is_file() {
+            fs::copy(&lockfile, dir.join("Cargo.lock"))?;
+        }
+
+        Ok(SessionDir {
+            root,
+            dir,
+            lock: Some((lock, lock_file)),
+        })
+    }
+
+    /// Compiles in `dir` itself, with no other sessions and no locking.
+    pub(crate) fn unshared(dir: &Path) -> Self {
+        SessionDir {
+            root: absolute(dir).unwrap_or_else(|_| dir.to_path_buf()),
+            dir: dir.to_path_buf(),
+            lock: None,
+        }
+    }
+
+    /// The directory the session's crate is written to.
+    pub(crate) fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The shared `target` directory, an absolute path.
+    pub(crate) fn target(&self) -> PathBuf {
+        self.root.join("target")
+    }
+
+    /// The shared library cache.
+    pub(crate) fn cache(&self) -> PathBuf {
+        self.root.join("cache")
+    }
+
+    /// Waits for other sessions' builds to finish and takes the build lock.
+    pub(crate) fn lock_build(&self, cancel: &Cancel) -> Result<BuildLock, CompilationError> {
+        if self.lock.is_none() {
+            return Ok(BuildLock { _lock: None });
+        }
+
+        let file = open_lock(&self.root.join(BUILD_LOCK)).map_err(CompilationError::IOError)?;
+        loop {
+            match try_lock(&file) {
+                Ok(true) => return Ok(BuildLock { _lock: Some(file) }),
+                Ok(false) => {
+                    cancel.check().map_err(CompilationError::Cancelled)?;
+                    std::thread::sleep(POLL_INTERVAL);
+                }
+                Err(e) => return Err(CompilationError::IOError(e)),
+            }
+        }
+    }
+}
+
+impl Drop for SessionDir {
+    fn drop(&mut self) {
+        if let Some((lock, lock_file)) = self.lock.take() {
+            // the directory is removed while locked, the lock file once closed as windows does
+            // not delete open files
+            fs::remove_dir_all(&self.dir).ok();
+            drop(lock);
+            fs::remove_file(lock_file).ok();
+        }
+    }
+}
+
+/// Removes the session directories in `sessions` which are not locked, returning the number of
+/// sessions still running.
+fn sweep_stale_sessions(sessions: &Path) -> io::Result<usize> {
+    let mut live = 0;
+    let entries = fs::read_dir(sessions)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    for path in entries {
+        if path.extension().map(|x| x == "lock").unwrap_or(false) {
+            let lock = match open_lock(&path) {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            match try_lock(&lock) {
+                Ok(true) => {
+                    fs::remove_dir_all(path.with_extension("")).ok();
+                    drop(lock);
+                    fs::remove_file(&path).ok();
+                }
+                Ok(false) | Err(_) => live += 1,
+            }
+        } else if path.is_dir() && !path.with_extension("lock").exists() {
+            fs::remove_dir_all(&path).ok();
+        }
+    }
+
+    Ok(live)
+}
+
+fn open_lock(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+}
+
+/// `path` made absolute with the current directory.
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    Ok(std::env::current_dir()?.join(path).components().collect())
+}
+
+/// Takes an exclusive lock on `file` without waiting, returning `false` if another handle holds
+/// it. The lock is released when the file is closed.
+#[cfg(unix)]
+fn try_lock(file: &File) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    match e.kind() {
+        io::ErrorKind::WouldBlock => Ok(false),
+        _ => Err(e),
+    }
+}
+
+/// Takes an exclusive lock on `file` without waiting, returning `false` if another handle holds
+/// it. The lock is released when the file is closed.
+#[cfg(windows)]
+fn try_lock(file: &File) -> io::Result<bool> {
+    use std::{ffi::c_void, os::windows::io::AsRawHandle};
+
+    #[repr(C)]
+    struct Overlapped {
+        internal: usize,
+        internal_high: usize,
+        offset: u32,
+        offset_high: u32,
+        event: *mut c_void,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn LockFileEx(
+            file: *mut c_void,
+            flags: u32,
+            reserved: u32,
+            len_low: u32,
+            len_high: u32,
+            overlapped: *mut Overlapped,
+        ) -> i32;
+    }
+
+    const LOCKFILE_FAIL_IMMEDIATELY: u32 = 0x1;
+    const LOCKFILE_EXCLUSIVE_LOCK: u32 = 0x2;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+
+    let mut overlapped = Overlapped {
+        internal: 0,
+        internal_high: 0,
+        offset: 0,
+        offset_high: 0,
+        event: std::ptr::null_mut(),
+    };
+    let flags = LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY;
+    let handle = file.as_raw_handle() as *mut c_void;
+    if unsafe { LockFileEx(handle, flags, 0, !0, !0, &mut overlapped) } != 0 {
+        return Ok(true);
+    }
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(ERROR_LOCK_VIOLATION) => Ok(false),
+        _ => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_are_isolated_and_swept() {
+        let root = Path::new("target/testing/session-dirs");
+        fs::remove_dir_all(root).ok();
+
+        let a = SessionDir::create(root).unwrap();
+        let b = SessionDir::create(root).unwrap();
+        assert_ne!(a.path(), b.path());
+        assert!(a.path().is_dir() && b.path().is_dir());
+        assert_eq!(a.target(), b.target());
+
+        // a stale session is one without a held lock, or without a lock file
+        let stale = root.join(SESSIONS_DIR).join("stale");
+        fs::create_dir_all(&stale).unwrap();
+        fs::write(root.join(SESSIONS_DIR).join("stale.lock"), "").unwrap();
+        let orphan = root.join(SESSIONS_DIR).join("orphan");
+        fs::create_dir_all(&orphan).unwrap();
+        assert_eq!(sweep_stale_sessions(&root.join(SESSIONS_DIR)).unwrap(), 2);
+        assert!(!stale.exists());
+        assert!(!root.join(SESSIONS_DIR).join("stale.lock").exists());
+        assert!(!orphan.exists());
+        assert!(a.path().is_dir() && b.path().is_dir());
+
+        let path = a.path().to_path_buf();
+        drop(a);
+        assert!(!path.exists());
+        assert!(b.path().is_dir());
+    }
+
+    #[test]
+    fn starting_sessions_are_not_swept() {
+        let root = Path::new("target/testing/session-start");
+        fs::remove_dir_all(root).ok();
+        let sessions = root.join(SESSIONS_DIR);
+
+        // a lock which is being taken is not a session yet
+        fs::create_dir_all(&sessions).unwrap();
+        fs::write(sessions.join("starting.new"), "").unwrap();
+        assert_eq!(sweep_stale_sessions(&sessions).unwrap(), 0);
+        assert!(sessions.join("starting.new").exists());
+
+        let started = std::thread::scope(|s| {
+            let sweep = s.spawn(|| {
+                for _ in 0..200 {
+                    sweep_stale_sessions(&sessions).unwrap();
+                }
+            });
+            let started = (0..50)
+                .map(|_| SessionDir::create(root).unwrap())
+                .collect::<Vec<_>>();
+            sweep.join().unwrap();
+            started
+        });
+
+        for session in &started {
+            assert!(session.path().is_dir());
+            assert!(session.path().with_extension("lock").is_file());
+        }
+    }
+
+    #[test]
+    fn build_lock_is_exclusive() {
+        let root = Path::new("target/testing/session-build-lock");
+        fs::remove_dir_all(root).ok();
+        let a = SessionDir::create(root).unwrap();
+        let b = SessionDir::create(root).unwrap();
+
+        let lock = a.lock_build(&Cancel::never()).unwrap();
+        let interrupt = crate::compile::Interrupt::default();
+        interrupt.interrupt();
+        let cancel = Cancel::new(interrupt, None);
+        assert!(matches!(
+            b.lock_build(&cancel),
+            Err(CompilationError::Cancelled(_))
+        ));
+        drop(lock);
+        assert!(b.lock_build(&cancel).is_ok());
+    }
+}
@@ -0,0 +1,186 @@
+//! Where `cargo` fetches crates from, for compiling without network access.
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// First line of a `.cargo/config.toml` written by papyrus, any other config is left alone.
+const CONFIG_HEADER: &str = "# generated by papyrus, changes are overwritten\n";
+
+/// The network access `cargo` is allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Network {
+    /// Crates are fetched from the network as needed. The default.
+    #[default]
+    Online,
+    /// `cargo` runs with `--offline`, crates must already be downloaded or vendored.
+    Offline,
+    /// `cargo` runs with `--frozen`, which is offline and also requires an up to date `Cargo.lock`
+    /// in the compilation directory.
+    Frozen,
+}
+
+/// Replaces crates.io with a local source.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceReplacement {
+    /// A directory of vendored crates, such as made by `cargo vendor`.
+    Vendored(PathBuf),
+    /// A local registry, such as made by `cargo local-registry`.
+    LocalRegistry(PathBuf),
+}
+
+/// How `cargo` sources the crates referenced by the REPL.
+///
+/// A source replacement is written to `.cargo/config.toml` in the compilation directory. A relative
+/// path is relative to the current directory.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CrateSource {
+    /// The network access `cargo` is allowed.
+    pub network: Network,
+    /// Replace crates.io with a local source.
+    pub replacement: Option<SourceReplacement>,
+}
+
+impl CrateSource {
+    /// Crates are fetched from the network, from crates.io.
+    pub fn is_online(&self) -> bool {
+        self.network == Network::Online && self.replacement.is_none()
+    }
+
+    /// The `cargo` flag for the network access.
+    pub(crate) fn network_arg(&self) -> Option<&'static str> {
+        match self.network {
+            Network::Online => None,
+            Network::Offline => Some("--offline"),
+            Network::Frozen => Some("--frozen"),
+        }
+    }
+
+    /// Writes, or removes, the source replacement config in the compilation directory.
+    pub(crate) fn write_config(&self, compile_dir: &Path) -> io::Result<()> {
+        let file = compile_dir.join(".cargo/config.toml");
+
+        let (key, path) = match &self.replacement {
+            Some(SourceReplacement::Vendored(p)) => ("directory", p),
+            Some(SourceReplacement::LocalRegistry(p)) => ("local-registry", p),
+            None => {
+                let generated = fs::read_to_string(&file)
+                    .map(|s| s.starts_with(CONFIG_HEADER))
+                    .unwrap_or(false);
+                if generated {
+                    fs::remove_file(&file)?;
+                }
+                return Ok(());
+            }
+        };
+
+        let path = std::env::current_dir()?.join(path);
+        let path = path.to_string_lossy();
+        let contents = format!(
+            r#"{}[source.crates-io]
+replace-with = "papyrus-replacement"
+
+[source.papyrus-replacement]
+{} = "{}"
+"#,
+            CONFIG_HEADER,
+            key,
+            path.replace('\\', "\\\\").replace('"', "\\\"")
+        );
+
+        fs::create_dir_all(file.parent().expect("has a parent"))?;
+        fs::write(file, contents)
+    }
+
+    /// Finds the crate `cargo` could not find, if it was looking offline or in a replacement.
+    pub(crate) fn unavailable_crate(&self, stderr: &str) -> Option<String> {
+        if self.is_online() {
+            return None;
+        }
+
+        stderr.lines().find_map(|line| {
+            let name = line
+                .strip_prefix("error: no matching package named `")
+                .or_else(|| {
+                    line.strip_prefix("error: failed to select a version for the requirement `")
+                })?;
+            let end = name.find(['`', ' '])?;
+            Some(name[..end].to_string())
+        })
+    }
+}
+
+impl fmt::Display for CrateSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.replacement {
+            Some(SourceReplacement::Vendored(p)) => {
+                write!(f, "the vendored directory `{}`", p.display())?
+            }
+            Some(SourceReplacement::LocalRegistry(p)) => {
+                write!(f, "the local registry `{}`", p.display())?
+            }
+            None => write!(f, "cargo's cache")?,
+        }
+        match self.network {
+            Network::Online => Ok(()),
+            Network::Offline => write!(f, " (offline)"),
+            Network::Frozen => write!(f, " (frozen)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_and_remove_config() {
+        let dir = Path::new("target/testing/crate-source");
+        let file = dir.join(".cargo/config.toml");
+        let source = CrateSource {
+            network: Network::Offline,
+            replacement: Some(SourceReplacement::Vendored("vendor".into())),
+        };
+        source.write_config(dir).unwrap();
+        let contents = fs::read_to_string(&file).unwrap();
+        assert!(contents.starts_with(CONFIG_HEADER));
+        assert!(contents.contains("replace-with = \"papyrus-replacement\""));
+        let vendor = std::env::current_dir().unwrap().join("vendor");
+        assert!(contents.contains(&format!("directory = \"{}\"", vendor.display())));
+
+        CrateSource::default().write_config(dir).unwrap();
+        assert!(!file.exists());
+
+        // a config not written by papyrus is kept
+        fs::write(&file, "[build]\n").unwrap();
+        CrateSource::default().write_config(dir).unwrap();
+        assert!(file.exists());
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn unavailable_crate_test() {
+        let stderr =
+            "error: no matching package named `rand` found\nlocation searched: crates.io index\n";
+        let offline = CrateSource {
+            network: Network::Offline,
+            replacement: None,
+        };
+        assert_eq!(offline.unavailable_crate(stderr), Some("rand".to_string()));
+        assert_eq!(CrateSource::default().unavailable_crate(stderr), None);
+        assert_eq!(
+            offline.unavailable_crate(
+                "error: failed to select a version for the requirement `rand = \"^9\"`\n"
+            ),
+            Some("rand".to_string())
+        );
+        assert_eq!(offline.unavailable_crate("error: oh no\n"), None);
+
+        assert_eq!(offline.to_string(), "cargo's cache (offline)");
+        let vendored = CrateSource {
+            network: Network::Online,
+            replacement: Some(SourceReplacement::Vendored("vendor".into())),
+        };
+        assert_eq!(vendored.to_string(), "the vendored directory `vendor`");
+    }
+}
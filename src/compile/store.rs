@@ -0,0 +1,801 @@
+use super::execute::LoadedLibrary;
+use super::CompileOptions;
+use crate::code::{ModsMap, Persisted, PersistedMap, StaticFiles};
+use crate::linking::LinkingConfiguration;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    os::raw::c_void,
+    path::{Path, PathBuf},
+    process::Command,
+    time::SystemTime,
+};
+
+/// The values of a module's bindings, held for the compiled libraries.
+///
+/// The libraries reach the values through `extern "C"` callbacks, so the host and the libraries
+/// share no Rust types.
+pub type Values = HashMap<String, Stored>;
+
+/// A value a compiled library stored, or a note it made, such as a marker.
+///
+/// A value is opaque to the host, it is dropped with the function of the library which stored
+/// it, so the library must be loaded while it is.
+pub struct Stored(Inner);
+
+enum Inner {
+    Value(RawValue),
+    Note(String),
+}
+
+/// Must match `papyrus_store::Value` in the generated code. A null `ptr` is no value.
+#[repr(C)]
+struct RawValue {
+    ptr: *mut c_void,
+    drop: Option<unsafe extern "C" fn(*mut c_void)>,
+}
+
+impl RawValue {
+    fn none() -> Self {
+        RawValue {
+            ptr: std::ptr::null_mut(),
+            drop: None,
+        }
+    }
+}
+
+// the libraries only store `Send` values
+unsafe impl Send for Stored {}
+
+impl Stored {
+    /// A note, holding text rather than a library's value.
+    pub(crate) fn note(text: &str) -> Self {
+        Stored(Inner::Note(text.to_string()))
+    }
+
+    fn into_note(mut self) -> Option<String> {
+        match &mut self.0 {
+            Inner::Note(text) => Some(std::mem::take(text)),
+            Inner::Value(_) => None,
+        }
+    }
+
+    fn into_raw(mut self) -> RawValue {
+        match &mut self.0 {
+            Inner::Value(value) => std::mem::replace(value, RawValue::none()),
+            Inner::Note(_) => RawValue::none(),
+        }
+    }
+}
+
+impl Drop for Stored {
+    fn drop(&mut self) {
+        if let Inner::Value(RawValue {
+            ptr,
+            drop: Some(drop),
+        }) = self.0
+        {
+            unsafe { drop(ptr) }
+        }
+    }
+}
+
+/// The store passed to a compiled library's evaluation function, `extern "C"` callbacks over the
+/// module's [`Values`].
+///
+/// Must match `papyrus_store::Store` in the generated code.
+#[repr(C)]
+pub(crate) struct StoreHandle {
+    values: *mut c_void,
+    take: unsafe extern "C" fn(*mut c_void, *const u8, usize) -> RawValue,
+    put: unsafe extern "C" fn(*mut c_void, *const u8, usize, RawValue),
+    note: unsafe extern "C" fn(*mut c_void, *const u8, usize, *const u8, usize),
+}
+
+impl StoreHandle {
+    /// A handle to `values`, which must outlive the evaluation it is passed to.
+    pub(crate) fn new(values: &mut Values) -> Self {
+        StoreHandle {
+            values: values as *mut Values as *mut c_void,
+            take: take_value,
+            put: put_value,
+            note: put_note,
+        }
+    }
+}
+
+unsafe fn from_raw<'a>(
+    values: *mut c_void,
+    name: *const u8,
+    len: usize,
+) -> (&'a mut Values, String) {
+    let name = std::slice::from_raw_parts(name, len);
+    (
+        &mut *(values as *mut Values),
+        String::from_utf8_lossy(name).into_owned(),
+    )
+}
+
+unsafe extern "C" fn take_value(values: *mut c_void, name: *const u8, len: usize) -> RawValue {
+    let (values, name) = from_raw(values, name, len);
+    values
+        .remove(&name)
+        .map(Stored::into_raw)
+        .unwrap_or_else(RawValue::none)
+}
+
+unsafe extern "C" fn put_value(values: *mut c_void, name: *const u8, len: usize, value: RawValue) {
+    let (values, name) = from_raw(values, name, len);
+    values.insert(name, Stored(Inner::Value(value)));
+}
+
+unsafe extern "C" fn put_note(
+    values: *mut c_void,
+    name: *const u8,
+    len: usize,
+    text: *const u8,
+    text_len: usize,
+) {
+    let (values, name) = from_raw(values, name, len);
+    let text = String::from_utf8_lossy(std::slice::from_raw_parts(text, text_len));
+    values.insert(name, Stored::note(&text));
+}
+
+/// Inserted by the compiled library if the bindings could not be restored.
+const REBUILD: &str = "papyrus::rebuild";
+/// Inserted by the compiled library if the statements returned early.
+const RETURNED: &str = "papyrus::returned";
+/// Inserted by the compiled library with the type name of the shown value.
+const TYPE: &str = "papyrus::type";
+
+type Hash = [u8; 32];
+
+/// A host owned store of the values bound by evaluated statements.
+///
+/// Rather than running every statement on each evaluation, the `let` and `out#` bindings are moved
+/// into the store at the end of an evaluation and moved back out at the start of the next. This
+/// way only the newest statements are run.
+///
+/// The store is tied to the code which produced it. If earlier statements, items, static files,
+/// linking, or the toolchain change then the store is invalidated, and all the statements are run
+/// again to repopulate it. A panic during evaluation drops the values which were moved out of the
+/// store, so the module's statements are run again on the next evaluation.
+///
+/// Bindings are only persisted if they are `Send + 'static`. A statement group whose `out#` can
+/// not be persisted is still restored, without its `out#`.
+///
+/// A value can reference the library which first stored it, and each library evaluated over it
+/// since. The store records the library each value came from, and unloads a library once no value
+/// needs it.
+#[derive(Default)]
+pub struct ValueStore {
+    // mods must be dropped before libs, the values can reference code in the libraries
+    mods: BTreeMap<PathBuf, ModValues>,
+    /// Hashes of the items in each module. Appending items keeps the store valid.
+    items: BTreeMap<PathBuf, Vec<Hash>>,
+    env: Hash,
+    /// The `rustc` version of each toolchain, queried once.
+    versions: BTreeMap<Option<String>, String>,
+    /// The libraries the values were produced with, by the order they were evaluated.
+    libs: BTreeMap<u64, LoadedLibrary>,
+    next_lib: u64,
+}
+
+#[derive(Default)]
+struct ModValues {
+    values: Values,
+    /// Hashes of the evaluated statement groups.
+    grps: Vec<Hash>,
+    /// The library each value was first stored by.
+    origins: HashMap<String, u64>,
+    /// The libraries evaluated over the values, oldest first.
+    libs: Vec<u64>,
+}
+
+impl ValueStore {
+    /// The names of the bindings persisted for a module.
+    pub fn bindings(&self, mod_path: &Path) -> impl Iterator<Item = &str> {
+        self.mods
+            .get(mod_path)
+            .into_iter()
+            .flat_map(|x| x.values.keys())
+            .map(|x| x.as_str())
+    }
+
+    /// There are no persisted values.
+    pub fn is_empty(&self) -> bool {
+        self.mods.values().all(|x| x.values.is_empty())
+    }
+
+    /// Drop all the values, and the libraries which produced them.
+    ///
+    /// The next evaluation will run all the statements.
+    pub fn clear(&mut self) {
+        self.mods.clear();
+        self.libs.clear();
+    }
+
+    /// The statement groups of each module which have persisted values.
+    ///
+    /// Invalidates any values which were produced with code that has since changed.
+    pub(crate) fn persisted(
+        &mut self,
+        mods_map: &ModsMap,
+        linking: &LinkingConfiguration,
+        static_files: &StaticFiles,
+        options: &CompileOptions,
+    ) -> PersistedMap {
+        let items = items_hashes(mods_map);
+        let items_appended = self.items.iter().all(|(k, v)| {
+            items
+                .get(k)
+                .map(|x| x.starts_with(v.as_slice()))
+                .unwrap_or(false)
+        });
+
+        let env = self.env_hash(mods_map, linking, static_files, options);
+        if !items_appended || self.env != env {
+            self.clear();
+        }
+
+        // statement groups can only be appended to keep the values
+        self.mods.retain(|k, v| {
+            mods_map
+                .get(k)
+                .map(|src| {
+                    src.stmts.len() >= v.grps.len()
+                        && src
+                            .stmts
+                            .iter()
+                            .zip(&v.grps)
+                            .all(|(a, b)| &grp_hash(a) == b)
+                })
+                .unwrap_or(false)
+        });
+        self.unload_libs();
+
+        self.mods
+            .iter()
+            .map(|(k, v)| {
+                let p = Persisted {
+                    grps: v.grps.len(),
+                    available: v.values.keys().cloned().collect(),
+                    excluded: Default::default(),
+                };
+                (k.clone(), p)
+            })
+            .collect()
+    }
+
+    /// The values of a module, to be passed to the evaluation function.
+    pub(crate) fn values_mut(&mut self, mod_path: &Path) -> &mut Values {
+        &mut self.mods.entry(mod_path.to_path_buf()).or_default().values
+    }
+
+    /// The modules which have values.
+    pub(crate) fn live_mods(&self) -> impl Iterator<Item = &Path> {
+        self.mods
+            .iter()
+            .filter(|x| !x.1.values.is_empty())
+            .map(|x| x.0.as_path())
+    }
+
+    /// Record the names of a module's values which are held by a worker process.
+    ///
+    /// The store then mirrors the worker, with placeholder values.
+    pub(crate) fn mirror(&mut self, mod_path: &Path, names: Vec<String>) {
+        let values = self.values_mut(mod_path);
+        values.clear();
+        for name in names {
+            values.insert(name, Stored::note(""));
+        }
+    }
+
+    /// Returns `true` if the compiled library could not restore the module's values. The values
+    /// are dropped.
+    pub(crate) fn take_rebuild(&mut self, mod_path: &Path) -> bool {
+        self.take_marker(mod_path, REBUILD)
+    }
+
+    /// Returns `true` if the statements returned early, skipping the persisting of their values.
+    /// The values are dropped.
+    pub(crate) fn take_returned(&mut self, mod_path: &Path) -> bool {
+        self.take_marker(mod_path, RETURNED)
+    }
+
+    /// The type name of the value shown by the evaluation, if it was recorded.
+    ///
+    /// A worker process drops the type, so it is only known for evaluations in this process.
+    pub(crate) fn take_type(&mut self, mod_path: &Path) -> Option<String> {
+        self.mods
+            .get_mut(mod_path)
+            .and_then(|x| x.values.remove(TYPE))
+            .and_then(Stored::into_note)
+    }
+
+    fn take_marker(&mut self, mod_path: &Path, marker: &str) -> bool {
+        let marked = self
+            .mods
+            .get(mod_path)
+            .map(|x| x.values.contains_key(marker))
+            .unwrap_or(false);
+        if marked {
+            self.remove(mod_path);
+        }
+        marked
+    }
+
+    /// Drop the values of a module.
+    pub(crate) fn remove(&mut self, mod_path: &Path) {
+        self.mods.remove(mod_path);
+        self.unload_libs();
+    }
+
+    /// Record the module's statements as evaluated, tying the values to the current code.
+    pub(crate) fn evaluated(
+        &mut self,
+        mod_path: &Path,
+        mods_map: &ModsMap,
+        linking: &LinkingConfiguration,
+        static_files: &StaticFiles,
+        options: &CompileOptions,
+    ) {
+        let grps = mods_map
+            .get(mod_path)
+            .map(|src| src.stmts.iter().map(grp_hash).collect())
+            .unwrap_or_default();
+        self.mods.entry(mod_path.to_path_buf()).or_default().grps = grps;
+        self.items = items_hashes(mods_map);
+        self.env = self.env_hash(mods_map, linking, static_files, options);
+    }
+
+    /// Hashes what the values depend on other than the code. The libraries pass values to each
+    /// other as Rust types, so they must be compiled by the same `rustc` against the same crates.
+    ///
+    /// A crate rebuilt in place keeps its types' `TypeId`s, so linked libraries are hashed by
+    /// their modification time and length, and path dependencies by their latest modification.
+    fn env_hash(
+        &mut self,
+        mods_map: &ModsMap,
+        linking: &LinkingConfiguration,
+        static_files: &StaticFiles,
+        options: &CompileOptions,
+    ) -> Hash {
+        let version = self
+            .versions
+            .entry(options.toolchain.clone())
+            .or_insert_with(|| rustc_version(options.toolchain.as_deref()));
+
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(options.toolchain.as_deref().unwrap_or_default().as_bytes());
+        hasher.update(version.as_bytes());
+        hasher.update(linking.persistent_module_code.as_bytes());
+        for x in linking.external_libs.iter() {
+            hasher.update(x.lib_path().to_string_lossy().as_bytes());
+            if let Ok(meta) = fs::metadata(x.lib_path()) {
+                hasher.update(format!("{:?}{}", meta.modified().ok(), meta.len()).as_bytes());
+            }
+        }
+        for x in static_files {
+            hasher.update(x.path.to_string_lossy().as_bytes());
+            hasher.update(x.codehash.as_ref());
+        }
+
+        let crates = mods_map
+            .values()
+            .flat_map(|src| &src.crates)
+            .chain(static_files.iter().flat_map(|x| &x.crates));
+        for x in crates {
+            hasher.update(format!("{}{:?}", x.cargo_name, x.dep).as_bytes());
+            if let Some(path) = &x.dep.path {
+                let path = std::env::current_dir()
+                    .map(|d| d.join(path))
+                    .unwrap_or_else(|_| path.into());
+                hasher.update(format!("{:?}", latest_modified(&path)).as_bytes());
+            }
+        }
+
+        hasher.finalize().into()
+    }
+
+    /// Keep the library evaluated in a module loaded if the module's values could reference it.
+    /// If there are no values, the library is returned.
+    ///
+    /// Libraries which no value needs any longer are unloaded.
+    pub(crate) fn keep_lib(
+        &mut self,
+        mod_path: &Path,
+        lib: LoadedLibrary,
+    ) -> Option<LoadedLibrary> {
+        let id = self.next_lib;
+        let kept = self
+            .mods
+            .get_mut(mod_path)
+            .map(|m| m.keep(id))
+            .unwrap_or(false);
+        let lib = if kept {
+            self.next_lib += 1;
+            self.libs.insert(id, lib);
+            None
+        } else {
+            Some(lib)
+        };
+
+        self.unload_libs();
+        lib
+    }
+
+    /// The libraries the values need.
+    fn needed_libs(&self) -> BTreeSet<u64> {
+        self.mods
+            .values()
+            .filter(|m| !m.values.is_empty())
+            .flat_map(|m| m.libs.iter().copied())
+            .collect()
+    }
+
+    /// Unloads the libraries no values need.
+    fn unload_libs(&mut self) {
+        let needed = self.needed_libs();
+        self.libs.retain(|id, _| needed.contains(id));
+    }
+}
+
+impl ModValues {
+    /// Records the library `id` as evaluated over the values, returning `false` if there are no
+    /// values to need it.
+    fn keep(&mut self, id: u64) -> bool {
+        if self.values.is_empty() {
+            return false;
+        }
+        let ModValues {
+            values,
+            origins,
+            libs,
+            ..
+        } = self;
+        origins.retain(|k, _| values.contains_key(k));
+        for name in values.keys() {
+            origins.entry(name.clone()).or_insert(id);
+        }
+        libs.push(id);
+        let oldest = origins.values().min().copied().unwrap_or(id);
+        libs.retain(|x| *x >= oldest);
+        true
+    }
+}
+
+fn grp_hash(grp: &crate::code::StmtGrp) -> Hash {
+    blake3::hash(grp.src_line().as_bytes()).into()
+}
+
+fn items_hashes(mods_map: &ModsMap) -> BTreeMap<PathBuf, Vec<Hash>> {
+    mods_map
+        .iter()
+        .map(|(k, v)| {
+            let hashes = v
+                .items
+                .iter()
+                .map(|x| blake3::hash(x.0.as_bytes()).into())
+                .collect();
+            (k.clone(), hashes)
+        })
+        .collect()
+}
+
+/// The output of `rustc --version` for the toolchain, empty if it can not be run.
+/// The latest modification time of `dir` and the files under it, skipping `target` and hidden
+/// directories.
+fn latest_modified(dir: &Path) -> Option<SystemTime> {
+    let mut latest = fs::metadata(dir).and_then(|m| m.modified()).ok();
+    for entry in fs::read_dir(dir).ok()?.filter_map(Result::ok) {
+        let path = entry.path();
+        let modified = if path.is_dir() {
+            let name = entry.file_name();
+            if name == "target" || name.to_string_lossy().starts_with('.') {
+                continue;
+            }
+            latest_modified(&path)
+        } else {
+            entry.metadata().and_then(|m| m.modified()).ok()
+        };
+        latest = latest.max(modified);
+    }
+    latest
+}
+
+fn rustc_version(toolchain: Option<&str>) -> String {
+    let mut cmd = Command::new(std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into()));
+    cmd.args(toolchain.map(|t| format!("+{}", t)))
+        .arg("--version");
+    cmd.output()
+        .map(|x| String::from_utf8_lossy(&x.stdout).into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::{SourceCode, Statement, StmtGrp};
+
+    fn grp(expr: &str) -> StmtGrp {
+        StmtGrp(vec![Statement {
+            expr: expr.to_string(),
+            semi: false,
+        }])
+    }
+
+    fn mods(grps: &[&str], items: &[&str]) -> ModsMap {
+        let src = SourceCode {
+            stmts: grps.iter().map(|x| grp(x)).collect(),
+            items: items.iter().map(|x| (x.to_string(), false)).collect(),
+            ..Default::default()
+        };
+        vec![(PathBuf::from("lib"), src)].into_iter().collect()
+    }
+
+    fn evaluate(store: &mut ValueStore, mods: &ModsMap, names: &[&str]) {
+        let linking = LinkingConfiguration::default();
+        let values = store.values_mut(Path::new("lib"));
+        for name in names {
+            values.insert(name.to_string(), Stored::note(""));
+        }
+        let options = CompileOptions::default();
+        store.evaluated(
+            Path::new("lib"),
+            mods,
+            &linking,
+            &StaticFiles::new(),
+            &options,
+        );
+    }
+
+    fn persisted(store: &mut ValueStore, mods: &ModsMap) -> Option<Persisted> {
+        let linking = LinkingConfiguration::default();
+        let options = CompileOptions::default();
+        store
+            .persisted(mods, &linking, &StaticFiles::new(), &options)
+            .remove(Path::new("lib"))
+    }
+
+    #[test]
+    fn appending_keeps_values() {
+        let mut store = ValueStore::default();
+        let m = mods(&["a"], &["fn a() {}"]);
+        assert_eq!(persisted(&mut store, &m), None);
+
+        evaluate(&mut store, &m, &["out0"]);
+
+        let m = mods(&["a", "b"], &["fn a() {}", "fn b() {}"]);
+        let p = persisted(&mut store, &m).unwrap();
+        assert_eq!(p.grps, 1);
+        assert_eq!(p.available, vec!["out0".to_string()].into_iter().collect());
+        assert_eq!(
+            store.bindings(Path::new("lib")).collect::<Vec<_>>(),
+            ["out0"]
+        );
+    }
+
+    #[test]
+    fn changes_invalidate() {
+        let mut store = ValueStore::default();
+        let m = mods(&["a", "b"], &["fn a() {}"]);
+        evaluate(&mut store, &m, &["out0", "out1"]);
+
+        // altered statement
+        let m2 = mods(&["a", "c"], &["fn a() {}"]);
+        assert_eq!(persisted(&mut store, &m2), None);
+        assert!(store.is_empty());
+
+        evaluate(&mut store, &m, &["out0", "out1"]);
+        // removed statement
+        let m2 = mods(&["a"], &["fn a() {}"]);
+        assert_eq!(persisted(&mut store, &m2), None);
+
+        evaluate(&mut store, &m, &["out0", "out1"]);
+        // altered item
+        let m2 = mods(&["a", "b"], &["fn b() {}"]);
+        assert_eq!(persisted(&mut store, &m2), None);
+
+        evaluate(&mut store, &m, &["out0", "out1"]);
+        // changed linking
+        let linking = LinkingConfiguration {
+            persistent_module_code: "use std::*;".to_string(),
+            ..Default::default()
+        };
+        let options = CompileOptions::default();
+        assert!(store
+            .persisted(&m, &linking, &StaticFiles::new(), &options)
+            .is_empty());
+
+        evaluate(&mut store, &m, &["out0", "out1"]);
+        // changed toolchain
+        let options = CompileOptions {
+            toolchain: Some("papyrus-test".to_string()),
+            ..Default::default()
+        };
+        let linking = LinkingConfiguration::default();
+        assert!(store
+            .persisted(&m, &linking, &StaticFiles::new(), &options)
+            .is_empty());
+    }
+
+    #[test]
+    fn dependencies_invalidate() {
+        let dir = Path::new("target/testing/store-deps");
+        fs::remove_dir_all(dir).ok();
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("deps")).unwrap();
+        fs::write(dir.join("src/lib.rs"), "pub struct A(u8);").unwrap();
+        fs::write(dir.join("libext.rlib"), "a").unwrap();
+
+        let mut store = ValueStore::default();
+        let mut m = mods(&["a"], &[]);
+        let dep = format!(r#"#[dep(path = "{}")] extern crate dep;"#, dir.display());
+        let krate = crate::code::CrateType::parse_str(&dep).unwrap();
+        m.get_mut(Path::new("lib")).unwrap().crates.push(krate);
+        evaluate(&mut store, &m, &["out0"]);
+        assert_eq!(persisted(&mut store, &m).unwrap().grps, 1);
+
+        // a path dependency edited on disk
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write(dir.join("src/lib.rs"), "pub struct A(u64);").unwrap();
+        assert_eq!(persisted(&mut store, &m), None);
+
+        evaluate(&mut store, &m, &["out0"]);
+        // changed dependency table
+        let src = m.get_mut(Path::new("lib")).unwrap();
+        src.crates[0].dep.features.push("a".to_string());
+        assert_eq!(persisted(&mut store, &m), None);
+
+        // a linked library rebuilt in place
+        let mut linking = LinkingConfiguration::default();
+        let ext = crate::linking::Extern::new(dir.join("libext.rlib")).unwrap();
+        linking.external_libs.insert(ext);
+        let options = CompileOptions::default();
+        evaluate(&mut store, &m, &["out0"]);
+        store.evaluated(
+            Path::new("lib"),
+            &m,
+            &linking,
+            &StaticFiles::new(),
+            &options,
+        );
+        assert!(!store
+            .persisted(&m, &linking, &StaticFiles::new(), &options)
+            .is_empty());
+        fs::write(dir.join("libext.rlib"), "ab").unwrap();
+        assert!(store
+            .persisted(&m, &linking, &StaticFiles::new(), &options)
+            .is_empty());
+    }
+
+    #[test]
+    fn mirror_worker() {
+        let mut store = ValueStore::default();
+        let m = mods(&["a"], &[]);
+        evaluate(&mut store, &m, &[]);
+        assert_eq!(store.live_mods().count(), 0);
+
+        store.mirror(Path::new("lib"), vec!["out0".to_string()]);
+        store.evaluated(
+            Path::new("lib"),
+            &m,
+            &LinkingConfiguration::default(),
+            &StaticFiles::new(),
+            &CompileOptions::default(),
+        );
+        assert_eq!(store.live_mods().collect::<Vec<_>>(), [Path::new("lib")]);
+        assert_eq!(persisted(&mut store, &m).unwrap().grps, 1);
+
+        store.mirror(Path::new("lib"), Vec::new());
+        assert_eq!(store.live_mods().count(), 0);
+    }
+
+    #[test]
+    fn rebuild_marker() {
+        let mut store = ValueStore::default();
+        let m = mods(&["a"], &[]);
+        evaluate(&mut store, &m, &["out0"]);
+        assert!(!store.take_rebuild(Path::new("lib")));
+
+        store
+            .values_mut(Path::new("lib"))
+            .insert(REBUILD.to_string(), Stored::note(""));
+        assert!(store.take_rebuild(Path::new("lib")));
+        assert_eq!(persisted(&mut store, &m), None);
+
+        evaluate(&mut store, &m, &["out0"]);
+        assert!(!store.take_returned(Path::new("lib")));
+        store
+            .values_mut(Path::new("lib"))
+            .insert(RETURNED.to_string(), Stored::note(""));
+        assert!(!store.take_rebuild(Path::new("lib")));
+        assert!(store.take_returned(Path::new("lib")));
+        assert_eq!(persisted(&mut store, &m), None);
+    }
+
+    #[test]
+    fn libraries_of_values() {
+        let mut store = ValueStore::default();
+        let m = mods(&["a", "b"], &[]);
+        let keep = |store: &mut ValueStore, id, names: &[&str]| {
+            let m = store.mods.entry(PathBuf::from("lib")).or_default();
+            m.values.clear();
+            for name in names {
+                m.values.insert(name.to_string(), Stored::note(""));
+            }
+            m.keep(id)
+        };
+
+        // no values need the library
+        assert!(!keep(&mut store, 0, &[]));
+        assert!(keep(&mut store, 1, &["out0"]));
+        assert!(keep(&mut store, 2, &["out0", "out1"]));
+        assert_eq!(store.needed_libs(), [1, 2].iter().copied().collect());
+
+        // the library which first stored the oldest value is needed until it is dropped
+        assert!(keep(&mut store, 3, &["out1"]));
+        assert_eq!(store.needed_libs(), [2, 3].iter().copied().collect());
+
+        assert_eq!(persisted(&mut store, &mods(&["c"], &[])), None);
+        assert!(store.needed_libs().is_empty());
+        evaluate(&mut store, &m, &["out0"]);
+        assert!(keep(&mut store, 4, &["out0"]));
+        assert_eq!(store.needed_libs(), [4].iter().copied().collect());
+        store.remove(Path::new("lib"));
+        assert!(store.needed_libs().is_empty());
+    }
+
+    #[test]
+    fn handle_callbacks() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        unsafe extern "C" fn drop_value(ptr: *mut c_void) {
+            drop(Box::from_raw(ptr as *mut u8));
+            DROPPED.fetch_add(1, Ordering::SeqCst);
+        }
+        let value = || RawValue {
+            ptr: Box::into_raw(Box::new(5u8)) as *mut c_void,
+            drop: Some(drop_value),
+        };
+
+        let mut values = Values::new();
+        let handle = StoreHandle::new(&mut values);
+        unsafe {
+            (handle.put)(handle.values, "a".as_ptr(), 1, value());
+            (handle.put)(handle.values, "b".as_ptr(), 1, value());
+            (handle.note)(handle.values, TYPE.as_ptr(), TYPE.len(), "u8".as_ptr(), 2);
+
+            // a taken value is owned by the library again
+            let a = (handle.take)(handle.values, "a".as_ptr(), 1);
+            assert_eq!(*(a.ptr as *mut u8), 5);
+            drop_value(a.ptr);
+            assert!((handle.take)(handle.values, "c".as_ptr(), 1).ptr.is_null());
+            assert!((handle.take)(handle.values, TYPE.as_ptr(), TYPE.len())
+                .ptr
+                .is_null());
+        }
+        assert_eq!(DROPPED.load(Ordering::SeqCst), 1);
+
+        assert_eq!(values.keys().collect::<Vec<_>>(), ["b"]);
+        drop(values);
+        assert_eq!(DROPPED.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn type_marker() {
+        let mut store = ValueStore::default();
+        let m = mods(&["a"], &[]);
+        evaluate(&mut store, &m, &["out0"]);
+        assert_eq!(store.take_type(Path::new("lib")), None);
+
+        store
+            .values_mut(Path::new("lib"))
+            .insert(TYPE.to_string(), Stored::note("i32"));
+        assert_eq!(store.take_type(Path::new("lib")).as_deref(), Some("i32"));
+        assert_eq!(store.take_type(Path::new("lib")), None);
+        assert_eq!(persisted(&mut store, &m).unwrap().grps, 1);
+    }
+}
@@ -0,0 +1,279 @@
+//! Transport of evaluation results from the compiled library to the host.
+//!
+//! The evaluation functions have an `extern "C"` signature and return an [`Output`] buffer
+//! holding the result encoded by the `papyrus_transport` module of the generated crate
+//! (`transport_codec.rs`). The host copies the bytes, frees the buffer with the library's
+//! `papyrus_free_output`, and decodes the bytes back into a `Kserd`. The result therefore does not
+//! depend on the host and the library agreeing on the Rust ABI or on the layout of `Kserd`.
+//!
+//! The same encoding carries the app data to and from a worker process, which is decoded with
+//! `serde` on both sides.
+use ::kserd::{
+    encode::{Deserialize, Serialize},
+    Kserd,
+};
+use std::fmt;
+
+#[allow(dead_code)]
+#[path = "transport_codec.rs"]
+mod codec;
+
+pub(crate) use self::codec::{decode, Evaluated, Output, TransportError};
+pub use self::codec::{ErrorChain, Location, Panic};
+
+/// The source of the `papyrus_transport` module of the generated crate.
+pub(crate) const TRANSPORT_SRC: &str = include_str!("transport_codec.rs");
+
+/// The `kserd` version the generated crate depends on, matching the version `papyrus` uses.
+pub(crate) const KSERD_VERSION: &str = "0.5";
+
+/// The library function which frees an [`Output`].
+pub(crate) const FREE_FN: &str = "papyrus_free_output";
+
+impl TransportError {
+    /// A static description of the error.
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            TransportError::Format => "evaluation result is not in the expected format",
+            TransportError::Version(_) => {
+                "evaluation result was encoded by an incompatible papyrus version"
+            }
+            TransportError::Malformed => "evaluation result is malformed",
+        }
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransportError::Version(v) => write!(
+                f,
+                "{}: format version {}, expected {}",
+                self.as_str(),
+                v,
+                codec::FORMAT_VERSION
+            ),
+            _ => write!(f, "{}", self.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for Panic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "evaluation panicked")?;
+        if let Some(l) = &self.location {
+            write!(f, " at {}", l)?;
+        }
+        write!(f, ":\n{}", self.message)?;
+        if let Some(b) = &self.backtrace {
+            write!(f, "\nstack backtrace:\n{}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ErrorChain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "evaluation returned error: {}", self.message)?;
+        for source in &self.sources {
+            write!(f, "\ncaused by: {}", source)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+/// Serialize app data to pass to a worker process.
+pub(crate) fn encode_data<D: Serialize>(data: &D) -> Result<Vec<u8>, String> {
+    Kserd::enc(data)
+        .map(|k| codec::encode_result(Ok(k)))
+        .map_err(|e| format!("failed to serialize app data: {}", e))
+}
+
+/// Deserialize app data returned by a worker process.
+pub(crate) fn decode_data<D>(bytes: &[u8]) -> Result<D, String>
+where
+    D: for<'de> Deserialize<'de>,
+{
+    match decode(bytes) {
+        Ok(Evaluated::Kserd(k)) => k
+            .decode()
+            .map_err(|e| format!("failed to deserialize app data: {}", e)),
+        Ok(Evaluated::Panic(e)) => Err(e.message),
+        Ok(Evaluated::Error(e)) => Err(e.message),
+        Err(e) => Err(format!("failed to decode app data: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ::kserd::{Kstr, Value};
+    use std::collections::BTreeMap;
+
+    fn roundtrip(result: Result<Kserd<'static>, Panic>) -> Evaluated {
+        let output = Output::new(result);
+        let bytes = unsafe { output.to_vec() };
+        output.free();
+        decode(&bytes).unwrap()
+    }
+
+    #[test]
+    fn roundtrip_values() {
+        let mut fields = BTreeMap::new();
+        fields.insert(Kstr::brwed("a"), Kserd::new_num(-1));
+        fields.insert(Kstr::brwed("b"), Kserd::new_num(1.5));
+        let mut map = BTreeMap::new();
+        map.insert(Kserd::new_str("k"), Kserd::new_barrv(vec![0, 1, 2]));
+
+        let kserds = vec![
+            Kserd::new_unit(),
+            Kserd::new_bool(true),
+            Kserd::new_num(u128::MAX),
+            Kserd::new_num(i128::MIN),
+            Kserd::new_num(std::f64::consts::PI),
+            Kserd::new_string("hello 🌏".to_string()),
+            Kserd::with_id(
+                "Point",
+                Value::Tuple(vec![Kserd::new_num(1), Kserd::new_num(2)]),
+            )
+            .unwrap(),
+            Kserd::with_id("Cntr", Value::Cntr(fields)).unwrap(),
+            Kserd::new(Value::Seq(vec![Kserd::new_str("a"), Kserd::new_unit()])),
+            Kserd::new(Value::Map(map)),
+        ];
+
+        for kserd in kserds {
+            assert_eq!(roundtrip(Ok(kserd.clone())), Evaluated::Kserd(kserd));
+        }
+    }
+
+    #[test]
+    fn roundtrip_panic() {
+        let panic = Panic::new("oh no".to_string());
+        assert_eq!(roundtrip(Err(panic.clone())), Evaluated::Panic(panic));
+
+        let panic = Panic {
+            message: "oh no".to_string(),
+            location: Some(Location {
+                file: "src/lib.rs".to_string(),
+                line: 4,
+                col: 1,
+            }),
+            backtrace: Some("   0: lib::eval".to_string()),
+        };
+        assert_eq!(roundtrip(Err(panic.clone())), Evaluated::Panic(panic));
+    }
+
+    #[test]
+    fn roundtrip_error() {
+        let eval = |r: codec::Eval| {
+            let output = Output::eval(Ok(r));
+            let bytes = unsafe { output.to_vec() };
+            output.free();
+            decode(&bytes).unwrap()
+        };
+
+        assert_eq!(
+            eval(Ok(Kserd::new_num(1))),
+            Evaluated::Kserd(Kserd::new_num(1))
+        );
+
+        let err = std::fs::File::open("not-a-file")
+            .map_err(Chained)
+            .unwrap_err();
+        let chain = match eval(Err(Box::new(err))) {
+            Evaluated::Error(x) => x,
+            x => panic!("expected an error, found {:?}", x),
+        };
+        assert_eq!(chain.message, "opening failed");
+        assert_eq!(chain.sources.len(), 1);
+        assert_eq!(
+            chain.to_string(),
+            format!(
+                "evaluation returned error: opening failed\ncaused by: {}",
+                chain.sources[0]
+            )
+        );
+
+        let chain = ErrorChain {
+            message: "a".to_string(),
+            sources: vec!["b".to_string(), "c".to_string()],
+        };
+        let bytes = codec::encode_evaluated(&Evaluated::Error(chain.clone()));
+        assert_eq!(decode(&bytes), Ok(Evaluated::Error(chain)));
+    }
+
+    #[derive(Debug)]
+    struct Chained(std::io::Error);
+
+    impl fmt::Display for Chained {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "opening failed")
+        }
+    }
+
+    impl std::error::Error for Chained {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn catch_panics() {
+        let r = codec::catch(|| Kserd::new_num(1));
+        assert_eq!(r, Ok(Kserd::new_num(1)));
+
+        let r = codec::catch(|| panic!("oh no {}", 1));
+        let panic = r.unwrap_err();
+        assert_eq!(&panic.message, "oh no 1");
+        let location = panic.location.unwrap();
+        assert_eq!(location.file, file!());
+        assert!(location.line > 0);
+
+        // non-string payloads have no message
+        let r = codec::catch(|| std::panic::panic_any(1));
+        assert_eq!(r.unwrap_err().message, "");
+    }
+
+    #[test]
+    fn roundtrip_data() {
+        let data = vec![(1u8, "one".to_string()), (2, "two".to_string())];
+        let bytes = encode_data(&data).unwrap();
+        assert_eq!(decode_data::<Vec<(u8, String)>>(&bytes), Ok(data));
+        assert!(decode_data::<u8>(&bytes).is_err());
+        assert!(decode_data::<u8>(b"nope").is_err());
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(decode(b""), Err(TransportError::Format));
+        assert_eq!(decode(b"nope, not this"), Err(TransportError::Format));
+        assert_eq!(decode(b"PAPY\x02\x00\x00"), Err(TransportError::Version(2)));
+        assert_eq!(decode(b"PAPY\x03\x00"), Err(TransportError::Malformed));
+        assert_eq!(
+            decode(b"PAPY\x03\x00\x00\x00"),
+            Err(TransportError::Malformed)
+        );
+        // length longer than the remaining bytes
+        assert_eq!(
+            decode(b"PAPY\x03\x00\x01\xff\xff\xff\xff\xff\xff\xff\xff"),
+            Err(TransportError::Malformed)
+        );
+        // trailing bytes
+        assert_eq!(
+            decode(b"PAPY\x03\x00\x00\x00\x00\x00"),
+            Err(TransportError::Malformed)
+        );
+        assert_eq!(
+            decode(b"PAPY\x03\x00\x00\x00\x00"),
+            Ok(Evaluated::Kserd(Kserd::new_unit()))
+        );
+    }
+}
@@ -0,0 +1,535 @@
+//! Encoding and decoding of evaluation results, shared by the host and the generated library.
+//!
+//! This file is included verbatim as the `papyrus_transport` module of the generated crate, and
+//! must only depend on `std` and `kserd`. The host includes it as `compile::transport::codec`.
+//!
+//! `kserd` is not imported, as the persistent module code may alias it to a linked library's.
+use std::{any::Any, collections::BTreeMap};
+
+/// Leading bytes of an encoded result.
+pub const MAGIC: &[u8; 4] = b"PAPY";
+/// Incremented with any change to the encoding.
+pub const FORMAT_VERSION: u16 = 3;
+
+/// The result of the closure wrapping an input's statements, which may exit early with an error.
+pub type Eval = Result<kserd::Kserd<'static>, Box<dyn std::error::Error>>;
+
+/// An encoded result, passed over the `extern "C"` boundary.
+///
+/// The buffer is allocated by the library and must be freed with `papyrus_free_output`.
+#[repr(C)]
+pub struct Output {
+    ptr: *mut u8,
+    len: usize,
+    cap: usize,
+}
+
+impl Output {
+    /// Encode the result of an evaluation, which may have panicked.
+    pub fn new(result: Result<kserd::Kserd<'static>, Panic>) -> Self {
+        Output::encoded(encode_result(result))
+    }
+
+    /// Encode the result of an evaluation function, which may have returned an error or panicked.
+    pub fn eval(result: Result<Eval, Panic>) -> Self {
+        let evaluated = match result {
+            Ok(Ok(k)) => Evaluated::Kserd(k),
+            Ok(Err(e)) => Evaluated::Error(ErrorChain::new(e.as_ref())),
+            Err(panic) => Evaluated::Panic(panic),
+        };
+        Output::encoded(encode_evaluated(&evaluated))
+    }
+
+    fn encoded(buf: Vec<u8>) -> Self {
+        // the library has its own `std` buffers, flush what the evaluation printed
+        std::io::Write::flush(&mut std::io::stdout()).ok();
+        let mut buf = std::mem::ManuallyDrop::new(buf);
+        Output {
+            ptr: buf.as_mut_ptr(),
+            len: buf.len(),
+            cap: buf.capacity(),
+        }
+    }
+
+    /// An output without a buffer.
+    pub fn empty() -> Self {
+        Output {
+            ptr: std::ptr::null_mut(),
+            len: 0,
+            cap: 0,
+        }
+    }
+
+    /// Copy the encoded bytes out of the buffer.
+    ///
+    /// # Safety
+    /// The buffer must have been returned by the library and not yet freed.
+    pub unsafe fn to_vec(&self) -> Vec<u8> {
+        if self.ptr.is_null() {
+            Vec::new()
+        } else {
+            std::slice::from_raw_parts(self.ptr, self.len).to_vec()
+        }
+    }
+
+    /// Frees the buffer, this must be called by the library which allocated it.
+    pub fn free(self) {
+        if !self.ptr.is_null() {
+            unsafe { drop(Vec::from_raw_parts(self.ptr, self.len, self.cap)) }
+        }
+    }
+}
+
+/// Serialized app data, passed to an evaluation function run by a worker process.
+///
+/// A mutating evaluation encodes the altered app data into `out`.
+#[repr(C)]
+pub struct Data {
+    ptr: *const u8,
+    len: usize,
+    /// The encoded app data after a mutating evaluation.
+    pub out: Output,
+}
+
+impl Data {
+    /// The encoded app data.
+    pub fn bytes(&self) -> &[u8] {
+        if self.ptr.is_null() {
+            &[]
+        } else {
+            unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+        }
+    }
+}
+
+/// The details of a panic in evaluated code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Panic {
+    /// The panic message, empty if the payload is not a string.
+    pub message: String,
+    /// Where the panic occurred.
+    pub location: Option<Location>,
+    /// The backtrace, if enabled through `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`.
+    pub backtrace: Option<String>,
+}
+
+/// The source location of a panic.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    /// The file, relative to the crate root for code in the crate, such as `src/lib.rs`.
+    pub file: String,
+    /// The 1-based line.
+    pub line: u32,
+    /// The 1-based column.
+    pub col: u32,
+}
+
+impl Panic {
+    /// A panic with only a message.
+    pub fn new(message: String) -> Self {
+        Panic {
+            message,
+            location: None,
+            backtrace: None,
+        }
+    }
+}
+
+/// An error returned by evaluated code, with the chain of its sources.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorChain {
+    /// The error's `Display`.
+    pub message: String,
+    /// The `Display` of each source, starting with the error's direct source.
+    pub sources: Vec<String>,
+}
+
+impl ErrorChain {
+    /// Collect the messages of `err` and its sources.
+    pub fn new(err: &dyn std::error::Error) -> Self {
+        let mut sources = Vec::new();
+        let mut source = err.source();
+        while let Some(e) = source {
+            sources.push(e.to_string());
+            source = e.source();
+        }
+        ErrorChain {
+            message: err.to_string(),
+            sources,
+        }
+    }
+}
+
+fn panic_msg(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        String::new()
+    }
+}
+
+/// Run `f`, catching a panic along with its location and backtrace.
+///
+/// The library has its own `std`, and so its own panic hook. A hook recording panics on this
+/// thread replaces it for the duration of the call, which also keeps the default hook from
+/// printing. Panics on other threads are passed to the previous hook.
+pub fn catch<F, T>(f: F) -> Result<T, Panic>
+where
+    F: FnOnce() -> T + std::panic::UnwindSafe,
+{
+    use std::backtrace::{Backtrace, BacktraceStatus};
+    use std::sync::{Arc, Mutex};
+
+    thread_local! {
+        // no destructor, which would run after the library is unloaded
+        static CATCHING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    }
+
+    let caught: Arc<Mutex<Option<Panic>>> = Arc::default();
+    let prev = Arc::new(std::panic::take_hook());
+
+    {
+        let caught = Arc::clone(&caught);
+        let prev = Arc::clone(&prev);
+        std::panic::set_hook(Box::new(move |info| {
+            if !CATCHING.with(|x| x.get()) {
+                return prev(info);
+            }
+
+            let backtrace = Backtrace::capture();
+            let panic = Panic {
+                message: panic_msg(info.payload()),
+                location: info.location().map(|l| Location {
+                    file: l.file().to_string(),
+                    line: l.line(),
+                    col: l.column(),
+                }),
+                backtrace: match backtrace.status() {
+                    BacktraceStatus::Captured => Some(backtrace.to_string()),
+                    _ => None,
+                },
+            };
+            // the last panic is the one which unwinds out of `f`
+            if let Ok(mut x) = caught.lock() {
+                *x = Some(panic);
+            }
+        }));
+    }
+
+    CATCHING.with(|x| x.set(true));
+    let result = std::panic::catch_unwind(f);
+    CATCHING.with(|x| x.set(false));
+
+    // dropping the hook releases its reference to the previous hook
+    drop(std::panic::take_hook());
+    if let Ok(prev) = Arc::try_unwrap(prev) {
+        std::panic::set_hook(prev);
+    }
+
+    result.map_err(|payload| {
+        caught
+            .lock()
+            .ok()
+            .and_then(|mut x| x.take())
+            .unwrap_or_else(|| Panic::new(panic_msg(payload.as_ref())))
+    })
+}
+
+/// Encodes the result of an evaluation.
+pub fn encode_result(result: Result<kserd::Kserd<'static>, Panic>) -> Vec<u8> {
+    match result {
+        Ok(k) => encode_evaluated(&Evaluated::Kserd(k)),
+        Err(panic) => encode_evaluated(&Evaluated::Panic(panic)),
+    }
+}
+
+/// Encodes a decoded evaluation result.
+pub fn encode_evaluated(evaluated: &Evaluated) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(64);
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    match evaluated {
+        Evaluated::Kserd(k) => {
+            buf.push(0);
+            encode(k, &mut buf);
+        }
+        Evaluated::Panic(panic) => {
+            buf.push(1);
+            encode_panic(panic, &mut buf);
+        }
+        Evaluated::Error(e) => {
+            buf.push(2);
+            encode_str(&e.message, &mut buf);
+            encode_len(e.sources.len(), &mut buf);
+            e.sources.iter().for_each(|x| encode_str(x, &mut buf));
+        }
+    }
+    buf
+}
+
+/// Encodes a `Kserd` into `buf`.
+pub fn encode(k: &kserd::Kserd, buf: &mut Vec<u8>) {
+    match k.id() {
+        Some(id) => {
+            buf.push(1);
+            encode_str(id, buf);
+        }
+        None => buf.push(0),
+    }
+
+    match &k.val {
+        kserd::Value::Unit => buf.push(0),
+        kserd::Value::Bool(b) => {
+            buf.push(1);
+            buf.push(*b as u8);
+        }
+        kserd::Value::Num(kserd::Number::Uint(x)) => {
+            buf.push(2);
+            buf.extend_from_slice(&x.to_le_bytes());
+        }
+        kserd::Value::Num(kserd::Number::Int(x)) => {
+            buf.push(3);
+            buf.extend_from_slice(&x.to_le_bytes());
+        }
+        kserd::Value::Num(kserd::Number::Float(x)) => {
+            buf.push(4);
+            buf.extend_from_slice(&x.to_bits().to_le_bytes());
+        }
+        kserd::Value::Str(s) => {
+            buf.push(5);
+            encode_str(s.as_str(), buf);
+        }
+        kserd::Value::Barr(b) => {
+            buf.push(6);
+            encode_bytes(b.as_bytes(), buf);
+        }
+        kserd::Value::Tuple(v) => {
+            buf.push(7);
+            encode_len(v.len(), buf);
+            v.iter().for_each(|x| encode(x, buf));
+        }
+        kserd::Value::Cntr(v) => {
+            buf.push(8);
+            encode_len(v.len(), buf);
+            for (k, x) in v {
+                encode_str(k.as_str(), buf);
+                encode(x, buf);
+            }
+        }
+        kserd::Value::Seq(v) => {
+            buf.push(9);
+            encode_len(v.len(), buf);
+            v.iter().for_each(|x| encode(x, buf));
+        }
+        kserd::Value::Map(v) => {
+            buf.push(10);
+            encode_len(v.len(), buf);
+            for (k, x) in v {
+                encode(k, buf);
+                encode(x, buf);
+            }
+        }
+    }
+}
+
+fn encode_panic(panic: &Panic, buf: &mut Vec<u8>) {
+    encode_str(&panic.message, buf);
+    match &panic.location {
+        Some(l) => {
+            buf.push(1);
+            encode_str(&l.file, buf);
+            buf.extend_from_slice(&l.line.to_le_bytes());
+            buf.extend_from_slice(&l.col.to_le_bytes());
+        }
+        None => buf.push(0),
+    }
+    match &panic.backtrace {
+        Some(b) => {
+            buf.push(1);
+            encode_str(b, buf);
+        }
+        None => buf.push(0),
+    }
+}
+
+fn encode_len(len: usize, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(len as u64).to_le_bytes());
+}
+
+fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    encode_len(bytes.len(), buf);
+    buf.extend_from_slice(bytes);
+}
+
+fn encode_str(s: &str, buf: &mut Vec<u8>) {
+    encode_bytes(s.as_bytes(), buf);
+}
+
+/// A decoded evaluation result.
+#[derive(Debug, PartialEq)]
+pub enum Evaluated {
+    /// The evaluation returned a value.
+    Kserd(kserd::Kserd<'static>),
+    /// The evaluation panicked.
+    Panic(Panic),
+    /// The evaluation returned an error, such as through the `?` operator.
+    Error(ErrorChain),
+}
+
+/// Failure to decode a result.
+#[derive(Debug, PartialEq)]
+pub enum TransportError {
+    /// The bytes are not an encoded result.
+    Format,
+    /// The result was encoded with a different format version.
+    Version(u16),
+    /// The bytes ended early or hold an invalid value.
+    Malformed,
+}
+
+/// Decode an encoded evaluation result.
+pub fn decode(bytes: &[u8]) -> Result<Evaluated, TransportError> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(TransportError::Format);
+    }
+
+    let mut rdr = Reader(&bytes[MAGIC.len()..]);
+    let version = u16::from_le_bytes(rdr.array()?);
+    if version != FORMAT_VERSION {
+        return Err(TransportError::Version(version));
+    }
+
+    let evaluated = match rdr.byte()? {
+        0 => Evaluated::Kserd(rdr.kserd()?),
+        1 => Evaluated::Panic(rdr.panic()?),
+        2 => Evaluated::Error(rdr.error()?),
+        _ => return Err(TransportError::Malformed),
+    };
+
+    if rdr.0.is_empty() {
+        Ok(evaluated)
+    } else {
+        Err(TransportError::Malformed)
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], TransportError> {
+        if self.0.len() < n {
+            return Err(TransportError::Malformed);
+        }
+        let (a, b) = self.0.split_at(n);
+        self.0 = b;
+        Ok(a)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], TransportError> {
+        let mut a = [0; N];
+        a.copy_from_slice(self.take(N)?);
+        Ok(a)
+    }
+
+    fn byte(&mut self) -> Result<u8, TransportError> {
+        self.take(1).map(|x| x[0])
+    }
+
+    fn len(&mut self) -> Result<usize, TransportError> {
+        let len = u64::from_le_bytes(self.array()?) as usize;
+        // a length can not exceed the remaining bytes, guarding against huge allocations
+        if len > self.0.len() {
+            Err(TransportError::Malformed)
+        } else {
+            Ok(len)
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, TransportError> {
+        let len = self.len()?;
+        self.take(len).map(|x| x.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String, TransportError> {
+        String::from_utf8(self.bytes()?).map_err(|_| TransportError::Malformed)
+    }
+
+    fn panic(&mut self) -> Result<Panic, TransportError> {
+        let message = self.string()?;
+        let location = match self.byte()? {
+            0 => None,
+            1 => Some(Location {
+                file: self.string()?,
+                line: u32::from_le_bytes(self.array()?),
+                col: u32::from_le_bytes(self.array()?),
+            }),
+            _ => return Err(TransportError::Malformed),
+        };
+        let backtrace = match self.byte()? {
+            0 => None,
+            1 => Some(self.string()?),
+            _ => return Err(TransportError::Malformed),
+        };
+
+        Ok(Panic {
+            message,
+            location,
+            backtrace,
+        })
+    }
+
+    fn error(&mut self) -> Result<ErrorChain, TransportError> {
+        let message = self.string()?;
+        let len = self.len()?;
+        let sources = (0..len).map(|_| self.string()).collect::<Result<_, _>>()?;
+        Ok(ErrorChain { message, sources })
+    }
+
+    fn kserd(&mut self) -> Result<kserd::Kserd<'static>, TransportError> {
+        let id = match self.byte()? {
+            0 => None,
+            1 => Some(kserd::Kstr::owned(self.string()?)),
+            _ => return Err(TransportError::Malformed),
+        };
+
+        let val = match self.byte()? {
+            0 => kserd::Value::Unit,
+            1 => kserd::Value::Bool(self.byte()? != 0),
+            2 => kserd::Value::Num(kserd::Number::Uint(u128::from_le_bytes(self.array()?))),
+            3 => kserd::Value::Num(kserd::Number::Int(i128::from_le_bytes(self.array()?))),
+            4 => kserd::Value::Num(kserd::Number::Float(f64::from_bits(u64::from_le_bytes(
+                self.array()?,
+            )))),
+            5 => kserd::Value::Str(kserd::Kstr::owned(self.string()?)),
+            6 => kserd::Value::Barr(kserd::Barr::owned(self.bytes()?)),
+            7 => kserd::Value::Tuple(self.list()?),
+            8 => {
+                let len = self.len()?;
+                let mut fields = BTreeMap::new();
+                for _ in 0..len {
+                    fields.insert(kserd::Kstr::owned(self.string()?), self.kserd()?);
+                }
+                kserd::Value::Cntr(fields)
+            }
+            9 => kserd::Value::Seq(self.list()?),
+            10 => {
+                let len = self.len()?;
+                let mut map = BTreeMap::new();
+                for _ in 0..len {
+                    map.insert(self.kserd()?, self.kserd()?);
+                }
+                kserd::Value::Map(map)
+            }
+            _ => return Err(TransportError::Malformed),
+        };
+
+        Ok(kserd::Kserd { id, val })
+    }
+
+    fn list(&mut self) -> Result<Vec<kserd::Kserd<'static>>, TransportError> {
+        let len = self.len()?;
+        (0..len).map(|_| self.kserd()).collect()
+    }
+}
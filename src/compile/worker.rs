@@ -0,0 +1,599 @@
+//! Evaluation in a worker process.
+//!
+//! Loading the compiled library into the host means a segfault, stack overflow, or abort in the
+//! evaluated code takes down the whole REPL. Instead, the library can be loaded and run by a long
+//! lived child process, the _worker_. A crash then only loses the worker, which is restarted on
+//! the next evaluation.
+//!
+//! The worker is a small binary crate built in the compilation directory (`worker_main.rs`), with
+//! the toolchain the libraries are compiled with. A worker started with another toolchain is
+//! restarted. It connects back to the host over a local TCP socket and proves itself with a token
+//! passed as an argument.
+//!
+//! The worker's stdout and stderr are piped to threads which forward them to the host's stdout
+//! and stderr, or capture them line by line into the output when evaluating with
+//! `capture_output`. After each evaluation the worker writes an end marker to both streams, so
+//! the host knows it has all the output of an evaluation.
+//!
+//! # Protocol
+//! Lengths are `u64` little endian, strings and byte arrays are length prefixed.
+//!
+//! A request is the library path, the function name, the module, the modules with values the host
+//! considers valid, then a flag byte followed by the encoded app data if the flag is non-zero. The
+//! worker drops the values of any module not listed.
+//!
+//! A response is a status byte. `0` is followed by the encoded result (see
+//! [`transport`](super::transport)), the encoded app data after a mutating evaluation (empty
+//! otherwise), and the names of the module's values held by the worker. `1` is followed by an
+//! error message.
+//!
+//! An evaluation which is interrupted or times out kills the worker, as the evaluated code can
+//! not be stopped otherwise.
+use super::interrupt::{Cancel, Cancelled, POLL_INTERVAL};
+use super::transport::{self, Evaluated, Panic};
+use crate::output::{self, Output};
+use ::kserd::{
+    encode::{Deserialize, Serialize},
+    Kserd,
+};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    time::{Duration, Instant},
+};
+
+const WORKER_SRC: &str = include_str!("worker_main.rs");
+const WORKER_NAME: &str = "papyrus_worker";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Written by the worker to stdout and stderr after each evaluation. Must match `END_MARKER` in
+/// `worker_main.rs`.
+const END_MARKER: &[u8] = b"\0papyrus_worker_end\0\n";
+/// How long to wait for the end markers once the worker responded.
+const END_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The REPL's worker, along with serialization of the app data.
+pub(crate) struct WorkerBackend<D> {
+    /// Started on the first evaluation, and again after a crash or a change of toolchain.
+    process: Option<Worker>,
+    /// The toolchain the running worker was built with.
+    toolchain: Option<String>,
+    encode: fn(&D) -> Result<Vec<u8>, String>,
+    decode: fn(&[u8]) -> Result<D, String>,
+}
+
+/// The app data passed to an evaluation.
+pub(crate) enum AppData<'a, D> {
+    /// No data type is linked.
+    None,
+    Brw(&'a D),
+    Mut(&'a mut D),
+}
+
+/// Failure to evaluate in a worker.
+#[derive(Debug, PartialEq)]
+pub(crate) enum WorkerError {
+    /// The worker process exited, losing all its values.
+    Crashed(String),
+    /// The evaluated code panicked, the worker is still running.
+    Panic(Panic),
+    /// The evaluation failed, the worker is still running.
+    Eval(String),
+    /// The evaluation was interrupted or timed out, and the worker killed.
+    Cancelled(Cancelled),
+}
+
+impl<D> WorkerBackend<D> {
+    pub(crate) fn new() -> Self
+    where
+        D: Serialize + for<'de> Deserialize<'de>,
+    {
+        WorkerBackend {
+            process: None,
+            toolchain: None,
+            encode: transport::encode_data::<D>,
+            decode: transport::decode_data::<D>,
+        }
+    }
+
+    /// Evaluate `function_name` in the worker, starting the worker if it is not running or was
+    /// built with a toolchain other than `toolchain`.
+    ///
+    /// `live` are the modules whose values are to be kept. The worker's stdout and stderr are
+    /// captured into `capture` while evaluating if given. Returns the result and the names of the
+    /// module's values now held by the worker.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn exec<'p>(
+        &mut self,
+        compile_dir: &Path,
+        toolchain: Option<&str>,
+        library_file: &Path,
+        function_name: &str,
+        mod_path: &Path,
+        live: impl Iterator<Item = &'p Path>,
+        app_data: AppData<D>,
+        cancel: &Cancel,
+        capture: Option<&mut Output<output::Write>>,
+    ) -> Result<(Kserd<'static>, Vec<String>), WorkerError> {
+        let data = match &app_data {
+            AppData::None => None,
+            AppData::Brw(d) => Some((self.encode)(d).map_err(WorkerError::Eval)?),
+            AppData::Mut(d) => Some((self.encode)(d).map_err(WorkerError::Eval)?),
+        };
+
+        if self.toolchain.as_deref() != toolchain {
+            self.process = None;
+        }
+        if self.process.is_none() {
+            let worker = Worker::spawn(compile_dir, toolchain).map_err(|e| {
+                WorkerError::Eval(format!("failed to start evaluation worker: {}", e))
+            })?;
+            self.process = Some(worker);
+            self.toolchain = toolchain.map(String::from);
+        }
+        let worker = self.process.as_mut().expect("worker was started");
+
+        let mut func = function_name.to_string();
+        if data.is_some() {
+            func.push_str("_data"); // the function taking serialized app data
+        }
+
+        let res = worker.eval(
+            library_file,
+            &func,
+            mod_path,
+            live,
+            data.as_deref(),
+            cancel,
+            capture,
+        );
+        if let Err(WorkerError::Crashed(_)) | Err(WorkerError::Cancelled(_)) = &res {
+            self.process = None;
+        }
+        let (kserd, data, names) = res?;
+
+        if let (AppData::Mut(d), Some(bytes)) = (app_data, data) {
+            *d = (self.decode)(&bytes).map_err(WorkerError::Eval)?;
+        }
+
+        Ok((kserd, names))
+    }
+}
+
+/// A running worker process.
+pub(crate) struct Worker {
+    child: Child,
+    rdr: BufReader<TcpStream>,
+    wtr: BufWriter<TcpStream>,
+    /// Set while the worker's output is captured.
+    capturing: Arc<AtomicBool>,
+    /// The captured output from the forwarding threads.
+    captured: mpsc::Receiver<Captured>,
+}
+
+/// Output of the worker forwarded to the host while capturing.
+enum Captured {
+    Line(output::Stream, String),
+    /// The end marker of an evaluation was read from the stream.
+    End(output::Stream),
+}
+
+/// The result, the app data, and the names of the module's values.
+type Response = (Kserd<'static>, Option<Vec<u8>>, Vec<String>);
+/// A [`Response`] with the result still encoded, or an error message.
+type RawResponse = Result<(Vec<u8>, Option<Vec<u8>>, Vec<String>), String>;
+
+impl Worker {
+    /// Build the worker in the compilation directory with the toolchain and start it.
+    pub(crate) fn spawn(compile_dir: &Path, toolchain: Option<&str>) -> io::Result<Self> {
+        let exe = build(&compile_dir.join("worker"), toolchain)?;
+
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let token = uuid::Uuid::new_v4().to_hyphenated().to_string();
+
+        let mut child = Command::new(exe)
+            .arg(listener.local_addr()?.to_string())
+            .arg(&token)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let capturing = Arc::new(AtomicBool::new(false));
+        let (tx, captured) = mpsc::channel();
+        if let Some(stdout) = child.stdout.take() {
+            forward(
+                stdout,
+                output::Stream::Stdout,
+                capturing.clone(),
+                tx.clone(),
+            );
+        }
+        if let Some(stderr) = child.stderr.take() {
+            forward(stderr, output::Stream::Stderr, capturing.clone(), tx);
+        }
+
+        match accept(&listener, &mut child, &token) {
+            Ok(stream) => {
+                stream.set_nodelay(true).ok();
+                Ok(Worker {
+                    child,
+                    rdr: BufReader::new(stream.try_clone()?),
+                    wtr: BufWriter::new(stream),
+                    capturing,
+                    captured,
+                })
+            }
+            Err(e) => {
+                child.kill().ok();
+                child.wait().ok();
+                Err(e)
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn eval<'p>(
+        &mut self,
+        library_file: &Path,
+        function_name: &str,
+        mod_path: &Path,
+        live: impl Iterator<Item = &'p Path>,
+        data: Option<&[u8]>,
+        cancel: &Cancel,
+        mut capture: Option<&mut Output<output::Write>>,
+    ) -> Result<Response, WorkerError> {
+        if let Some(output) = capture.as_deref_mut() {
+            // left over from a previous evaluation
+            self.write_captured(output);
+            self.capturing.store(true, Ordering::Release);
+        }
+
+        let live: Vec<_> = live.collect();
+        let sent = self
+            .request(library_file, function_name, mod_path, &live, data)
+            .and_then(|_| self.wait(cancel, capture.as_deref_mut()));
+        let res = match sent {
+            Ok(Err(c)) => Err(WorkerError::Cancelled(c)),
+            sent => Ok(sent.and_then(|_| self.response())),
+        };
+
+        if let Some(output) = capture {
+            if let Ok(Ok(_)) = &res {
+                self.finish_captured(output);
+            }
+            self.capturing.store(false, Ordering::Release);
+            self.write_captured(output);
+        }
+
+        let res = res?;
+
+        match res {
+            Ok(Ok((result, data, names))) => match transport::decode(&result) {
+                Ok(Evaluated::Kserd(kserd)) => Ok((kserd, data, names)),
+                Ok(Evaluated::Panic(panic)) => Err(WorkerError::Panic(panic)),
+                Ok(Evaluated::Error(e)) => Err(WorkerError::Eval(e.to_string())),
+                Err(e) => {
+                    error!("failed to decode evaluation result: {}", e);
+                    Err(WorkerError::Eval(e.as_str().to_string()))
+                }
+            },
+            Ok(Err(e)) => {
+                error!("worker failed to evaluate: {}", e);
+                Err(WorkerError::Eval(e))
+            }
+            Err(e) => {
+                error!("lost connection to evaluation worker: {}", e);
+                Err(WorkerError::Crashed(format!(
+                    "evaluation worker crashed ({}), it will be restarted on the next evaluation",
+                    self.exit_status()
+                )))
+            }
+        }
+    }
+
+    fn request(
+        &mut self,
+        library_file: &Path,
+        function_name: &str,
+        mod_path: &Path,
+        live: &[&Path],
+        data: Option<&[u8]>,
+    ) -> io::Result<()> {
+        let w = &mut self.wtr;
+        write_bytes(w, library_file.to_string_lossy().as_bytes())?;
+        write_bytes(w, function_name.as_bytes())?;
+        write_bytes(w, mod_path.to_string_lossy().as_bytes())?;
+        write_len(w, live.len())?;
+        for m in live {
+            write_bytes(w, m.to_string_lossy().as_bytes())?;
+        }
+        match data {
+            Some(data) => {
+                w.write_all(&[1])?;
+                write_bytes(w, data)?;
+            }
+            None => w.write_all(&[0])?,
+        }
+        w.flush()
+    }
+
+    /// Wait for the worker to start responding, or for `cancel` to trip, writing captured output
+    /// as it arrives.
+    fn wait(
+        &mut self,
+        cancel: &Cancel,
+        mut capture: Option<&mut Output<output::Write>>,
+    ) -> io::Result<Result<(), Cancelled>> {
+        if !self.rdr.buffer().is_empty() {
+            return Ok(Ok(()));
+        }
+
+        let stream = self.rdr.get_ref();
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let r = loop {
+            // a closed connection reads zero bytes, which the response then fails on
+            match stream.peek(&mut [0]) {
+                Ok(_) => break Ok(Ok(())),
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    if let Some(output) = capture.as_deref_mut() {
+                        self.write_captured(output);
+                    }
+                    if let Err(c) = cancel.check() {
+                        break Ok(Err(c));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => break Err(e),
+            }
+        };
+        stream.set_read_timeout(None)?;
+        r
+    }
+
+    fn response(&mut self) -> io::Result<RawResponse> {
+        let r = &mut self.rdr;
+        let mut status = [0];
+        r.read_exact(&mut status)?;
+        if status[0] != 0 {
+            return read_str(r).map(Err);
+        }
+
+        let result = read_bytes(r)?;
+        let data = Some(read_bytes(r)?).filter(|x| !x.is_empty());
+        let names = (0..read_len(r)?)
+            .map(|_| read_str(r))
+            .collect::<io::Result<_>>()?;
+
+        Ok(Ok((result, data, names)))
+    }
+
+    /// Writes the captured lines received so far into `output`.
+    fn write_captured(&self, output: &mut Output<output::Write>) {
+        for captured in self.captured.try_iter() {
+            if let Captured::Line(stream, line) = captured {
+                output.write_captured(stream, &line);
+            }
+        }
+    }
+
+    /// Writes the captured lines into `output` until the end markers of both streams are read.
+    fn finish_captured(&self, output: &mut Output<output::Write>) {
+        let start = Instant::now();
+        let (mut stdout, mut stderr) = (false, false);
+        while !(stdout && stderr) {
+            let timeout = END_TIMEOUT.saturating_sub(start.elapsed());
+            match self.captured.recv_timeout(timeout) {
+                Ok(Captured::Line(stream, line)) => output.write_captured(stream, &line),
+                Ok(Captured::End(output::Stream::Stdout)) => stdout = true,
+                Ok(Captured::End(output::Stream::Stderr)) => stderr = true,
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Describes the exit of a worker which lost its connection.
+    fn exit_status(&mut self) -> String {
+        // the connection closes as the process exits, give it a moment to be reaped
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(1) {
+            match self.child.try_wait() {
+                Ok(Some(status)) => return status.to_string(),
+                Ok(None) => std::thread::sleep(Duration::from_millis(5)),
+                Err(e) => return e.to_string(),
+            }
+        }
+        self.child.kill().ok();
+        self.child.wait().ok();
+        "unresponsive".to_string()
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+/// Writes the worker crate and builds it with the toolchain, returning the path to the executable.
+///
+/// Files are only written if they have changed, so an up to date worker is not rebuilt. Each
+/// toolchain builds into its own target directory, so switching toolchains does not rebuild.
+fn build(dir: &Path, toolchain: Option<&str>) -> io::Result<PathBuf> {
+    let cargotoml = format!(
+        r#"[package]
+name = "{name}"
+version = "0.1.0"
+edition = "2018"
+
+[[bin]]
+name = "{name}"
+path = "src/main.rs"
+
+[workspace]
+"#,
+        name = WORKER_NAME
+    );
+
+    write_if_changed(&dir.join("Cargo.toml"), &cargotoml)?;
+    write_if_changed(&dir.join("src/main.rs"), WORKER_SRC)?;
+
+    // relative to the worker crate
+    let target = match toolchain {
+        Some(t) => Path::new("target").join(format!("toolchain-{}", t)),
+        None => PathBuf::from("target"),
+    };
+    let output = Command::new("cargo")
+        .current_dir(dir)
+        .env("CARGO_TARGET_DIR", &target)
+        .args(toolchain.map(|t| format!("+{}", t)))
+        .args(["build", "--quiet"])
+        .output()?;
+
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "failed to build worker: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        ));
+    }
+
+    Ok(dir.join(target).join("debug").join(format!(
+        "{}{}",
+        WORKER_NAME,
+        std::env::consts::EXE_SUFFIX
+    )))
+}
+
+fn write_if_changed(path: &Path, contents: &str) -> io::Result<()> {
+    if fs::read_to_string(path).ok().as_deref() != Some(contents) {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)?;
+    }
+    Ok(())
+}
+
+/// Forwards the worker's `stream` from a thread, sending the lines while `capturing` and writing
+/// them to the host's stream otherwise. The end markers are removed.
+fn forward<R>(
+    rdr: R,
+    stream: output::Stream,
+    capturing: Arc<AtomicBool>,
+    tx: mpsc::Sender<Captured>,
+) where
+    R: Read + Send + 'static,
+{
+    std::thread::spawn(move || {
+        let mut rdr = BufReader::new(rdr);
+        let mut line = Vec::new();
+        // the worker closing the stream ends the thread
+        while rdr.read_until(b'\n', &mut line).unwrap_or(0) > 0 {
+            let end = line.ends_with(END_MARKER);
+            if end {
+                // a trailing incomplete line is written before the marker
+                line.truncate(line.len() - END_MARKER.len());
+            }
+
+            if capturing.load(Ordering::Acquire) {
+                if !line.is_empty() {
+                    let s = String::from_utf8_lossy(&line).into_owned();
+                    tx.send(Captured::Line(stream, s)).ok();
+                }
+                if end {
+                    tx.send(Captured::End(stream)).ok();
+                }
+            } else {
+                let written = match stream {
+                    output::Stream::Stdout => write_flush(&mut io::stdout(), &line),
+                    output::Stream::Stderr => write_flush(&mut io::stderr(), &line),
+                };
+                if let Err(e) = written {
+                    warn!("failed to forward output of evaluation worker: {}", e);
+                }
+            }
+
+            line.clear();
+        }
+    });
+}
+
+fn write_flush<W: Write>(wtr: &mut W, bytes: &[u8]) -> io::Result<()> {
+    wtr.write_all(bytes)?;
+    wtr.flush()
+}
+
+/// Accept the worker's connection, checking its token.
+fn accept(listener: &TcpListener, child: &mut Child, token: &str) -> io::Result<TcpStream> {
+    listener.set_nonblocking(true)?;
+    let start = Instant::now();
+
+    loop {
+        match listener.accept() {
+            Ok((mut stream, _)) => {
+                stream.set_nonblocking(false)?;
+                stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+                let mut buf = vec![0; token.len()];
+                let valid = stream.read_exact(&mut buf).is_ok() && buf == token.as_bytes();
+                stream.set_read_timeout(None)?;
+                if valid {
+                    return Ok(stream);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if let Some(status) = child.try_wait()? {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("worker exited before connecting ({})", status),
+                    ));
+                }
+                if start.elapsed() > CONNECT_TIMEOUT {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "worker did not connect",
+                    ));
+                }
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn read_len<R: Read>(rdr: &mut R) -> io::Result<usize> {
+    let mut b = [0; 8];
+    rdr.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b) as usize)
+}
+
+fn read_bytes<R: Read>(rdr: &mut R) -> io::Result<Vec<u8>> {
+    let mut v = vec![0; read_len(rdr)?];
+    rdr.read_exact(&mut v)?;
+    Ok(v)
+}
+
+fn read_str<R: Read>(rdr: &mut R) -> io::Result<String> {
+    String::from_utf8(read_bytes(rdr)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_len<W: Write>(wtr: &mut W, len: usize) -> io::Result<()> {
+    wtr.write_all(&(len as u64).to_le_bytes())
+}
+
+fn write_bytes<W: Write>(wtr: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_len(wtr, bytes.len())?;
+    wtr.write_all(bytes)
+}
@@ -0,0 +1,456 @@
+//! The evaluation worker process.
+//!
+//! This file is written as the `main.rs` of a binary crate in the compilation directory, built
+//! with the toolchain the libraries are compiled with. It must only depend on `std`, and only
+//! shares `#[repr(C)]` types and `extern "C"` functions with the libraries.
+//!
+//! The worker connects to the host over TCP, sends its token, and then serves requests until the
+//! connection closes. Each request loads a compiled library and runs an evaluation function, with
+//! the values of evaluated bindings held in the worker. The protocol is defined in
+//! `compile::worker`.
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::TcpStream,
+    os::raw::c_void,
+};
+
+/// The values of a module, as `compile::Values`.
+type Values = HashMap<String, Stored>;
+
+/// The values of a module and the libraries they need, as `compile::ValueStore` records them.
+///
+/// A value can reference the library which first stored it, and each library evaluated over it
+/// since.
+#[derive(Default)]
+struct Module {
+    values: Values,
+    /// The library each value was first stored by.
+    origins: HashMap<String, u64>,
+    /// The libraries evaluated over the values, oldest first.
+    libs: Vec<u64>,
+}
+
+impl Module {
+    /// Records the library `id` as evaluated over the values, returning `false` if there are no
+    /// values to need it.
+    fn keep(&mut self, id: u64) -> bool {
+        if self.values.is_empty() {
+            return false;
+        }
+        let Module {
+            values,
+            origins,
+            libs,
+        } = self;
+        origins.retain(|k, _| values.contains_key(k));
+        for name in values.keys() {
+            origins.entry(name.clone()).or_insert(id);
+        }
+        libs.push(id);
+        let oldest = origins.values().min().copied().unwrap_or(id);
+        libs.retain(|x| *x >= oldest);
+        true
+    }
+}
+
+/// A value a library stored, or a note it made, as `compile::Stored`.
+enum Stored {
+    Value(Value),
+    Note,
+}
+
+/// Must match `papyrus_store::Value` in the generated code. A null `ptr` is no value.
+#[repr(C)]
+struct Value {
+    ptr: *mut c_void,
+    drop: Option<unsafe extern "C" fn(*mut c_void)>,
+}
+
+impl Value {
+    fn none() -> Self {
+        Value {
+            ptr: std::ptr::null_mut(),
+            drop: None,
+        }
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        if let Some(drop) = self.drop.take() {
+            unsafe { drop(self.ptr) }
+        }
+    }
+}
+
+/// Must match `papyrus_store::Store` in the generated code.
+#[repr(C)]
+struct Store {
+    values: *mut c_void,
+    take: unsafe extern "C" fn(*mut c_void, *const u8, usize) -> Value,
+    put: unsafe extern "C" fn(*mut c_void, *const u8, usize, Value),
+    note: unsafe extern "C" fn(*mut c_void, *const u8, usize, *const u8, usize),
+}
+
+impl Store {
+    fn new(values: &mut Values) -> Self {
+        Store {
+            values: values as *mut Values as *mut c_void,
+            take: take_value,
+            put: put_value,
+            note: put_note,
+        }
+    }
+}
+
+unsafe fn from_raw<'a>(
+    values: *mut c_void,
+    name: *const u8,
+    len: usize,
+) -> (&'a mut Values, String) {
+    let name = std::slice::from_raw_parts(name, len);
+    (
+        &mut *(values as *mut Values),
+        String::from_utf8_lossy(name).into_owned(),
+    )
+}
+
+unsafe extern "C" fn take_value(values: *mut c_void, name: *const u8, len: usize) -> Value {
+    let (values, name) = from_raw(values, name, len);
+    match values.remove(&name) {
+        Some(Stored::Value(value)) => value,
+        _ => Value::none(),
+    }
+}
+
+unsafe extern "C" fn put_value(values: *mut c_void, name: *const u8, len: usize, value: Value) {
+    let (values, name) = from_raw(values, name, len);
+    values.insert(name, Stored::Value(value));
+}
+
+unsafe extern "C" fn put_note(
+    values: *mut c_void,
+    name: *const u8,
+    len: usize,
+    _: *const u8,
+    _: usize,
+) {
+    let (values, name) = from_raw(values, name, len);
+    values.insert(name, Stored::Note);
+}
+
+/// Must match `Output` in `transport_codec.rs`.
+#[repr(C)]
+struct Output {
+    ptr: *mut u8,
+    len: usize,
+    cap: usize,
+}
+
+/// Must match `Data` in `transport_codec.rs`.
+#[repr(C)]
+struct Data {
+    ptr: *const u8,
+    len: usize,
+    out: Output,
+}
+
+type EvalFunc = unsafe extern "C" fn(&mut Store) -> Output;
+type DataFunc = unsafe extern "C" fn(&mut Store, &mut Data) -> Output;
+type FreeFunc = unsafe extern "C" fn(Output);
+
+const FREE_FN: &str = "papyrus_free_output";
+/// Written to stdout and stderr after each evaluation, must match `compile::worker`.
+const END_MARKER: &[u8] = b"\0papyrus_worker_end\0\n";
+/// The type name of the shown value, inserted by the evaluation functions. It is not sent to the
+/// host.
+const TYPE: &str = "papyrus::type";
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let addr = args.next().expect("expecting host address argument");
+    let token = args.next().expect("expecting token argument");
+
+    let stream = TcpStream::connect(addr).expect("failed to connect to host");
+    stream.set_nodelay(true).ok();
+    let mut rdr = BufReader::new(stream.try_clone().expect("failed to clone stream"));
+    let mut wtr = BufWriter::new(stream);
+
+    wtr.write_all(token.as_bytes())
+        .and_then(|_| wtr.flush())
+        .expect("failed to send token");
+
+    // mods must be dropped before libs, the values can reference code in the libraries
+    let mut libs: HashMap<u64, Lib> = HashMap::new();
+    let mut mods: HashMap<String, Module> = HashMap::new();
+    let mut next_lib = 0;
+
+    // the host closing the connection ends the worker
+    while let Ok(req) = read_request(&mut rdr) {
+        mods.retain(|k, _| req.live.contains(k));
+
+        let res = eval(&req, &mut mods).map(|(evaluated, lib)| {
+            if mods.entry(req.module.clone()).or_default().keep(next_lib) {
+                libs.insert(next_lib, lib);
+                next_lib += 1;
+            }
+            evaluated
+        });
+
+        // unload the libraries no values need
+        let needed: HashSet<u64> = mods
+            .values()
+            .filter(|m| !m.values.is_empty())
+            .flat_map(|m| m.libs.iter().copied())
+            .collect();
+        libs.retain(|id, _| needed.contains(id));
+
+        // the host reads the output up to the markers once it has the response
+        write_end(&mut io::stdout());
+        write_end(&mut io::stderr());
+
+        if write_response(&mut wtr, res, &mods, &req.module).is_err() {
+            break;
+        }
+    }
+}
+
+struct Request {
+    lib: String,
+    func: String,
+    module: String,
+    live: Vec<String>,
+    data: Option<Vec<u8>>,
+}
+
+struct Evaluated {
+    result: Vec<u8>,
+    data: Vec<u8>,
+}
+
+fn eval(req: &Request, mods: &mut HashMap<String, Module>) -> Result<(Evaluated, Lib), String> {
+    let lib = Lib::open(&req.lib)?;
+    let free: FreeFunc = unsafe { std::mem::transmute(lib.symbol(FREE_FN)?) };
+    let values = &mut mods.entry(req.module.clone()).or_default().values;
+
+    let evaluated = unsafe {
+        match &req.data {
+            Some(bytes) => {
+                let func: DataFunc = std::mem::transmute(lib.symbol(&req.func)?);
+                let mut data = Data {
+                    ptr: bytes.as_ptr(),
+                    len: bytes.len(),
+                    out: Output {
+                        ptr: std::ptr::null_mut(),
+                        len: 0,
+                        cap: 0,
+                    },
+                };
+                let output = func(&mut Store::new(values), &mut data);
+                let result = take(output, free);
+                let data = take(data.out, free);
+                Evaluated { result, data }
+            }
+            None => {
+                let func: EvalFunc = std::mem::transmute(lib.symbol(&req.func)?);
+                let result = take(func(&mut Store::new(values)), free);
+                Evaluated {
+                    result,
+                    data: Vec::new(),
+                }
+            }
+        }
+    };
+
+    values.remove(TYPE);
+
+    Ok((evaluated, lib))
+}
+
+unsafe fn take(output: Output, free: FreeFunc) -> Vec<u8> {
+    if output.ptr.is_null() {
+        Vec::new()
+    } else {
+        let bytes = std::slice::from_raw_parts(output.ptr, output.len).to_vec();
+        free(output);
+        bytes
+    }
+}
+
+fn read_request<R: Read>(rdr: &mut R) -> io::Result<Request> {
+    let lib = read_str(rdr)?;
+    let func = read_str(rdr)?;
+    let module = read_str(rdr)?;
+    let live = (0..read_len(rdr)?)
+        .map(|_| read_str(rdr))
+        .collect::<io::Result<_>>()?;
+    let data = match read_byte(rdr)? {
+        0 => None,
+        _ => Some(read_bytes(rdr)?),
+    };
+
+    Ok(Request {
+        lib,
+        func,
+        module,
+        live,
+        data,
+    })
+}
+
+fn write_response<W: Write>(
+    wtr: &mut W,
+    res: Result<Evaluated, String>,
+    mods: &HashMap<String, Module>,
+    module: &str,
+) -> io::Result<()> {
+    match res {
+        Ok(evaluated) => {
+            wtr.write_all(&[0])?;
+            write_bytes(wtr, &evaluated.result)?;
+            write_bytes(wtr, &evaluated.data)?;
+            let names: Vec<&String> = mods
+                .get(module)
+                .into_iter()
+                .flat_map(|x| x.values.keys())
+                .collect();
+            write_len(wtr, names.len())?;
+            for name in names {
+                write_bytes(wtr, name.as_bytes())?;
+            }
+        }
+        Err(e) => {
+            wtr.write_all(&[1])?;
+            write_bytes(wtr, e.as_bytes())?;
+        }
+    }
+    wtr.flush()
+}
+
+fn write_end<W: Write>(wtr: &mut W) {
+    wtr.flush()
+        .and_then(|_| wtr.write_all(END_MARKER))
+        .and_then(|_| wtr.flush())
+        .ok();
+}
+
+fn read_byte<R: Read>(rdr: &mut R) -> io::Result<u8> {
+    let mut b = [0; 1];
+    rdr.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn read_len<R: Read>(rdr: &mut R) -> io::Result<usize> {
+    let mut b = [0; 8];
+    rdr.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b) as usize)
+}
+
+fn read_bytes<R: Read>(rdr: &mut R) -> io::Result<Vec<u8>> {
+    let mut v = vec![0; read_len(rdr)?];
+    rdr.read_exact(&mut v)?;
+    Ok(v)
+}
+
+fn read_str<R: Read>(rdr: &mut R) -> io::Result<String> {
+    String::from_utf8(read_bytes(rdr)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_len<W: Write>(wtr: &mut W, len: usize) -> io::Result<()> {
+    wtr.write_all(&(len as u64).to_le_bytes())
+}
+
+fn write_bytes<W: Write>(wtr: &mut W, bytes: &[u8]) -> io::Result<()> {
+    write_len(wtr, bytes.len())?;
+    wtr.write_all(bytes)
+}
+
+/// A loaded library, unloaded and its file deleted on drop.
+struct Lib(*mut std::os::raw::c_void, String);
+
+impl Drop for Lib {
+    fn drop(&mut self) {
+        unsafe { sys::close(self.0) }
+        std::fs::remove_file(&self.1).ok();
+    }
+}
+
+impl Lib {
+    fn open(path: &str) -> Result<Self, String> {
+        let handle = unsafe { sys::open(path) };
+        if handle.is_null() {
+            Err(format!("failed to load library file: {}", path))
+        } else {
+            Ok(Lib(handle, path.to_string()))
+        }
+    }
+
+    fn symbol(&self, name: &str) -> Result<*mut std::os::raw::c_void, String> {
+        let cname = std::ffi::CString::new(name).map_err(|e| e.to_string())?;
+        let sym = unsafe { sys::symbol(self.0, cname.as_ptr()) };
+        if sym.is_null() {
+            Err(format!("failed to find function in library: {}", name))
+        } else {
+            Ok(sym)
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::os::raw::{c_char, c_int, c_void};
+
+    const RTLD_NOW: c_int = 2;
+
+    #[cfg_attr(any(target_os = "linux", target_os = "android"), link(name = "dl"))]
+    extern "C" {
+        fn dlopen(filename: *const c_char, flag: c_int) -> *mut c_void;
+        fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+        fn dlclose(handle: *mut c_void) -> c_int;
+    }
+
+    pub unsafe fn open(path: &str) -> *mut c_void {
+        match std::ffi::CString::new(path) {
+            Ok(path) => dlopen(path.as_ptr(), RTLD_NOW),
+            Err(_) => std::ptr::null_mut(),
+        }
+    }
+
+    pub unsafe fn symbol(handle: *mut c_void, name: *const c_char) -> *mut c_void {
+        dlsym(handle, name)
+    }
+
+    pub unsafe fn close(handle: *mut c_void) {
+        dlclose(handle);
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn LoadLibraryW(filename: *const u16) -> *mut c_void;
+        fn GetProcAddress(module: *mut c_void, name: *const c_char) -> *mut c_void;
+        fn FreeLibrary(module: *mut c_void) -> c_int;
+    }
+
+    pub unsafe fn open(path: &str) -> *mut c_void {
+        let path: Vec<u16> = std::ffi::OsStr::new(path)
+            .encode_wide()
+            .chain(Some(0))
+            .collect();
+        LoadLibraryW(path.as_ptr())
+    }
+
+    pub unsafe fn symbol(handle: *mut c_void, name: *const c_char) -> *mut c_void {
+        GetProcAddress(handle, name)
+    }
+
+    pub unsafe fn close(handle: *mut c_void) {
+        FreeLibrary(handle);
+    }
+}
@@ -85,9 +85,10 @@ impl CodeCompleter {
             repl_data.mods_map(),
             repl_data.linking(),
             repl_data.static_files(),
+            &crate::code::PersistedMap::new(),
         );
 
-        let split = map.get(repl_data.current_mod()).cloned().unwrap_or(0..0); // return an empty range if this fails
+        let split = map.return_range(repl_data.current_mod()).unwrap_or(0..0); // return an empty range if this fails
 
         CodeCompleter { last_code, split }
     }
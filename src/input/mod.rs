@@ -1,4 +1,5 @@
 use crate::code::{CrateType, Input};
+use std::{error, fmt, ops::Range};
 use syn::Expr;
 
 mod parse;
@@ -22,7 +23,64 @@ pub enum InputResult {
     /// End of file reached.
     Eof,
     /// Error while parsing input.
-    InputError(String),
+    InputError(InputError),
+}
+
+/// An error parsing input, with the location of the error if known.
+#[derive(Debug, PartialEq)]
+pub struct InputError {
+    /// The error message.
+    pub msg: String,
+    /// The location in the input the error occurred.
+    pub span: Option<InputSpan>,
+}
+
+/// A location in the input.
+#[derive(Debug, PartialEq)]
+pub struct InputSpan {
+    /// The 0-based line of the input.
+    pub line: usize,
+    /// The character columns of the line which are spanned.
+    pub cols: Range<usize>,
+    /// The source line.
+    pub src: String,
+}
+
+impl InputError {
+    /// An error without any location information.
+    pub fn msg<S: Into<String>>(msg: S) -> Self {
+        Self {
+            msg: msg.into(),
+            span: None,
+        }
+    }
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)?;
+        if let Some(span) = &self.span {
+            write!(f, "\n{}\n", span.src)?;
+            underline(f, &span.src, span.cols.clone())?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for InputError {}
+
+/// Write a caret underline of the `cols` of `src`.
+///
+/// Tabs in the prefix are kept so the carets line up with the source line.
+pub(crate) fn underline(f: &mut dyn fmt::Write, src: &str, cols: Range<usize>) -> fmt::Result {
+    for ch in src.chars().take(cols.start) {
+        f.write_char(if ch == '\t' { '\t' } else { ' ' })?;
+    }
+    let len = std::cmp::max(cols.end.saturating_sub(cols.start), 1);
+    for _ in 0..len {
+        f.write_char('^')?;
+    }
+    Ok(())
 }
 
 /// Parse `input` and `line` and determine what `InputResult`.
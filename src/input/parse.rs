@@ -1,6 +1,7 @@
 use super::*;
 use crate::code::{self, Statement};
 use quote::ToTokens;
+use syn::visit_mut::{self, VisitMut};
 use syn::{self, Block, File, Item, Stmt};
 
 /// Parses a line of input as a command.
@@ -20,12 +21,14 @@ pub fn parse_command(line: &str) -> InputResult {
 pub fn parse_program(code: &str) -> InputResult {
     debug!("parse program: {}", code);
 
-    let reterr = |e: syn::Error| {
-        let e = e.to_string();
-        if (&e == "LexError") || (&e == "lex error") {
-            InputResult::More
-        } else {
-            InputResult::InputError(e)
+    let reterr = |offset: usize| {
+        move |e: syn::Error| {
+            let msg = e.to_string();
+            if (&msg == "LexError") || (&msg == "lex error") {
+                InputResult::More
+            } else {
+                InputResult::InputError(input_error(msg, &e, code, offset))
+            }
         }
     };
 
@@ -46,13 +49,14 @@ pub fn parse_program(code: &str) -> InputResult {
                     crates: vec![],
                 })
             })
-            .unwrap_or_else(reterr);
+            .unwrap_or_else(reterr(0));
     }
 
-    let code = format!("{{ {} }}", code); // wrap in a block so the parser can parse through it without need to guess the type!
+    let wrapped = format!("{{ {} }}", code); // wrap in a block so the parser can parse through it without need to guess the type!
 
-    syn::parse_str::<Block>(&code)
-        .map(|block| {
+    syn::parse_str::<Block>(&wrapped)
+        .map(|mut block| {
+            ReturnRewrite.visit_block_mut(&mut block);
             let mut stmts = Vec::new();
             let mut items: Vec<code::Item> = Vec::new();
             let mut crates = Vec::new();
@@ -70,7 +74,7 @@ pub fn parse_program(code: &str) -> InputResult {
                         ParseItemResult::ExternCrate(string) => {
                             match CrateType::parse_str(&fmt(string)) {
                                 Ok(c) => crates.push(c),
-                                Err(e) => error!("crate parsing failed: {}", e),
+                                Err(e) => return InputResult::InputError(InputError::msg(e)),
                             }
                         }
                         ParseItemResult::Span(string) => items.push((fmt(string), false)),
@@ -78,22 +82,21 @@ pub fn parse_program(code: &str) -> InputResult {
                             expr: fmt(string),
                             semi,
                         }), // macro item are plopped in as exprs
-                        ParseItemResult::Error(s) => return InputResult::InputError(s),
-                    },
-                    Stmt::Expr(expr) => match parse_expr(expr) {
-                        Ok(string) => stmts.push(Statement {
-                            expr: fmt(string),
-                            semi: false,
-                        }),
-                        Err(s) => return InputResult::InputError(s),
-                    },
-                    Stmt::Semi(expr, _) => match parse_expr(expr) {
-                        Ok(string) => stmts.push(Statement {
-                            expr: fmt(string),
-                            semi: true,
-                        }),
-                        Err(s) => return InputResult::InputError(s),
+                        ParseItemResult::Error(s) => {
+                            return InputResult::InputError(InputError::msg(s))
+                        }
                     },
+                    // a non-trailing expression without a semi can only be block-like (`if`,
+                    // `match`, `loop`, etc.), and like rustc it is kept as a statement, only a
+                    // trailing expression gets bound to `out#`.
+                    Stmt::Expr(expr) => stmts.push(Statement {
+                        expr: fmt_expr(parse_expr(expr)),
+                        semi: false,
+                    }),
+                    Stmt::Semi(expr, _) => stmts.push(Statement {
+                        expr: fmt_expr(parse_expr(expr)),
+                        semi: true,
+                    }),
                 }
             }
             InputResult::Program(Input {
@@ -102,7 +105,95 @@ pub fn parse_program(code: &str) -> InputResult {
                 crates,
             })
         })
-        .unwrap_or_else(reterr)
+        .unwrap_or_else(reterr(BLOCK_OFFSET))
+}
+
+/// The number of characters the block wrapping prepends to the first line of the input.
+const BLOCK_OFFSET: usize = 2;
+
+/// Build an `InputError` with the span of `err` mapped back onto the `code` input.
+///
+/// `offset` is the number of characters prepended to the first line before parsing. Spans which
+/// fall past the input (such as an unexpected end of input) point at the end of the line.
+fn input_error(msg: String, err: &syn::Error, code: &str, offset: usize) -> InputError {
+    let (start, end) = (err.span().start(), err.span().end());
+    let nlines = code.lines().count();
+
+    let span = if nlines == 0 || start.line == 0 {
+        None
+    } else {
+        let line = std::cmp::min(start.line - 1, nlines - 1);
+        let src = code.lines().nth(line).unwrap_or_default();
+        let len = src.chars().count();
+
+        let col = |line_col: proc_macro2::LineColumn| {
+            let c = if line_col.line == 1 {
+                line_col.column.saturating_sub(offset)
+            } else {
+                line_col.column
+            };
+            if line_col.line > nlines {
+                len
+            } else {
+                std::cmp::min(c, len)
+            }
+        };
+
+        let col_start = col(start);
+        let col_end = if end.line == start.line {
+            std::cmp::max(col(end), col_start)
+        } else {
+            len // multiline spans underline to the end of the first line
+        };
+
+        Some(InputSpan {
+            line,
+            cols: col_start..col_end,
+            src: src.to_string(),
+        })
+    };
+
+    InputError { msg, span }
+}
+
+/// Rewrites the `return` expressions which exit the evaluation function, leaving those in
+/// closures, async blocks, and items alone.
+///
+/// The evaluation function returns the value's `Kserd`, `crate::papyrus_ret!` converts the value
+/// and marks the statements as not having run to completion.
+struct ReturnRewrite;
+
+impl VisitMut for ReturnRewrite {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Closure(_) | Expr::Async(_) => (),
+            Expr::Return(ret) => {
+                if let Some(e) = ret.expr.as_mut() {
+                    self.visit_expr_mut(e);
+                }
+                let value = ret
+                    .expr
+                    .take()
+                    .map(|e| *e)
+                    .unwrap_or_else(|| syn::parse_quote!(()));
+                ret.expr = Some(Box::new(syn::parse_quote!(
+                    crate::papyrus_ret!(__papyrus_store, #value)
+                )));
+            }
+            _ => visit_mut::visit_expr_mut(self, expr),
+        }
+    }
+
+    fn visit_item_mut(&mut self, _: &mut Item) {}
+}
+
+/// Formats an expression, `rustfmt` terminates some, such as a `return`, with a semi.
+fn fmt_expr(s: String) -> String {
+    let mut s = fmt(s);
+    if s.ends_with(';') {
+        s.pop();
+    }
+    s
 }
 
 #[cfg(feature = "format")]
@@ -124,42 +215,12 @@ enum ParseItemResult {
 
 fn parse_item(item: Item) -> ParseItemResult {
     match &item {
-        Item::Static(_) => {
-            error!("haven't handled item variant Static");
-            ParseItemResult::Error("haven't handled item variant Static. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::Const(_) => {
-            error!("haven't handled item variant Const");
-            ParseItemResult::Error("haven't handled item variant Const. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::Mod(_) => {
-            error!("haven't handled item variant Mod");
-            ParseItemResult::Error("haven't handled item variant Mod. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::ForeignMod(_) => {
-            error!("haven't handled item variant ForeignMod");
-            ParseItemResult::Error("haven't handled item variant ForeignMod. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::Type(_) => {
-            error!("haven't handled item variant Type");
-            ParseItemResult::Error("haven't handled item variant Type. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-
-        Item::Enum(_) => {
-            error!("haven't handled item variant Enum");
-            ParseItemResult::Error("haven't handled item variant Enum. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::Union(_) => {
-            error!("haven't handled item variant Union");
-            ParseItemResult::Error("haven't handled item variant Union. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::Trait(_) => {
-            error!("haven't handled item variant Trait");
-            ParseItemResult::Error("haven't handled item variant Trait. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-        }
-        Item::TraitAlias(_) => {
-            error!("haven't handled item variant TraitAlias");
-            ParseItemResult::Error("haven't handled item variant TraitAlias. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
+        Item::Mod(m) if m.content.is_none() => {
+            error!("out-of-line mod item");
+            ParseItemResult::Error(
+                "mod items must have a body. Use `:static-files add` to import a module file"
+                    .to_string(),
+            )
         }
         Item::Macro2(_) => {
             error!("haven't handled item variant Macro2");
@@ -182,135 +243,8 @@ fn parse_item(item: Item) -> ParseItemResult {
     }
 }
 
-fn parse_expr(expr: Expr) -> Result<String, String> {
-    match expr {
-		Expr::Box(_) => {
-			error!("haven't handled expr variant Box");
-			Err("haven't handled expr variant Box. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Array(_) => {
-			error!("haven't handled expr variant Array");
-			Err("haven't handled expr variant Array. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Unary(_) => {
-			error!("haven't handled expr variant Unary");
-			Err("haven't handled expr variant Unary. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Lit(_) => {
-			error!("haven't handled expr variant Lit");
-			Err("haven't handled expr variant Lit. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Cast(_) => {
-			error!("haven't handled expr variant Cast");
-			Err("haven't handled expr variant Cast. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Type(_) => {
-			error!("haven't handled expr variant Type");
-			Err("haven't handled expr variant Type. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::If(_) => {
-			error!("haven't handled expr variant If");
-			Err("haven't handled expr variant If. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::While(_) => {
-			error!("haven't handled expr variant While");
-			Err("haven't handled expr variant While. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Loop(_) => {
-			error!("haven't handled expr variant For");
-			Err("haven't handled expr variant For. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Match(_) => {
-			error!("haven't handled expr variant Match");
-			Err("haven't handled expr variant Match. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Closure(_) => {
-			error!("haven't handled expr variant Closure");
-			Err("haven't handled expr variant Closure. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Unsafe(_) => {
-			error!("haven't handled expr variant Unsafe");
-			Err("haven't handled expr variant Unsafe. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Block(_) => {
-			error!("haven't handled expr variant Block");
-			Err("haven't handled expr variant Block. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Assign(_) => {
-			error!("haven't handled expr variant Assign");
-			Err("haven't handled expr variant Assign. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::AssignOp(_) => {
-			error!("haven't handled expr variant AssignOp");
-			Err("haven't handled expr variant AssignOp. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Field(_) => {
-			error!("haven't handled expr variant Field");
-			Err("haven't handled expr variant Field. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Index(_) => {
-			Err("haven't handled expr variant Index. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Range(_) => {
-			error!("haven't handled expr variant Range");
-			Err("haven't handled expr variant Range. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Reference(_) => {
-			error!("haven't handled expr variant Reference");
-			Err("haven't handled expr variant Reference. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Break(_) => {
-			error!("haven't handled expr variant Break");
-			Err("haven't handled expr variant Break. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Continue(_) => {
-			error!("haven't handled expr variant Continue");
-			Err("haven't handled expr variant Continue. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Return(_) => {
-			error!("haven't handled expr variant Return");
-			Err("haven't handled expr variant Return. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Struct(_) => {
-			error!("haven't handled expr variant Struct");
-			Err("haven't handled expr variant Struct. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Repeat(_) => {
-			error!("haven't handled expr variant Repeat");
-			Err("haven't handled expr variant Repeat. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Paren(_) => {
-			error!("haven't handled expr variant Paren");
-			Err("haven't handled expr variant Paren. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Group(_) => {
-			error!("haven't handled expr variant Group");
-			Err("haven't handled expr variant Group. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Try(_) => {
-			error!("haven't handled expr variant Try");
-			Err("haven't handled expr variant Try. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Async(_) => {
-			error!("haven't handled expr variant Async");
-			Err("haven't handled expr variant Async. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::TryBlock(_) => {
-			error!("haven't handled expr variant TryBlock");
-			Err("haven't handled expr variant TryBlock. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Yield(_) => {
-			error!("haven't handled expr variant Yield");
-			Err("haven't handled expr variant Yield. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		Expr::Verbatim(_) => {
-			error!("haven't handled expr variant Verbatim");
-			Err("haven't handled expr variant Verbatim. Raise a request here https://github.com/kurtlawrence/papyrus/issues".to_string())
-		}
-		_ => {
-			let s = format!("{}", expr.into_token_stream());
-			debug!("Expression parsed: {:?}", s);
-			Ok(s)
-		}
-	}
+fn parse_expr(expr: Expr) -> String {
+    let s = format!("{}", expr.into_token_stream());
+    debug!("Expression parsed: {:?}", s);
+    s
 }
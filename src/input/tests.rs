@@ -46,6 +46,18 @@ struct A {
             crates: vec![CrateType::parse_str(&"extern crate rand as r;").unwrap()]
         })
     ); // Item::ExternCrate
+    let dep = r#"#[dep(version = "0.7", features = ["small_rng"])] extern crate rand;"#;
+    match parse_program(dep) {
+        InputResult::Program(input) => {
+            assert_eq!(input.crates[0].dep.version.as_deref(), Some("0.7"));
+            assert_eq!(input.crates[0].dep.features, vec!["small_rng"]);
+        }
+        x => panic!("expecting a program, found {:?}", x),
+    }
+    assert!(matches!(
+        parse_program("#[dep(version = 7)] extern crate rand;"),
+        InputResult::InputError(_)
+    ));
     assert_eq!(
         parse_program("impl Eq for MyStruct {}"),
         InputResult::Program(Input {
@@ -72,6 +84,67 @@ struct A {
     ); // Item::Use
 }
 
+#[cfg(feature = "format")] // have to turn formatting on to check this
+#[test]
+fn test_more_items() {
+    fn items(item: &str) -> InputResult {
+        InputResult::Program(Input {
+            items: vec![(item.to_string(), false)],
+            stmts: vec![],
+            crates: vec![],
+        })
+    }
+
+    assert_eq!(
+        parse_program("enum A { B, C }"),
+        items("enum A {\n    B,\n    C,\n}")
+    ); // Item::Enum
+    assert_eq!(
+        parse_program("trait A { fn b(&self); }"),
+        items("trait A {\n    fn b(&self);\n}")
+    ); // Item::Trait
+    assert_eq!(parse_program("const A: u8 = 1;"), items("const A: u8 = 1;")); // Item::Const
+    assert_eq!(
+        parse_program("static A: u8 = 1;"),
+        items("static A: u8 = 1;")
+    ); // Item::Static
+    assert_eq!(parse_program("type A = u8;"), items("type A = u8;")); // Item::Type
+    assert_eq!(
+        parse_program("union A { a: u8 }"),
+        items("union A {\n    a: u8,\n}")
+    ); // Item::Union
+    assert_eq!(
+        parse_program("mod a { pub fn b() {} }"),
+        items("mod a {\n    pub fn b() {}\n}")
+    ); // Item::Mod
+    assert_eq!(
+        parse_program("extern \"C\" { fn abs(x: i32) -> i32; }"),
+        items("extern \"C\" {\n    fn abs(x: i32) -> i32;\n}")
+    ); // Item::ForeignMod
+    assert_eq!(parse_program("trait A = B;"), items("trait A = B;")); // Item::TraitAlias
+
+    // items mix with statements
+    assert_eq!(
+        parse_program("enum A { B } A::B"),
+        InputResult::Program(Input {
+            items: vec![("enum A {\n    B,\n}".to_string(), false)],
+            stmts: vec![Statement {
+                expr: "A::B".to_string(),
+                semi: false
+            }],
+            crates: vec![]
+        })
+    );
+
+    // out-of-line modules can not be resolved
+    assert_eq!(
+        parse_program("mod a;"),
+        InputResult::InputError(InputError::msg(
+            "mod items must have a body. Use `:static-files add` to import a module file"
+        ))
+    );
+}
+
 #[cfg(feature = "format")] // have to turn formatting on to check this
 #[test]
 fn test_exprs() {
@@ -239,6 +312,114 @@ fn test_exprs() {
     );
 }
 
+#[cfg(feature = "format")] // have to turn formatting on to check this
+#[test]
+fn test_all_expr_variants() {
+    fn stmts(v: &[(&str, bool)]) -> InputResult {
+        InputResult::Program(Input {
+            items: vec![],
+            stmts: v
+                .iter()
+                .map(|(expr, semi)| Statement {
+                    expr: expr.to_string(),
+                    semi: *semi,
+                })
+                .collect(),
+            crates: vec![],
+        })
+    }
+
+    assert_eq!(parse_program("5"), stmts(&[("5", false)])); // Expr::Lit
+    assert_eq!(parse_program("-x"), stmts(&[("-x", false)])); // Expr::Unary
+    assert_eq!(parse_program("out0.len"), stmts(&[("out0.len", false)])); // Expr::Field
+    assert_eq!(parse_program("a[0]"), stmts(&[("a[0]", false)])); // Expr::Index
+    assert_eq!(parse_program("[1, 2]"), stmts(&[("[1, 2]", false)])); // Expr::Array
+    assert_eq!(parse_program("[0; 3]"), stmts(&[("[0; 3]", false)])); // Expr::Repeat
+    assert_eq!(parse_program("1 as u8"), stmts(&[("1 as u8", false)])); // Expr::Cast
+    assert_eq!(parse_program("0..3"), stmts(&[("0..3", false)])); // Expr::Range
+    assert_eq!(parse_program("&a"), stmts(&[("&a", false)])); // Expr::Reference
+    assert_eq!(parse_program("(a)"), stmts(&[("(a)", false)])); // Expr::Paren
+    assert_eq!(parse_program("|x| x + 1"), stmts(&[("|x| x + 1", false)])); // Expr::Closure
+    assert_eq!(parse_program("a = 1;"), stmts(&[("a = 1", true)])); // Expr::Assign
+    assert_eq!(parse_program("a += 1;"), stmts(&[("a += 1", true)])); // Expr::AssignOp
+    assert_eq!(parse_program("A { a: 1 }"), stmts(&[("A { a: 1 }", false)])); // Expr::Struct
+    assert_eq!(
+        parse_program("if a { 1 } else { 2 }"),
+        stmts(&[("if a {\n    1\n} else {\n    2\n}", false)])
+    ); // Expr::If
+    assert_eq!(
+        parse_program("match a { _ => 1 }"),
+        stmts(&[("match a {\n    _ => 1,\n}", false)])
+    ); // Expr::Match
+    assert_eq!(
+        parse_program("loop { break 1 }"),
+        stmts(&[("loop {\n    break 1;\n}", false)])
+    ); // Expr::Loop, Expr::Break
+    assert_eq!(parse_program("while a {}"), stmts(&[("while a {}", false)])); // Expr::While
+    assert_eq!(
+        parse_program("unsafe { f() }"),
+        stmts(&[("unsafe { f() }", false)])
+    ); // Expr::Unsafe
+    assert_eq!(parse_program("{ 1 }"), stmts(&[("{\n    1\n}", false)])); // Expr::Block
+
+    // block-like expressions that are not trailing are statements, as in rustc
+    assert_eq!(
+        parse_program("if a { f() } b"),
+        stmts(&[("if a {\n    f()\n}", false), ("b", false)])
+    );
+    assert_eq!(
+        parse_program("for i in 0..3 {}\nmatch a { _ => () }"),
+        stmts(&[
+            ("for i in 0..3 {}", false),
+            ("match a {\n    _ => (),\n}", false)
+        ])
+    );
+    assert_eq!(parse_program("f().await"), stmts(&[("f().await", false)])); // Expr::Await
+    assert_eq!(
+        parse_program("async { 1 }"),
+        stmts(&[("async { 1 }", false)])
+    ); // Expr::Async
+    assert_eq!(
+        parse_program("File::open(\"a\")?"),
+        stmts(&[("File::open(\"a\")?", false)])
+    ); // Expr::Try
+
+    // returns exit the evaluation function through the value store, other than those in closures
+    // and items
+    assert_eq!(
+        parse_program("return 1;"),
+        stmts(&[("return crate::papyrus_ret!(__papyrus_store, 1)", true)])
+    ); // Expr::Return
+    assert_eq!(
+        parse_program("if a { return; } b"),
+        stmts(&[
+            (
+                "if a {\n    return crate::papyrus_ret!(__papyrus_store, ());\n}",
+                false
+            ),
+            ("b", false)
+        ])
+    );
+    assert_eq!(
+        parse_program("let f = || return 1;"),
+        stmts(&[("let f = || return 1", true)])
+    );
+    assert_eq!(
+        parse_program("fn f() -> u8 { return 1 }"),
+        InputResult::Program(Input {
+            items: vec![("fn f() -> u8 {\n    return 1;\n}".to_string(), false)],
+            stmts: vec![],
+            crates: vec![],
+        })
+    );
+
+    // ... and a trailing semi still requires more input
+    assert_eq!(
+        determine_result("if a { 1 } else { 2 };", "if a { 1 } else { 2 };", false),
+        InputResult::More
+    );
+}
+
 #[test]
 fn test_determine_result() {
     assert_eq!(
@@ -274,12 +455,54 @@ fn test_determine_result() {
 
 #[test]
 fn fail_parse_program() {
+    let msg = |input| match parse_program(input) {
+        InputResult::InputError(e) => e.msg,
+        x => panic!("expecting input error: {:?}", x),
+    };
     assert_eq!(
-        parse_program("extern crate "),
-        InputResult::InputError("unexpected end of input, expected identifier".to_string())
+        msg("extern crate "),
+        "unexpected end of input, expected identifier"
     );
+    assert_eq!(msg("let a = 1"), "expected `;`");
+}
+
+#[test]
+fn input_error_spans() {
+    let err = |input| match parse_program(input) {
+        InputResult::InputError(e) => e,
+        x => panic!("expecting input error: {:?}", x),
+    };
+
+    let e = err("let a = 1 2;");
     assert_eq!(
-        parse_program("let a = 1"),
-        InputResult::InputError("expected `;`".to_string())
+        e.span,
+        Some(InputSpan {
+            line: 0,
+            cols: 10..11,
+            src: "let a = 1 2;".to_string()
+        })
     );
+    assert_eq!(e.to_string(), "expected `;`\nlet a = 1 2;\n          ^");
+
+    // second line has no block offset
+    let e = err("let a = 1;\nlet b = 2 3;");
+    let span = e.span.unwrap();
+    assert_eq!(span.line, 1);
+    assert_eq!(span.cols, 10..11);
+    assert_eq!(&span.src, "let b = 2 3;");
+
+    // past the end of input points to the end of the line
+    let e = err("let a = 1");
+    let span = e.span.as_ref().unwrap();
+    assert_eq!(span.line, 0);
+    assert_eq!(span.cols, 9..9);
+    assert_eq!(e.to_string(), "expected `;`\nlet a = 1\n         ^");
+
+    // tabs are preserved in the underline
+    let e = err("\tlet a = 1 2;");
+    assert_eq!(e.to_string(), "expected `;`\n\tlet a = 1 2;\n\t          ^");
+
+    // file level attributes are not wrapped
+    let e = err("#![feature(test)] 1");
+    assert_eq!(e.span.unwrap().cols, 18..19);
 }
@@ -155,6 +155,15 @@
 //! 1. Keep the app_data that is being transfered simple.
 //! 2. Develop wrappers that only pass through a _clone_ of the data.
 //!
+//! ### Worker process
+//!
+//! With [`ReplData::with_worker`](crate::repl::ReplData::with_worker) the evaluation runs in a
+//! separate process, which can not borrow `app_data`. Instead the data is serialized with `serde`
+//! (through `kserd`'s `encode` feature) and deserialized in the worker, and a mutating block
+//! passes the altered data back. The data type must implement `Serialize` and `Deserialize`.
+//! The generated crate's `kserd` then needs to use the same `serde` as the linked library, which
+//! is solved by aliasing the library's `kserd` as described below.
+//!
 //! ## Dependency Duplication
 //! When linking an external library, the `deps` folder is linked to ensure that the dependencies that
 //! the library is built with link properly. There are specific use cases where the rust compiler will
@@ -188,12 +197,15 @@
 //! crate and has implemented `ToKserd` so data types can automatically be transferred across the REPL
 //! boundary. The REPL needs to _not_ use the `kserd` dependency it is using and use the `kserd`
 //! dependency from the external library. Using `use external_lib::kserd;` will manage this.
+//! The persistent module code is also used by the module which encodes evaluation results for the
+//! host, so the library's `kserd` must be the same minor version `papyrus` uses (`0.5`).
 //!
 //! This is also important as then if the user of the REPL wants to implement `ToKserd` on REPL types,
 //! it will still be using the consistent `kserd` dependency, although an astute user might try to
 //! implement `::kserd::ToKserd` which would break! At least at this point it is easy to back out
 //! changes in the temporary REPL session.
 
+use crate::compile::Executor;
 use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::{fs, io};
@@ -229,6 +241,17 @@ pub struct LinkingConfiguration {
     /// It is sometimes necessary to have injected code, especially to solve dependency duplication
     /// issues. See [`the _linking_ module for a description`](crate::linking).
     pub persistent_module_code: String,
+
+    /// Flag whether the evaluation is run in a worker process.
+    ///
+    /// The app data can not be borrowed by a worker process, instead it is serialized and passed
+    /// to an additional evaluation function which deserializes it.
+    pub worker: bool,
+
+    /// The executor of asynchronously evaluated statements, `None` evaluates synchronously.
+    ///
+    /// With an executor the statements are wrapped in an `async` block, so they can `.await`.
+    pub executor: Option<Executor>,
 }
 
 impl Default for LinkingConfiguration {
@@ -238,6 +261,8 @@ impl Default for LinkingConfiguration {
             mutable: false,
             external_libs: HashSet::new(),
             persistent_module_code: String::new(),
+            worker: false,
+            executor: None,
         }
     }
 }
@@ -252,6 +277,11 @@ impl LinkingConfiguration {
         self
     }
 
+    /// The data type, if the app data is serialized for a worker process.
+    pub(crate) fn worker_data_type(&self) -> Option<&str> {
+        self.data_type.as_deref().filter(|_| self.worker)
+    }
+
     /// Constructs the function arguments signature.
     /// Appends result to buffer.
     pub fn construct_fn_args(&self, buf: &mut String) {
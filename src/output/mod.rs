@@ -75,13 +75,14 @@
 //!
 //!         for chg in rx.iter() {
 //!             match chg {
-//!                 OutputChange::CurrentLine(line) => {
+//!                 OutputChange::CurrentLine(line) | OutputChange::Captured(_, line) => {
 //!                     let mut lock = stdout.lock();
 //!                     erase_console_line(&mut lock);
 //!                     write!(&mut lock, "{}", line).unwrap();
 //!                     lock.flush().unwrap();
 //!                 }
 //!                 OutputChange::NewLine => writeln!(&mut stdout, "").unwrap(),
+//!                 OutputChange::Progress(_) => (), // compilation progress is not shown
 //!             }
 //!         }
 //!     })
@@ -165,6 +166,11 @@
 //! includes any input changes, it will get updated on calls to `Repl.line_input()`. This example shows
 //! a naive implementation which writes to a file as it goes.
 //!
+//! As the output is not written to the terminal, the output of evaluated code (`println!` and
+//! `eprintln!`) can be captured into the `Output` as well, arriving as `OutputChange::Captured`
+//! lines. Capturing is enabled with [`ReplData::capture_output`] and is supported on Linux, or
+//! on any platform when evaluating in a worker process.
+//!
 //! This tutorial works through the example at
 //! [`papyrus/examples/output-file.rs`](https://github.com/kurtlawrence/papyrus/blob/master/papyrus/examples/output-file.rs).
 //!
@@ -197,7 +203,7 @@
 //!
 //!         for chg in rx.iter() {
 //!             match chg {
-//!                 OutputChange::CurrentLine(line) => {
+//!                 OutputChange::CurrentLine(line) | OutputChange::Captured(_, line) => {
 //!                     output.truncate(pos);
 //!                     output.push_str(&line);
 //!                     std::fs::write("repl-output.txt", &output).unwrap();
@@ -206,6 +212,7 @@
 //!                     output.push('\n');
 //!                     pos = output.len();
 //!                 }
+//!                 OutputChange::Progress(_) => (),
 //!             }
 //!         }
 //!     })
@@ -221,6 +228,9 @@
 //! // alias the state in the variable name.
 //! let mut read = repl;
 //!
+//! // capture what evaluated code prints into the output
+//! read.data.capture_output = true;
+//!
 //! // as we want to update as input comes in,
 //! // we need to listen to output changes
 //! let rx = read.output_listen();
@@ -264,11 +274,13 @@
 //! ```
 //!
 //! [`Output`]: crate::output::Output
+//! [`ReplData::capture_output`]: crate::repl::ReplData::capture_output
 //! [`Repl`]: crate::repl::Repl
 mod any_state;
 mod read;
 mod write;
 
+use crate::compile::CompileEvent;
 use crossbeam_channel as channel;
 
 /// Line change receiving end.
@@ -300,8 +312,24 @@ pub struct Output<S> {
 pub enum OutputChange {
     /// A change was made on the current line.
     CurrentLine(String),
+    /// The current line was written by evaluated code to one of the standard streams.
+    ///
+    /// Holds the whole current line, as with `CurrentLine`, and is followed by a `NewLine`.
+    Captured(Stream, String),
     /// Output is on a new line now.
     NewLine,
+    /// Progress compiling the REPL crate. The buffer is unchanged, the current line is erased once
+    /// compilation finishes.
+    Progress(CompileEvent),
+}
+
+/// The standard stream a captured line of output was written to.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Stream {
+    /// Standard output, such as `println!`.
+    Stdout,
+    /// Standard error, such as `eprintln!`.
+    Stderr,
 }
 
 /// Only read functions available.
@@ -387,6 +415,30 @@ impl<S> Output<S> {
         }
     }
 
+    /// Sends the last line content as captured from `stream`.
+    fn send_captured(&mut self, stream: Stream) {
+        if let Some(tx) = self.tx.as_ref() {
+            let line = self
+                .line(self.lines_len().saturating_sub(1))
+                .unwrap_or("")
+                .to_string();
+
+            match tx.try_send(OutputChange::Captured(stream, line)) {
+                Ok(_) => (),
+                Err(_) => self.tx = None, // receiver disconnected, stop sending msgs
+            }
+        }
+    }
+
+    fn send_progress(&mut self, event: CompileEvent) {
+        if let Some(tx) = self.tx.as_ref() {
+            match tx.try_send(OutputChange::Progress(event)) {
+                Ok(_) => (),
+                Err(_) => self.tx = None, // receiver disconnected, stop sending msgs
+            }
+        }
+    }
+
     fn send_newline(&mut self) {
         if let Some(tx) = self.tx.as_ref() {
             match tx.try_send(OutputChange::NewLine) {
@@ -497,10 +549,11 @@ mod tests {
 
         for msg in rx.iter() {
             match msg {
-                OutputChange::CurrentLine(s) => {
+                OutputChange::CurrentLine(s) | OutputChange::Captured(_, s) => {
                     lines.last_mut().map(|x| *x = s);
                 }
                 OutputChange::NewLine => lines.push(String::new()),
+                OutputChange::Progress(_) => (),
             }
         }
 
@@ -50,6 +50,28 @@ impl Output<Write> {
         self.push_ch('\n');
     }
 
+    /// Writes a line of output captured from evaluated code, appending a new line (`\n`)
+    /// character. New line and carriage return characters in `line` are ignored.
+    ///
+    /// # Line Changes
+    /// Triggers a `Captured` line change event, followed by a new line event.
+    pub(crate) fn write_captured(&mut self, stream: Stream, line: &str) {
+        self.buf
+            .extend(line.chars().filter(|&ch| ch != '\n' && ch != '\r'));
+        self.send_captured(stream);
+        self.send_newline();
+        self.lines_pos.push(self.buf.len());
+        self.buf.push('\n');
+    }
+
+    /// Reports progress compiling the REPL crate. The buffer is unchanged.
+    ///
+    /// # Line Changes
+    /// Triggers a `Progress` event.
+    pub(crate) fn write_progress(&mut self, event: CompileEvent) {
+        self.send_progress(event);
+    }
+
     /// Erase the last line in the buffer. This does not actually _remove_
     /// the line, but removes all its contents.
     ///
@@ -132,4 +154,33 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn writing_captured() {
+        let mut o = Output::new().into_write();
+
+        let rx = o.listen();
+
+        o.write_str("> ");
+        o.write_captured(Stream::Stdout, "Hello\r\n");
+        o.write_captured(Stream::Stderr, "world");
+
+        o.close();
+
+        let msgs = rx.iter().collect::<Vec<_>>();
+
+        assert_eq!(o.buffer(), "> Hello\nworld\n");
+        assert_eq!(o.lines_len(), 3);
+
+        assert_eq!(
+            &msgs,
+            &[
+                OutputChange::CurrentLine("> ".to_owned()),
+                OutputChange::Captured(Stream::Stdout, "> Hello".to_owned()),
+                OutputChange::NewLine,
+                OutputChange::Captured(Stream::Stderr, "world".to_owned()),
+                OutputChange::NewLine
+            ]
+        );
+    }
 }
@@ -1,4 +1,5 @@
 use super::*;
+use ::kserd::encode::{Deserialize, Serialize};
 use crate::code::{
     parse_crates_in_file, validate_static_file_path, AddingStaticFileError, ModsMap, SourceCode,
 };
@@ -18,10 +19,20 @@ impl<Data> Default for ReplData<Data> {
             prompt_colour: Color::Cyan,
             out_colour: Color::BrightGreen,
             compilation_dir: default_compile_dir(),
+            session: Default::default(),
             linking: LinkingConfiguration::default(),
             editing: None,
             editing_src: None,
             static_files: StaticFiles::new(),
+            values: Default::default(),
+            out_types: Default::default(),
+            worker: None,
+            capture_output: false,
+            timeout: None,
+            crate_source: Default::default(),
+            compile_options: Default::default(),
+            compile_cache_size: 16,
+            interrupt: Default::default(),
             loadedlibs: VecDeque::new(),
             loaded_libs_size_limit: 0,
         };
@@ -35,13 +46,27 @@ impl<Data> Default for ReplData<Data> {
 
 impl<Data> ReplData<Data> {
     /// Set the compilation directory. The default is set to `$HOME/.papyrus`.
+    ///
+    /// The compilation directory can be shared by concurrent sessions, each session compiles in
+    /// its own directory under `sessions/` which is removed when the session ends. The `target`
+    /// directory is shared.
     pub fn with_compilation_dir<P: AsRef<Path>>(&mut self, dir: P) -> io::Result<&mut Self> {
         let dir = dir.as_ref();
         if !dir.exists() {
             fs::create_dir_all(dir)?;
         }
         assert!(dir.is_dir());
+        let session = compile::SessionDir::create(dir)?;
+
+        // static files already added are moved to the new session
+        for file in self.static_files.iter() {
+            let to = session.path().join("src").join(&file.path);
+            fs::create_dir_all(to.parent().expect("should exist"))?;
+            fs::copy(self.static_file_name(&file.path), to)?;
+        }
+
         self.compilation_dir = dir.to_path_buf();
+        self.session = std::sync::Mutex::new(Some(session.into()));
         Ok(self)
     }
 
@@ -76,6 +101,19 @@ impl<Data> ReplData<Data> {
         &self.mods_map
     }
 
+    /// The type of the `out#` value of a module's statement group, if it was shown.
+    ///
+    /// Types are recorded when statements are evaluated in this process. A type is forgotten once
+    /// the statement group is altered.
+    pub fn out_type(&self, mod_path: &Path, idx: usize) -> Option<&str> {
+        let src = self.mods_map.get(mod_path)?.stmts.get(idx)?.src_line();
+        self.out_types
+            .get(mod_path)
+            .and_then(|x| x.get(&idx))
+            .filter(|x| x.0 == src)
+            .map(|x| x.1.as_str())
+    }
+
     /// The current linking configuration.
     /// Not mutable as it could lead to undefined behaviour if changed.
     pub fn linking(&self) -> &LinkingConfiguration {
@@ -147,8 +185,20 @@ impl<Data> ReplData<Data> {
         removed
     }
 
-    fn static_file_name(&self, path: &Path) -> PathBuf {
-        self.compilation_dir.join("src").join(path)
+    pub(super) fn static_file_name(&self, path: &Path) -> PathBuf {
+        self.session().path().join("src").join(path)
+    }
+
+    /// The session directory, created in the compilation directory when first needed.
+    pub(super) fn session(&self) -> std::sync::Arc<compile::SessionDir> {
+        let mut session = self.session.lock().unwrap_or_else(|e| e.into_inner());
+        let session = session.get_or_insert_with(|| {
+            let dir = &self.compilation_dir;
+            compile::SessionDir::create(dir)
+                .unwrap_or_else(|_| compile::SessionDir::unshared(dir))
+                .into()
+        });
+        std::sync::Arc::clone(session)
     }
 
     /// Clears the cached loaded libraries.
@@ -161,6 +211,85 @@ impl<Data> ReplData<Data> {
         self.loadedlibs.clear()
     }
 
+    /// Drops the values bound by evaluated statements.
+    ///
+    /// The next evaluation will run all the statements again.
+    pub fn clear_values(&mut self) {
+        self.values.clear()
+    }
+
+    /// A handle to interrupt the in-flight compilation or evaluation, such as on Ctrl-C.
+    ///
+    /// Compilation is stopped by killing `cargo`. How an evaluation is stopped depends on where it
+    /// runs:
+    /// - in a [worker process](ReplData::with_worker) the worker is killed, losing the values
+    ///   bound by evaluated statements, and is restarted on the next evaluation,
+    /// - in process with no app data linked the evaluation is abandoned on its thread, which keeps
+    ///   running until the evaluated code returns, and the current module's values are lost,
+    /// - in process with app data linked the evaluation can not be stopped, as the borrow of the
+    ///   app data can not be abandoned. It runs to completion and a note is written to the
+    ///   output. Use a worker process or asynchronous evaluation if this is needed.
+    ///
+    /// The handle can be held across evaluations, interrupting whichever is in flight.
+    pub fn interrupt_handle(&self) -> compile::Interrupt {
+        self.interrupt.clone()
+    }
+
+    /// Evaluate in a long lived worker process, rather than loading the compiled library into this
+    /// process.
+    ///
+    /// A crash in the evaluated code, such as a segfault, stack overflow, or abort, only takes down
+    /// the worker. The crash is reported as an evaluation error, the values bound by evaluated
+    /// statements are lost, and the worker is restarted on the next evaluation.
+    ///
+    /// The worker can not borrow the app data. Instead it is serialized with `serde` and passed to
+    /// the worker, and a mutating block passes back the altered data. The linked data type must
+    /// implement `Serialize` and `Deserialize`, see the [_linking_ module](crate::linking).
+    pub fn with_worker(&mut self) -> &mut Self
+    where
+        Data: Serialize + for<'de> Deserialize<'de>,
+    {
+        if self.worker.is_none() {
+            self.values.clear();
+            self.linking.worker = true;
+            self.worker = Some(compile::WorkerBackend::new());
+        }
+        self
+    }
+
+    /// Evaluate by loading the compiled library into this process. This is the default.
+    ///
+    /// Any worker process is stopped.
+    pub fn with_in_process(&mut self) -> &mut Self {
+        if self.worker.is_some() {
+            self.values.clear();
+            self.linking.worker = false;
+            self.worker = None;
+        }
+        self
+    }
+
+    /// Evaluation happens in a worker process.
+    pub fn uses_worker(&self) -> bool {
+        self.worker.is_some()
+    }
+
+    /// Evaluate statements asynchronously, wrapped in an `async` block which `executor` drives to
+    /// completion, so they can `.await`.
+    ///
+    /// Interrupting or timing out an evaluation drops its future, including when app data is
+    /// borrowed.
+    pub fn with_async(&mut self, executor: compile::Executor) -> &mut Self {
+        self.linking.executor = Some(executor);
+        self
+    }
+
+    /// Evaluate statements synchronously. This is the default.
+    pub fn with_sync(&mut self) -> &mut Self {
+        self.linking.executor = None;
+        self
+    }
+
     /// Not meant to used by developer. Use the macros instead.
     /// [See _linking_ module](../pfh/linking.html)
     ///
@@ -179,6 +308,21 @@ impl<Data> ReplData<Data> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn session_dir_is_lazy() {
+        let mut data: ReplData<()> = ReplData::default();
+        assert!(data.session.lock().unwrap().is_none());
+
+        data.with_compilation_dir("./target/lazy-session-test")
+            .unwrap();
+        assert!(data.session.lock().unwrap().is_some());
+        assert!(data.session().path().starts_with(
+            std::env::current_dir()
+                .unwrap()
+                .join("target/lazy-session-test")
+        ));
+    }
+
     #[test]
     fn static_files_test() {
         let mut data: ReplData<()> = ReplData::default();
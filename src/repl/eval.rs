@@ -16,6 +16,8 @@ impl<D> Repl<Evaluate, D> {
         use std::cell::Cell;
         use std::rc::Rc;
 
+        self.data.interrupt.reset();
+
         let ptr = Rc::into_raw(Rc::new(app_data));
 
         // as I am playing around with pointers here, I am going to do assertions in the rebuilding
@@ -52,6 +54,10 @@ impl<D> Repl<Evaluate, D> {
 
         let clone = Arc::clone(app_data);
 
+        // reset before returning, so cancelling straight away is not missed
+        let interrupt = self.data.interrupt_handle();
+        interrupt.reset();
+
         std::thread::spawn(move || {
             let eval = map_variants(
                 self,
@@ -62,7 +68,7 @@ impl<D> Repl<Evaluate, D> {
             tx.send(eval).unwrap();
         });
 
-        Evaluating { jh: rx }
+        Evaluating { jh: rx, interrupt }
     }
 
     /// Begin listening to line change events on the output.
@@ -89,6 +95,13 @@ impl<D> Evaluating<D> {
         !self.jh.is_empty()
     }
 
+    /// Interrupt the evaluation, see [`ReplData::interrupt_handle`].
+    ///
+    /// The result is still obtained through [`wait`](Evaluating::wait).
+    pub fn cancel(&self) {
+        self.interrupt.interrupt()
+    }
+
     /// Waits for the evaluating to finish before return the result.
     /// If evaluating is `completed` this will return immediately.
     pub fn wait(self) -> EvalResult<D> {
@@ -124,14 +137,19 @@ where
     // map variants into Result<HandleInputResult, EvalSignal>
     let mapped = match result {
         InputResult::Command(cmds) => {
-            let r = data.handle_command(&cmds, &mut output, obtain_mut_data);
+            let mut obtain_mut_data = Some(obtain_mut_data);
+            let r = data.handle_command(&cmds, &mut output, &mut obtain_mut_data);
             keep_mutating = data.linking.mutable; // a command can alter the mutating state, needs to persist
-            r.map(EvalOutput::Print)
+            match r {
+                Ok(Handled::Print(s)) => Ok(EvalOutput::Print(s)),
+                Ok(Handled::TypeOf(expr)) => Ok(data.type_of(&expr, &mut output)),
+                Err(sig) => Err(sig),
+            }
         }
         InputResult::Program(input) => {
             Ok(data.handle_program(input, &mut output, obtain_mut_data, obtain_brw_data))
         }
-        InputResult::InputError(err) => Ok(EvalOutput::Print(Cow::Owned(err))),
+        InputResult::InputError(err) => Ok(EvalOutput::Print(Cow::Owned(err.to_string()))),
         InputResult::Eof => Err(Signal::Exit),
         _ => Ok(EvalOutput::Print(Cow::Borrowed(""))),
     };
@@ -160,13 +178,19 @@ where
     }
 }
 
+/// A handled command, which may need to evaluate code.
+enum Handled {
+    Print(Cow<'static, str>),
+    TypeOf(String),
+}
+
 impl<D> ReplData<D> {
     fn handle_command<F, R, W>(
         &mut self,
         cmds: &str,
         writer: &mut W,
-        obtain_mut_app_data: F,
-    ) -> Result<Cow<'static, str>, Signal>
+        obtain_mut_app_data: &mut Option<F>,
+    ) -> Result<Handled, Signal>
     where
         F: FnOnce() -> R,
         R: DerefMut<Target = D>,
@@ -201,8 +225,12 @@ impl<D> ReplData<D> {
                 }
 
                 CommandResult::ActionOnReplData(action) => Cow::Owned(action(self, writer)),
+                CommandResult::TypeOf(expr) => return Ok(Handled::TypeOf(expr)),
                 CommandResult::ActionOnAppData(action) => {
-                    let mut r = obtain_mut_app_data();
+                    let obtain = obtain_mut_app_data
+                        .take()
+                        .expect("app data is only obtained once");
+                    let mut r = obtain();
                     let app_data: &mut D = r.borrow_mut();
                     let s = action(app_data, self, writer);
                     Cow::Owned(s)
@@ -212,7 +240,97 @@ impl<D> ReplData<D> {
             _ => Cow::Borrowed(""),
         };
 
-        Ok(tuple)
+        Ok(Handled::Print(tuple))
+    }
+
+    /// Evaluate an expression's type name in the current module, without running any code.
+    ///
+    /// A [type probe](code::type_probe) is added to a copy of the sources and compiled, and only
+    /// the probe is called. The sources, values and app data are not touched.
+    fn type_of(&mut self, expr: &str, writer: &mut Output<output::Write>) -> EvalOutput {
+        match crate::input::parse_program(expr) {
+            InputResult::Program(input)
+                if input.stmts.len() == 1
+                    && !input.stmts[0].semi
+                    && input.items.is_empty()
+                    && input.crates.is_empty() => {}
+            InputResult::InputError(e) => return EvalOutput::Print(Cow::Owned(e.to_string())),
+            _ => return EvalOutput::Print(Cow::Borrowed("type expects an expression")),
+        }
+
+        let mut mods_map = self.mods_map.clone();
+        let probe = code::type_probe(self.current_src(), &self.linking, expr);
+        mods_map
+            .get_mut(&self.current_mod)
+            .expect("current module exists")
+            .items
+            .push((probe, false));
+
+        // as for an evaluation, compilation is retried with the bindings which can not be stored
+        // excluded from the value store
+        let mut persisted = code::PersistedMap::new();
+        loop {
+            let session = self.session();
+            let res = compile::build_compile_dir(
+                session.path(),
+                &mods_map,
+                &self.linking,
+                &self.static_files,
+                &persisted,
+                &self.compile_options,
+            );
+            let srcmap = match res {
+                Ok(map) => map,
+                Err(e) => {
+                    return EvalOutput::Print(Cow::Owned(format!(
+                        "failed to build compile directory: {}",
+                        e
+                    )))
+                }
+            };
+
+            let cancel = compile::Cancel::new(self.interrupt.clone(), None);
+            let lib_file = session.lock_build(&cancel).and_then(|lock| {
+                compile::compile_cached(
+                    &session,
+                    &self.linking,
+                    &self.crate_source,
+                    &self.compile_options,
+                    self.compile_cache_size,
+                    &cancel,
+                    |event| writer.write_progress(event.clone()),
+                )
+                .map(|f| (f, lock))
+            });
+
+            writer.erase_last_line();
+
+            let ((lib_file, _), build_lock) = match lib_file {
+                Ok(f) => f,
+                Err(e) => {
+                    let mut retry = false;
+                    for (path, name) in e.unpersistable(&srcmap) {
+                        retry |= persisted
+                            .entry(path.to_path_buf())
+                            .or_default()
+                            .excluded
+                            .insert(name);
+                    }
+                    if retry {
+                        continue;
+                    }
+                    return EvalOutput::Print(Cow::Owned(e.render(&srcmap, &mods_map)));
+                }
+            };
+
+            // only the probe is called, the library is unloaded once it returns
+            let lib_file = compile::unshackle_library_file(lib_file);
+            drop(build_lock);
+            return match compile::type_name(&lib_file) {
+                Ok(name) => EvalOutput::Print(Cow::Owned(name)),
+                Err(e) => EvalOutput::Print(Cow::Owned(e.to_string())),
+            };
+        }
     }
 
     fn handle_program<Fmut, Fbrw, Rmut, Rbrw>(
@@ -232,6 +350,17 @@ impl<D> ReplData<D> {
 
         let has_stmts = !input.stmts.is_empty();
 
+        // a synchronous evaluation borrowing app data in process can not be abandoned
+        let uninterruptible = self.worker.is_none()
+            && self.linking.data_type.is_some()
+            && self.linking.executor.is_none();
+        if uninterruptible && has_stmts && self.timeout.is_some() {
+            return EvalOutput::Print(Cow::Borrowed(
+                "cannot interrupt an evaluation borrowing app data, so it can not have a timeout: \
+                 evaluate in a worker or asynchronously, or remove the timeout",
+            ));
+        }
+
         let (lstmts, litem, lcrates) = {
             let src = self.current_src();
             (src.stmts.len(), src.items.len(), src.crates.len())
@@ -300,69 +429,298 @@ impl<D> ReplData<D> {
             }
         };
 
-        // build directory
-        let res = compile::build_compile_dir(
-            &self.compilation_dir,
+        let mut persisted = self.values.persisted(
             &self.mods_map,
             &self.linking,
             &self.static_files,
+            &self.compile_options,
         );
-        if let Err(e) = res {
-            maybe_pop_input(self); // failed so don't save
-            return EvalOutput::Print(Cow::Owned(format!(
-                "failed to build compile directory: {}",
-                e
-            )));
-        }
 
-        // compile
-        let lib_file = compile::compile(&self.compilation_dir, &self.linking, |line| {
+        // the new input, for showing only its warnings
+        let current_mod = self.current_mod.clone();
+        let is_new_input = |loc: &code::SrcLocation| {
+            loc.mod_path == current_mod.as_path()
+                && match loc.kind {
+                    code::SrcKind::Stmt { grp, .. } => has_stmts && grp == stmt_idx,
+                    code::SrcKind::Item(i) => i >= item_idx && i < item_idx + nitems,
+                }
+        };
+        let mut warned = false;
+
+        let mut obtain_mut_data = Some(obtain_mut_data);
+        let mut obtain_brw_data = Some(obtain_brw_data);
+        let mut mut_data = None;
+        let mut brw_data = None;
+
+        // compilation can be retried with bindings excluded from the value store, and evaluation
+        // retried if the compiled library could not restore the stored values
+        loop {
+            // build directory
+            let session = self.session();
+            let res = compile::build_compile_dir(
+                session.path(),
+                &self.mods_map,
+                &self.linking,
+                &self.static_files,
+                &persisted,
+                &self.compile_options,
+            );
+            let srcmap = match res {
+                Ok(map) => map,
+                Err(e) => {
+                    maybe_pop_input(self); // failed so don't save
+                    return EvalOutput::Print(Cow::Owned(format!(
+                        "failed to build compile directory: {}",
+                        e
+                    )));
+                }
+            };
+
+            // compile, holding the build lock until the library is unshackled
+            let cancel = compile::Cancel::new(self.interrupt.clone(), None);
+            let lib_file = session.lock_build(&cancel).and_then(|lock| {
+                compile::compile_cached(
+                    &session,
+                    &self.linking,
+                    &self.crate_source,
+                    &self.compile_options,
+                    self.compile_cache_size,
+                    &cancel,
+                    |event| writer.write_progress(event.clone()),
+                )
+                .map(|f| (f, lock))
+            });
+
             writer.erase_last_line();
-            writer.write_str(line);
-        });
 
-        writer.erase_last_line();
+            let ((lib_file, warnings), build_lock) = match lib_file {
+                Ok(f) => f,
+                Err(e) => {
+                    // bindings which can not be stored (such as borrows) are excluded and
+                    // compilation retried
+                    let mut retry = false;
+                    for (path, name) in e.unpersistable(&srcmap) {
+                        retry |= persisted
+                            .entry(path.to_path_buf())
+                            .or_default()
+                            .excluded
+                            .insert(name);
+                    }
+                    if retry {
+                        continue;
+                    }
+
+                    let e = e.render(&srcmap, &self.mods_map); // render against the failing inputs
+                    maybe_pop_input(self); // failed so don't save
+                    return EvalOutput::Print(Cow::Owned(e));
+                }
+            };
 
-        let lib_file = match lib_file {
-            Ok(f) => f,
-            Err(e) => {
-                maybe_pop_input(self); // failed so don't save
-                return EvalOutput::Print(Cow::Owned(format!("{}", e)));
+            // warnings are shown once, evaluation can compile again
+            if !warned {
+                let filter = |loc: &code::SrcLocation| match self.compile_options.warnings {
+                    compile::Warnings::Off => false,
+                    compile::Warnings::NewInput => is_new_input(loc),
+                    compile::Warnings::All => true,
+                };
+                writer.write_str(&compile::render_warnings(
+                    &warnings,
+                    &srcmap,
+                    &self.mods_map,
+                    filter,
+                ));
+                warned = true;
+            }
+
+            if !has_stmts {
+                // this will keep inputs, might not be preferrable to do so in mutating state?
+                return EvalOutput::Print(Cow::Borrowed("")); // do not execute if no extra statements have been added
             }
-        };
 
-        if has_stmts {
             // execute
             let exec_res = {
                 // once compilation succeeds and we are going to evaluate it (which libloads) we
                 // first rename the files to avoid locking for the next compilation that might
                 // happen
                 let lib_file = compile::unshackle_library_file(lib_file);
+                drop(build_lock);
 
                 let mut fn_name = String::new();
                 code::eval_fn_name(&code::into_mod_path_vec(self.current_mod()), &mut fn_name);
 
-                if self.linking.mutable {
-                    let mut r = obtain_mut_data();
-                    let app_data: &mut D = r.borrow_mut();
-                    compile::exec(&lib_file, &fn_name, app_data)
+                let cancel = compile::Cancel::new(self.interrupt.clone(), self.timeout);
+
+                let capture = self.capture_output;
+                if let Some(worker) = self.worker.as_mut() {
+                    let app_data = if self.linking.data_type.is_none() {
+                        compile::AppData::None
+                    } else if self.linking.mutable {
+                        let r = mut_data.get_or_insert_with(|| {
+                            (obtain_mut_data.take().expect("data is only obtained once"))()
+                        });
+                        let app_data: &mut D = r.borrow_mut();
+                        compile::AppData::Mut(app_data)
+                    } else {
+                        let r = brw_data.get_or_insert_with(|| {
+                            (obtain_brw_data.take().expect("data is only obtained once"))()
+                        });
+                        let app_data: &D = (*r).borrow();
+                        compile::AppData::Brw(app_data)
+                    };
+
+                    let res = worker.exec(
+                        &self.compilation_dir,
+                        self.compile_options.toolchain.as_deref(),
+                        &lib_file,
+                        &fn_name,
+                        &self.current_mod,
+                        self.values.live_mods(),
+                        app_data,
+                        &cancel,
+                        Some(&mut *writer).filter(|_| capture),
+                    );
+
+                    match res {
+                        Ok((kserd, names)) => {
+                            self.values.mirror(&self.current_mod, names);
+                            Ok((kserd, None))
+                        }
+                        Err(compile::WorkerError::Crashed(e)) => {
+                            // the worker held all the values
+                            self.values.clear();
+                            Err(Cow::Owned(e))
+                        }
+                        Err(compile::WorkerError::Panic(panic)) => Err(Cow::Owned(
+                            compile::ExecError::Panic(panic).render(&srcmap, &self.mods_map),
+                        )),
+                        Err(compile::WorkerError::Eval(e)) => Err(Cow::Owned(e)),
+                        Err(compile::WorkerError::Cancelled(c)) => {
+                            self.values.clear();
+                            Err(Cow::Owned(format!(
+                                "evaluation {}, the worker will be restarted on the next evaluation",
+                                c
+                            )))
+                        }
+                    }
                 } else {
-                    let r = obtain_brw_data();
-                    let app_data: &D = r.borrow();
-                    compile::exec(&lib_file, &fn_name, app_data)
+                    let mutable = self.linking.mutable;
+                    let no_data = self.linking.data_type.is_none();
+                    let asynchronous = self.linking.executor.is_some();
+                    let store = self.values.values_mut(&self.current_mod);
+
+                    let mut run = || {
+                        if no_data {
+                            // without a borrow of app data the evaluation can be abandoned
+                            compile::exec_cancellable(&lib_file, &fn_name, store, &cancel)
+                        } else if mutable {
+                            let r = mut_data.get_or_insert_with(|| {
+                                (obtain_mut_data.take().expect("data is only obtained once"))()
+                            });
+                            let app_data: &mut D = r.borrow_mut();
+                            compile::exec(&lib_file, &fn_name, store, app_data)
+                        } else {
+                            let r = brw_data.get_or_insert_with(|| {
+                                (obtain_brw_data.take().expect("data is only obtained once"))()
+                            });
+                            let app_data: &D = (*r).borrow();
+                            compile::exec(&lib_file, &fn_name, store, app_data)
+                        }
+                    };
+
+                    // a borrow of app data can not be abandoned, but an asynchronous evaluation
+                    // can be cancelled through the library
+                    let mut run = || {
+                        if no_data || !asynchronous {
+                            return run();
+                        }
+                        match compile::watch(&lib_file, &cancel, &mut run) {
+                            (Err(_), Some(c)) => Err(compile::ExecError::Cancelled(c)),
+                            (r, _) => r,
+                        }
+                    };
+
+                    let res = if capture {
+                        compile::capture(writer, run)
+                    } else {
+                        run()
+                    };
+
+                    if uninterruptible && cancel.check().is_err() {
+                        writer.write_line(
+                            "cannot interrupt an evaluation borrowing app data, it ran to completion",
+                        );
+                    }
+
+                    let mods = &self.mods_map;
+                    res.map(|(kserd, lib)| (kserd, Some(lib)))
+                        .map_err(|e| Cow::Owned(e.render(&srcmap, mods)))
                 }
             };
-            match exec_res {
+
+            let out_type = self.values.take_type(&self.current_mod);
+
+            return match exec_res {
+                Ok((_, lib)) if self.values.take_rebuild(&self.current_mod) => {
+                    // the values could not be restored, run all the statements
+                    persisted.remove(&self.current_mod);
+                    if let Some(lib) = lib {
+                        add_to_limit_vec(
+                            &mut self.loadedlibs,
+                            Box::new(lib),
+                            self.loaded_libs_size_limit,
+                        );
+                    }
+                    continue;
+                }
+                Ok((kserd, lib)) if self.values.take_returned(&self.current_mod) => {
+                    // the statements after the return did not run, so the input is not kept
+                    maybe_pop_input(self);
+                    if let Some(lib) = lib {
+                        add_to_limit_vec(
+                            &mut self.loadedlibs,
+                            Box::new(lib),
+                            self.loaded_libs_size_limit,
+                        );
+                    }
+                    EvalOutput::Print(Cow::Owned(format!("returned early: {}", kserd)))
+                }
                 Ok((kserd, lib)) => {
+                    if self.linking.mutable {
+                        maybe_pop_input(self); // don't save mutating inputs
+                                               // the stored values could have been altered by statements which are no
+                                               // longer kept
+                        self.values.remove(&self.current_mod);
+                    } else {
+                        self.values.evaluated(
+                            &self.current_mod,
+                            &self.mods_map,
+                            &self.linking,
+                            &self.static_files,
+                            &self.compile_options,
+                        );
+                        // the shown value is the last statement group's
+                        let stmts = &self.current_src().stmts;
+                        let shown = stmts.len().checked_sub(1).map(|i| (i, stmts[i].src_line()));
+                        if let (Some(ty), Some((idx, src))) = (out_type, shown) {
+                            self.out_types
+                                .entry(self.current_mod.clone())
+                                .or_default()
+                                .insert(idx, (src, ty));
+                        }
+                    }
+
                     // store vec, maybe
-                    add_to_limit_vec(
-                        &mut self.loadedlibs,
-                        Box::new(lib),
-                        self.loaded_libs_size_limit,
-                    );
+                    if let Some(lib) =
+                        lib.and_then(|lib| self.values.keep_lib(&self.current_mod, lib))
+                    {
+                        add_to_limit_vec(
+                            &mut self.loadedlibs,
+                            Box::new(lib),
+                            self.loaded_libs_size_limit,
+                        );
+                    }
 
                     if self.linking.mutable {
-                        maybe_pop_input(self); // don't save mutating inputs
                         EvalOutput::Print(Cow::Owned(format!("finished mutating block: {}", kserd)))
                     // don't print as `out#`
                     } else {
@@ -370,13 +728,12 @@ impl<D> ReplData<D> {
                     }
                 }
                 Err(e) => {
+                    // values moved out of the store are lost with a panic
+                    self.values.remove(&self.current_mod);
                     maybe_pop_input(self); // failed so don't save
-                    EvalOutput::Print(Cow::Borrowed(e))
+                    EvalOutput::Print(e)
                 }
-            }
-        } else {
-            // this will keep inputs, might not be preferrable to do so in mutating state?
-            EvalOutput::Print(Cow::Borrowed("")) // do not execute if no extra statements have been added
+            };
         }
     }
 
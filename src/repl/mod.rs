@@ -6,10 +6,14 @@ mod data;
 mod eval;
 mod print;
 mod read;
+mod session;
+
+pub use self::session::{SessionError, SESSION_VERSION};
 
 use crate::{
     cmds::CommandResult,
     code::{ModsMap, StaticFile, StaticFiles},
+    compile,
     input::InputResult,
     linking::{self, LinkingConfiguration},
     output::{self, Output},
@@ -20,7 +24,7 @@ use crossbeam_channel::Receiver;
 use kserd::Kserd;
 use std::{
     borrow::Cow,
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
     fmt, fs, io,
     marker::PhantomData,
     path::{Path, PathBuf},
@@ -60,6 +64,9 @@ pub struct ReplData<Data> {
     /// The directory for which compilation is done within.
     /// Defaults to `$HOME/.papyrus/`.
     compilation_dir: PathBuf,
+    /// This session's directory in the compilation directory, so concurrent sessions do not
+    /// overwrite each other's code. Created when first needed.
+    session: std::sync::Mutex<Option<std::sync::Arc<compile::SessionDir>>>,
 
     /// The external crate linking configuration,
     linking: LinkingConfiguration,
@@ -85,8 +92,63 @@ pub struct ReplData<Data> {
     /// Store of static files written to disk and to be included in REPL cycle.
     static_files: StaticFiles,
 
+    /// The values bound by evaluated statements.
+    values: compile::ValueStore,
+    /// The types of the shown `out#` values of each module, with the source of the statement
+    /// group. A type is only valid while the group's source is unchanged.
+    out_types: BTreeMap<PathBuf, BTreeMap<usize, (String, String)>>,
+
+    /// Evaluate in a worker process rather than in this process.
+    worker: Option<compile::WorkerBackend<Data>>,
+
+    /// Capture what evaluated code writes to stdout and stderr into the output, as
+    /// [`OutputChange::Captured`] lines.
+    ///
+    /// Defaults to `false`. The redirection is process wide, so anything written to stdout or
+    /// stderr while evaluating is captured. Do not enable capturing if the output listener
+    /// writes to the terminal itself. Only supported on Linux, except for evaluation in a worker
+    /// process, whose output is always piped to the host.
+    ///
+    /// [`OutputChange::Captured`]: crate::output::OutputChange::Captured
+    pub capture_output: bool,
+
+    /// Stop an evaluation which runs longer than this, from when the compiled library is run.
+    ///
+    /// Defaults to `None`, no timeout. A timed out evaluation is handled as an
+    /// [interrupted](ReplData::interrupt_handle) one. An evaluation which can not be interrupted,
+    /// one borrowing app data synchronously in process, is not run while a timeout is set.
+    pub timeout: Option<std::time::Duration>,
+
+    /// Where `cargo` sources crates from, allowing compilation without network access.
+    ///
+    /// Defaults to online, from crates.io. Compile with `--offline` or `--frozen` through
+    /// [`CrateSource::network`], and replace crates.io with a vendored directory or local registry
+    /// through [`CrateSource::replacement`]. A crate which is not available is reported as
+    /// [`CompilationError::CrateUnavailable`].
+    ///
+    /// [`CrateSource::network`]: crate::compile::CrateSource::network
+    /// [`CrateSource::replacement`]: crate::compile::CrateSource::replacement
+    /// [`CompilationError::CrateUnavailable`]: crate::compile::CompilationError::CrateUnavailable
+    pub crate_source: compile::CrateSource,
+
+    /// How the REPL crate is built: the profile, edition, extra `rustc` arguments, and
+    /// toolchain. Defaults to a debug build with the 2018 edition. Also set with the `:build`
+    /// commands.
+    pub compile_options: compile::CompileOptions,
+
+    /// The number of compiled libraries kept in the compilation directory for reuse.
+    ///
+    /// Defaults to 16. If the generated source, manifest, linked libraries and crate source are
+    /// unchanged from a previous compilation, the cached library is used rather than compiling
+    /// again. The least recently used libraries are removed once the limit is reached. Set to zero
+    /// to disable the cache.
+    pub compile_cache_size: usize,
+
+    /// Interrupts the in-flight compilation or evaluation.
+    interrupt: compile::Interrupt,
+
     /// Stored loaded libraries of the papyrus mem code.
-    loadedlibs: VecDeque<Box<libloading::Library>>,
+    loadedlibs: VecDeque<Box<compile::LoadedLibrary>>,
     /// Limit the number of loaded libraries that are kept in memory and not dropped.
     ///
     /// There exists a use pattern which can create segmentation faults if code defined in the
@@ -98,6 +160,13 @@ pub struct ReplData<Data> {
     ///
     /// The default is to keep the size limit at zero, thus ensuring no libraries are kept in
     /// memory. This is recommended unless issues are arising from esoteric use cases.
+    ///
+    /// Libraries which produced values held in the value store are kept regardless of the limit,
+    /// until the values are dropped.
+    ///
+    /// The renamed library file is deleted once its library is dropped. Files left behind by a
+    /// session which did not exit cleanly are deleted when the REPL's session directory is created,
+    /// and when the compilation directory is set.
     pub loaded_libs_size_limit: usize,
 }
 
@@ -117,6 +186,7 @@ pub struct Evaluate {
 /// Repl evaluating state. This can be constructed via a `eval_async` call.
 pub struct Evaluating<D> {
     jh: Receiver<EvalResult<D>>,
+    interrupt: compile::Interrupt,
 }
 
 /// Repl print state.
@@ -1,4 +1,5 @@
 use super::*;
+use crate::code::Repr;
 use ::kserd::fmt::FormattingConfig;
 
 /// > **These methods are available when the REPL is in the [`Print`] state.**
@@ -35,11 +36,17 @@ impl<D> Repl<Print, D> {
 
                 let out_stmt = format!("[out{}]", num);
 
+                // a fallback representation is a string, shown unquoted and marked
+                let value = match Repr::of(&k) {
+                    Repr::Kserd => k.as_str_with_config(config),
+                    repr => format!("({}) {}", repr, k.str().unwrap_or_default()),
+                };
+
                 let line = format!(
                     "{} {}: {}",
                     repl_data.cmdtree.path().color(repl_data.prompt_colour),
                     out_stmt.color(repl_data.out_colour),
-                    k.as_str_with_config(config)
+                    value
                 );
 
                 output.write_line(&line);
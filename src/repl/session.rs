@@ -0,0 +1,699 @@
+use super::*;
+use crate::code::{
+    parse_crates_in_file, validate_static_file_path, AddingStaticFileError, CrateType, SourceCode,
+    Statement, StaticFile, StmtGrp,
+};
+use serde_json::{json, Value};
+use std::error;
+
+/// The version of the session file format written by [`ReplData::save_session`].
+pub const SESSION_VERSION: u64 = 2;
+
+/// Error loading or saving a session.
+#[derive(Debug)]
+pub enum SessionError {
+    /// Reading or writing the session file, or a static file, failed.
+    Io(io::Error),
+    /// The session file is not valid, with a description of the problem.
+    Malformed(String),
+    /// The session file has a version this `papyrus` can not read.
+    UnsupportedVersion(u64),
+    /// The session was saved with a different app data type, which is given.
+    DataTypeMismatch(Option<String>),
+    /// A static file in the session could not be added.
+    StaticFile(AddingStaticFileError),
+    /// An external library in the session could not be linked.
+    Extern(PathBuf, io::Error),
+}
+
+impl error::Error for SessionError {}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::Io(e) => write!(f, "an io error occurred: {}", e),
+            SessionError::Malformed(e) => write!(f, "session file is malformed: {}", e),
+            SessionError::UnsupportedVersion(v) => write!(
+                f,
+                "session file version {} is not supported, expecting version {}",
+                v, SESSION_VERSION
+            ),
+            SessionError::DataTypeMismatch(Some(t)) => {
+                write!(f, "session was saved with app data type `{}`", t)
+            }
+            SessionError::DataTypeMismatch(None) => {
+                write!(f, "session was saved without an app data type")
+            }
+            SessionError::StaticFile(e) => write!(f, "{}", e),
+            SessionError::Extern(path, e) => {
+                write!(f, "failed to link `{}`: {}", path.display(), e)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for SessionError {
+    fn from(e: io::Error) -> Self {
+        SessionError::Io(e)
+    }
+}
+
+impl<Data> ReplData<Data> {
+    /// Save the session to a file, which can be loaded with [`load_session`].
+    ///
+    /// A session is the REPL inputs of every module, the current module, static files, the
+    /// persistent module code, linked external libraries, the executor, the compile options, and
+    /// the mutating and editing state. The
+    /// values bound by evaluated statements are _not_ saved, the statements are run again on the
+    /// first evaluation after loading.
+    ///
+    /// # Format
+    /// The session is a json object, with `version` being [`SESSION_VERSION`]. A change to the
+    /// format increments the version.
+    ///
+    /// ```json
+    /// {
+    ///   "version": 2,
+    ///   "data_type": "String",
+    ///   "current_mod": "lib",
+    ///   "mods": [
+    ///     {
+    ///       "path": "lib",
+    ///       "crates": ["extern crate rand;"],
+    ///       "items": [{ "code": "fn a() {}", "top": false }],
+    ///       "stmts": [[{ "expr": "let a = 1", "semi": true }, { "expr": "a", "semi": false }]]
+    ///     }
+    ///   ],
+    ///   "static_files": [{ "path": "foo.rs", "code": "pub fn foo() {}" }],
+    ///   "persistent_module_code": "",
+    ///   "externs": [{ "path": "/path/to/libname.rlib", "alias": null }],
+    ///   "executor": { "kind": "custom", "call": "futures::executor::block_on" },
+    ///   "compile_options": {
+    ///     "profile": "debug",
+    ///     "edition": "2018",
+    ///     "rustc_args": ["-C", "target-cpu=native"],
+    ///     "toolchain": null,
+    ///     "warnings": "off",
+    ///     "clippy": false,
+    ///     "fast_path": true
+    ///   },
+    ///   "mutable": false,
+    ///   "editing": { "kind": "stmt", "index": 0 }
+    /// }
+    /// ```
+    ///
+    /// `data_type`, `executor` and `editing` can be `null`, a `null` executor evaluates
+    /// synchronously. `executor.kind` is `block-on` or `custom`, a custom executor has its `call`.
+    /// The compile options are written as the `:build` command takes them, `profile` is `debug`,
+    /// `release` or an opt-level and `warnings` is `off`, `new` or `all`. `toolchain` can be
+    /// `null`. `editing.kind` is one of `stmt`, `item`, or `crate`. Static file `code` is the whole file, including any leading `extern crate`s.
+    ///
+    /// [`load_session`]: ReplData::load_session
+    pub fn save_session<P: AsRef<Path>>(&self, path: P) -> Result<(), SessionError> {
+        let mods = self
+            .mods_map
+            .iter()
+            .map(|(path, src)| {
+                json!({
+                    "path": path_str(path),
+                    "crates": src.crates.iter().map(|c| &c.src_line).collect::<Vec<_>>(),
+                    "items": src
+                        .items
+                        .iter()
+                        .map(|(code, top)| json!({ "code": code, "top": top }))
+                        .collect::<Vec<_>>(),
+                    "stmts": src
+                        .stmts
+                        .iter()
+                        .map(|grp| {
+                            grp.0
+                                .iter()
+                                .map(|s| json!({ "expr": s.expr, "semi": s.semi }))
+                                .collect::<Vec<_>>()
+                        })
+                        .collect::<Vec<_>>(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let static_files = self
+            .static_files
+            .iter()
+            .map(|sf| {
+                // the crates are parsed off the code before it is written to disk
+                let mut code = sf
+                    .crates
+                    .iter()
+                    .map(|c| c.src_line.as_str())
+                    .collect::<String>();
+                code.push_str(&fs::read_to_string(self.static_file_name(&sf.path))?);
+                Ok(json!({ "path": path_str(&sf.path), "code": code }))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut externs = self
+            .linking
+            .external_libs
+            .iter()
+            .map(|e| json!({ "path": path_str(e.lib_path()), "alias": e.alias() }))
+            .collect::<Vec<_>>();
+        externs.sort_by(|a, b| a["path"].as_str().cmp(&b["path"].as_str()));
+
+        let executor = self.linking.executor.as_ref().map(|e| match e {
+            compile::Executor::BlockOn => json!({ "kind": "block-on" }),
+            compile::Executor::Custom(call) => json!({ "kind": "custom", "call": call }),
+        });
+
+        let options = &self.compile_options;
+        let compile_options = json!({
+            "profile": match &options.profile {
+                compile::Profile::Debug => "debug",
+                compile::Profile::Release => "release",
+                compile::Profile::OptLevel(level) => level,
+            },
+            "edition": options.edition.to_string(),
+            "rustc_args": options.rustc_args,
+            "toolchain": options.toolchain,
+            "warnings": match options.warnings {
+                compile::Warnings::Off => "off",
+                compile::Warnings::NewInput => "new",
+                compile::Warnings::All => "all",
+            },
+            "clippy": options.clippy,
+            "fast_path": options.fast_path,
+        });
+
+        let editing = self.editing.map(|ei| {
+            let kind = match ei.editing {
+                Editing::Stmt => "stmt",
+                Editing::Item => "item",
+                Editing::Crate => "crate",
+            };
+            json!({ "kind": kind, "index": ei.index })
+        });
+
+        let session = json!({
+            "version": SESSION_VERSION,
+            "data_type": self.linking.data_type,
+            "current_mod": path_str(&self.current_mod),
+            "mods": mods,
+            "static_files": static_files,
+            "persistent_module_code": self.linking.persistent_module_code,
+            "externs": externs,
+            "executor": executor,
+            "compile_options": compile_options,
+            "mutable": self.linking.mutable,
+            "editing": editing,
+        });
+
+        let s = serde_json::to_string_pretty(&session)
+            .map_err(|e| SessionError::Malformed(e.to_string()))?;
+        fs::write(path, s)?;
+
+        Ok(())
+    }
+
+    /// Load a session saved with [`save_session`], replacing the current session.
+    ///
+    /// The session must have been saved with the same app data type. The file is validated, and its
+    /// static files parsed, before any state is replaced, and the values bound by evaluated
+    /// statements are dropped. If the static files fail to write, the previous static files are
+    /// put back and the session is left as it was.
+    ///
+    /// External libraries are linked by path, so must exist on this machine. An alias is leaked
+    /// to satisfy [`Extern::with_alias`](linking::Extern::with_alias).
+    ///
+    /// [`save_session`]: ReplData::save_session
+    pub fn load_session<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SessionError> {
+        let s = fs::read_to_string(path)?;
+        let session = Session::parse(&s)?;
+
+        if session.data_type != self.linking.data_type {
+            return Err(SessionError::DataTypeMismatch(session.data_type));
+        }
+
+        let externs = session
+            .externs
+            .into_iter()
+            .map(|(path, alias)| {
+                match alias {
+                    Some(alias) => linking::Extern::with_alias(&path, leak(alias)),
+                    None => linking::Extern::new(&path),
+                }
+                .map_err(|e| SessionError::Extern(path, e))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // the current static files are read to be put back if the new ones fail to write
+        let previous = self
+            .static_files
+            .iter()
+            .map(|sf| fs::read_to_string(self.static_file_name(&sf.path)))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // validated, replace the session
+        let previous = std::mem::take(&mut self.static_files)
+            .into_iter()
+            .zip(previous)
+            .collect::<Vec<_>>();
+        for (sf, _) in &previous {
+            fs::remove_file(self.static_file_name(&sf.path)).ok();
+        }
+        if let Err(e) = self.write_static_files(session.static_files) {
+            for sf in std::mem::take(&mut self.static_files) {
+                fs::remove_file(self.static_file_name(&sf.path)).ok();
+            }
+            self.write_static_files(previous).ok();
+            return Err(SessionError::Io(e));
+        }
+
+        self.mods_map = session.mods;
+        self.current_mod = session.current_mod;
+        self.linking.persistent_module_code = session.persistent_module_code;
+        self.linking.external_libs = externs.into_iter().collect();
+        self.linking.executor = session.executor;
+        self.compile_options = session.compile_options;
+        self.linking.mutable = session.mutable;
+        self.editing = session.editing;
+        self.editing_src = None;
+        self.values.clear();
+
+        Ok(())
+    }
+
+    /// Write the code of parsed static files, which has the crates parsed off, and add them.
+    fn write_static_files(&mut self, files: Vec<(StaticFile, String)>) -> io::Result<()> {
+        for (sf, code) in files {
+            let file = self.static_file_name(&sf.path);
+            fs::create_dir_all(file.parent().expect("should exist"))?;
+            fs::write(file, code)?;
+            self.static_files.insert(sf);
+        }
+        Ok(())
+    }
+
+    /// Export the session as a standalone Cargo project in `dir`.
+    ///
+    /// The `lib` module becomes `src/main.rs` with its statements run in `fn main()`, other modules
+    /// become files with their statements in a `pub fn run()`. The functions return a `Result`,
+    /// so `?` works as when evaluated, and a statement group which returns early is wrapped in a
+    /// closure so the return only ends that group. Static files are copied and the referenced
+    /// crates are added as dependencies. See [`export_project`](crate::compile::export_project)
+    /// for the layout.
+    pub fn export<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        compile::export_project(
+            dir,
+            self.session().path(),
+            &self.mods_map,
+            &self.linking,
+            &self.static_files,
+            self.compile_options.edition,
+        )
+    }
+}
+
+/// A parsed session file.
+struct Session {
+    data_type: Option<String>,
+    current_mod: PathBuf,
+    mods: ModsMap,
+    static_files: Vec<(StaticFile, String)>,
+    persistent_module_code: String,
+    externs: Vec<(PathBuf, Option<String>)>,
+    executor: Option<compile::Executor>,
+    compile_options: compile::CompileOptions,
+    mutable: bool,
+    editing: Option<EditingIndex>,
+}
+
+impl Session {
+    fn parse(s: &str) -> Result<Self, SessionError> {
+        let value: Value =
+            serde_json::from_str(s).map_err(|e| SessionError::Malformed(e.to_string()))?;
+
+        let version = value["version"]
+            .as_u64()
+            .ok_or_else(|| malformed("version"))?;
+        if version != SESSION_VERSION {
+            return Err(SessionError::UnsupportedVersion(version));
+        }
+
+        let data_type = opt_str(&value["data_type"], "data_type")?;
+
+        let current_mod = PathBuf::from(get_str(&value["current_mod"], "current_mod")?);
+
+        let mut mods = ModsMap::new();
+        for m in get_array(&value["mods"], "mods")? {
+            let mut src = SourceCode::default();
+            for c in get_array(&m["crates"], "mods.crates")? {
+                let c = get_str(c, "mods.crates")?;
+                src.crates
+                    .push(CrateType::parse_str(c).map_err(|_| malformed("mods.crates"))?);
+            }
+            for i in get_array(&m["items"], "mods.items")? {
+                let code = get_str(&i["code"], "mods.items.code")?;
+                let top = get_bool(&i["top"], "mods.items.top")?;
+                src.items.push((code.to_string(), top));
+            }
+            for grp in get_array(&m["stmts"], "mods.stmts")? {
+                let stmts = get_array(grp, "mods.stmts")?
+                    .iter()
+                    .map(|s| {
+                        Ok(Statement {
+                            expr: get_str(&s["expr"], "mods.stmts.expr")?.to_string(),
+                            semi: get_bool(&s["semi"], "mods.stmts.semi")?,
+                        })
+                    })
+                    .collect::<Result<_, SessionError>>()?;
+                src.stmts.push(StmtGrp(stmts));
+            }
+            mods.insert(get_str(&m["path"], "mods.path")?.into(), src);
+        }
+        if !mods.contains_key(&current_mod) {
+            return Err(malformed("current_mod"));
+        }
+
+        let static_files = get_array(&value["static_files"], "static_files")?
+            .iter()
+            .map(|sf| {
+                let path = PathBuf::from(get_str(&sf["path"], "static_files.path")?);
+                validate_static_file_path(&path)
+                    .map_err(|e| SessionError::StaticFile(AddingStaticFileError::InvalidPath(e)))?;
+                let code = get_str(&sf["code"], "static_files.code")?;
+                let (rest, crates) = parse_crates_in_file(code);
+                let sf = StaticFile {
+                    path,
+                    codehash: Box::new(blake3::hash(code.as_bytes()).into()),
+                    crates,
+                };
+                Ok((sf, rest.to_string()))
+            })
+            .collect::<Result<_, SessionError>>()?;
+
+        let persistent_module_code =
+            get_str(&value["persistent_module_code"], "persistent_module_code")?.to_string();
+
+        let externs = get_array(&value["externs"], "externs")?
+            .iter()
+            .map(|e| {
+                Ok((
+                    get_str(&e["path"], "externs.path")?.into(),
+                    opt_str(&e["alias"], "externs.alias")?,
+                ))
+            })
+            .collect::<Result<_, SessionError>>()?;
+
+        let executor = match &value["executor"] {
+            Value::Null => None,
+            e => match get_str(&e["kind"], "executor.kind")? {
+                "block-on" => Some(compile::Executor::BlockOn),
+                "custom" => Some(compile::Executor::Custom(
+                    get_str(&e["call"], "executor.call")?.to_string(),
+                )),
+                _ => return Err(malformed("executor.kind")),
+            },
+        };
+
+        let options = &value["compile_options"];
+        let compile_options = compile::CompileOptions {
+            profile: get_parsed(&options["profile"], "compile_options.profile")?,
+            edition: get_parsed(&options["edition"], "compile_options.edition")?,
+            rustc_args: get_array(&options["rustc_args"], "compile_options.rustc_args")?
+                .iter()
+                .map(|x| get_str(x, "compile_options.rustc_args").map(String::from))
+                .collect::<Result<_, _>>()?,
+            toolchain: opt_str(&options["toolchain"], "compile_options.toolchain")?,
+            warnings: get_parsed(&options["warnings"], "compile_options.warnings")?,
+            clippy: get_bool(&options["clippy"], "compile_options.clippy")?,
+            fast_path: get_bool(&options["fast_path"], "compile_options.fast_path")?,
+        };
+
+        let mutable = get_bool(&value["mutable"], "mutable")?;
+
+        let editing = match &value["editing"] {
+            Value::Null => None,
+            e => {
+                let editing = match get_str(&e["kind"], "editing.kind")? {
+                    "stmt" => Editing::Stmt,
+                    "item" => Editing::Item,
+                    "crate" => Editing::Crate,
+                    _ => return Err(malformed("editing.kind")),
+                };
+                let index = e["index"]
+                    .as_u64()
+                    .ok_or_else(|| malformed("editing.index"))?
+                    as usize;
+                Some(EditingIndex { editing, index })
+            }
+        };
+
+        Ok(Session {
+            data_type,
+            current_mod,
+            mods,
+            static_files,
+            persistent_module_code,
+            externs,
+            executor,
+            compile_options,
+            mutable,
+            editing,
+        })
+    }
+}
+
+fn malformed(field: &str) -> SessionError {
+    SessionError::Malformed(format!("missing or invalid `{}`", field))
+}
+
+fn get_str<'a>(v: &'a Value, field: &str) -> Result<&'a str, SessionError> {
+    v.as_str().ok_or_else(|| malformed(field))
+}
+
+fn get_bool(v: &Value, field: &str) -> Result<bool, SessionError> {
+    v.as_bool().ok_or_else(|| malformed(field))
+}
+
+fn get_array<'a>(v: &'a Value, field: &str) -> Result<&'a Vec<Value>, SessionError> {
+    v.as_array().ok_or_else(|| malformed(field))
+}
+
+fn get_parsed<T: std::str::FromStr>(v: &Value, field: &str) -> Result<T, SessionError> {
+    get_str(v, field)?.parse().map_err(|_| malformed(field))
+}
+
+fn opt_str(v: &Value, field: &str) -> Result<Option<String>, SessionError> {
+    match v {
+        Value::Null => Ok(None),
+        v => get_str(v, field).map(|s| Some(s.to_string())),
+    }
+}
+
+/// Module paths are written with `/` separators on all platforms.
+fn path_str(path: &Path) -> String {
+    path.iter()
+        .map(|c| c.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn leak(s: String) -> &'static str {
+    Box::leak(s.into_boxed_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load_session() {
+        let dir = PathBuf::from("target/testing/session");
+        let mut data: ReplData<()> = ReplData::default();
+        data.with_compilation_dir(dir.join("a")).unwrap();
+
+        let src = data.mods_map.get_mut(Path::new("lib")).unwrap();
+        src.crates
+            .push(CrateType::parse_str("extern crate rand;").unwrap());
+        src.items.push(("fn a() -> i32 { 1 }".to_string(), false));
+        src.stmts.push(StmtGrp(vec![
+            Statement {
+                expr: "let b = a()".to_string(),
+                semi: true,
+            },
+            Statement {
+                expr: "b".to_string(),
+                semi: false,
+            },
+        ]));
+        crate::cmds::switch_module(&mut data, Path::new("foo/bar"));
+        data.add_static_file("stat.rs".into(), "extern crate rand;\npub fn s() {}")
+            .unwrap();
+        data.persistent_module_code().push_str("use std::io;");
+        data.with_async(compile::Executor::Custom(
+            "futures::executor::block_on".to_string(),
+        ));
+        data.compile_options.profile = compile::Profile::OptLevel("s".to_string());
+        data.compile_options.edition = compile::Edition::E2021;
+        data.compile_options.rustc_args = vec!["-C".into(), "target-cpu=native".into()];
+        data.compile_options.warnings = compile::Warnings::NewInput;
+        data.linking.mutable = true;
+        data.editing = Some(EditingIndex {
+            editing: Editing::Item,
+            index: 0,
+        });
+
+        let file = dir.join("session.json");
+        data.save_session(&file).unwrap();
+
+        let mut loaded: ReplData<()> = ReplData::default();
+        loaded.with_compilation_dir(dir.join("b")).unwrap();
+        loaded.load_session(&file).unwrap();
+
+        assert_eq!(loaded.current_mod(), Path::new("foo/bar"));
+        assert_eq!(
+            loaded.mods_map().keys().collect::<Vec<_>>(),
+            vec![Path::new("foo"), Path::new("foo/bar"), Path::new("lib")]
+        );
+        let src = &loaded.mods_map()[Path::new("lib")];
+        assert_eq!(src.crates[0].cargo_name, "rand");
+        assert_eq!(src.items, vec![("fn a() -> i32 { 1 }".to_string(), false)]);
+        assert_eq!(&src.stmts[0].src_line(), "let b = a(); b");
+        let sf = loaded.static_files().iter().next().unwrap();
+        assert_eq!(sf.path, Path::new("stat.rs"));
+        assert_eq!(sf.crates[0].cargo_name, "rand");
+        assert_eq!(
+            fs::read_to_string(dir.join("b/src/stat.rs")).unwrap(),
+            "\npub fn s() {}"
+        );
+        assert_eq!(loaded.linking.persistent_module_code, "use std::io;");
+        assert_eq!(
+            loaded.linking.executor,
+            Some(compile::Executor::Custom(
+                "futures::executor::block_on".to_string()
+            ))
+        );
+        assert_eq!(loaded.compile_options, data.compile_options);
+        assert!(loaded.linking.mutable);
+        assert!(matches!(
+            loaded.editing,
+            Some(EditingIndex {
+                editing: Editing::Item,
+                index: 0
+            })
+        ));
+
+        // saving the loaded session gives the same file
+        loaded.save_session(dir.join("session2.json")).unwrap();
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            fs::read_to_string(dir.join("session2.json")).unwrap()
+        );
+    }
+
+    #[test]
+    fn export_session() {
+        let dir = PathBuf::from("target/testing/export-session");
+        let mut data: ReplData<()> = ReplData::default();
+        data.with_compilation_dir(dir.join("compile")).unwrap();
+
+        let src = data.mods_map.get_mut(Path::new("lib")).unwrap();
+        src.crates
+            .push(CrateType::parse_str("extern crate rand;").unwrap());
+        src.items.push(("fn a() -> i32 { 1 }".to_string(), false));
+        src.stmts.push(StmtGrp(vec![Statement {
+            expr: "a()".to_string(),
+            semi: false,
+        }]));
+        src.stmts.push(StmtGrp(vec![Statement {
+            expr: "println!(\"{}\", out0)".to_string(),
+            semi: true,
+        }]));
+        crate::cmds::switch_module(&mut data, Path::new("foo/bar"));
+        data.mods_map
+            .get_mut(Path::new("foo/bar"))
+            .unwrap()
+            .stmts
+            .push(StmtGrp(vec![Statement {
+                expr: "stat::s()".to_string(),
+                semi: false,
+            }]));
+        data.add_static_file("stat.rs".into(), "extern crate rand;\npub fn s() {}")
+            .unwrap();
+
+        let export = dir.join("My Project");
+        data.export(&export).unwrap();
+
+        let toml = fs::read_to_string(export.join("Cargo.toml")).unwrap();
+        assert!(toml.contains("name = \"my-project\""), "{}", toml);
+        assert!(toml.contains("[dependencies]\nrand = \"*\"\n"), "{}", toml);
+        assert!(!toml.contains("cdylib"));
+        assert_eq!(
+            fs::read_to_string(export.join("src/main.rs")).unwrap(),
+            "mod foo;\nmod stat;\n\nfn main() -> Result<(), Box<dyn std::error::Error>> {\n    let out0 = a();\n    println!(\"{}\", out0);\n    Ok(())\n}\n\nfn a() -> i32 { 1 }\n"
+        );
+        assert_eq!(
+            fs::read_to_string(export.join("src/foo.rs")).unwrap(),
+            "mod bar;\nuse crate::stat;\n"
+        );
+        assert_eq!(
+            fs::read_to_string(export.join("src/foo/bar.rs")).unwrap(),
+            "use crate::stat;\n\npub fn run() -> Result<(), Box<dyn std::error::Error>> {\n    stat::s();\n    Ok(())\n}\n"
+        );
+        assert_eq!(
+            fs::read_to_string(export.join("src/stat.rs")).unwrap(),
+            "\npub fn s() {}"
+        );
+    }
+
+    #[test]
+    fn load_session_errors() {
+        let parse = |s: &str| Session::parse(s).map(|_| ()).unwrap_err().to_string();
+
+        assert!(parse("not json").starts_with("session file is malformed: "));
+        assert_eq!(
+            parse(r#"{ "version": 1 }"#),
+            "session file version 1 is not supported, expecting version 2"
+        );
+        assert_eq!(
+            parse(r#"{ "version": 2, "data_type": null }"#),
+            "session file is malformed: missing or invalid `current_mod`"
+        );
+
+        let dir = PathBuf::from("target/testing/session-errors");
+        fs::create_dir_all(&dir).unwrap();
+        let mut data: ReplData<()> = ReplData::default();
+        data.with_compilation_dir(&dir).unwrap();
+        let file = dir.join("session.json");
+        data.save_session(&file).unwrap();
+
+        let mut data: ReplData<String> = ReplData::default();
+        data = unsafe { data.set_data_type("String") };
+        assert_eq!(
+            data.load_session(&file).unwrap_err().to_string(),
+            "session was saved without an app data type"
+        );
+
+        // a failed load leaves the session as it was
+        let mut saved: ReplData<()> = ReplData::default();
+        saved.with_compilation_dir(dir.join("saved")).unwrap();
+        saved
+            .add_static_file("x/mod.rs".into(), "pub fn x() {}")
+            .unwrap();
+        saved.save_session(&file).unwrap();
+
+        let mut data: ReplData<()> = ReplData::default();
+        data.with_compilation_dir(dir.join("data")).unwrap();
+        data.add_static_file("keep.rs".into(), "extern crate rand;\npub fn k() {}")
+            .unwrap();
+        crate::cmds::switch_module(&mut data, Path::new("foo"));
+        fs::write(data.static_file_name(Path::new("x")), "").unwrap(); // blocks `x/mod.rs`
+        assert!(data.load_session(&file).is_err());
+        assert_eq!(data.current_mod(), Path::new("foo"));
+        let paths = data.static_files().iter().map(|x| x.path.as_path());
+        assert_eq!(paths.collect::<Vec<_>>(), vec![Path::new("keep.rs")]);
+        assert_eq!(
+            fs::read_to_string(data.static_file_name(Path::new("keep.rs"))).unwrap(),
+            "\npub fn k() {}"
+        );
+    }
+}
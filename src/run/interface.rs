@@ -1,4 +1,6 @@
 use super::map_xterm_err;
+use crate::compile::Interrupt;
+use crate::compile::{self, CompileEvent};
 use crate::output::OutputChange;
 use crossbeam_channel::{unbounded, Receiver};
 use crossterm as xterm;
@@ -21,9 +23,20 @@ use xterm::{
 
 const TAB_WIDTH: usize = 8;
 
-pub struct Screen(pub(super) Receiver<Event>);
+pub struct Screen {
+    rx: Receiver<Event>,
+    /// Events received while evaluating, to be read before the receiver.
+    pending: VecDeque<Event>,
+}
 
 impl Screen {
+    pub(super) fn with_events(rx: Receiver<Event>) -> Self {
+        Screen {
+            rx,
+            pending: VecDeque::new(),
+        }
+    }
+
     pub fn new() -> io::Result<Self> {
         let (tx, rx) = unbounded();
         std::thread::Builder::new()
@@ -43,7 +56,7 @@ impl Screen {
                     Err(_) => break,
                 }
             })?;
-        Ok(Screen(rx))
+        Ok(Screen::with_events(rx))
     }
 
     pub fn begin_interface_input<'a>(
@@ -146,7 +159,7 @@ impl<'a> Interface<'a> {
             code: xterm::event::KeyCode::Char('c'),
         });
 
-        while let Ok(ev) = self.screen.0.recv() {
+        while let Some(ev) = self.next_event() {
             last = ev;
             if events.contains(&ev) {
                 break;
@@ -242,6 +255,52 @@ impl<'a> Interface<'a> {
         self.history.push_back(line);
     }
 
+    /// The next event, with the events held back while watching for an interrupt first.
+    fn next_event(&mut self) -> Option<Event> {
+        let screen = &mut self.screen;
+        screen.pending.pop_front().or_else(|| screen.rx.recv().ok())
+    }
+
+    /// Run `f`, interrupting it through `interrupt` if Ctrl-C is pressed.
+    ///
+    /// Other events are kept to be read once `f` returns.
+    pub fn watch_interrupt<F, R>(&mut self, interrupt: &Interrupt, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        const BREAK: Event = Key(KeyEvent {
+            modifiers: KeyModifiers::CONTROL,
+            code: Char('c'),
+        });
+
+        let done = AtomicBool::new(false);
+        let rx = &self.screen.rx;
+
+        let (r, events) = std::thread::scope(|s| {
+            let watcher = s.spawn(|| {
+                let mut events = Vec::new();
+                while !done.load(Ordering::Acquire) {
+                    match rx.recv_timeout(std::time::Duration::from_millis(50)) {
+                        Ok(ev) if ev == BREAK => interrupt.interrupt(),
+                        Ok(ev) => events.push(ev),
+                        Err(e) if e.is_timeout() => (),
+                        Err(_) => break,
+                    }
+                }
+                events
+            });
+
+            let r = f();
+            done.store(true, Ordering::Release);
+            (r, watcher.join().unwrap_or_default())
+        });
+
+        self.screen.pending.extend(events);
+        r
+    }
+
     fn apply_history_line(&mut self) {
         // get line
         let line = self
@@ -476,20 +535,50 @@ fn overwrite_text<T: fmt::Display + Clone>(
 /// Returns the number of lines the written text accounts for
 pub fn write_output_chg(current_lines_covered: u16, change: OutputChange) -> io::Result<u16> {
     use OutputChange::*;
-    let mut stdout = stdout();
     match change {
-        CurrentLine(line) => {
-            for _ in 1..current_lines_covered {
-                queue!(stdout, Clear(ClearType::CurrentLine), MoveUp(1))
-                    .map_err(|e| map_xterm_err(e, "Clear a line"))?;
-            }
-            let mut stdout = erase_current_line(stdout)?;
-            queue!(stdout, Print(&line)).map_err(|e| map_xterm_err(e, "printing a line"))?;
-            stdout.flush()?;
-            Ok(lines_covered(0, term_width_nofail(), line.chars().count()) as u16)
-        }
-        NewLine => writeln!(&mut stdout).map(|_| 1),
+        CurrentLine(line) | Captured(_, line) => write_current_line(current_lines_covered, &line),
+        NewLine => writeln!(&mut stdout()).map(|_| 1),
+        Progress(event) => match event {
+            CompileEvent::CrateStarted(name, _, progress)
+            | CompileEvent::CrateFinished(name, progress) => write_current_line(
+                current_lines_covered,
+                &progress_bar(&name, progress, term_width_nofail()),
+            ),
+            CompileEvent::Status(line) => write_current_line(current_lines_covered, &line),
+            _ => Ok(current_lines_covered),
+        },
+    }
+}
+
+/// Replaces the current line, returning the lines it covers.
+fn write_current_line(current_lines_covered: u16, line: &str) -> io::Result<u16> {
+    let mut stdout = stdout();
+    for _ in 1..current_lines_covered {
+        queue!(stdout, Clear(ClearType::CurrentLine), MoveUp(1))
+            .map_err(|e| map_xterm_err(e, "Clear a line"))?;
     }
+    let mut stdout = erase_current_line(stdout)?;
+    queue!(stdout, Print(line)).map_err(|e| map_xterm_err(e, "printing a line"))?;
+    stdout.flush()?;
+    Ok(lines_covered(0, term_width_nofail(), line.chars().count()) as u16)
+}
+
+/// Renders a progress bar, such as `Building [=====>    ] 12/40: rand`, fitting within `width`.
+fn progress_bar(name: &str, progress: compile::Progress, width: usize) -> String {
+    const BAR: usize = 20;
+    let filled = match progress.total {
+        0 => 0,
+        total => progress.finished * BAR / total,
+    };
+    let bar = format!(
+        "Building [{}{}{}] {}: {}",
+        "=".repeat(filled.saturating_sub(1)),
+        if filled > 0 { ">" } else { "" },
+        " ".repeat(BAR - filled),
+        progress,
+        name
+    );
+    bar.chars().take(width.saturating_sub(1)).collect()
 }
 
 /// Resets position to start of line.
@@ -606,6 +695,24 @@ mod tests {
         assert_eq!(inputbuf.cursor_delta(inputbuf.pos, 4), (3, 0));
     }
 
+    #[test]
+    fn test_progress_bar() {
+        let progress = |finished, total| compile::Progress { finished, total };
+        assert_eq!(
+            progress_bar("rand", progress(10, 40), 80),
+            "Building [====>               ] 10/40: rand"
+        );
+        assert_eq!(
+            progress_bar("rand", progress(0, 0), 80),
+            "Building [                    ] 0/0: rand"
+        );
+        assert_eq!(
+            progress_bar("rand", progress(40, 40), 80),
+            "Building [===================>] 40/40: rand"
+        );
+        assert_eq!(progress_bar("rand", progress(1, 2), 10), "Building ");
+    }
+
     #[test]
     #[cfg(feature = "test-runnable")]
     fn verify_terminal_output() {
@@ -182,6 +182,9 @@ impl<'a, D, T, U, V> RunCallbacks<'a, D, T, U, V> {
 /// Available with the `runnable` feature and when the REPL is in the `Read` state.
 impl<D> Repl<Read, D> {
     /// Run the repl inside the terminal, consuming the repl. Returns the output of the REPL.
+    ///
+    /// Pressing Ctrl-C while compiling or evaluating interrupts it (see
+    /// [`ReplData::interrupt_handle`]), pressing it at the prompt exits.
     pub fn run<T, U, V>(self, run_callbacks: RunCallbacks<D, T, U, V>) -> io::Result<String>
     where
         T: FnMut(&Repl<Print, D>) -> kserd::fmt::FormattingConfig,
@@ -264,7 +267,8 @@ where
         match read.read() {
             ReadResult::Read(repl) => read = repl,
             ReadResult::Eval(repl) => {
-                match do_eval(repl, &mut runcb) {
+                let interrupt = repl.data.interrupt_handle();
+                match interface.watch_interrupt(&interrupt, || do_eval(repl, &mut runcb)) {
                     (mut repl, Signal::Exit) => {
                         // run exit function
                         if let Some(exitfn) = runcb.exitfn {
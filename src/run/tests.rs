@@ -198,7 +198,7 @@ fn interface_integration() {
     let (tx, rx) = unbounded();
     let tx = Tx(tx);
     let mut inputbuf = InputBuffer::new();
-    let mut screen = Screen(rx);
+    let mut screen = Screen::with_events(rx);
     writeln!(io::stdout()).unwrap();
     slp();
     let mut history = std::collections::VecDeque::from(vec![String::default(); 2]);
@@ -362,7 +362,7 @@ fn colour_off() {
 
 fn fire_off_run(rx: Receiver<Event>) -> JoinHandle<Result<String>> {
     std::thread::spawn(|| {
-        let screen = Screen(rx);
+        let screen = Screen::with_events(rx);
         let repl = crate::repl::Repl::<_, ()>::default();
         run(repl, RunCallbacks::new(&mut ()), || Ok(screen))
     })
@@ -75,3 +75,542 @@ a
         }
     };
 }
+
+#[cfg(feature = "test-runnable")]
+fn eval_input(
+    repl: Repl<repl::Read, ()>,
+    input: &str,
+) -> (Repl<repl::Read, ()>, Option<(usize, Kserd<'static>)>) {
+    let mut repl = repl;
+    repl.line_input(input);
+    match repl.read() {
+        ReadResult::Read(_) => panic!("should be at Eval state!"),
+        ReadResult::Eval(repl) => {
+            let repl::EvalResult { repl, signal } = repl.eval(&mut ());
+            assert_eq!(signal, Signal::None);
+            repl.print()
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn previous_statements_are_not_rerun() {
+    let repl = chg_compile_dir(repl!());
+
+    std::env::remove_var("PAPYRUS_RERUN_TEST");
+    let (repl, r) = eval_input(
+        repl,
+        "let a = std::env::var(\"PAPYRUS_RERUN_TEST\").is_ok();\na\n",
+    );
+    assert_eq!(r, Some((0, Kserd::new_bool(false))));
+
+    // if the first input was run again, `a` would be true
+    std::env::set_var("PAPYRUS_RERUN_TEST", "1");
+    let (repl, r) = eval_input(repl, "a\n");
+    assert_eq!(r, Some((1, Kserd::new_bool(false))));
+
+    // borrowing bindings can not be persisted, the input is run again
+    let (repl, _) = eval_input(
+        repl,
+        "let s = String::from(\"hello\");\nlet r = &s;\nr.len()\n",
+    );
+    let (_, r) = eval_input(repl, "r.len() + out1 as usize\n");
+    assert_eq!(r, Some((3, Kserd::new_num(5))));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn outputs_without_clone_are_not_rerun() {
+    let repl = chg_compile_dir(repl!());
+
+    std::env::remove_var("PAPYRUS_OUT_RUNS");
+    let (repl, r) = eval_input(
+        repl,
+        "std::env::set_var(\"PAPYRUS_OUT_RUNS\", std::env::var(\"PAPYRUS_OUT_RUNS\").unwrap_or_default() + \"1\");\nstd::sync::Mutex::new(5u8)\n",
+    );
+    assert_eq!(r.map(|x| x.0), Some(0));
+
+    // the `Mutex` is not `Clone`, it is stored by move rather than running the input again
+    let (repl, r) = eval_input(repl, "*out0.lock().unwrap()\n");
+    assert_eq!(r, Some((1, Kserd::new_num(5u8))));
+    let (_, r) = eval_input(repl, "out1 + *out0.lock().unwrap()\n");
+    assert_eq!(r, Some((2, Kserd::new_num(10u8))));
+    assert_eq!(std::env::var("PAPYRUS_OUT_RUNS").as_deref(), Ok("1"));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn worker_survives_abort() {
+    let mut repl = chg_compile_dir(repl!());
+    repl.data.with_worker();
+
+    let (repl, r) = eval_input(repl, "let a = 1;\na\n");
+    assert_eq!(r, Some((0, Kserd::new_num(1))));
+
+    // an abort would end this process if evaluated in it
+    let (repl, r) = eval_input(repl, "std::process::abort()\n");
+    assert_eq!(r, None);
+
+    // the worker is restarted, running the statements again to recover `a`
+    let (_, r) = eval_input(repl, "a + 1\n");
+    assert_eq!(r, Some((1, Kserd::new_num(2))));
+}
+
+#[test]
+#[cfg(all(feature = "test-runnable", target_os = "linux"))]
+fn captured_output_is_written_to_output() {
+    let mut repl = chg_compile_dir(repl!());
+    repl.data.capture_output = true;
+
+    let (repl, r) = eval_input(repl, "println!(\"Hello from stdout\");\n1\n");
+    assert_eq!(r, Some((0, Kserd::new_num(1))));
+    assert!(repl.output().contains("Hello from stdout\n"));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn worker_output_is_captured() {
+    let mut repl = chg_compile_dir(repl!());
+    repl.data.with_worker();
+    repl.data.capture_output = true;
+
+    let (repl, r) = eval_input(repl, "eprintln!(\"Hello from the worker\");\n1\n");
+    assert_eq!(r, Some((0, Kserd::new_num(1))));
+    assert!(repl.output().contains("Hello from the worker\n"));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn panic_is_rendered_under_input() {
+    let repl = chg_compile_dir(repl!());
+
+    let (repl, r) = eval_input(
+        repl,
+        "let a = 1;\nSome(a).filter(|x| *x > 1).expect(\"oh no\")\n",
+    );
+    assert_eq!(r, None);
+    let output = repl.output();
+    assert!(output.contains("evaluation panicked: oh no"), "{}", output);
+    assert!(
+        output.contains(" --> [lib] out0\nSome(a).filter(|x| *x > 1).expect(\"oh no\")\n"),
+        "{}",
+        output
+    );
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn panic_showing_a_stored_value() {
+    let repl = chg_compile_dir(repl!());
+
+    // the value is stored before it is shown, it is dropped before its library is unloaded
+    let (repl, r) = eval_input(
+        repl,
+        "struct P(String);\nimpl std::fmt::Debug for P { fn fmt(&self, _: &mut std::fmt::Formatter) -> std::fmt::Result { panic!(\"no show\") } }\nP(String::from(\"p\"))\n",
+    );
+    assert_eq!(r, None);
+    assert!(repl.output().contains("evaluation panicked: no show"));
+
+    let (_, r) = eval_input(repl, "1 + 1\n");
+    assert_eq!(r, Some((0, Kserd::new_num(2))));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn timed_out_evaluation_is_abandoned() {
+    let mut repl = chg_compile_dir(repl!());
+    repl.data.timeout = Some(std::time::Duration::from_millis(500));
+
+    let (repl, r) = eval_input(
+        repl,
+        "std::thread::sleep(std::time::Duration::from_secs(60));\n1\n",
+    );
+    assert_eq!(r, None);
+    assert!(repl.output().contains("evaluation timed out after 0.5s"));
+
+    // the REPL is still usable
+    let (_, r) = eval_input(repl, "2\n");
+    assert_eq!(r, Some((0, Kserd::new_num(2))));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn timeout_with_app_data_is_rejected() {
+    let mut repl = chg_compile_dir(repl!(String));
+    repl.data.timeout = Some(std::time::Duration::from_millis(500));
+    let mut app_data = String::from("hello");
+
+    repl.line_input("app_data.len()\n");
+    let repl = match repl.read() {
+        ReadResult::Read(_) => panic!("should be at Eval state!"),
+        ReadResult::Eval(repl) => repl,
+    };
+    let (mut repl, r) = repl.eval(&mut app_data).repl.print();
+    assert_eq!(r, None);
+    assert!(repl
+        .output()
+        .contains("cannot interrupt an evaluation borrowing app data"));
+
+    // a type probe does not run, so it is not rejected
+    repl.line_input(":type app_data.as_str()\n");
+    let repl = match repl.read() {
+        ReadResult::Read(_) => panic!("should be at Eval state!"),
+        ReadResult::Eval(repl) => repl,
+    };
+    let (mut repl, r) = repl.eval(&mut app_data).repl.print();
+    assert_eq!(r, None);
+    assert!(
+        repl.output().contains(":type app_data.as_str()\n\n&str\n"),
+        "{}",
+        repl.output()
+    );
+
+    // the input was not kept
+    repl.data.timeout = None;
+    repl.line_input("app_data.len()\n");
+    let repl = match repl.read() {
+        ReadResult::Read(_) => panic!("should be at Eval state!"),
+        ReadResult::Eval(repl) => repl,
+    };
+    let (_, r) = repl.eval(&mut app_data).repl.print();
+    assert_eq!(r, Some((0, Kserd::new_num(5))));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn async_evaluation_can_be_cancelled() {
+    let mut repl = chg_compile_dir(repl!());
+    repl.data.with_worker();
+
+    repl.line_input("loop { std::thread::yield_now(); }\n1\n");
+    let repl = match repl.read() {
+        ReadResult::Read(_) => panic!("should be at Eval state!"),
+        ReadResult::Eval(repl) => repl,
+    };
+    let evaluating = repl.eval_async(&std::sync::Arc::new(std::sync::Mutex::new(())));
+    while !evaluating.completed() {
+        evaluating.cancel();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    let (repl, r) = evaluating.wait().repl.print();
+    assert_eq!(r, None);
+    assert!(repl.output().contains("interrupted"));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn exported_session_runs() {
+    let repl = chg_compile_dir(repl!());
+
+    let (repl, _) = eval_input(repl, "fn double(x: i32) -> i32 { x * 2 }\ndouble(21)\n");
+    let (repl, _) = eval_input(repl, "println!(\"answer {}\", out0)\n");
+    // `?` works in the exported function, and an early return ends only its group
+    let (repl, _) = eval_input(repl, "let n: i32 = \"2\".parse()?;\nn\n");
+    std::env::remove_var("PAPYRUS_EXPORT_RETURN");
+    let (repl, _) = eval_input(
+        repl,
+        "if std::env::var(\"PAPYRUS_EXPORT_RETURN\").is_ok() { return 0; }\nprintln!(\"not returned\")\n",
+    );
+    let (repl, _) = eval_input(repl, "println!(\"after {}\", n)\n");
+
+    let dir = PathBuf::from("target/testing/exported-session");
+    repl.data.export(&dir).unwrap();
+
+    let output = std::process::Command::new("cargo")
+        .args(["run", "--quiet"])
+        .env("PAPYRUS_EXPORT_RETURN", "1")
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "answer 42\nafter 2\n"
+    );
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn exported_session_with_modules_and_inner_attributes_runs() {
+    let repl = chg_compile_dir(repl!());
+
+    let (repl, _) = eval_input(repl, "#![allow(unused)]\n");
+    let (repl, _) = eval_input(repl, ":mod switch helper\n");
+    let (repl, _) = eval_input(repl, "pub fn one() -> i32 { 1 }\n");
+    let (repl, _) = eval_input(repl, ":mod switch lib\n");
+    let (repl, _) = eval_input(repl, "println!(\"one {}\", crate::helper::one())\n");
+
+    let dir = PathBuf::from("target/testing/exported-session-modules");
+    repl.data.export(&dir).unwrap();
+
+    let output = std::process::Command::new("cargo")
+        .args(["run", "--quiet"])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "one 1\n");
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn warnings_of_new_input_are_shown() {
+    let mut repl = chg_compile_dir(repl!());
+    repl.data.compile_options.warnings = papyrus::compile::Warnings::NewInput;
+
+    let (repl, r) = eval_input(repl, "let mut a = 1;\na\n");
+    assert_eq!(r, Some((0, Kserd::new_num(1))));
+    let warning = "warning[unused_mut]: variable does not need to be mutable\n --> [lib] out0\nlet mut a = 1\n    ^^^^^\n";
+    assert!(repl.output().contains(warning), "{}", repl.output());
+
+    // the warning is in a previous input so is not shown again
+    let (repl, r) = eval_input(repl, "a + 1\n");
+    assert_eq!(r, Some((1, Kserd::new_num(2))));
+    assert_eq!(repl.output().matches(warning).count(), 1);
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn try_and_return_exit_early() {
+    let repl = chg_compile_dir(repl!());
+
+    let (repl, r) = eval_input(repl, "let a = 1;\na\n");
+    assert_eq!(r, Some((0, Kserd::new_num(1))));
+
+    let (repl, r) = eval_input(
+        repl,
+        "let f = std::fs::File::open(\"not-a-file\")?;\nf.metadata()?.len()\n",
+    );
+    assert_eq!(r, None);
+    let output = repl.output();
+    assert!(output.contains("evaluation returned error: "), "{}", output);
+
+    // statements after the return do not run and the input is not kept
+    let (repl, r) = eval_input(repl, "if a == 1 { return \"one\"; }\na + 1\n");
+    assert_eq!(r, None);
+    assert!(
+        repl.output().contains("returned early: \"one\""),
+        "{}",
+        repl.output()
+    );
+
+    let (_, r) = eval_input(repl, "a + 1\n");
+    assert_eq!(r, Some((1, Kserd::new_num(2))));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn async_statements_are_awaited() {
+    let mut repl = chg_compile_dir(repl!());
+    repl.data.with_async(papyrus::compile::Executor::BlockOn);
+
+    let (repl, r) = eval_input(
+        repl,
+        "async fn double(x: u8) -> u8 { x * 2 }\nlet a = double(2).await;\na\n",
+    );
+    assert_eq!(r, Some((0, Kserd::new_num(4))));
+
+    // bindings persist between asynchronous evaluations
+    let (repl, r) = eval_input(repl, "double(a).await\n");
+    assert_eq!(r, Some((1, Kserd::new_num(8))));
+
+    let (mut repl, _) = eval_input(repl, ":type double(a).await\n");
+    assert!(
+        repl.output().contains(":type double(a).await\n\nu8\n"),
+        "{}",
+        repl.output()
+    );
+
+    // a timed out evaluation drops its future
+    std::env::remove_var("PAPYRUS_ASYNC_DROPPED");
+    repl.data.timeout = Some(std::time::Duration::from_millis(500));
+    let (repl, r) = eval_input(
+        repl,
+        "struct Flag;\nimpl Drop for Flag { fn drop(&mut self) { std::env::set_var(\"PAPYRUS_ASYNC_DROPPED\", \"1\"); } }\nlet _flag = Flag;\nstd::future::pending::<()>().await\n",
+    );
+    assert_eq!(r, None);
+    assert!(repl.output().contains("evaluation timed out after 0.5s"));
+    assert!(std::env::var("PAPYRUS_ASYNC_DROPPED").is_ok());
+
+    let (_, r) = eval_input(repl, "a + 1\n");
+    assert_eq!(r, Some((2, Kserd::new_num(5))));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn async_cancel_before_the_future_is_built() {
+    // the executor is evaluated before its future, so the timeout trips before the future exists
+    let mut repl = chg_compile_dir(repl!());
+    repl.data.with_async(papyrus::compile::Executor::Custom(
+        "({ std::thread::sleep(std::time::Duration::from_millis(300)); crate::papyrus_async::block_on })"
+            .to_string(),
+    ));
+    repl.data.timeout = Some(std::time::Duration::from_millis(50));
+
+    std::env::remove_var("PAPYRUS_EARLY_CANCEL_DROPPED");
+    let (repl, r) = eval_input(
+        repl,
+        "struct Flag;\nimpl Drop for Flag { fn drop(&mut self) { std::env::set_var(\"PAPYRUS_EARLY_CANCEL_DROPPED\", \"1\"); } }\nlet _flag = Flag;\nstd::future::pending::<()>().await\n",
+    );
+    assert_eq!(r, None);
+    assert!(
+        repl.output().contains("evaluation timed out"),
+        "{}",
+        repl.output()
+    );
+    assert!(std::env::var("PAPYRUS_EARLY_CANCEL_DROPPED").is_ok());
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn values_without_to_kserd_fall_back() {
+    use papyrus::code::Repr;
+
+    let repl = chg_compile_dir(repl!());
+    let repr =
+        |r: Option<(usize, Kserd)>| r.map(|(_, k)| (Repr::of(&k), k.str().map(String::from)));
+
+    let (repl, r) = eval_input(repl, "#[derive(Debug)]\nstruct A(u8);\nA(1)\n");
+    assert_eq!(repr(r), Some((Repr::Debug, Some("A(1)".to_string()))));
+    assert!(repl.output().contains("(Debug) A(1)"));
+
+    let (repl, r) = eval_input(
+        repl,
+        "struct B;\nimpl std::fmt::Display for B { fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { write!(f, \"b\") } }\nB\n",
+    );
+    assert_eq!(repr(r), Some((Repr::Display, Some("b".to_string()))));
+
+    let (repl, r) = eval_input(repl, "struct C;\nC\n");
+    let (repr, name) = repr(r).unwrap();
+    assert_eq!(repr, Repr::TypeName);
+    assert!(name.unwrap().ends_with("::C"));
+
+    // ToKserd is preferred over Debug
+    let (repl, r) = eval_input(repl, "1u8\n");
+    assert_eq!(r, Some((3, Kserd::new_num(1u8))));
+
+    // as are returned values
+    let (repl, r) = eval_input(repl, "if true { return A(2); }\n1\n");
+    assert_eq!(r, None);
+    assert!(repl.output().contains("returned early: "));
+    assert!(repl.output().contains("A(2)"));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn type_of_expressions_and_outputs() {
+    let repl = chg_compile_dir(repl!());
+
+    let (repl, r) = eval_input(repl, "let v = vec![1u8, 2];\nv.len()\n");
+    assert_eq!(r, Some((0, Kserd::new_num(2))));
+
+    let (repl, r) = eval_input(repl, ":type out0\n");
+    assert_eq!(r, None);
+    assert!(repl.output().contains("usize"), "{}", repl.output());
+
+    let (repl, _) = eval_input(repl, ":type v.iter().map(|x| *x as u16)\n");
+    assert!(
+        repl.output()
+            .contains("core::iter::adapters::map::Map<core::slice::iter::Iter<'_, u8>"),
+        "{}",
+        repl.output()
+    );
+
+    // the probe is not kept, and the values are
+    let (repl, r) = eval_input(repl, "v[1]\n");
+    assert_eq!(r, Some((1, Kserd::new_num(2u8))));
+
+    // the expression is not run, and a move does not consume the binding
+    std::env::remove_var("PAPYRUS_TYPE_RAN");
+    let (repl, _) = eval_input(
+        repl,
+        ":type { std::env::set_var(\"PAPYRUS_TYPE_RAN\", \"1\"); v.into_iter() }\n",
+    );
+    assert!(
+        repl.output()
+            .contains("alloc::vec::into_iter::IntoIter<u8>"),
+        "{}",
+        repl.output()
+    );
+    assert!(std::env::var("PAPYRUS_TYPE_RAN").is_err());
+
+    // the module's statements are not run again, even those whose values are not stored
+    let (repl, r) = eval_input(
+        repl,
+        "std::env::set_var(\"PAPYRUS_TYPE_RERUN\", \"1\");\nlet s = String::from(\"ab\");\nlet r = &s;\nr.len()\n",
+    );
+    assert_eq!(r, Some((2, Kserd::new_num(2usize))));
+    std::env::remove_var("PAPYRUS_TYPE_RERUN");
+    let (repl, _) = eval_input(repl, ":type r\n");
+    assert!(
+        repl.output().contains("&alloc::string::String"),
+        "{}",
+        repl.output()
+    );
+    assert!(std::env::var("PAPYRUS_TYPE_RERUN").is_err());
+
+    // the sources and an edit in progress are left as they were
+    let mut repl = repl;
+    let edit = repl::EditingIndex {
+        editing: repl::Editing::Stmt,
+        index: 0,
+    };
+    repl.data.editing = Some(edit);
+    let (mut repl, _) = eval_input(repl, ":type 5u16\n");
+    assert!(repl.output().contains("u16"), "{}", repl.output());
+    assert_eq!(repl.data.editing.map(|x| x.index), Some(0));
+    assert_eq!(repl.data.current_src().stmts.len(), 3);
+    repl.data.editing = None;
+    let (_, r) = eval_input(repl, "v.len()\n");
+    assert_eq!(r, Some((3, Kserd::new_num(2usize))));
+}
+
+#[test]
+#[cfg(feature = "test-runnable")]
+fn history_lists_indexed_inputs() {
+    let repl = chg_compile_dir(repl!());
+
+    let (repl, _) = eval_input(repl, "fn two() -> u8 { 2 }\nlet a = two();\na\n");
+    let (repl, _) = eval_input(repl, "vec![2u8]\n");
+    let (repl, r) = eval_input(repl, ":history\n");
+    assert_eq!(r, None);
+    let out = repl.output();
+    assert!(out.contains("[lib]\n"), "{}", out);
+    assert!(out.contains("  item 0: fn two() -> u8"), "{}", out);
+    assert!(
+        out.contains("  stmt 0 (out0: u8): let a = two(); a\n"),
+        "{}",
+        out
+    );
+    assert!(
+        out.contains("  stmt 1 (out1: alloc::vec::Vec<u8>): vec![2u8]\n"),
+        "{}",
+        out
+    );
+
+    // the type of an altered statement group is forgotten
+    let mut repl = repl;
+    repl.data.editing = Some(repl::EditingIndex {
+        editing: repl::Editing::Stmt,
+        index: 0,
+    });
+    let (repl, _) = eval_input(repl, "two()\n");
+    let (repl, _) = eval_input(repl, ":ls\n");
+    let out = repl.output();
+    assert!(out.contains("  stmt 0 (out0): two()\n"), "{}", out);
+    assert!(
+        out.contains("  stmt 1 (out1: alloc::vec::Vec<u8>)"),
+        "{}",
+        out
+    );
+}